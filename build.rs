@@ -0,0 +1,22 @@
+//! Build script which makes information about the build available to the server.
+//!
+//! It sets the `GIT_COMMIT_HASH` environment variable at compile time,
+//! which holds the hash of the commit being built, or "unknown" if it
+//! cannot be determined (for example, when building outside a git repository).
+
+use std::process::Command;
+
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".into());
+
+    println!("cargo:rustc-env=GIT_COMMIT_HASH={}", hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
@@ -3,24 +3,158 @@
 //! This module provides data types and request guards for
 //! authenticating and interacting with administrators.
 //!
-//! Administrator credentials are held in the `admins` table, which
-//! should be populated from outside the program, since the server
-//! only reads its contents.
+//! Administrator credentials are held in the `admins` table. The first
+//! administrator should be added from outside the program. Afterwards,
+//! administrators can add and remove other accounts. The number of accounts
+//! can be capped through the `max_admins` setting.
+//!
+//! Admin passwords are stored as salted Argon2 hashes, encoded as
+//! [PHC strings](https://github.com/P-H-C/phc-string-format/blob/master/phc-sf-spec.md),
+//! such as the ones produced by `hash_password`, or by the `argon2` command-line
+//! utility when called with the `-e` flag. Older, unsalted SHA-256 hashes
+//! are still accepted, and are replaced with Argon2 hashes on the next login.
 
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use rocket::outcome::try_outcome;
 use rocket::request::{FromRequest, Outcome, Request};
 use rocket_sync_db_pools::postgres::row::Row;
-use rocket_sync_db_pools::postgres::Client;
 use sha2::{Digest, Sha256};
 
-use crate::db;
+use crate::db::{self, Client, GenericClient};
 use crate::sessions::Session;
 use crate::users::User;
 use crate::*;
 
+/// Returns the hash of an admin password, as it should be stored in the database.
+///
+/// Each call uses a new random salt, so hashing the same password twice
+/// produces different strings. Use `verify_password` to check passwords.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .expect("Error while encoding the password salt.");
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Error while hashing the password.")
+        .to_string()
+}
+
+/// Checks if a plaintext password matches a hash stored in the database.
+///
+/// Hashes which are not PHC strings are treated as legacy SHA-256 hashes.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => is_legacy_match(password, hash),
+    }
+}
+
+/// Checks if a password matches a legacy, unsalted SHA-256 hash.
+fn is_legacy_match(password: &str, hash: &str) -> bool {
+    format!("{:x}", Sha256::digest(password.as_bytes())) == hash
+}
+
 /// Holds the data of an administrator.
 pub struct Admin(pub Session);
 
+/// The results of trying to create an administrator account.
+pub enum AdminCreation {
+    Created,
+    /// There is already an account with the same username.
+    Exists,
+    /// The maximum number of accounts was reached.
+    TooMany,
+}
+
+/// The results of trying to remove an administrator account.
+pub enum AdminRemoval {
+    Removed,
+    NotFound,
+    /// The account is the only one left, so it cannot be removed.
+    LastAdmin,
+}
+
+impl Admin {
+    /// Returns the username of the account the administrator logged in with.
+    ///
+    /// Sessions which became admin sessions before usernames were recorded
+    /// have no username.
+    pub fn username(&self) -> Option<&str> {
+        self.0.admin_username()
+    }
+
+    /// Counts the administrator accounts.
+    pub fn count(client: &mut impl GenericClient) -> Result<i64, db::Error> {
+        query_one_row!(client, "SELECT COUNT(*) FROM admins;", &[], |row: Row| row
+            .get(0))
+    }
+
+    /// Creates an administrator account, unless there are already `max`
+    /// accounts. There is no limit if `max` is `None`.
+    ///
+    /// Accounts are created one at a time, so concurrent requests
+    /// cannot exceed the limit.
+    pub fn create(
+        client: &mut Client,
+        username: &str,
+        password: &str,
+        max: Option<i64>,
+    ) -> Result<AdminCreation, db::Error> {
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute("LOCK TABLE admins IN SHARE ROW EXCLUSIVE MODE;")?;
+
+        let count = Admin::count(&mut transaction)?;
+        if max.is_some_and(|max| count >= max) {
+            return Ok(AdminCreation::TooMany);
+        }
+        let inserted = transaction.execute(
+            "INSERT INTO admins (username, password) VALUES ($1, $2)
+            ON CONFLICT (username) DO NOTHING;",
+            &[&username, &hash_password(password)],
+        )?;
+        transaction.commit()?;
+
+        match inserted {
+            1 => Ok(AdminCreation::Created),
+            _ => Ok(AdminCreation::Exists),
+        }
+    }
+
+    /// Deletes an administrator account, unless it's the last one.
+    ///
+    /// Sessions which logged in with the account lose their admin rights.
+    pub fn delete(client: &mut Client, username: &str) -> Result<AdminRemoval, db::Error> {
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute("LOCK TABLE admins IN SHARE ROW EXCLUSIVE MODE;")?;
+
+        if Admin::count(&mut transaction)? <= 1 {
+            let exists = transaction
+                .query_opt("SELECT 1 FROM admins WHERE username = $1;", &[&username])?
+                .is_some();
+            return Ok(if exists {
+                AdminRemoval::LastAdmin
+            } else {
+                AdminRemoval::NotFound
+            });
+        }
+        let deleted =
+            transaction.execute("DELETE FROM admins WHERE username = $1;", &[&username])?;
+        transaction.execute(
+            "UPDATE sessions SET is_admin = FALSE, admin_username = NULL
+                WHERE admin_username = $1;",
+            &[&username],
+        )?;
+        transaction.commit()?;
+
+        match deleted {
+            1 => Ok(AdminRemoval::Removed),
+            _ => Ok(AdminRemoval::NotFound),
+        }
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Admin {
     type Error = ();
@@ -36,18 +170,27 @@ impl<'r> FromRequest<'r> for Admin {
     }
 }
 
+/// The content of a form used by admins to create other administrator accounts.
+#[derive(FromForm)]
+pub struct NewAdmin {
+    pub username: String,
+    /// The plaintext password of the account.
+    pub password: String,
+}
+
 /// The content of a form used to log in administrators.
 #[derive(FromForm)]
 pub struct AdminLogin {
-    username: String,
+    pub username: String,
     password: String,
 }
 
 impl AdminLogin {
     /// Checks if the login form references an administrator account.
     ///
-    /// Administrators are identified by their username,
-    /// and their passwords are held in a database as SHA-256 hashes.
+    /// Administrators are identified by their username, and their passwords
+    /// are held in a database as hashes. If the stored hash uses the legacy
+    /// SHA-256 scheme, it gets upgraded after a successful check.
     pub fn is_valid(&self, client: &mut Client) -> Result<bool, db::Error> {
         let wanted: String = query_one_row!(
             client,
@@ -56,8 +199,95 @@ impl AdminLogin {
             |row: Row| row.get(0)
         )?;
 
-        let actual = format!("{:x}", Sha256::digest(self.password.as_bytes()));
+        if !verify_password(&self.password, &wanted) {
+            return Ok(false);
+        }
+
+        if is_legacy_match(&self.password, &wanted) {
+            client.execute(
+                "UPDATE admins SET password = $1 WHERE username = $2;",
+                &[&hash_password(&self.password), &self.username],
+            )?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_passwords_verify() {
+        let hash = hash_password("hunter2");
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+    }
+
+    #[test]
+    fn hashes_are_salted() {
+        assert_ne!(hash_password("hunter2"), hash_password("hunter2"));
+    }
+
+    #[test]
+    fn legacy_hashes_still_verify() {
+        let hash = format!("{:x}", Sha256::digest(b"hunter2"));
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn legacy_hashes_are_upgraded_on_login() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let username = db::testing::unique_name("admin");
+        let legacy = format!("{:x}", Sha256::digest(b"hunter2"));
+        client
+            .execute(
+                "INSERT INTO admins (username, password) VALUES ($1, $2);",
+                &[&username, &legacy],
+            )
+            .unwrap();
+
+        let login = AdminLogin {
+            username: username.clone(),
+            password: "hunter2".into(),
+        };
+        assert!(login.is_valid(&mut client).unwrap());
+        let stored: String = client
+            .query_one(
+                "SELECT password FROM admins WHERE username = $1;",
+                &[&username],
+            )
+            .unwrap()
+            .get(0);
+        assert!(stored.starts_with("$argon2"));
+        assert!(login.is_valid(&mut client).unwrap());
+
+        client
+            .execute("DELETE FROM admins WHERE username = $1;", &[&username])
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn admins_cannot_be_created_past_the_cap() {
+        let mut connection = db::testing::isolated();
+        let mut client = connection.client();
+        let mut create = |username: &str| Admin::create(&mut client, username, "hunter2", Some(2));
+        assert!(matches!(create("alice").unwrap(), AdminCreation::Created));
+        assert!(matches!(create("alice").unwrap(), AdminCreation::Exists));
+        assert!(matches!(create("bob").unwrap(), AdminCreation::Created));
+        assert!(matches!(create("carol").unwrap(), AdminCreation::TooMany));
 
-        Ok(actual == wanted)
+        assert!(matches!(
+            Admin::delete(&mut client, "alice").unwrap(),
+            AdminRemoval::Removed
+        ));
+        let created = Admin::create(&mut client, "carol", "hunter2", Some(2));
+        assert!(matches!(created.unwrap(), AdminCreation::Created));
     }
 }
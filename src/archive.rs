@@ -0,0 +1,310 @@
+//! Module for mirroring posted messages to object storage.
+//!
+//! For backups or compliance, each message posted in a room can be written as
+//! a JSON record to an S3-compatible bucket. Records are uploaded by a
+//! background task, so posting never waits for the storage, and failed uploads
+//! are only logged. Records wait in a queue of limited capacity, and are
+//! dropped (and logged) if it's full.
+//!
+//! Archival is disabled unless a bucket is set in the `archive` section of the
+//! configuration. Uploading also requires the server to be built with the
+//! `s3-archive` feature. The credentials of the bucket are read from the
+//! environment, like for other AWS tools.
+//!
+//! Messages of encrypted rooms are never archived, since their contents
+//! should not leave the database in plaintext.
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+#[cfg(any(feature = "s3-archive", test))]
+use rocket::serde::json::serde_json;
+use rocket::tokio::sync::mpsc::error::TrySendError;
+#[cfg(any(feature = "s3-archive", test))]
+use rocket::tokio::sync::mpsc::Receiver;
+use rocket::tokio::sync::mpsc::Sender;
+use rocket::{Build, Rocket};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ForumConfig;
+use crate::messages::ShownId;
+
+/// The settings of message archival.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// The bucket which receives the records. Archival is disabled if it's missing.
+    pub bucket: Option<String>,
+    /// A prefix added to the keys of all records.
+    pub prefix: String,
+    /// The URL of the storage service, for services other than AWS S3.
+    pub endpoint: Option<String>,
+    /// The number of records which can wait to be uploaded. Further records
+    /// are dropped until there is room in the queue again.
+    pub queue_capacity: usize,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            bucket: None,
+            prefix: String::new(),
+            endpoint: None,
+            queue_capacity: 1000,
+        }
+    }
+}
+
+/// The record of a posted message, as it's archived.
+///
+/// Records are stored as JSON, with the key
+/// `<prefix><room>/<table_id>/<id>.json`. The table id tells apart the
+/// rooms which had the same name, so a recreated room does not overwrite
+/// the records of the old one.
+#[derive(Debug, Serialize)]
+pub struct ArchiveRecord {
+    pub room: String,
+    pub table_id: i32,
+    /// The id of the message, which is its public id if those are enabled.
+    pub id: ShownId,
+    pub timestamp: i64,
+    pub reply_to: Option<ShownId>,
+    pub system: bool,
+    /// The HTML content of the message.
+    pub content: String,
+}
+
+impl ArchiveRecord {
+    /// Returns the key under which the record is stored.
+    #[cfg(any(feature = "s3-archive", test))]
+    fn key(&self, prefix: &str) -> String {
+        format!("{}{}/{}/{}.json", prefix, self.room, self.table_id, self.id)
+    }
+}
+
+/// A handle used to archive messages, as managed state.
+///
+/// If archival is disabled, archiving messages does nothing.
+#[derive(Clone, Debug, Default)]
+pub struct Archiver(Option<Sender<ArchiveRecord>>);
+
+impl Archiver {
+    /// Checks if messages are archived.
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Queues a record to be archived in the background.
+    /// The record is dropped if the queue is full.
+    pub fn archive(&self, record: ArchiveRecord) {
+        if let Some(sender) = &self.0 {
+            match sender.try_send(record) {
+                Ok(()) => {}
+                Err(TrySendError::Full(record)) => eprintln!(
+                    "Did not archive message {} of room {}, since the queue is full.",
+                    record.id, record.room
+                ),
+                Err(TrySendError::Closed(_)) => {
+                    eprintln!("Could not archive a message, since the archiver stopped.")
+                }
+            }
+        }
+    }
+}
+
+/// A fairing which starts the archiver and makes it available as managed state.
+///
+/// It should be attached after the `ConfigFairing`.
+#[derive(Default)]
+pub struct ArchiveFairing;
+
+#[rocket::async_trait]
+impl Fairing for ArchiveFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Archive Fairing",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = match rocket.state::<ForumConfig>() {
+            Some(config) => config.archive.clone(),
+            None => return Err(rocket),
+        };
+        let archiver = match config.bucket.clone() {
+            Some(bucket) => start(config, bucket),
+            None => Archiver::default(),
+        };
+        Ok(rocket.manage(archiver))
+    }
+}
+
+/// A store which receives the uploaded records, like an S3 bucket.
+#[cfg(any(feature = "s3-archive", test))]
+#[rocket::async_trait]
+trait ObjectStore {
+    /// Stores a JSON object under the given key.
+    async fn put(&self, key: String, body: Vec<u8>) -> Result<(), String>;
+}
+
+/// Uploads the records to the store, until the archiver stops.
+#[cfg(any(feature = "s3-archive", test))]
+async fn upload_all(store: impl ObjectStore, prefix: String, mut records: Receiver<ArchiveRecord>) {
+    while let Some(record) = records.recv().await {
+        let body = match serde_json::to_vec(&record) {
+            Ok(body) => body,
+            Err(_) => continue,
+        };
+        if let Err(err) = store.put(record.key(&prefix), body).await {
+            eprintln!(
+                "Could not archive message {} of room {}: {}",
+                record.id, record.room, err
+            );
+        }
+    }
+}
+
+/// An S3-compatible bucket.
+#[cfg(feature = "s3-archive")]
+struct Bucket {
+    client: aws_sdk_s3::Client,
+    name: String,
+}
+
+#[cfg(feature = "s3-archive")]
+#[rocket::async_trait]
+impl ObjectStore for Bucket {
+    async fn put(&self, key: String, body: Vec<u8>) -> Result<(), String> {
+        use aws_sdk_s3::primitives::ByteStream;
+
+        self.client
+            .put_object()
+            .bucket(&self.name)
+            .key(key)
+            .content_type("application/json")
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Starts the background task which uploads records to the bucket.
+#[cfg(feature = "s3-archive")]
+fn start(config: ArchiveConfig, bucket: String) -> Archiver {
+    use rocket::tokio::sync::mpsc;
+
+    let (sender, records) = mpsc::channel::<ArchiveRecord>(config.queue_capacity);
+    rocket::tokio::spawn(async move {
+        let shared = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let mut builder = aws_sdk_s3::config::Builder::from(&shared);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+        let bucket = Bucket {
+            client: aws_sdk_s3::Client::from_conf(builder.build()),
+            name: bucket,
+        };
+        upload_all(bucket, config.prefix, records).await;
+    });
+    Archiver(Some(sender))
+}
+
+/// Reports that messages cannot be archived without the `s3-archive` feature.
+#[cfg(not(feature = "s3-archive"))]
+fn start(_config: ArchiveConfig, _bucket: String) -> Archiver {
+    eprintln!("Messages are not archived, since the server was built without `s3-archive`.");
+    Archiver::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use rocket::tokio::sync::mpsc;
+
+    use super::*;
+
+    /// The keys and bodies of uploaded objects.
+    type Uploads = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// A store which keeps the uploaded objects in memory.
+    #[derive(Clone, Default)]
+    struct MockStore(Uploads);
+
+    #[rocket::async_trait]
+    impl ObjectStore for MockStore {
+        async fn put(&self, key: String, body: Vec<u8>) -> Result<(), String> {
+            self.0.lock().unwrap().push((key, body));
+            Ok(())
+        }
+    }
+
+    fn record(table_id: i32, id: ShownId) -> ArchiveRecord {
+        ArchiveRecord {
+            room: "general".into(),
+            table_id,
+            id,
+            timestamp: 1662139461337,
+            reply_to: None,
+            system: false,
+            content: "<p>Hello!</p>".into(),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn records_are_uploaded_as_json() {
+        let store = MockStore::default();
+        let (sender, records) = mpsc::channel(1);
+        let archiver = Archiver(Some(sender));
+        archiver.archive(record(3, ShownId::Id(7)));
+        drop(archiver);
+
+        upload_all(store.clone(), "forum/".into(), records).await;
+
+        let uploaded = store.0.lock().unwrap();
+        assert_eq!(uploaded.len(), 1);
+        let (key, body) = &uploaded[0];
+        assert_eq!(key, "forum/general/3/7.json");
+        let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(json["id"], 7);
+        assert_eq!(json["content"], "<p>Hello!</p>");
+        assert_eq!(json["table_id"], 3);
+    }
+
+    #[rocket::async_test]
+    async fn records_are_dropped_if_the_queue_is_full() {
+        let store = MockStore::default();
+        let (sender, records) = mpsc::channel(2);
+        let archiver = Archiver(Some(sender));
+        for id in 1..=3 {
+            archiver.archive(record(3, ShownId::Id(id)));
+        }
+        drop(archiver);
+
+        upload_all(store.clone(), "".into(), records).await;
+
+        let keys: Vec<String> = store
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        assert_eq!(keys, ["general/3/1.json", "general/3/2.json"]);
+    }
+
+    #[test]
+    fn recreated_rooms_get_different_keys() {
+        assert_ne!(
+            record(3, ShownId::Id(1)).key(""),
+            record(4, ShownId::Id(1)).key("")
+        );
+    }
+
+    #[test]
+    fn public_ids_are_used_in_keys() {
+        let record = record(3, ShownId::Public("bKqZrT".into()));
+        assert_eq!(record.key("forum/"), "forum/general/3/bKqZrT.json");
+    }
+}
@@ -0,0 +1,98 @@
+//! Module for finding the IP addresses of clients.
+//!
+//! When the server runs behind a reverse proxy, all connections come from the
+//! proxy, so the address of the client must be taken from the headers added by
+//! the proxy. These headers can be forged by anyone who connects to the server
+//! directly, so they are only used if the `trust_proxy` setting is enabled.
+//!
+//! Features which depend on clients' addresses should use this module, instead
+//! of Rocket's `IpAddr` guard, which trusts the `X-Real-IP` header by default.
+
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+use rocket::request::{self, FromRequest, Request};
+
+use crate::config::ForumConfig;
+
+/// Returns the IP address of the client which sent a request, if it's known.
+///
+/// If the server trusts its proxy, the address is taken from the
+/// `X-Forwarded-For` header, or from the `X-Real-IP` header if the former is
+/// missing. Otherwise, the address of the peer which connected is used.
+pub fn client_ip(req: &Request<'_>) -> Option<IpAddr> {
+    let trust_proxy = req
+        .rocket()
+        .state::<ForumConfig>()
+        .is_some_and(|config| config.trust_proxy);
+
+    if trust_proxy {
+        // Each proxy appends the address it received the request from,
+        // so the last address is the one seen by the trusted proxy. The
+        // others were sent by the client, and could be forged.
+        let forwarded = req
+            .headers()
+            .get("X-Forwarded-For")
+            .last()
+            .and_then(|header| header.rsplit(',').next())
+            .or_else(|| req.headers().get_one("X-Real-IP"));
+        if let Some(ip) = forwarded.and_then(|ip| ip.trim().parse().ok()) {
+            return Some(ip);
+        }
+    }
+
+    req.remote().map(|remote| remote.ip())
+}
+
+/// A request guard which holds the IP address of the client, if it's known.
+///
+/// It never fails. The address is found using `client_ip`.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(ClientIp(client_ip(req)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[rocket::get("/ip")]
+    fn ip(ip: ClientIp) -> String {
+        ip.0.map(|ip| ip.to_string()).unwrap_or_default()
+    }
+
+    /// Returns the address found for a request from `127.0.0.1`,
+    /// which went through a proxy at `10.0.0.2`.
+    fn forwarded_ip(trust_proxy: bool) -> String {
+        let config = ForumConfig {
+            trust_proxy,
+            ..ForumConfig::default()
+        };
+        let rocket = rocket::build()
+            .manage(config)
+            .mount("/", rocket::routes![ip]);
+        let client = Client::untracked(rocket).unwrap();
+        client
+            .get("/ip")
+            .remote("127.0.0.1:8000".parse().unwrap())
+            .header(Header::new("X-Forwarded-For", "1.2.3.4, 10.0.0.2"))
+            .dispatch()
+            .into_string()
+            .unwrap()
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_used_if_trusted() {
+        assert_eq!(forwarded_ip(true), "10.0.0.2");
+        assert_eq!(forwarded_ip(false), "127.0.0.1");
+    }
+}
@@ -0,0 +1,114 @@
+//! Module for reading the current time.
+//!
+//! The timestamps saved by the server, such as those of messages and sessions,
+//! are all taken from this module, instead of reading the system's clock
+//! directly. In tests, time-dependent behaviour, like the expiry of sessions,
+//! can be checked deterministically by replacing the clock with a
+//! `testing::FakeClock`, without waiting for real time to pass.
+//!
+//! The in-memory limiters measure durations with `Instant`s instead, since
+//! they never store timestamps.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of the current time.
+pub trait Clock {
+    /// Returns the current Unix time, in milliseconds.
+    fn now_millis(&self) -> i64;
+}
+
+/// The system's clock, used unless another clock is set.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Error while reading the system clock.")
+            .as_millis() as i64
+    }
+}
+
+/// Returns the current Unix time, in milliseconds, according to the clock.
+pub fn now_millis() -> i64 {
+    #[cfg(test)]
+    if let Some(now) = testing::now_millis() {
+        return now;
+    }
+    SystemClock.now_millis()
+}
+
+/// Returns the current Unix time, in seconds, according to the clock.
+pub fn now_secs() -> i64 {
+    now_millis() / 1000
+}
+
+/// Helpers for tests which control the time.
+#[cfg(test)]
+pub mod testing {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use super::Clock;
+
+    thread_local! {
+        /// The clock used instead of the system's clock, if one is set.
+        ///
+        /// Each test runs on its own thread, so tests which set a clock do
+        /// not affect the others.
+        static CLOCK: RefCell<Option<Box<dyn Clock>>> = const { RefCell::new(None) };
+    }
+
+    /// Replaces the clock from which timestamps are taken, on the current thread.
+    pub fn set_clock(clock: Box<dyn Clock>) {
+        CLOCK.with(|current| *current.borrow_mut() = Some(clock));
+    }
+
+    /// Returns the time shown by the clock set on the current thread, if any.
+    pub(super) fn now_millis() -> Option<i64> {
+        CLOCK.with(|clock| clock.borrow().as_ref().map(|clock| clock.now_millis()))
+    }
+
+    /// A clock which only moves when it's told to.
+    ///
+    /// Clones share the same time, so a test can keep a clone to move the
+    /// clock after setting it.
+    #[derive(Clone)]
+    pub struct FakeClock(Rc<Cell<i64>>);
+
+    impl FakeClock {
+        /// Builds a clock which shows the given Unix time, in milliseconds.
+        pub fn new(now_millis: i64) -> Self {
+            Self(Rc::new(Cell::new(now_millis)))
+        }
+
+        /// Moves the clock forward by the given number of milliseconds.
+        pub fn advance(&self, millis: i64) {
+            self.0.set(self.0.get() + millis);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now_millis(&self) -> i64 {
+            self.0.get()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{set_clock, FakeClock};
+    use super::*;
+
+    #[test]
+    fn fake_clocks_move_when_told() {
+        let clock = FakeClock::new(1_500);
+        set_clock(Box::new(clock.clone()));
+        assert_eq!(now_millis(), 1_500);
+        assert_eq!(now_secs(), 1);
+
+        clock.advance(2_000);
+        assert_eq!(now_millis(), 3_500);
+        assert_eq!(now_secs(), 3);
+    }
+}
@@ -0,0 +1,149 @@
+//! Module for compressing the responses sent to clients.
+//!
+//! Some responses, such as the message updates of a room, can get quite large,
+//! especially when a client receives the entire content of a room at once.
+//! Since these responses are mostly text, they compress well.
+//!
+//! Compression is negotiated through the `Accept-Encoding` header, so clients
+//! which do not support it still receive normal, uncompressed responses.
+
+use std::io::{Cursor, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::serde_json;
+use rocket::Request;
+use serde::Serialize;
+
+/// Responses smaller than this (in bytes) are not worth compressing.
+const MIN_COMPRESSED_LEN: usize = 1024;
+
+/// A JSON response which gets compressed with gzip if the client accepts it.
+pub struct CompressedJson<T>(pub T);
+
+/// Checks if the client which sent a request accepts gzip-encoded responses.
+///
+/// Encodings explicitly marked as unacceptable (with `q=0`) are ignored.
+/// The wildcard `*` only counts if gzip is not listed itself, so `gzip;q=0`
+/// turns gzip off even if other encodings are accepted.
+fn accepts_gzip(req: &Request<'_>) -> bool {
+    let encodings: Vec<(&str, bool)> = req
+        .headers()
+        .get("Accept-Encoding")
+        .flat_map(|header| header.split(','))
+        .map(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let rejected = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (name, !rejected)
+        })
+        .collect();
+
+    let gzip = encodings
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("gzip"))
+        .map(|&(_, accepted)| accepted)
+        .reduce(|first, second| first || second);
+    gzip.unwrap_or_else(|| {
+        encodings
+            .iter()
+            .any(|&(name, accepted)| name == "*" && accepted)
+    })
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for CompressedJson<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let json = serde_json::to_vec(&self.0).map_err(|_| Status::InternalServerError)?;
+
+        let mut response = Response::build();
+        response
+            .header(ContentType::JSON)
+            .raw_header("Vary", "Accept-Encoding");
+
+        if json.len() < MIN_COMPRESSED_LEN || !accepts_gzip(req) {
+            return response.sized_body(json.len(), Cursor::new(json)).ok();
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let compressed = encoder
+            .write_all(&json)
+            .and_then(|_| encoder.finish())
+            .map_err(|_| Status::InternalServerError)?;
+
+        response
+            .raw_header("Content-Encoding", "gzip")
+            .sized_body(compressed.len(), Cursor::new(compressed))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+    use rocket::serde::json::Value;
+
+    use super::*;
+
+    /// A response large enough to get compressed.
+    fn large_response() -> Vec<String> {
+        (0..MIN_COMPRESSED_LEN)
+            .map(|i| format!("message {}", i))
+            .collect()
+    }
+
+    #[rocket::get("/large")]
+    fn large() -> CompressedJson<Vec<String>> {
+        CompressedJson(large_response())
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::build().mount("/", rocket::routes![large]);
+        Client::tracked(rocket).expect("The test server should start")
+    }
+
+    fn expected() -> Value {
+        serde_json::to_value(large_response()).unwrap()
+    }
+
+    #[test]
+    fn gzip_responses_decompress_to_the_json() {
+        let client = client();
+        let response = client
+            .get("/large")
+            .header(Header::new("Accept-Encoding", "deflate, gzip"))
+            .dispatch();
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+
+        let mut json = String::new();
+        GzDecoder::new(&response.into_bytes().unwrap()[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), expected());
+    }
+
+    #[test]
+    fn other_clients_get_plain_json() {
+        let client = client();
+        for encoding in [None, Some("deflate"), Some("gzip;q=0"), Some("gzip;q=0, *")] {
+            let mut request = client.get("/large");
+            if let Some(encoding) = encoding {
+                request.add_header(Header::new("Accept-Encoding", encoding));
+            }
+            let response = request.dispatch();
+            assert_eq!(response.headers().get_one("Content-Encoding"), None);
+            assert_eq!(response.into_json::<Value>(), Some(expected()));
+        }
+    }
+}
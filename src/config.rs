@@ -0,0 +1,826 @@
+//! Module for working with the configuration of the forum.
+//!
+//! Apart from Rocket's own settings, the server reads the settings of the forum
+//! from the `forum` section of Rocket's configuration. This means they can be
+//! set in `Rocket.toml`, like so:
+//!
+//! ```toml
+//! [global.forum]
+//! encryption_secret = "SOME_LONG_RANDOM_STRING"
+//!
+//! [global.forum.constraints]
+//! max_message_len = 4096
+//! ```
+//!
+//! or through the `ROCKET_FORUM` environment variable. Missing settings
+//! receive default values, which match the server's historical behaviour.
+//!
+//! Rooms which should always exist can be listed too, each in its own table:
+//!
+//! ```toml
+//! [[global.forum.rooms]]
+//! name = "team"
+//! password_file = "/run/secrets/team_password"
+//! ```
+//!
+//! The settings of new rooms which are not chosen explicitly, whether the rooms
+//! are listed or created by admins, are taken from the `room_defaults` section:
+//!
+//! ```toml
+//! [global.forum.room_defaults]
+//! store_author = false
+//! markdown_enabled = false
+//! ```
+//!
+//! The configuration is read once, when the server starts, and is then
+//! available as managed state.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::figment::Figment;
+use rocket::{Build, Rocket};
+use serde::{Deserialize, Serialize};
+
+use crate::archive::{ArchiveConfig, Archiver};
+use crate::constraints::Constraints;
+use crate::https::HttpsPolicy;
+use crate::limited_json;
+use crate::login_limiter::LoginLimit;
+use crate::messages::{
+    BudgetPolicy, MessageJson, RoomBudget, StorageOptions, ThreadOrder, DEFAULT_MAX_REPLY_DEPTH,
+};
+use crate::rate_limiter::RateLimit;
+use crate::room_creation_limiter::RoomCreationLimit;
+use crate::rooms::{ProvisionedRoom, RoomSettings};
+use crate::sanitizer::Sanitizer;
+use crate::security_headers::SecurityHeaders;
+use crate::sessions::{CLEANER_PERIOD_SECS, SESSION_TIMEOUT_SECS};
+use crate::template_variables::{WelcomeMessageJson, DEFAULT_TEMPLATE_HISTORY_LEN};
+use crate::webhooks::{WebhookConfig, WebhookDispatcher};
+use crate::word_filter::WordFilter;
+
+/// The key of the configuration section which holds the forum's settings.
+const CONFIG_KEY: &str = "forum";
+
+/// The default number of seconds allowed for reading a request body.
+pub const DEFAULT_BODY_READ_TIMEOUT_SECS: u64 = 10;
+
+/// The room left for the structure of a JSON body, apart from the message itself.
+const JSON_OVERHEAD: usize = 1024;
+
+/// Holds the settings of the forum.
+///
+/// Admins can read the settings, so secrets should never be serialized.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ForumConfig {
+    /// A secret used to derive the keys which encrypt the messages of
+    /// encrypted rooms. Encrypted rooms cannot be used if it's missing.
+    ///
+    /// Changing this secret makes existing encrypted messages unreadable.
+    #[serde(skip_serializing)]
+    pub encryption_secret: Option<String>,
+    /// A secret used to derive the anonymous tokens which tell apart the
+    /// authors of each thread. Messages have no tokens if it's missing.
+    ///
+    /// Changing this secret changes all the tokens.
+    #[serde(skip_serializing)]
+    pub author_token_secret: Option<String>,
+    /// A secret used to derive the opaque public ids sent instead of the ids
+    /// of messages, which hide how many messages rooms have. If it's missing,
+    /// the ids themselves are sent.
+    ///
+    /// Changing this secret changes all the public ids.
+    #[serde(skip_serializing)]
+    pub public_id_secret: Option<String>,
+    /// The limits enforced by the server.
+    pub constraints: Constraints,
+    /// For how many seconds after deleting a message its author can undo the deletion.
+    pub undelete_window_secs: i64,
+    /// For how many seconds after posting a message its author can edit it.
+    /// Messages can always be edited if it's 0. Admins can edit any message.
+    pub edit_window_secs: i64,
+    /// The order in which threads are listed if clients do not choose one.
+    pub default_thread_order: ThreadOrder,
+    /// For how many seconds the server waits for a request body to be received
+    /// by the custom data guards, before giving up.
+    pub body_read_timeout_secs: u64,
+    /// Whether a hash of each message's content is stored alongside it, which
+    /// makes finding identical messages cheap. Encrypted messages are not hashed.
+    pub hash_message_content: bool,
+    /// Whether the content of messages is stored compressed, which saves space
+    /// in rooms with long or repetitive messages. Encrypted messages are not compressed.
+    pub compress_message_content: bool,
+    /// Whether the original source of messages, as written by their authors,
+    /// is stored too, so it can be edited. It's not stored by default.
+    pub store_message_source: bool,
+    /// Whether messages which consist only of links, a common form of spam,
+    /// are refused. Announcements made by admins are not affected.
+    pub reject_link_only_messages: bool,
+    /// The maximum number of consecutive blank lines kept in new messages.
+    /// Longer runs are collapsed, except in code blocks. If it's missing,
+    /// messages are stored with all their blank lines.
+    pub max_blank_lines: Option<usize>,
+    /// How far (in seconds) from the server's time the times sent by clients,
+    /// for when they wrote their messages, can be. These times are only hints
+    /// for displaying messages. If it's missing, they are not stored.
+    pub client_timestamp_window_secs: Option<i64>,
+    /// What the main page does for users who can already access exactly one room.
+    pub room_shortcut: RoomShortcut,
+    /// What the server's `robots.txt` tells search engines.
+    pub robots: Robots,
+    /// The addresses allowed to scrape the server's metrics.
+    /// If it's missing, anyone can scrape them.
+    pub metrics_allowed_ips: Option<Vec<IpAddr>>,
+    /// The filter applied to the words of new messages.
+    pub word_filter: WordFilter,
+    /// The maximum number of messages sent in response to one update request.
+    /// Clients which missed more messages receive them in several chunks.
+    pub max_messages_per_update: usize,
+    /// The minimum time (in milliseconds) between two polls of a client for the
+    /// updates of a room. Clients which poll sooner are told to wait. It's not
+    /// enforced if this is 0.
+    pub min_update_interval_ms: u64,
+    /// The maximum size (in bytes) of the JSON body through which messages are
+    /// posted. If it's missing, it's derived from the maximum message length.
+    /// It's Rocket's `json/post` limit, unless that's set explicitly, and it
+    /// does not affect the `json` limit of other routes.
+    pub post_body_limit: Option<usize>,
+    /// Whether sessions are removed when the server starts, logging everyone out.
+    /// If not, users keep their sessions and their access to rooms across restarts,
+    /// as long as Rocket's `secret_key` does not change, since it encrypts the
+    /// session cookies.
+    pub clear_sessions_on_start: bool,
+    /// Whether all users can see the names of the rooms, not just admins.
+    pub public_room_list: bool,
+    /// Whether admins can subscribe to a stream of events which tells them
+    /// when rooms are created, deleted, or archived.
+    pub room_event_stream: bool,
+    /// The maximum number of notifications recorded for the author of each
+    /// message, about replies to it. Authors are only notified while their
+    /// sessions are live. Replies are not notified if this is 0.
+    pub max_reply_notifications: usize,
+    /// The rules used when sanitizing the HTML of messages and of the welcome message.
+    pub sanitizer: Sanitizer,
+    /// Other sets of sanitizer rules, by their names, which rooms can choose
+    /// instead of the default ones.
+    pub sanitizer_profiles: HashMap<String, Sanitizer>,
+    /// The maximum number of replies sent in one page of a thread,
+    /// which is also the number sent if clients do not choose one.
+    pub max_thread_page_len: i64,
+    /// The maximum number of messages which can be requested at once by their ids.
+    pub max_messages_per_batch: usize,
+    /// Whether users are told when a room does not exist. If not, they are
+    /// told that their credentials are invalid, as if the room existed,
+    /// so the names of rooms cannot be guessed.
+    pub reveal_missing_rooms: bool,
+    /// Whether the server runs behind a trusted reverse proxy, in which case
+    /// the addresses of clients are taken from the headers added by the proxy.
+    pub trust_proxy: bool,
+    /// What happens to requests sent over plaintext HTTP. Behind a proxy which
+    /// terminates TLS, it needs `trust_proxy`, so the scheme can be known.
+    pub https: HttpsPolicy,
+    /// The port to which plaintext requests are redirected, if it's not the
+    /// default HTTPS port.
+    pub https_port: Option<u16>,
+    /// The maximum number of stale sessions removed by one statement of the
+    /// session cleaner. Larger backlogs are removed in several batches.
+    pub session_cleaner_batch_size: i64,
+    /// For how many seconds an admin's session is kept after its last update.
+    /// Other sessions are kept for `SESSION_TIMEOUT_SECS` seconds.
+    pub admin_session_timeout_secs: i64,
+    /// For how many seconds after their creation sessions are kept, however
+    /// active they are. If it's missing, active sessions are kept indefinitely.
+    pub max_session_lifetime_secs: Option<i64>,
+    /// The maximum number of administrator accounts which admins can reach
+    /// by creating new ones. If it's missing, there is no limit.
+    pub max_admins: Option<i64>,
+    /// Whether the entry message of a room is shown only the first time a user
+    /// enters the room. If not, it's shown every time.
+    pub entry_message_once: bool,
+    /// Whether the page of a room receives the time when the room was created,
+    /// and the time when it starts accepting posts, so it can show them.
+    pub show_room_info: bool,
+    /// Whether the files under `static/` are served at `/static`. It can be
+    /// disabled when they are served by something else, like a CDN. The pages
+    /// which the server shows from that directory work either way.
+    pub serve_static_files: bool,
+    /// The security headers added to all responses.
+    pub security_headers: SecurityHeaders,
+    /// The duration (in milliseconds) after which database queries are logged
+    /// as slow. If it's missing, slow queries are not logged.
+    pub slow_query_threshold_ms: Option<u64>,
+    /// The maximum number of replies followed when looking for the message
+    /// which started a thread. It guards against replies which form cycles.
+    pub max_reply_depth: usize,
+    /// How long (in seconds) the welcome message is kept in memory before being
+    /// read from the database again. It's not kept at all if this is 0.
+    pub welcome_message_cache_secs: u64,
+    /// The number of versions kept in the history of each template variable,
+    /// like the welcome message, so admins can restore them.
+    pub template_history_len: usize,
+    /// The limits on failed attempts to log into rooms.
+    pub login_limit: LoginLimit,
+    /// The limit on the rate of requests from each IP address, which is disabled by default.
+    pub rate_limit: RateLimit,
+    /// The limit on the rooms created by each admin, which is disabled by default.
+    pub room_creation_limit: RoomCreationLimit,
+    /// The settings of message archival, which is disabled by default.
+    pub archive: ArchiveConfig,
+    /// The settings of the webhooks of rooms, which are disabled by default.
+    pub webhooks: WebhookConfig,
+    /// The maximum total size (in bytes) of the messages stored in each room.
+    /// If it's missing, rooms can grow without limit.
+    pub max_room_bytes: Option<i64>,
+    /// What happens to messages which would make a room exceed `max_room_bytes`.
+    pub room_budget_policy: BudgetPolicy,
+    /// The maximum number of threads sent in the snapshot of a room.
+    pub max_snapshot_threads: i64,
+    /// Whether visitors who only read rooms are not given sessions. They can
+    /// still read public rooms, but need a session for anything else.
+    pub guest_mode: bool,
+    /// Whether only the hashes of session ids are stored, so the database
+    /// cannot be used to build valid cookies. Changing it ends all sessions.
+    pub hash_session_ids: bool,
+    /// How the session cookie protects the session id.
+    pub session_cookie: SessionCookieMode,
+    /// The secret used to sign session cookies, if they are signed.
+    ///
+    /// Changing this secret ends all sessions.
+    #[serde(skip_serializing)]
+    pub session_cookie_secret: Option<String>,
+    /// The maximum number of rooms which a session can try to log into, until
+    /// it forgets some of them. If it's missing, there is no limit.
+    pub max_rooms_per_session: Option<i64>,
+    /// The rooms which are created when the server starts, if they are missing.
+    pub rooms: Vec<ProvisionedRoom>,
+    /// The settings given to new rooms, unless they are chosen explicitly
+    /// when the rooms are created.
+    pub room_defaults: RoomSettings,
+    /// The duration (in milliseconds) after which rooms created by admins
+    /// start accepting posts, unless a time is chosen explicitly.
+    pub new_room_grace_period_ms: i64,
+    /// The time (as a Unix timestamp in milliseconds) before which admins
+    /// cannot create rooms. If it's missing, there is no such time.
+    pub room_creation_opens: Option<i64>,
+    /// The time (as a Unix timestamp in milliseconds) after which admins
+    /// cannot create rooms. If it's missing, there is no such time.
+    pub room_creation_closes: Option<i64>,
+}
+
+impl Default for ForumConfig {
+    fn default() -> Self {
+        Self {
+            encryption_secret: None,
+            author_token_secret: None,
+            public_id_secret: None,
+            constraints: Constraints::default(),
+            undelete_window_secs: 60,
+            edit_window_secs: 900,
+            default_thread_order: ThreadOrder::Created,
+            body_read_timeout_secs: DEFAULT_BODY_READ_TIMEOUT_SECS,
+            hash_message_content: false,
+            compress_message_content: false,
+            store_message_source: false,
+            reject_link_only_messages: false,
+            max_blank_lines: None,
+            client_timestamp_window_secs: None,
+            room_shortcut: RoomShortcut::Off,
+            robots: Robots::Allow,
+            metrics_allowed_ips: None,
+            word_filter: WordFilter::default(),
+            max_messages_per_update: 500,
+            min_update_interval_ms: 0,
+            post_body_limit: None,
+            clear_sessions_on_start: true,
+            public_room_list: false,
+            room_event_stream: false,
+            max_reply_notifications: 0,
+            sanitizer: Sanitizer::default(),
+            sanitizer_profiles: HashMap::new(),
+            max_thread_page_len: 100,
+            max_messages_per_batch: 100,
+            reveal_missing_rooms: false,
+            trust_proxy: false,
+            https: HttpsPolicy::Allow,
+            https_port: None,
+            session_cleaner_batch_size: 1000,
+            admin_session_timeout_secs: SESSION_TIMEOUT_SECS,
+            max_session_lifetime_secs: None,
+            max_admins: None,
+            entry_message_once: false,
+            show_room_info: false,
+            serve_static_files: true,
+            security_headers: SecurityHeaders::default(),
+            slow_query_threshold_ms: None,
+            max_reply_depth: DEFAULT_MAX_REPLY_DEPTH,
+            welcome_message_cache_secs: 60,
+            template_history_len: DEFAULT_TEMPLATE_HISTORY_LEN,
+            login_limit: LoginLimit::default(),
+            rate_limit: RateLimit::default(),
+            room_creation_limit: RoomCreationLimit::default(),
+            archive: ArchiveConfig::default(),
+            webhooks: WebhookConfig::default(),
+            max_room_bytes: None,
+            room_budget_policy: BudgetPolicy::Reject,
+            max_snapshot_threads: 50,
+            guest_mode: false,
+            hash_session_ids: false,
+            session_cookie: SessionCookieMode::Private,
+            session_cookie_secret: None,
+            max_rooms_per_session: None,
+            rooms: Vec::new(),
+            room_defaults: RoomSettings::default(),
+            new_room_grace_period_ms: 0,
+            room_creation_opens: None,
+            room_creation_closes: None,
+        }
+    }
+}
+
+/// The configuration in effect, as it's shown to admins. Secrets are left out.
+#[derive(Serialize)]
+pub struct EffectiveConfig<'a> {
+    /// The settings of the forum.
+    forum: &'a ForumConfig,
+    /// For how many seconds sessions other than admins' are kept after their last update.
+    session_timeout_secs: i64,
+    /// How often (in seconds) old sessions are removed.
+    session_cleaner_period_secs: u64,
+    /// The optional features which the server was built with.
+    features: Vec<&'static str>,
+}
+
+/// The ways in which the main page can lead users to a room they can already access.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomShortcut {
+    /// The main page is shown as usual.
+    Off,
+    /// The main page shows a link to the room.
+    Link,
+    /// Users are redirected to the room, unless there is a message to show them.
+    Redirect,
+}
+
+/// How the session cookie protects the session id.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionCookieMode {
+    /// The cookie is encrypted with Rocket's `secret_key`, so sessions end
+    /// when the key changes.
+    Private,
+    /// The cookie holds the session id in plaintext, signed with the
+    /// `session_cookie_secret`, so it does not depend on Rocket's key.
+    Signed,
+}
+
+/// What the server's `robots.txt` tells search engines.
+///
+/// It can be `"allow"`, `"disallow"`, or `{ custom = "..." }`, in which case
+/// the file has exactly the given content.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Robots {
+    /// All pages can be indexed.
+    Allow,
+    /// No page can be indexed.
+    Disallow,
+    /// The file has the given directives.
+    Custom(String),
+}
+
+impl Robots {
+    /// Returns the content of the `robots.txt` file.
+    pub fn content(&self) -> &str {
+        match self {
+            Robots::Allow => "User-agent: *\nDisallow:\n",
+            Robots::Disallow => "User-agent: *\nDisallow: /\n",
+            Robots::Custom(content) => content,
+        }
+    }
+}
+
+impl ForumConfig {
+    /// Returns the configuration in effect, without secrets.
+    pub fn effective(&self) -> EffectiveConfig<'_> {
+        let features = [
+            ("s3-archive", cfg!(feature = "s3-archive")),
+            ("webhooks", cfg!(feature = "webhooks")),
+        ];
+        EffectiveConfig {
+            forum: self,
+            session_timeout_secs: SESSION_TIMEOUT_SECS,
+            session_cleaner_period_secs: CLEANER_PERIOD_SECS,
+            features: features
+                .into_iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name)
+                .collect(),
+        }
+    }
+
+    /// Returns the settings which affect how messages posted by users are stored.
+    ///
+    /// Messages are not archived or sent to webhooks with these settings, since
+    /// the archiver and the dispatcher are managed state. Handlers should use
+    /// the `StorageOptions` request guard.
+    pub fn storage_options(&self) -> StorageOptions {
+        StorageOptions {
+            hash_content: self.hash_message_content,
+            compress_content: self.compress_message_content,
+            store_source: self.store_message_source,
+            reject_only_links: self.reject_link_only_messages,
+            max_blank_lines: self.max_blank_lines,
+            client_timestamp_window_secs: self.client_timestamp_window_secs,
+            filter: self.word_filter.clone(),
+            sanitizer: self.sanitizer.clone(),
+            sanitizer_profiles: self.sanitizer_profiles.clone(),
+            max_reply_depth: self.max_reply_depth,
+            max_len: self.constraints.max_message_len,
+            max_reply_notifications: self.max_reply_notifications,
+            room_budget: self.room_budget(),
+            archiver: Archiver::default(),
+            dispatcher: WebhookDispatcher::default(),
+        }
+    }
+
+    /// Checks if admins can create rooms at the given time, given as a Unix
+    /// timestamp in milliseconds. The rooms listed in the configuration are
+    /// created regardless.
+    pub fn room_creation_allowed(&self, now: i64) -> bool {
+        self.room_creation_opens.is_none_or(|opens| opens <= now)
+            && self.room_creation_closes.is_none_or(|closes| now < closes)
+    }
+
+    /// Returns the cap on the size of each room, if there is one.
+    pub fn room_budget(&self) -> Option<RoomBudget> {
+        self.max_room_bytes.map(|max_bytes| RoomBudget {
+            max_bytes,
+            policy: self.room_budget_policy,
+        })
+    }
+
+    /// Returns the maximum size (in bytes) of the JSON body of a posted message.
+    ///
+    /// Unless it's configured explicitly, the limit allows messages of the
+    /// maximum length, even if each of their characters needs escaping.
+    pub fn post_body_limit(&self) -> usize {
+        self.post_body_limit
+            .unwrap_or(2 * self.constraints.max_message_len + JSON_OVERHEAD)
+    }
+
+    /// Returns the maximum size (in bytes) of the JSON body which changes the
+    /// welcome message.
+    ///
+    /// Like the limit of posted messages, it allows welcome messages of the
+    /// maximum length, even if each of their characters needs escaping.
+    pub fn welcome_message_body_limit(&self) -> usize {
+        2 * self.constraints.max_welcome_message_len + JSON_OVERHEAD
+    }
+
+    /// Checks if the configuration makes sense, returning the reason if it doesn't.
+    fn validate(&self) -> Result<(), String> {
+        if self.max_thread_page_len < 1 {
+            return Err("max_thread_page_len must be at least 1.".into());
+        }
+        if let (Some(opens), Some(closes)) = (self.room_creation_opens, self.room_creation_closes) {
+            if opens >= closes {
+                return Err("room_creation_opens must be before room_creation_closes.".into());
+            }
+        }
+        if self.max_messages_per_batch == 0 {
+            return Err("max_messages_per_batch must be at least 1.".into());
+        }
+        if self.undelete_window_secs < 0 {
+            return Err("undelete_window_secs cannot be negative.".into());
+        }
+        if self.edit_window_secs < 0 {
+            return Err("edit_window_secs cannot be negative.".into());
+        }
+        if self
+            .max_rooms_per_session
+            .is_some_and(|max_rooms| max_rooms < 1)
+        {
+            return Err("max_rooms_per_session must be at least 1.".into());
+        }
+        if self.max_snapshot_threads < 1 {
+            return Err("max_snapshot_threads must be at least 1.".into());
+        }
+        if self.login_limit.max_failures == 0 {
+            return Err("login_limit.max_failures must be at least 1.".into());
+        }
+        if self.rate_limit.window_secs == 0 {
+            return Err("rate_limit.window_secs must be at least 1.".into());
+        }
+        if self.room_creation_limit.window_secs == 0 {
+            return Err("room_creation_limit.window_secs must be at least 1.".into());
+        }
+        if self.session_cleaner_batch_size < 1 {
+            return Err("session_cleaner_batch_size must be at least 1.".into());
+        }
+        if self.admin_session_timeout_secs < 1 {
+            return Err("admin_session_timeout_secs must be at least 1.".into());
+        }
+        if self
+            .max_session_lifetime_secs
+            .is_some_and(|lifetime| lifetime < 1)
+        {
+            return Err("max_session_lifetime_secs must be at least 1.".into());
+        }
+        if self.template_history_len < 1 {
+            return Err("template_history_len must be at least 1.".into());
+        }
+        if self
+            .client_timestamp_window_secs
+            .is_some_and(|window| window < 0)
+        {
+            return Err("client_timestamp_window_secs cannot be negative.".into());
+        }
+        if self.max_blank_lines.is_some_and(|max| max < 1) {
+            return Err("max_blank_lines must be at least 1.".into());
+        }
+        if self.max_admins.is_some_and(|max_admins| max_admins < 1) {
+            return Err("max_admins must be at least 1.".into());
+        }
+        if self.max_room_bytes.is_some_and(|max_bytes| max_bytes < 1) {
+            return Err("max_room_bytes must be at least 1.".into());
+        }
+        if self.archive.queue_capacity == 0 {
+            return Err("archive.queue_capacity must be at least 1.".into());
+        }
+        if self.webhooks.max_attempts == 0 {
+            return Err("webhooks.max_attempts must be at least 1.".into());
+        }
+        if self.webhooks.queue_capacity == 0 {
+            return Err("webhooks.queue_capacity must be at least 1.".into());
+        }
+        if self.webhooks.max_concurrent_requests == 0 {
+            return Err("webhooks.max_concurrent_requests must be at least 1.".into());
+        }
+        if self.new_room_grace_period_ms < 0 {
+            return Err("new_room_grace_period_ms cannot be negative.".into());
+        }
+        if self.max_messages_per_update == 0 {
+            return Err("max_messages_per_update must be at least 1.".into());
+        }
+        if self.session_cookie == SessionCookieMode::Signed && self.session_cookie_secret.is_none()
+        {
+            return Err(
+                "Session cookies are signed, but there is no session_cookie_secret.".into(),
+            );
+        }
+        if self.room_defaults.encrypted && self.encryption_secret.is_none() {
+            return Err(
+                "New rooms are encrypted by default, but there is no encryption secret.".into(),
+            );
+        }
+        if let Some(profile) = self.room_defaults.sanitizer_profile.as_deref() {
+            if !self.sanitizer_profiles.contains_key(profile) {
+                return Err(format!(
+                    "New rooms use the sanitizer profile {:?}, which is not configured.",
+                    profile
+                ));
+            }
+        }
+        self.constraints.validate()?;
+        self.validate_rooms()
+    }
+
+    /// Checks the rooms which should be created when the server starts.
+    ///
+    /// The constraints should be applied first, since they affect room names.
+    fn validate_rooms(&self) -> Result<(), String> {
+        let mut names = Vec::new();
+        for room in &self.rooms {
+            let name = self
+                .constraints
+                .check_new_room_name(&room.name)
+                .map_err(|reason| format!("Invalid room {:?}: {}", room.name, reason))?
+                .0;
+            if names.contains(&name) {
+                return Err(format!(
+                    "The room {:?} is listed more than once.",
+                    room.name
+                ));
+            }
+            let password = room
+                .password
+                .as_deref()
+                .ok_or_else(|| format!("The room {:?} has no password.", room.name))?;
+            self.constraints
+                .check_password(password)
+                .map_err(|reason| format!("Invalid room {:?}: {}", room.name, reason))?;
+            if room.settings(&self.room_defaults).encrypted && self.encryption_secret.is_none() {
+                return Err(format!(
+                    "The room {:?} is encrypted, but there is no encryption secret.",
+                    room.name
+                ));
+            }
+            if let Some(profile) = room.sanitizer_profile.as_deref() {
+                if !self.sanitizer_profiles.contains_key(profile) {
+                    return Err(format!(
+                        "The room {:?} uses the sanitizer profile {:?}, which is not configured.",
+                        room.name, profile
+                    ));
+                }
+            }
+            names.push(name);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the forum's configuration before Rocket is built, for the settings
+/// which Rocket needs early, like its limits and its routes.
+///
+/// The configuration is checked properly later, by the `ConfigFairing`,
+/// so the defaults are used here if it's invalid.
+pub fn read_early(figment: &Figment) -> ForumConfig {
+    figment
+        .focus(CONFIG_KEY)
+        .extract::<ForumConfig>()
+        .unwrap_or_default()
+}
+
+/// Sets Rocket's data limits which depend on the forum's configuration.
+///
+/// Only the limits specific to the forum's routes are set, and only if they
+/// are not set in Rocket's configuration already.
+pub fn with_limits(figment: Figment) -> Figment {
+    let config = read_early(&figment);
+    figment
+        .join((
+            limited_json::limit_path::<MessageJson>(),
+            config.post_body_limit(),
+        ))
+        .join((
+            limited_json::limit_path::<WelcomeMessageJson>(),
+            config.welcome_message_body_limit(),
+        ))
+}
+
+/// A fairing which reads the forum's configuration and makes it available
+/// as managed state.
+///
+/// It should be attached before other fairings which need the configuration.
+#[derive(Default)]
+pub struct ConfigFairing;
+
+#[rocket::async_trait]
+impl Fairing for ConfigFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Forum Config Fairing",
+            kind: Kind::Ignite,
+        }
+    }
+
+    /// Reads the configuration, failing to launch if it's invalid.
+    ///
+    /// The files referenced by the configuration are read here too.
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let mut config = match rocket.figment().focus(CONFIG_KEY).extract::<ForumConfig>() {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("Invalid forum configuration: {}", err);
+                return Err(rocket);
+            }
+        };
+
+        let loaded = config.rooms.iter_mut().try_for_each(ProvisionedRoom::load);
+        if let Err(reason) = config.word_filter.load().and(loaded) {
+            eprintln!("Invalid forum configuration: {}", reason);
+            return Err(rocket);
+        }
+
+        match config.validate() {
+            Ok(()) => Ok(rocket.manage(config)),
+            Err(reason) => {
+                eprintln!("Invalid forum configuration: {}", reason);
+                Err(rocket)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::data::{ByteUnit, Limits};
+    use rocket::serde::json::serde_json;
+
+    use super::*;
+
+    fn limits(figment: Figment) -> Limits {
+        with_limits(figment).extract_inner("limits").unwrap()
+    }
+
+    #[test]
+    fn post_limit_does_not_override_json_limit() {
+        let limits = limits(Figment::from(("limits.json", 1234)));
+        let post_limit = ForumConfig::default().post_body_limit();
+
+        assert_eq!(limits.get("json"), Some(ByteUnit::from(1234)));
+        assert_eq!(
+            limits.find(["json", "post"]),
+            Some(ByteUnit::from(post_limit))
+        );
+    }
+
+    #[test]
+    fn welcome_message_gets_its_own_limit() {
+        let limits = limits(Figment::from(("limits.json", 1234)));
+        let limit = ForumConfig::default().welcome_message_body_limit();
+        assert_eq!(
+            limits.find(["json", "welcome_message"]),
+            Some(ByteUnit::from(limit))
+        );
+    }
+
+    #[test]
+    fn empty_room_creation_window_is_rejected() {
+        let mut config = ForumConfig::default();
+        config.room_creation_limit.window_secs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn negative_undelete_window_is_rejected() {
+        let config = ForumConfig {
+            undelete_window_secs: -1,
+            ..ForumConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rooms_are_only_created_within_the_window() {
+        let config = ForumConfig {
+            room_creation_opens: Some(1_000),
+            room_creation_closes: Some(2_000),
+            ..ForumConfig::default()
+        };
+        assert!(!config.room_creation_allowed(999));
+        assert!(config.room_creation_allowed(1_000));
+        assert!(config.room_creation_allowed(1_999));
+        assert!(!config.room_creation_allowed(2_000));
+        assert!(ForumConfig::default().room_creation_allowed(0));
+    }
+
+    #[test]
+    fn effective_configs_leave_secrets_out() {
+        let config = ForumConfig {
+            max_messages_per_update: 123,
+            encryption_secret: Some("encryption-secret".into()),
+            author_token_secret: Some("author-secret".into()),
+            public_id_secret: Some("public-id-secret".into()),
+            session_cookie_secret: Some("cookie-secret".into()),
+            rooms: vec![ProvisionedRoom {
+                name: "lobby".into(),
+                password: Some("room-password".into()),
+                ..ProvisionedRoom::default()
+            }],
+            ..ForumConfig::default()
+        };
+        let shown = serde_json::to_value(config.effective()).unwrap();
+        assert_eq!(shown["forum"]["max_messages_per_update"], 123);
+        assert_eq!(shown["forum"]["rooms"][0]["name"], "lobby");
+        assert_eq!(shown["session_timeout_secs"], SESSION_TIMEOUT_SECS);
+
+        let text = shown.to_string();
+        for secret in [
+            "encryption-secret",
+            "author-secret",
+            "public-id-secret",
+            "cookie-secret",
+            "room-password",
+        ] {
+            assert!(!text.contains(secret), "{} was shown", secret);
+        }
+    }
+
+    #[test]
+    fn explicit_post_limit_is_kept() {
+        let limits = limits(Figment::from(("limits.json/post", 4321)));
+        assert_eq!(limits.find(["json", "post"]), Some(ByteUnit::from(4321)));
+    }
+
+    #[test]
+    fn unchosen_room_settings_are_taken_from_the_defaults() {
+        let figment = Figment::from(("forum.room_defaults.store_author", false))
+            .merge(("forum.room_defaults.markdown_enabled", false));
+        let config = read_early(&figment);
+        let room = ProvisionedRoom {
+            name: "lobby".into(),
+            markdown_enabled: Some(true),
+            ..ProvisionedRoom::default()
+        };
+
+        let settings = room.settings(&config.room_defaults);
+        assert!(!settings.store_author);
+        assert!(settings.markdown_enabled);
+        assert!(settings.threading_enabled);
+    }
+}
@@ -7,19 +7,149 @@
 //!
 //! The types provided by this module implement certain traits which should
 //! make them easy to use, especially as parameter guards and data guards.
+//!
+//! Some of these limits can be changed through the server's configuration.
+//! The `Constraints` struct holds the limits which are actually in effect.
+//!
+//! Request bodies read by the data guards are also subject to a time limit,
+//! so slow clients cannot keep the server busy by sending data very slowly.
+//!
+//! New room names are ASCII-only by default. Servers can allow Unicode letters
+//! and digits too. Names are normalized to NFC, so names which look the same
+//! because they are canonically equivalent cannot name different rooms.
 
 use std::fmt::{self, Display, Formatter};
 
 use rocket::data::{Data, FromData, Outcome, ToByteUnit};
-use rocket::http::Status;
+use rocket::http::{RawStr, Status};
 use rocket::request::{self, FromParam, Request};
-use serde::Serialize;
+use rocket::tokio::time::{timeout, Duration};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::{ForumConfig, DEFAULT_BODY_READ_TIMEOUT_SECS};
+use crate::template_variables::MAX_WELCOME_MESSAGE_LEN;
 
-/// The maximum length (in bytes) allowed for a messsage.
+/// The default maximum length (in bytes) allowed for a messsage.
 pub const MAX_MESSAGE_LEN: usize = 2048;
 /// The maximum length (in bytes) allowed for a room name.
+///
+/// Room names which are longer than this are never valid, regardless of
+/// the server's configuration.
 pub const MAX_ROOM_NAME_LEN: usize = 128;
 
+/// Holds the limits which are enforced by the server.
+///
+/// These can be changed from the `constraints` section of the forum's
+/// configuration, and are exposed to clients so they can validate their
+/// inputs before sending them.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Constraints {
+    /// The maximum length (in bytes) allowed for a message.
+    pub max_message_len: usize,
+    /// The maximum length (in bytes) allowed for the name of a new room.
+    /// It cannot exceed `MAX_ROOM_NAME_LEN`.
+    pub max_room_name_len: usize,
+    /// The maximum length (in bytes) allowed for the welcome message.
+    pub max_welcome_message_len: usize,
+    /// The minimum length (in bytes) of a room password.
+    pub min_password_len: usize,
+    /// Whether room names can contain Unicode letters and digits.
+    /// If not, only ASCII ones are allowed.
+    pub unicode_room_names: bool,
+}
+
+impl Default for Constraints {
+    fn default() -> Self {
+        Self {
+            max_message_len: MAX_MESSAGE_LEN,
+            max_room_name_len: MAX_ROOM_NAME_LEN,
+            max_welcome_message_len: MAX_WELCOME_MESSAGE_LEN,
+            min_password_len: 1,
+            unicode_room_names: false,
+        }
+    }
+}
+
+impl Constraints {
+    /// Checks if the limits make sense, returning the reason if they don't.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_room_name_len > MAX_ROOM_NAME_LEN {
+            return Err(format!(
+                "max_room_name_len cannot exceed {}.",
+                MAX_ROOM_NAME_LEN
+            ));
+        }
+        if self.min_password_len == 0 {
+            return Err("min_password_len must be at least 1.".into());
+        }
+        Ok(())
+    }
+
+    /// Checks if a room password respects the password rules.
+    /// If it does not, a reason is returned as a human-readable string.
+    pub fn check_password(&self, password: &str) -> Result<(), String> {
+        if password.is_empty() {
+            return Err("The password cannot be empty.".into());
+        }
+        if password.len() < self.min_password_len {
+            return Err(format!(
+                "The password must have at least {} characters.",
+                self.min_password_len
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks if a name is valid for a new room.
+    /// If it is not, a reason is returned as a human-readable string.
+    pub fn check_new_room_name(&self, name: &str) -> Result<RoomName, String> {
+        if name.len() > self.max_room_name_len {
+            return Err(format!(
+                "The room name is too long. It can have at most {} characters.",
+                self.max_room_name_len
+            ));
+        }
+        let name = RoomName::parse(name)?;
+        if !self.unicode_room_names && !name.0.is_ascii() {
+            return Err("The room name contains invalid characters.".into());
+        }
+        Ok(name)
+    }
+}
+
+/// Reads the body of a request as a string of at most `limit` bytes.
+///
+/// Reading is aborted if it takes longer than the configured timeout.
+/// If reading fails, the returned error can be used as a data guard's outcome.
+pub async fn read_body(
+    req: &Request<'_>,
+    data: Data<'_>,
+    limit: usize,
+) -> Result<String, (Status, String)> {
+    let secs = req
+        .rocket()
+        .state::<ForumConfig>()
+        .map_or(DEFAULT_BODY_READ_TIMEOUT_SECS, |config| {
+            config.body_read_timeout_secs
+        });
+
+    match timeout(
+        Duration::from_secs(secs),
+        data.open(limit.bytes()).into_string(),
+    )
+    .await
+    {
+        Ok(Ok(string)) => Ok(string.into_inner()),
+        Ok(Err(err)) => Err((Status::InternalServerError, format!("{:?}", err))),
+        Err(_) => Err((
+            Status::RequestTimeout,
+            "Timed out while reading the request body.".into(),
+        )),
+    }
+}
+
 /// Represents a valid name for a room.
 #[derive(Serialize)]
 pub struct RoomName(pub String);
@@ -29,9 +159,15 @@ impl RoomName {
     /// If the name is invalid, a reason is returned as a human-readable string.
     ///
     /// Valid room names are not allowed to be empty. They also should not
-    /// be too long. The only characters permitted are alphanumeric ASCII
-    /// characters and a few "special" ones, such as: '_' and '-'.
+    /// be too long. The only characters permitted are alphanumeric characters
+    /// and a few "special" ones, such as: '_' and '-'. The name is normalized
+    /// first.
+    ///
+    /// Names are parsed the same way whether Unicode room names are allowed
+    /// or not, since rooms might have been created while they were. Whether a
+    /// new room can have a Unicode name is checked by `check_new_room_name`.
     pub fn parse(name: &str) -> Result<Self, String> {
+        let name = &normalize_room_name(name);
         if name.is_empty() {
             return Err("The room name cannot be empty.".into());
         }
@@ -39,7 +175,7 @@ impl RoomName {
             return Err("The room name is too long.".into());
         }
 
-        let valid = |ch: char| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-';
+        let valid = |ch: char| ch.is_alphanumeric() || ch == '_' || ch == '-';
         if name.chars().all(valid) {
             Ok(Self(name.to_string()))
         } else {
@@ -48,6 +184,19 @@ impl RoomName {
     }
 }
 
+/// Normalizes the name of a room.
+///
+/// Names should be normalized before being compared to the names of
+/// existing rooms. ASCII names never change.
+pub fn normalize_room_name(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Returns the URL of a room's page, given its name, percent-encoded.
+pub fn room_url(name: &str) -> String {
+    format!("/room/{}", RawStr::new(name).percent_encode())
+}
+
 impl<'r> FromParam<'r> for RoomName {
     type Error = &'r str;
 
@@ -61,9 +210,9 @@ impl<'r> FromData<'r> for RoomName {
     type Error = String;
 
     async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
-        let name = match data.open(MAX_ROOM_NAME_LEN.bytes()).into_string().await {
-            Ok(string) => string.into_inner(),
-            Err(err) => return Outcome::Error((Status::InternalServerError, format!("{:?}", err))),
+        let name = match read_body(req, data, MAX_ROOM_NAME_LEN).await {
+            Ok(string) => string,
+            Err(err) => return Outcome::Error(err),
         };
 
         // Suggested replacement for `FromDataSimple`.
@@ -83,3 +232,98 @@ impl Display for RoomName {
         write!(f, "{}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use rocket::config::LogLevel;
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::net::TcpStream;
+    use rocket::tokio::time::sleep;
+    use rocket::Config;
+
+    use super::*;
+
+    fn constraints(unicode_room_names: bool) -> Constraints {
+        Constraints {
+            unicode_room_names,
+            ..Constraints::default()
+        }
+    }
+
+    #[test]
+    fn new_room_names_are_ascii_by_default() {
+        assert!(constraints(false).check_new_room_name("general-2").is_ok());
+        assert!(constraints(false).check_new_room_name("café").is_err());
+    }
+
+    #[test]
+    fn new_room_names_can_be_unicode() {
+        let name = constraints(true)
+            .check_new_room_name("cafe\u{301}")
+            .unwrap();
+        assert_eq!(name.0, "caf\u{e9}");
+        assert!(constraints(true).check_new_room_name("a b").is_err());
+    }
+
+    #[test]
+    fn room_names_are_normalized_when_parsed() {
+        assert_eq!(RoomName::parse("cafe\u{301}").unwrap().0, "caf\u{e9}");
+        assert_eq!(RoomName::parse("general").unwrap().0, "general");
+        assert!(RoomName::parse("").is_err());
+    }
+
+    #[rocket::post("/name", data = "<name>")]
+    fn name(name: RoomName) -> String {
+        name.0
+    }
+
+    #[rocket::async_test]
+    async fn stalled_bodies_time_out() {
+        // Local clients send bodies all at once, so a real server is needed.
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let config = Config {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port,
+            log_level: LogLevel::Off,
+            ..Config::debug_default()
+        };
+        let rocket = rocket::custom(config)
+            .mount("/", rocket::routes![name])
+            .manage(ForumConfig {
+                body_read_timeout_secs: 1,
+                ..ForumConfig::default()
+            })
+            .ignite()
+            .await
+            .unwrap();
+        let shutdown = rocket.shutdown();
+        rocket::tokio::spawn(rocket.launch());
+
+        let mut stream = loop {
+            match TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+                Ok(stream) => break stream,
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        };
+        // Rocket itself waits for the first bytes of the body, so some are sent.
+        let request = "POST /name HTTP/1.1\r\nHost: localhost\r\nContent-Length: 40\r\n\r\n";
+        let body = "a".repeat(20);
+        stream
+            .write_all(format!("{}{}", request, body).as_bytes())
+            .await
+            .unwrap();
+        let mut response = vec![0; 64];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut response))
+            .await
+            .expect("The body read should have timed out")
+            .unwrap();
+        shutdown.notify();
+
+        assert!(response[..read].starts_with(b"HTTP/1.1 408"));
+    }
+}
@@ -3,19 +3,295 @@
 //! The app uses one database with many tables. This module provides
 //! data types and fairings to connect to this database, and to correctly
 //! set it up when starting.
+//!
+//! All the queries are made through the `Client` and `Transaction` types of
+//! this module, which time them. If the `slow_query_threshold_ms` setting is
+//! configured, the queries which take longer are logged, to help with
+//! diagnosing performance issues.
+//!
+//! Under load, requests might find no free connection in the pool before the
+//! pool's timeout. They receive a `503 Service Unavailable` response, with a
+//! `Retry-After` header, so clients back off instead of retrying immediately.
+
+use std::time::Instant;
 
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::{fairing, Build, Rocket};
+use rocket::http::{Header, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{json, serde_json, Json};
+use rocket::{catch, fairing, Build, Either, Phase, Request, Rocket};
+use rocket_sync_db_pools::postgres::types::ToSql;
+use rocket_sync_db_pools::postgres::{IsolationLevel, Row};
 use rocket_sync_db_pools::{database, postgres, rocket};
 
-/// A connection to the database.
+use crate::config::ForumConfig;
+use crate::prefers_json;
+use crate::rooms::{ProvisionedRoom, Room, RoomSettings};
+
+/// The pool of connections to the database.
 #[database("db")]
-pub struct DbConn(postgres::Client);
+pub struct DbPool(postgres::Client);
 
 /// An error which can result from interacting with the database.
 /// This type can "hide" the concrete type used by the database library.
 pub type Error = postgres::Error;
 
+/// The parameters of a query.
+type Params<'a> = [&'a (dyn ToSql + Sync)];
+
+/// Times queries, and logs those which take too long.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueryTimer {
+    /// The duration (in milliseconds) after which queries are logged as slow.
+    /// Slow queries are not logged if it's missing or 0.
+    threshold_ms: Option<u64>,
+}
+
+impl QueryTimer {
+    /// Builds the timer configured for the server.
+    fn configured<P: Phase>(rocket: &Rocket<P>) -> Self {
+        Self {
+            threshold_ms: rocket
+                .state::<ForumConfig>()
+                .and_then(|config| config.slow_query_threshold_ms),
+        }
+    }
+
+    /// Runs a query, logging it if it takes too long.
+    ///
+    /// The query is identified by the first line of its SQL.
+    fn time<T>(self, sql: &str, query: impl FnOnce() -> T) -> T {
+        let (result, warning) = self.measure(sql, query);
+        if let Some(warning) = warning {
+            eprintln!("{}", warning);
+        }
+        result
+    }
+
+    /// Runs a query, returning its result and, if it took too long,
+    /// the warning which should be logged about it.
+    fn measure<T>(self, sql: &str, query: impl FnOnce() -> T) -> (T, Option<String>) {
+        let start = Instant::now();
+        let result = query();
+        let elapsed = start.elapsed().as_millis();
+        let warning = self.is_slow(elapsed).then(|| {
+            let operation = sql.trim().lines().next().unwrap_or_default();
+            format!("Slow query ({} ms): {}", elapsed, operation)
+        });
+        (result, warning)
+    }
+
+    /// Checks if a query which took the given number of milliseconds is slow.
+    fn is_slow(self, elapsed_ms: u128) -> bool {
+        self.threshold_ms
+            .is_some_and(|threshold| threshold > 0 && elapsed_ms >= u128::from(threshold))
+    }
+}
+
+/// A connection to the database, taken from the pool.
+///
+/// The queries made through it are timed, as the server is configured.
+pub struct DbConn {
+    pool: DbPool,
+    timer: QueryTimer,
+}
+
+impl DbConn {
+    /// Returns the fairing which sets up the pool of connections.
+    pub fn fairing() -> impl Fairing {
+        DbPool::fairing()
+    }
+
+    /// Takes a connection from the pool, outside of requests.
+    pub async fn get_one<P: Phase>(rocket: &Rocket<P>) -> Option<Self> {
+        Some(Self {
+            pool: DbPool::get_one(rocket).await?,
+            timer: QueryTimer::configured(rocket),
+        })
+    }
+
+    /// Runs a function with the connection, on a thread where it can block.
+    pub async fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Client<'_>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let timer = self.timer;
+        self.pool
+            .run(move |inner| f(&mut Client { inner, timer }))
+            .await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DbConn {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let pool = try_outcome!(req.guard::<DbPool>().await);
+        request::Outcome::Success(Self {
+            pool,
+            timer: QueryTimer::configured(req.rocket()),
+        })
+    }
+}
+
+/// The queries which can be made both outside and inside transactions.
+///
+/// The methods work like those of the database library, except that the
+/// queries are timed.
+pub trait GenericClient {
+    /// Executes a statement, returning the number of rows it changed.
+    fn execute(&mut self, sql: &str, params: &Params) -> Result<u64, Error>;
+    /// Executes a query, returning its rows.
+    fn query(&mut self, sql: &str, params: &Params) -> Result<Vec<Row>, Error>;
+    /// Executes a query which should return exactly one row.
+    fn query_one(&mut self, sql: &str, params: &Params) -> Result<Row, Error>;
+    /// Executes a query which should return at most one row.
+    fn query_opt(&mut self, sql: &str, params: &Params) -> Result<Option<Row>, Error>;
+    /// Executes a sequence of statements, without parameters.
+    fn batch_execute(&mut self, sql: &str) -> Result<(), Error>;
+    /// Starts a transaction, or a savepoint inside a transaction.
+    fn transaction(&mut self) -> Result<Transaction<'_>, Error>;
+}
+
+/// Implements `GenericClient` for a type which has a `timer`, and whose
+/// `inner` field is a client of the database library.
+macro_rules! timed_client {
+    ($client:ty) => {
+        impl GenericClient for $client {
+            fn execute(&mut self, sql: &str, params: &Params) -> Result<u64, Error> {
+                self.timer.time(sql, || self.inner.execute(sql, params))
+            }
+
+            fn query(&mut self, sql: &str, params: &Params) -> Result<Vec<Row>, Error> {
+                self.timer.time(sql, || self.inner.query(sql, params))
+            }
+
+            fn query_one(&mut self, sql: &str, params: &Params) -> Result<Row, Error> {
+                self.timer.time(sql, || self.inner.query_one(sql, params))
+            }
+
+            fn query_opt(&mut self, sql: &str, params: &Params) -> Result<Option<Row>, Error> {
+                self.timer.time(sql, || self.inner.query_opt(sql, params))
+            }
+
+            fn batch_execute(&mut self, sql: &str) -> Result<(), Error> {
+                self.timer.time(sql, || self.inner.batch_execute(sql))
+            }
+
+            fn transaction(&mut self) -> Result<Transaction<'_>, Error> {
+                Ok(Transaction {
+                    inner: self.inner.transaction()?,
+                    timer: self.timer,
+                })
+            }
+        }
+    };
+}
+
+/// A client of the database, whose queries are timed.
+pub struct Client<'c> {
+    inner: &'c mut postgres::Client,
+    timer: QueryTimer,
+}
+
+timed_client!(Client<'_>);
+
+impl Client<'_> {
+    /// Starts building a transaction with non-default settings.
+    pub fn build_transaction(&mut self) -> TransactionBuilder<'_> {
+        TransactionBuilder {
+            inner: self.inner.build_transaction(),
+            timer: self.timer,
+        }
+    }
+}
+
+/// A database transaction, whose queries are timed.
+///
+/// Like in the database library, it's rolled back if it's dropped without
+/// being committed.
+pub struct Transaction<'t> {
+    inner: postgres::Transaction<'t>,
+    timer: QueryTimer,
+}
+
+timed_client!(Transaction<'_>);
+
+impl Transaction<'_> {
+    /// Commits the transaction.
+    pub fn commit(self) -> Result<(), Error> {
+        let inner = self.inner;
+        self.timer.time("COMMIT", || inner.commit())
+    }
+}
+
+/// Builds a transaction with non-default settings.
+pub struct TransactionBuilder<'a> {
+    inner: postgres::TransactionBuilder<'a>,
+    timer: QueryTimer,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    /// Sets the isolation level of the transaction.
+    pub fn isolation_level(self, level: IsolationLevel) -> Self {
+        Self {
+            inner: self.inner.isolation_level(level),
+            ..self
+        }
+    }
+
+    /// Sets whether the transaction can only read.
+    pub fn read_only(self, read_only: bool) -> Self {
+        Self {
+            inner: self.inner.read_only(read_only),
+            ..self
+        }
+    }
+
+    /// Starts the transaction.
+    pub fn start(self) -> Result<Transaction<'a>, Error> {
+        Ok(Transaction {
+            inner: self.inner.start()?,
+            timer: self.timer,
+        })
+    }
+}
+
+/// How long (in seconds) clients should wait before retrying a request
+/// which found no free database connection.
+const RETRY_AFTER_SECS: u32 = 5;
+
+/// A response which tells clients that the server is too busy, and
+/// when they should retry their request.
+pub struct Unavailable<R>(R);
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Unavailable<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        Response::build_from(self.0.respond_to(req)?)
+            .status(Status::ServiceUnavailable)
+            .header(Header::new("Retry-After", RETRY_AFTER_SECS.to_string()))
+            .ok()
+    }
+}
+
+/// A catcher for requests which could not get a database connection in time.
+///
+/// Clients which prefer JSON receive an error object instead of a message.
+#[catch(503)]
+pub fn unavailable(req: &Request<'_>) -> Unavailable<Either<Json<serde_json::Value>, String>> {
+    if prefers_json(req) {
+        return Unavailable(Either::Left(Json(json!({ "error": "unavailable" }))));
+    }
+    Unavailable(Either::Right(format!(
+        "The server is busy. Please try again in {} seconds.",
+        RETRY_AFTER_SECS
+    )))
+}
+
 /// A fairing which makes sure we can interact with the database correctly.
 #[derive(Default)]
 pub struct DbInitFairing;
@@ -23,9 +299,22 @@ pub struct DbInitFairing;
 impl DbInitFairing {
     /// Initializes the database, as it should be when starting the server.
     ///
-    /// It drops tables which should not be persistent, and makes sure
-    /// all tables needed for the server to function are set up correctly.
-    fn init_db(client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    /// It makes sure all tables needed for the server to function are set up
+    /// correctly, including the message tables of existing rooms. Message tables
+    /// which belong to no room are dropped.
+    ///
+    /// If `clear_sessions` is set, the data of the sessions, which should not
+    /// be persistent, is removed. Otherwise, sessions keep their access to rooms,
+    /// and the stale ones are removed later, by the session cleaner.
+    ///
+    /// The given rooms are created, if they do not exist yet, with the default
+    /// settings for the settings they do not list.
+    fn init_db(
+        client: &mut Client,
+        clear_sessions: bool,
+        rooms: &[ProvisionedRoom],
+        room_defaults: &RoomSettings,
+    ) -> Result<(), postgres::Error> {
         client.batch_execute(
             "CREATE TABLE IF NOT EXISTS admins (
                 username TEXT PRIMARY KEY,
@@ -58,15 +347,94 @@ impl DbInitFairing {
                 FOREIGN KEY (id) REFERENCES sessions(id) ON DELETE CASCADE,
                 FOREIGN KEY (name) REFERENCES rooms(name) ON DELETE CASCADE
             );
+            CREATE TABLE IF NOT EXISTS invites (
+                code      TEXT PRIMARY KEY,
+                name      TEXT NOT NULL,
+                uses_left INT,
+                expires   BIGINT,
+                FOREIGN KEY (name) REFERENCES rooms(name) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS archived_rooms (
+                name      TEXT NOT NULL,
+                table_id  INT NOT NULL,
+                encrypted BOOLEAN NOT NULL,
+                archived  BIGINT NOT NULL
+            );
             CREATE TABLE IF NOT EXISTS template_variables (
                 name  TEXT PRIMARY KEY,
                 value TEXT
             );
+            CREATE TABLE IF NOT EXISTS template_history (
+                id    SERIAL PRIMARY KEY,
+                name  TEXT NOT NULL,
+                value TEXT NOT NULL,
+                saved BIGINT NOT NULL,
+                admin TEXT
+            );
+            CREATE TABLE IF NOT EXISTS room_webhooks (
+                id     SERIAL PRIMARY KEY,
+                room   TEXT NOT NULL,
+                url    TEXT NOT NULL,
+                secret TEXT,
+                FOREIGN KEY (room) REFERENCES rooms(name) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS notifications (
+                id      SERIAL PRIMARY KEY,
+                session TEXT NOT NULL,
+                room    TEXT NOT NULL,
+                message INT NOT NULL,
+                reply   INT NOT NULL,
+                created BIGINT NOT NULL,
+                FOREIGN KEY (session) REFERENCES sessions(id) ON DELETE CASCADE,
+                FOREIGN KEY (room) REFERENCES rooms(name) ON DELETE CASCADE
+            );
 
-            DELETE FROM sessions;
-            DELETE FROM room_attempts;
-            DELETE FROM room_updates;",
-        )
+            -- Columns added after the tables were first created.
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS encrypted BOOLEAN NOT NULL DEFAULT FALSE;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS threading_enabled BOOLEAN NOT NULL DEFAULT TRUE;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS store_author BOOLEAN NOT NULL DEFAULT TRUE;
+            ALTER TABLE room_updates ADD COLUMN IF NOT EXISTS last_change BIGINT;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS entry_message TEXT;
+            ALTER TABLE room_attempts ADD COLUMN IF NOT EXISTS entry_message_seen BOOLEAN NOT NULL DEFAULT FALSE;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS public BOOLEAN NOT NULL DEFAULT FALSE;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS markdown_enabled BOOLEAN NOT NULL DEFAULT TRUE;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS post_permission TEXT NOT NULL DEFAULT 'everyone';
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS accepting_posts_after BIGINT;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS compacted_change BIGINT NOT NULL DEFAULT 0;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS created_by TEXT;
+            ALTER TABLE rooms ADD COLUMN IF NOT EXISTS sanitizer_profile TEXT;
+            ALTER TABLE sessions ADD COLUMN IF NOT EXISTS admin_username TEXT;
+            ALTER TABLE sessions ADD COLUMN IF NOT EXISTS created_at BIGINT;
+            -- The creation times of older sessions are not known, so their
+            -- lifetimes start from their last updates.
+            UPDATE sessions SET created_at = last_update WHERE created_at IS NULL;
+            -- Archives used to be identified by their names, so rooms with the
+            -- same name could not both be archived. They are identified by the
+            -- ids of their tables now.
+            ALTER TABLE archived_rooms DROP CONSTRAINT IF EXISTS archived_rooms_pkey;
+            CREATE UNIQUE INDEX IF NOT EXISTS archived_rooms_table_id ON archived_rooms (table_id);
+
+            -- Numbers the changes made to the messages of all rooms.
+            CREATE SEQUENCE IF NOT EXISTS message_changes;",
+        )?;
+
+        if clear_sessions {
+            client.batch_execute(
+                "DELETE FROM sessions;
+                DELETE FROM room_attempts;
+                DELETE FROM room_updates;",
+            )?;
+        }
+
+        Room::drop_orphan_tables(client)?;
+        Room::setup_all_tables(client)?;
+
+        for room in rooms {
+            if Room::provision(client, room, room_defaults)? {
+                println!("Created the configured room {}.", room.name);
+            }
+        }
+        Ok(())
     }
 }
 
@@ -80,13 +448,26 @@ impl Fairing for DbInitFairing {
     }
 
     /// Sets up the database so that the server can start working.
+    ///
+    /// It should be attached after the `ConfigFairing`.
     async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let (clear_sessions, rooms, room_defaults) = match rocket.state::<ForumConfig>() {
+            Some(config) => (
+                config.clear_sessions_on_start,
+                config.rooms.clone(),
+                config.room_defaults.clone(),
+            ),
+            None => return Err(rocket),
+        };
         let conn = match DbConn::get_one(&rocket).await {
             Some(conn) => conn,
             _ => return Err(rocket),
         };
 
-        match conn.run(DbInitFairing::init_db).await {
+        match conn
+            .run(move |c| DbInitFairing::init_db(c, clear_sessions, &rooms, &room_defaults))
+            .await
+        {
             Ok(_) => Ok(rocket),
             _ => Err(rocket),
         }
@@ -122,3 +503,258 @@ macro_rules! query_and_map {
         $client.query($sql, $params)?.into_iter().map($row_map)
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use rocket::http::Accept;
+
+    use super::*;
+    use crate::rooms::{ProvisionedRoom, RoomLogin, RoomSettings};
+    use crate::sessions::Session;
+
+    #[test]
+    fn queries_over_the_threshold_are_slow() {
+        let timer = QueryTimer {
+            threshold_ms: Some(100),
+        };
+        assert!(!timer.is_slow(99));
+        assert!(timer.is_slow(100));
+    }
+
+    #[test]
+    fn queries_are_not_slow_without_a_threshold() {
+        assert!(!QueryTimer::default().is_slow(10_000));
+        let timer = QueryTimer {
+            threshold_ms: Some(0),
+        };
+        assert!(!timer.is_slow(10_000));
+    }
+
+    #[test]
+    fn slow_queries_are_logged_by_their_first_line() {
+        let timer = QueryTimer {
+            threshold_ms: Some(10),
+        };
+        let sql = "
+            SELECT pg_sleep(0.05)
+            FROM sessions;";
+        let (_, warning) = timer.measure(sql, || thread::sleep(Duration::from_millis(50)));
+        let warning = warning.unwrap();
+        assert!(warning.starts_with("Slow query ("));
+        assert!(warning.ends_with(" ms): SELECT pg_sleep(0.05)"));
+
+        let (_, warning) = timer.measure(sql, || ());
+        assert_eq!(warning, None);
+    }
+
+    /// Checks if a session is stored.
+    fn session_exists(client: &mut Client, id: &str) -> bool {
+        client
+            .query_opt("SELECT 1 FROM sessions WHERE id = $1;", &[&id])
+            .unwrap()
+            .is_some()
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn sessions_are_only_cleared_on_start_if_configured() {
+        let mut connection = testing::isolated();
+        let mut client = connection.client();
+        let id = Session::start_for_tests(&mut client).id();
+        let defaults = RoomSettings::default();
+
+        DbInitFairing::init_db(&mut client, false, &[], &defaults).unwrap();
+        assert!(session_exists(&mut client, &id));
+        DbInitFairing::init_db(&mut client, true, &[], &defaults).unwrap();
+        assert!(!session_exists(&mut client, &id));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn configured_rooms_are_only_created_once() {
+        let mut connection = testing::isolated();
+        let mut client = connection.client();
+        let defaults = RoomSettings::default();
+        let mut room = ProvisionedRoom {
+            name: "lobby".into(),
+            password: Some("first".into()),
+            public: Some(true),
+            ..ProvisionedRoom::default()
+        };
+        let can_log_in = |client: &mut Client, password: &str| {
+            let login = RoomLogin {
+                name: "lobby".into(),
+                password: password.into(),
+            };
+            login.can_log_in(client).unwrap()
+        };
+
+        DbInitFairing::init_db(&mut client, false, &[room.clone()], &defaults).unwrap();
+        assert!(can_log_in(&mut client, "first"));
+        client
+            .execute("UPDATE rooms SET public = FALSE WHERE name = 'lobby';", &[])
+            .unwrap();
+
+        room.password = Some("second".into());
+        DbInitFairing::init_db(&mut client, false, &[room], &defaults).unwrap();
+        assert!(can_log_in(&mut client, "first"));
+        let public: bool = client
+            .query_one("SELECT public FROM rooms WHERE name = 'lobby';", &[])
+            .unwrap()
+            .get(0);
+        assert!(!public);
+    }
+
+    /// Fails like requests which find no free database connection.
+    #[rocket::get("/busy")]
+    fn busy() -> Status {
+        Status::ServiceUnavailable
+    }
+
+    #[test]
+    fn busy_servers_tell_clients_when_to_retry() {
+        let rocket = rocket::build()
+            .mount("/", rocket::routes![busy])
+            .register("/", rocket::catchers![unavailable]);
+        let server = rocket::local::blocking::Client::untracked(rocket).unwrap();
+
+        let retry_after = RETRY_AFTER_SECS.to_string();
+        let response = server.get("/busy").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(
+            response.headers().get_one("Retry-After"),
+            Some(retry_after.as_str())
+        );
+        let response = server.get("/busy").header(Accept::JSON).dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(
+            response.headers().get_one("Retry-After"),
+            Some(retry_after.as_str())
+        );
+        assert_eq!(
+            response.into_json::<serde_json::Value>(),
+            Some(json!({ "error": "unavailable" }))
+        );
+    }
+}
+
+/// Helpers for the tests which need a database.
+///
+/// These tests are ignored by default. To run them, set `TEST_DATABASE_URL`
+/// to the address of a database which they can freely change, then run
+/// `cargo test -- --include-ignored`.
+#[cfg(test)]
+pub mod testing {
+    use std::sync::Once;
+
+    use rand::distributions::Alphanumeric;
+    use rand::prelude::*;
+    use rocket_sync_db_pools::postgres::{self, NoTls};
+
+    use super::{Client, DbInitFairing, QueryTimer};
+    use crate::rooms::RoomSettings;
+
+    /// A connection to the test database.
+    ///
+    /// Isolated connections work in a schema of their own, which is dropped
+    /// together with the connection.
+    pub struct Connection(postgres::Client, Option<String>);
+
+    impl Connection {
+        /// Returns the URL through which servers can use the same schema
+        /// as the connection.
+        pub fn url(&self) -> String {
+            match &self.1 {
+                Some(schema) => {
+                    let separator = if url().contains('?') { '&' } else { '?' };
+                    format!("{}{}options=-csearch_path%3D{}", url(), separator, schema)
+                }
+                None => url(),
+            }
+        }
+
+        /// Returns a client which makes queries through the connection.
+        pub fn client(&mut self) -> Client<'_> {
+            Client {
+                inner: &mut self.0,
+                timer: QueryTimer::default(),
+            }
+        }
+    }
+
+    /// Returns the URL of the test database.
+    pub fn url() -> String {
+        std::env::var("TEST_DATABASE_URL")
+            .expect("TEST_DATABASE_URL should be set for tests which need a database")
+    }
+
+    /// Connects to the test database, setting it up first if needed.
+    pub fn connect() -> Connection {
+        static SETUP: Once = Once::new();
+
+        let mut connection = Connection(
+            postgres::Client::connect(&url(), NoTls)
+                .expect("The test database should be reachable"),
+            None,
+        );
+        SETUP.call_once(|| {
+            DbInitFairing::init_db(
+                &mut connection.client(),
+                false,
+                &[],
+                &RoomSettings::default(),
+            )
+            .expect("The test database should be set up");
+        });
+        connection
+    }
+
+    /// Connects to a new schema of the test database, set up like a fresh
+    /// database, which only the returned connection uses.
+    ///
+    /// It's meant for tests which affect all the rooms or sessions, so they
+    /// do not disturb other tests.
+    pub fn isolated() -> Connection {
+        let schema = unique_name("test");
+        let mut client = postgres::Client::connect(&url(), NoTls)
+            .expect("The test database should be reachable");
+        client
+            .batch_execute(&format!(
+                "CREATE SCHEMA {schema}; SET search_path TO {schema};",
+                schema = schema
+            ))
+            .expect("The test schema should be created");
+
+        let mut connection = Connection(client, Some(schema));
+        DbInitFairing::init_db(
+            &mut connection.client(),
+            false,
+            &[],
+            &RoomSettings::default(),
+        )
+        .expect("The test schema should be set up");
+        connection
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            if let Some(schema) = &self.1 {
+                let _ = self
+                    .0
+                    .batch_execute(&format!("DROP SCHEMA {} CASCADE;", schema));
+            }
+        }
+    }
+
+    /// Returns a name which no other test uses, for rooms and such.
+    pub fn unique_name(prefix: &str) -> String {
+        let suffix: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(12)
+            .collect();
+        format!("{}_{}", prefix, suffix.to_lowercase())
+    }
+}
@@ -0,0 +1,98 @@
+//! Module for encrypting the messages of rooms at rest.
+//!
+//! Rooms can opt into having their messages encrypted before being stored.
+//! Each room uses its own key, derived from a server secret and the room's
+//! `table_id`, so the messages of a room cannot be decrypted using another
+//! room's key.
+//!
+//! Messages are encrypted with ChaCha20-Poly1305, using a random nonce for
+//! each message. The nonce is stored together with the ciphertext, and the
+//! result is encoded as base64, so it fits in the existing text columns.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+
+/// The length (in bytes) of the nonces used for encryption.
+const NONCE_LEN: usize = 12;
+
+/// Encrypts and decrypts the messages of a single room.
+#[derive(Clone)]
+pub struct RoomCipher(ChaCha20Poly1305);
+
+impl RoomCipher {
+    /// Builds the cipher of the room with the given `table_id`.
+    pub fn new(secret: &str, table_id: i32) -> Self {
+        let key = Sha256::new()
+            .chain(secret.as_bytes())
+            .chain(table_id.to_be_bytes())
+            .finalize();
+        Self(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    /// Encrypts a message, returning it in the form in which it should be stored.
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .expect("Error while encrypting a message.");
+
+        let mut stored = nonce.to_vec();
+        stored.extend(ciphertext);
+        BASE64.encode(stored)
+    }
+
+    /// Decrypts a stored message.
+    ///
+    /// It returns `None` if the message is malformed, or if it was not
+    /// encrypted with this room's key.
+    pub fn decrypt(&self, stored: &str) -> Option<String> {
+        let stored = BASE64.decode(stored).ok()?;
+        if stored.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+        let plaintext = self.0.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let cipher = RoomCipher::new("secret", 1);
+        let stored = cipher.encrypt("Hello, world!");
+        assert!(!stored.contains("Hello"));
+        assert_eq!(cipher.decrypt(&stored).as_deref(), Some("Hello, world!"));
+    }
+
+    #[test]
+    fn equal_messages_are_stored_differently() {
+        let cipher = RoomCipher::new("secret", 1);
+        assert_ne!(cipher.encrypt("Hello"), cipher.encrypt("Hello"));
+    }
+
+    #[test]
+    fn other_keys_cannot_decrypt() {
+        let stored = RoomCipher::new("secret", 1).encrypt("Hello");
+        assert_eq!(RoomCipher::new("secret", 2).decrypt(&stored), None);
+        assert_eq!(RoomCipher::new("other", 1).decrypt(&stored), None);
+    }
+
+    #[test]
+    fn malformed_messages_are_refused() {
+        let cipher = RoomCipher::new("secret", 1);
+        let mut stored = BASE64.decode(cipher.encrypt("Hello")).unwrap();
+        *stored.last_mut().unwrap() ^= 1;
+        assert_eq!(cipher.decrypt(&BASE64.encode(stored)), None);
+        assert_eq!(cipher.decrypt("not base64!"), None);
+        assert_eq!(cipher.decrypt(&BASE64.encode([0; NONCE_LEN - 1])), None);
+    }
+}
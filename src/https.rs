@@ -0,0 +1,181 @@
+//! Module for keeping clients from using the server over plaintext HTTP.
+//!
+//! Session cookies travel with every request, so requests sent over plaintext
+//! HTTP expose them. If the `https` setting is `redirect`, such requests are
+//! redirected to the same address over HTTPS, with a `308 Permanent Redirect`
+//! response. The port of the plaintext address is dropped, so the default
+//! HTTPS port is used, unless `https_port` is set. If it's `reject`, they
+//! receive a `403 Forbidden` response instead. Requests are allowed by default.
+//!
+//! When the server handles TLS itself, the scheme of a request is known from
+//! the connection. Behind a reverse proxy which terminates TLS, the scheme is
+//! taken from the `X-Forwarded-Proto` header added by the proxy, but only if
+//! the `trust_proxy` setting is enabled.
+//!
+//! Like for the rate limiter, plaintext requests are sent to a path which
+//! matches no route, and their responses are replaced.
+
+use std::io::Cursor;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::{Host, Origin};
+use rocket::http::{ContentType, Header, Status};
+use rocket::{Data, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ForumConfig;
+
+/// The path to which plaintext requests are sent. No route should match it.
+const PLAINTEXT_PATH: &str = "/https_required";
+
+/// What happens to requests sent over plaintext HTTP.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpsPolicy {
+    /// Requests are handled normally.
+    Allow,
+    /// Requests are redirected to HTTPS.
+    Redirect,
+    /// Requests are refused.
+    Reject,
+}
+
+/// Checks if a request was sent over HTTPS.
+fn is_https(req: &Request<'_>, trust_proxy: bool) -> bool {
+    if trust_proxy {
+        // The proxy nearest to the client sees the original scheme,
+        // which it sends first.
+        if let Some(proto) = req
+            .headers()
+            .get_one("X-Forwarded-Proto")
+            .and_then(|header| header.split(',').next())
+        {
+            return proto.trim().eq_ignore_ascii_case("https");
+        }
+    }
+    req.rocket().config().tls_enabled()
+}
+
+/// Returns the HTTPS address to which a plaintext request is redirected.
+///
+/// The port of the request is meant for plaintext HTTP, so it's replaced.
+fn redirect_target(host: &Host<'_>, port: Option<u16>, uri: &Origin<'_>) -> String {
+    match port.filter(|port| *port != 443) {
+        Some(port) => format!("https://{}:{}{}", host.domain(), port, uri),
+        None => format!("https://{}{}", host.domain(), uri),
+    }
+}
+
+/// How the response to a plaintext request is replaced, kept in the
+/// request's local cache.
+enum Plaintext {
+    /// The request was sent over HTTPS, or it's allowed anyway.
+    Allowed,
+    /// The client should send the request again, to the given address.
+    Redirect(String),
+    /// The request is refused, either by the policy or because it has no
+    /// host to redirect it to.
+    Rejected,
+}
+
+/// A fairing which redirects or rejects requests sent over plaintext HTTP.
+///
+/// It should be attached after the `ConfigFairing`.
+#[derive(Default)]
+pub struct HttpsFairing;
+
+#[rocket::async_trait]
+impl Fairing for HttpsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTPS Fairing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let config = match req.rocket().state::<ForumConfig>() {
+            Some(config) if config.https != HttpsPolicy::Allow => config,
+            _ => return,
+        };
+        if is_https(req, config.trust_proxy) {
+            return;
+        }
+
+        // Requests without a host cannot be redirected.
+        let target = match (config.https, req.host()) {
+            (HttpsPolicy::Redirect, Some(host)) => {
+                Plaintext::Redirect(redirect_target(host, config.https_port, req.uri()))
+            }
+            _ => Plaintext::Rejected,
+        };
+        req.local_cache(|| target);
+        req.set_uri(Origin::parse(PLAINTEXT_PATH).expect("The path should be valid."));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let body = match req.local_cache(|| Plaintext::Allowed) {
+            Plaintext::Allowed => return,
+            Plaintext::Redirect(target) => {
+                res.set_status(Status::PermanentRedirect);
+                res.set_header(Header::new("Location", target.clone()));
+                "This server can only be accessed over HTTPS. Redirecting."
+            }
+            Plaintext::Rejected => {
+                res.set_status(Status::Forbidden);
+                "This server can only be accessed over HTTPS."
+            }
+        };
+        res.set_header(ContentType::Plain);
+        res.set_sized_body(body.len(), Cursor::new(body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    /// Starts a server with the given settings, without any routes.
+    fn server(https: HttpsPolicy, https_port: Option<u16>) -> Client {
+        let config = ForumConfig {
+            https,
+            https_port,
+            trust_proxy: true,
+            ..ForumConfig::default()
+        };
+        let rocket = rocket::build().manage(config).attach(HttpsFairing);
+        Client::untracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn plaintext_requests_are_redirected_without_their_port() {
+        for (port, expected) in [
+            (None, "https://forum.example/room/lobby?x=1"),
+            (Some(443), "https://forum.example/room/lobby?x=1"),
+            (Some(8443), "https://forum.example:8443/room/lobby?x=1"),
+        ] {
+            let server = server(HttpsPolicy::Redirect, port);
+            let mut request = server.get("/room/lobby?x=1");
+            request.set_host(Host::parse("forum.example:8000").unwrap());
+            let response = request.dispatch();
+            assert_eq!(response.status(), Status::PermanentRedirect);
+            assert_eq!(response.headers().get_one("Location"), Some(expected));
+        }
+    }
+
+    #[test]
+    fn only_plaintext_requests_are_rejected() {
+        let server = server(HttpsPolicy::Reject, None);
+        let request = |proto: &'static str| {
+            server
+                .get("/")
+                .header(Header::new("X-Forwarded-Proto", proto))
+                .dispatch()
+                .status()
+        };
+        assert_eq!(request("http"), Status::Forbidden);
+        assert_eq!(request("https"), Status::NotFound);
+    }
+}
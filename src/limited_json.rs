@@ -0,0 +1,63 @@
+//! Module for reading JSON bodies under limits specific to their routes.
+//!
+//! Rocket's `Json` data guard reads all JSON bodies under the `json` limit.
+//! Some routes need limits of their own, derived from the forum's
+//! configuration, without changing the `json` limit which the server's
+//! operator might have configured for all the other routes.
+//!
+//! Bodies read through `LimitedJson` use the limit named `json/<name>`, where
+//! the name is given by the type of the body. Like other limits, it can be
+//! set in Rocket's configuration, and it falls back to the `json` limit.
+
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::serde::json::serde_json;
+use serde::de::DeserializeOwned;
+
+/// The types of JSON bodies which have limits of their own.
+pub trait JsonLimit {
+    /// The name of the limit, under `json`.
+    const NAME: &'static str;
+}
+
+/// Returns the path of the limit which applies to bodies of the given type,
+/// as it's used in Rocket's configuration.
+pub fn limit_path<T: JsonLimit>() -> String {
+    format!("limits.json/{}", T::NAME)
+}
+
+/// The ways in which reading a JSON body can fail.
+#[derive(Debug)]
+pub enum JsonError {
+    /// The body is larger than its limit. It holds the number of bytes read.
+    TooLarge(usize),
+    /// The body could not be read.
+    Io,
+    /// The body is not valid JSON, or it does not have the expected fields.
+    Parse,
+}
+
+/// A JSON body, read under the limit of its type.
+pub struct LimitedJson<T>(pub T);
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned + JsonLimit> FromData<'r> for LimitedJson<T> {
+    type Error = JsonError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = req.limits().find(["json", T::NAME]).unwrap_or(Limits::JSON);
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(body) => {
+                let read = body.into_inner().len();
+                return data::Outcome::Error((Status::PayloadTooLarge, JsonError::TooLarge(read)));
+            }
+            Err(_) => return data::Outcome::Error((Status::BadRequest, JsonError::Io)),
+        };
+        match serde_json::from_str(&body) {
+            Ok(value) => data::Outcome::Success(LimitedJson(value)),
+            Err(_) => data::Outcome::Error((Status::UnprocessableEntity, JsonError::Parse)),
+        }
+    }
+}
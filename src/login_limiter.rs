@@ -0,0 +1,172 @@
+//! Module for limiting the login attempts made to rooms.
+//!
+//! Room passwords could be guessed by trying many of them quickly. To prevent
+//! this, clients which fail to log into a room too many times are locked out
+//! of that room for a while. Each further failure doubles the lockout, up to
+//! a maximum.
+//!
+//! Clients are identified by their IP addresses when they are known, since
+//! new sessions are easy to get. The failures are only kept in memory, so
+//! they are forgotten when the server restarts.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// The settings of the login limiter.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoginLimit {
+    /// The number of failed attempts allowed before a client is locked out.
+    pub max_failures: u32,
+    /// The duration (in seconds) of the first lockout.
+    pub lockout_secs: u64,
+    /// The maximum duration (in seconds) of a lockout. Failures older
+    /// than this are forgotten.
+    pub max_lockout_secs: u64,
+}
+
+impl Default for LoginLimit {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout_secs: 30,
+            max_lockout_secs: 3600,
+        }
+    }
+}
+
+impl LoginLimit {
+    /// Returns the duration of the lockout which follows the given number of failures.
+    fn lockout(&self, failures: u32) -> Option<Duration> {
+        let extra = failures.checked_sub(self.max_failures)?;
+        let secs = self
+            .lockout_secs
+            .saturating_mul(2u64.saturating_pow(extra))
+            .min(self.max_lockout_secs);
+        Some(Duration::from_secs(secs))
+    }
+}
+
+/// The failed login attempts of a client to a room.
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Keeps track of failed login attempts, as managed state.
+#[derive(Default)]
+pub struct LoginLimiter(Mutex<HashMap<(String, String), Failures>>);
+
+impl LoginLimiter {
+    /// Checks if a client may try to log into a room.
+    ///
+    /// If the client is locked out, the remaining duration of the lockout is returned.
+    pub fn check(&self, client: &str, room: &str) -> Result<(), Duration> {
+        let failures = match self.0.lock() {
+            Ok(failures) => failures,
+            Err(_) => return Ok(()),
+        };
+        let locked_until = failures
+            .get(&(client.to_owned(), room.to_owned()))
+            .and_then(|failures| failures.locked_until);
+        match locked_until {
+            Some(until) if until > Instant::now() => Err(until - Instant::now()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a failed attempt of a client to log into a room,
+    /// locking the client out if it failed too many times.
+    pub fn record_failure(&self, client: &str, room: &str, limit: &LoginLimit) {
+        let mut all = match self.0.lock() {
+            Ok(failures) => failures,
+            Err(_) => return,
+        };
+        let now = Instant::now();
+
+        // Forget old failures, so the map does not keep growing.
+        let forget_after = Duration::from_secs(limit.max_lockout_secs);
+        all.retain(|_, failures| now.duration_since(failures.last) < forget_after);
+
+        let failures = all
+            .entry((client.to_owned(), room.to_owned()))
+            .or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+        failures.count = failures.count.saturating_add(1);
+        failures.last = now;
+        failures.locked_until = limit.lockout(failures.count).map(|lockout| now + lockout);
+    }
+
+    /// Forgets the failed attempts of a client which logged into a room.
+    pub fn record_success(&self, client: &str, room: &str) {
+        if let Ok(mut failures) = self.0.lock() {
+            failures.remove(&(client.to_owned(), room.to_owned()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit() -> LoginLimit {
+        LoginLimit {
+            max_failures: 3,
+            lockout_secs: 30,
+            max_lockout_secs: 100,
+        }
+    }
+
+    /// Makes the lockout of a client end, as if its cooldown passed.
+    fn end_lockout(limiter: &LoginLimiter, client: &str, room: &str) {
+        let mut all = limiter.0.lock().unwrap();
+        let failures = all.get_mut(&(client.into(), room.into())).unwrap();
+        failures.locked_until = Some(Instant::now());
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let limit = limit();
+        assert_eq!(limit.lockout(2), None);
+        assert_eq!(limit.lockout(3), Some(Duration::from_secs(30)));
+        assert_eq!(limit.lockout(4), Some(Duration::from_secs(60)));
+        assert_eq!(limit.lockout(5), Some(Duration::from_secs(100)));
+        assert_eq!(limit.lockout(u32::MAX), Some(Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn repeated_failures_lock_clients_out() {
+        let limiter = LoginLimiter::default();
+        for _ in 0..2 {
+            limiter.record_failure("client", "room", &limit());
+            assert!(limiter.check("client", "room").is_ok());
+        }
+        limiter.record_failure("client", "room", &limit());
+        let wait = limiter.check("client", "room").unwrap_err();
+        assert!(wait <= Duration::from_secs(30));
+
+        assert!(limiter.check("client", "other").is_ok());
+        assert!(limiter.check("other", "room").is_ok());
+    }
+
+    #[test]
+    fn logins_succeed_after_the_cooldown() {
+        let limiter = LoginLimiter::default();
+        for _ in 0..3 {
+            limiter.record_failure("client", "room", &limit());
+        }
+        end_lockout(&limiter, "client", "room");
+        assert!(limiter.check("client", "room").is_ok());
+
+        limiter.record_success("client", "room");
+        limiter.record_failure("client", "room", &limit());
+        assert!(limiter.check("client", "room").is_ok());
+    }
+}
@@ -35,51 +35,128 @@
 //! [release]
 //! port = 80
 //! ```
+//!
+//! The settings of the forum itself are read from the `forum` section, such as
+//! `[global.forum]` in `Rocket.toml`. You can find them in the `config` module.
 
 mod admins;
+mod archive;
+mod client_ip;
+mod clock;
+mod compression;
+mod config;
 mod constraints;
 mod db;
+mod encryption;
+mod https;
+mod limited_json;
+mod login_limiter;
 mod messages;
+mod metrics;
+mod ndjson;
+mod poll_limiter;
+mod rate_limiter;
+mod room_creation_limiter;
+mod room_events;
 mod rooms;
+mod sanitizer;
+mod security_headers;
 mod sessions;
 mod static_resources;
 mod template_variables;
 mod users;
+mod version;
+mod webhooks;
+mod word_filter;
 
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use rocket::form::Form;
-use rocket::fs::NamedFile;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::request::FlashMessage;
 use rocket::response::status::NotFound;
+use rocket::response::stream::{Event, EventStream, TextStream};
 use rocket::response::{Flash, Redirect};
-use rocket::serde::json::Json;
+use rocket::serde::json::{json, serde_json, Json};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
 use rocket::*;
 use rocket_dyn_templates::Template;
 
-use admins::{Admin, AdminLogin};
-use constraints::RoomName;
-use db::{DbConn, DbInitFairing};
-use messages::{Message, MessageJson, Updates};
-use rooms::{Room, RoomLogin};
-use sessions::{Session, SessionFairing};
+use admins::{Admin, AdminCreation, AdminLogin, AdminRemoval, NewAdmin};
+use archive::{ArchiveFairing, Archiver};
+use client_ip::ClientIp;
+use compression::CompressedJson;
+use config::{ConfigFairing, EffectiveConfig, ForumConfig, RoomShortcut};
+use constraints::{Constraints, RoomName};
+use db::{DbConn, DbInitFairing, GenericClient};
+use https::HttpsFairing;
+use limited_json::{JsonError, LimitedJson};
+use login_limiter::LoginLimiter;
+use messages::{
+    EditJson, EditOutcome, IntervalChecksum, Message, MessageJson, MessagePreview, MessageRef,
+    Rejection, StorageOptions, ThreadOrder, UpdateQuery,
+};
+use metrics::{Metrics, MetricsFairing};
+use ndjson::{AcceptsNdjson, NegotiatedUpdates};
+use poll_limiter::{PollLimiter, PollPermit};
+use rate_limiter::{RateLimitFairing, RateLimiter};
+use room_creation_limiter::RoomCreationLimiter;
+use room_events::{RoomEventKind, RoomEvents};
+use rooms::{
+    ArchiveInfo, EntryMessageForm, InviteForm, NameAvailability, NewRoom, PasswordChange,
+    RestoreForm, Room, RoomAccess, RoomInfo, RoomLogin, Snapshot,
+};
+use security_headers::SecurityHeadersFairing;
+use sessions::{Notification, Session, SessionFairing};
 use static_resources::StaticFile;
-use template_variables::WelcomeMessage;
+use template_variables::{
+    DefaultTheme, VariableVersion, WelcomeMessage, WelcomeMessageCache, WelcomeMessageJson,
+    WelcomeMessageResult, THEMES, VARIABLES,
+};
+use version::VersionInfo;
+use webhooks::{Webhook, WebhookDispatcher, WebhookFairing, WebhookForm};
 
 #[get("/")]
-fn index(flash: Option<FlashMessage>, welcome_message: WelcomeMessage) -> Template {
+async fn index(
+    flash: Option<FlashMessage<'_>>,
+    welcome_message: WelcomeMessage,
+    default_theme: DefaultTheme,
+    session: Option<Session>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Either<Template, Redirect> {
+    let info = flash
+        .map(|flash| flash.message().to_string())
+        .unwrap_or_else(|| "".into());
+
+    // Only users who can access exactly one room get a shortcut to it.
+    let shortcut = match (config.room_shortcut, session) {
+        (RoomShortcut::Off, _) | (_, None) => None,
+        (_, Some(session)) => conn
+            .run(move |c| session.accessible_rooms(c))
+            .await
+            .ok()
+            .filter(|rooms| rooms.len() == 1)
+            .and_then(|mut rooms| rooms.pop()),
+    };
+
+    if config.room_shortcut == RoomShortcut::Redirect && info.is_empty() {
+        if let Some(name) = shortcut {
+            return Either::Right(Redirect::to(constraints::room_url(&name)));
+        }
+    }
+
     // Populate the template.
-    let mut context = HashMap::new();
-    context.insert("welcome_message", welcome_message.0);
-    context.insert(
-        "info",
-        flash
-            .map(|flash| flash.message().to_string())
-            .unwrap_or_else(|| "".into()),
-    );
-    Template::render("index", &context)
+    let context = json!({
+        "welcome_message": welcome_message.0,
+        "info": info,
+        "shortcut": shortcut,
+        "default_theme": default_theme.0,
+    });
+    Either::Left(Template::render("index", &context))
 }
 
 #[get("/admin_login")]
@@ -101,6 +178,7 @@ async fn admin_login(
     login: Form<AdminLogin>,
     conn: DbConn,
 ) -> Result<Redirect, Flash<Redirect>> {
+    let username = login.username.clone();
     match conn.run(move |c| login.is_valid(c)).await {
         Ok(true) => (),
         _ => {
@@ -111,7 +189,7 @@ async fn admin_login(
         }
     };
 
-    if conn.run(move |c| session.make_admin(c)).await {
+    if conn.run(move |c| session.make_admin(c, &username)).await {
         Ok(Redirect::to("/admin_pane"))
     } else {
         Err(Flash::error(
@@ -134,6 +212,51 @@ fn admin_pane_for_non_admin() -> Flash<Redirect> {
     )
 }
 
+#[post("/create_admin", format = "form", data = "<admin>")]
+async fn create_admin(
+    _admin: Admin,
+    admin: Form<NewAdmin>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> String {
+    let admin = admin.into_inner();
+    if admin.username.trim().is_empty() {
+        return "The username cannot be empty.".into();
+    }
+    if let Err(reason) = config.constraints.check_password(&admin.password) {
+        return reason;
+    }
+
+    let max = config.max_admins;
+    match conn
+        .run(move |c| Admin::create(c, &admin.username, &admin.password, max))
+        .await
+    {
+        Ok(AdminCreation::Created) => "The admin account was created.".into(),
+        Ok(AdminCreation::Exists) => "An admin with this username already exists.".into(),
+        Ok(AdminCreation::TooMany) => format!(
+            "There can be at most {} admin accounts. Remove one first.",
+            max.unwrap_or_default()
+        ),
+        Err(_) => "Could not create the admin account.".into(),
+    }
+}
+
+#[delete("/admin/<username>?<confirm>")]
+async fn remove_admin(admin: Admin, username: String, confirm: bool, conn: DbConn) -> String {
+    // Admins could lock themselves out by mistake, so they have to confirm it.
+    if admin.username() == Some(username.as_str()) && !confirm {
+        return "This is your own admin account. Confirm to remove it.".into();
+    }
+
+    match conn.run(move |c| Admin::delete(c, &username)).await {
+        Ok(AdminRemoval::Removed) => "The admin account was removed.".into(),
+        Ok(AdminRemoval::NotFound) => "There is no admin with this username.".into(),
+        Ok(AdminRemoval::LastAdmin) => "The last admin account cannot be removed.".into(),
+        Err(_) => "Could not remove the admin account.".into(),
+    }
+}
+
 #[get("/session_count")]
 async fn session_count(_admin: Admin, conn: DbConn) -> Result<String, Status> {
     conn.run(Session::count_sessions)
@@ -142,19 +265,235 @@ async fn session_count(_admin: Admin, conn: DbConn) -> Result<String, Status> {
         .map_err(|_| Status::InternalServerError)
 }
 
+#[post("/clean_sessions")]
+async fn clean_sessions(
+    _admin: Admin,
+    session: Session,
+    config: &State<ForumConfig>,
+    metrics: &State<Metrics>,
+    conn: DbConn,
+) -> Result<String, Status> {
+    let batch_size = config.session_cleaner_batch_size;
+    let admin_timeout = config.admin_session_timeout_secs;
+    let max_lifetime = config.max_session_lifetime_secs;
+
+    // The admin's session is active, but it's kept explicitly to be safe.
+    let deleted = conn
+        .run(move |c| {
+            SessionFairing::delete_old(
+                c,
+                batch_size,
+                admin_timeout,
+                max_lifetime,
+                Some(&session.id()),
+            )
+        })
+        .await
+        .map_err(|_| {
+            metrics.db_error();
+            Status::InternalServerError
+        })?;
+    metrics.sessions_expired(deleted);
+    Ok(deleted.to_string())
+}
+
 #[get("/welcome_message")]
 fn welcome_message(_admin: Admin, message: WelcomeMessage) -> String {
     message.0
 }
 
 #[post("/change_welcome_message", format = "plain", data = "<message>")]
-async fn change_welcome_message(_admin: Admin, message: WelcomeMessage, conn: DbConn) -> String {
-    match conn.run(move |c| message.save_to_db(c)).await {
+async fn change_welcome_message(
+    admin: Admin,
+    message: Result<WelcomeMessage, String>,
+    config: &State<ForumConfig>,
+    cache: &State<WelcomeMessageCache>,
+    conn: DbConn,
+) -> String {
+    let message = match message {
+        Ok(message) => message,
+        Err(reason) => return reason,
+    };
+    let username = admin.username().map(str::to_owned);
+    let history_len = config.template_history_len;
+    let result = conn
+        .run(move |c| message.save_to_db(c, username.as_deref(), history_len))
+        .await;
+    cache.invalidate();
+    match result {
         Ok(_) => "Saved your message succesfully.".into(),
         _ => "Could not save your welcome message.".into(),
     }
 }
 
+#[post("/change_welcome_message", format = "json", data = "<message>")]
+async fn change_welcome_message_json(
+    admin: Admin,
+    message: Result<LimitedJson<WelcomeMessageJson>, JsonError>,
+    config: &State<ForumConfig>,
+    cache: &State<WelcomeMessageCache>,
+    conn: DbConn,
+) -> Result<Json<WelcomeMessageResult>, Status> {
+    let max_length = config.constraints.max_welcome_message_len;
+    let rejected = |length: usize, reason: String| WelcomeMessageResult {
+        ok: false,
+        stored_html: None,
+        length,
+        max_length,
+        reason: Some(reason),
+    };
+    let too_long = || {
+        format!(
+            "The message is too long. It can have at most {} bytes.",
+            max_length
+        )
+    };
+
+    let message = match message {
+        Ok(message) => message.0,
+        Err(JsonError::TooLarge(read)) => return Ok(Json(rejected(read, too_long()))),
+        Err(JsonError::Io) => return Err(Status::BadRequest),
+        Err(JsonError::Parse) => return Err(Status::UnprocessableEntity),
+    };
+    let length = message.message.len();
+    if length > max_length {
+        return Ok(Json(rejected(length, too_long())));
+    }
+    let message = match WelcomeMessage::clean(&message.message, Some(&config.sanitizer)) {
+        Ok(message) => message,
+        Err(reason) => return Ok(Json(rejected(length, reason))),
+    };
+
+    let username = admin.username().map(str::to_owned);
+    let history_len = config.template_history_len;
+    let (result, message) = conn
+        .run(move |c| {
+            let result = message.save_to_db(c, username.as_deref(), history_len);
+            (result, message)
+        })
+        .await;
+    cache.invalidate();
+    result.map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(WelcomeMessageResult {
+        ok: true,
+        stored_html: Some(message.0),
+        length,
+        max_length,
+        reason: None,
+    }))
+}
+
+#[post("/change_default_theme", format = "plain", data = "<name>")]
+async fn change_default_theme(
+    admin: Admin,
+    name: String,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> String {
+    let theme = match DefaultTheme::new(&name) {
+        Some(theme) => theme,
+        None => {
+            return format!(
+                "Unknown theme. The known themes are: {}.",
+                THEMES.join(", ")
+            )
+        }
+    };
+
+    let username = admin.username().map(str::to_owned);
+    let history_len = config.template_history_len;
+    match conn
+        .run(move |c| theme.save_to_db(c, username.as_deref(), history_len))
+        .await
+    {
+        Ok(_) => "Saved the default theme succesfully.".into(),
+        _ => "Could not save the default theme.".into(),
+    }
+}
+
+#[get("/template_history/<name>")]
+async fn template_history(
+    _admin: Admin,
+    name: &str,
+    conn: DbConn,
+) -> Result<Json<Vec<VariableVersion>>, Status> {
+    if !VARIABLES.contains(&name) {
+        return Err(Status::NotFound);
+    }
+    let name = name.to_owned();
+    conn.run(move |c| VariableVersion::list(c, &name))
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[post("/template_history/<id>/restore")]
+async fn restore_template_version(
+    admin: Admin,
+    id: i32,
+    config: &State<ForumConfig>,
+    cache: &State<WelcomeMessageCache>,
+    conn: DbConn,
+) -> String {
+    let username = admin.username().map(str::to_owned);
+    let history_len = config.template_history_len;
+    let result = conn
+        .run(move |c| VariableVersion::restore(c, id, username.as_deref(), history_len))
+        .await;
+    cache.invalidate();
+    match result {
+        Ok(Some(name)) => format!("Restored version {} of {}.", id, name),
+        Ok(None) => "There is no version with this id.".into(),
+        Err(_) => "Could not restore the version.".into(),
+    }
+}
+
+#[post("/broadcast", format = "plain", data = "<content>")]
+async fn broadcast(
+    _admin: Admin,
+    content: String,
+    config: &State<ForumConfig>,
+    archiver: &State<Archiver>,
+    dispatcher: &State<WebhookDispatcher>,
+    conn: DbConn,
+) -> String {
+    if content.is_empty() {
+        return "The message cannot be empty.".into();
+    }
+    if content.len() > config.constraints.max_message_len {
+        return "The message is too long.".into();
+    }
+
+    // System messages are only sanitized, not filtered or hashed.
+    let secret = config.encryption_secret.clone();
+    let options = StorageOptions {
+        sanitizer: config.sanitizer.clone(),
+        compress_content: config.compress_message_content,
+        max_len: config.constraints.max_message_len,
+        room_budget: config.room_budget(),
+        archiver: archiver.inner().clone(),
+        dispatcher: dispatcher.inner().clone(),
+        ..Default::default()
+    };
+    match conn
+        .run(move |c| Room::broadcast(c, &content, secret.as_deref(), &options))
+        .await
+    {
+        Ok(failed) if failed.is_empty() => "The message was posted in all rooms.".into(),
+        Ok(failed) => format!(
+            "The message could not be posted in these rooms: {}.",
+            failed.join(", ")
+        ),
+        Err(_) => "Could not broadcast the message.".into(),
+    }
+}
+
+#[get("/config")]
+fn effective_config(_admin: Admin, config: &State<ForumConfig>) -> Json<EffectiveConfig<'_>> {
+    Json(config.effective())
+}
+
 #[get("/active_rooms")]
 async fn active_rooms(_admin: Admin, conn: DbConn) -> Result<Json<Vec<String>>, Status> {
     conn.run(Room::active_rooms)
@@ -163,33 +502,221 @@ async fn active_rooms(_admin: Admin, conn: DbConn) -> Result<Json<Vec<String>>,
         .map_err(|_| Status::InternalServerError)
 }
 
+#[get("/rooms_info")]
+async fn rooms_info(_admin: Admin, conn: DbConn) -> Result<Json<Vec<RoomInfo>>, Status> {
+    conn.run(Room::rooms_info)
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/rooms/stream")]
+fn room_event_stream(
+    _admin: Admin,
+    events: &State<RoomEvents>,
+    config: &State<ForumConfig>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], Status> {
+    if !config.room_event_stream {
+        return Err(Status::NotFound);
+    }
+
+    let mut receiver = events.subscribe();
+    Ok(EventStream! {
+        loop {
+            let event = select! {
+                event = receiver.recv() => event,
+                _ = &mut shutdown => break,
+            };
+            match event {
+                Ok(event) => yield Event::json(&event).event(event.kind.as_str()),
+                // Some events were missed, so the subscriber should list the rooms again.
+                Err(RecvError::Lagged(_)) => yield Event::empty().event("resync"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+#[get("/rooms")]
+async fn public_rooms(
+    _session: Session,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<Json<Vec<String>>, Status> {
+    if !config.public_room_list {
+        return Err(Status::Unauthorized);
+    }
+
+    conn.run(Room::active_rooms)
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/my_rooms")]
+async fn my_rooms(session: Session, conn: DbConn) -> Result<Json<Vec<String>>, Status> {
+    conn.run(move |c| session.accessible_rooms(c))
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/notifications")]
+async fn notifications(
+    session: Session,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<Json<Vec<Notification>>, Status> {
+    let secret = config.public_id_secret.clone();
+    conn.run(move |c| session.take_notifications(c, secret.as_deref()))
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/unread_counts")]
+async fn unread_counts(
+    session: Session,
+    conn: DbConn,
+) -> Result<Json<BTreeMap<String, i64>>, Status> {
+    /// The count shown for rooms with this many unread messages or more.
+    const MAX_UNREAD_COUNT: i64 = 100;
+
+    conn.run(move |c| Room::unread_counts(c, &session, MAX_UNREAD_COUNT))
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[delete("/my_rooms/<name>")]
+async fn forget_my_room(name: RoomName, session: Session, conn: DbConn) -> String {
+    match conn.run(move |c| session.forget_room(c, &name.0)).await {
+        Ok(true) => "You have been logged out of the room.".into(),
+        Ok(false) => "You were not logged into this room.".into(),
+        Err(_) => "Could not log you out of the room.".into(),
+    }
+}
+
+#[delete("/my_rooms")]
+async fn forget_my_rooms(session: Session, conn: DbConn) -> String {
+    match conn.run(move |c| session.forget_all_rooms(c)).await {
+        Ok(0) => "You were not logged into any room.".into(),
+        Ok(_) => "You have been logged out of all rooms.".into(),
+        Err(_) => "Could not log you out of your rooms.".into(),
+    }
+}
+
+#[get("/room_name_available?<name>")]
+async fn room_name_available(
+    _admin: Admin,
+    name: String,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<Json<NameAvailability>, Status> {
+    let name = match config.constraints.check_new_room_name(&name) {
+        Ok(name) => name.0,
+        Err(reason) => {
+            return Ok(Json(NameAvailability {
+                valid: false,
+                available: false,
+                reason: Some(reason),
+            }))
+        }
+    };
+
+    let exists = conn
+        .run(move |c| Room::exists(c, &name))
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(Json(NameAvailability {
+        valid: true,
+        available: !exists,
+        reason: exists.then(|| "A room with this name already exists.".into()),
+    }))
+}
+
 #[post("/create_room", format = "form", data = "<room>")]
-async fn create_room(_admin: Admin, room: Form<RoomLogin>, conn: DbConn) -> String {
+async fn create_room(
+    admin: Admin,
+    room: Form<NewRoom>,
+    limiter: &State<RoomCreationLimiter>,
+    events: &State<RoomEvents>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> String {
+    if !config.room_creation_allowed(clock::now_millis()) {
+        return "Rooms cannot be created at this time.".into();
+    }
+
     // Validate the input.
-    if let Err(reason) = RoomName::parse(&room.name) {
+    let name = match config.constraints.check_new_room_name(&room.name) {
+        Ok(name) => name.0,
+        Err(reason) => return reason,
+    };
+    if let Err(reason) = config.constraints.check_password(&room.password) {
         return reason;
     }
-    if room.password.is_empty() {
-        return "The password cannot be empty.".into();
+    let settings = room.settings(&config.room_defaults);
+    if settings.encrypted && config.encryption_secret.is_none() {
+        return "Encrypted rooms are not enabled on this server.".into();
+    }
+    if let Some(profile) = settings.sanitizer_profile.as_deref() {
+        if !config.sanitizer_profiles.contains_key(profile) {
+            return format!("There is no sanitizer profile named {}.", profile);
+        }
     }
 
-    let name = &room.name;
     let hashed_password = rooms::hash_password(&room.password);
+    let grace = config.new_room_grace_period_ms;
+    let accepting_posts_after = room
+        .accepting_posts_after
+        .or_else(|| (grace > 0).then(|| clock::now_millis() + grace));
+
+    let created_by = admin.username().map(str::to_owned);
+    let creator = created_by.clone().unwrap_or_else(|| admin.0.id());
+    if let Err(wait) = limiter.check(&creator, &config.room_creation_limit) {
+        // Admins should not retry before the window allows it, so round up.
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        return format!(
+            "You are creating rooms too quickly. Please try again in {} seconds.",
+            secs
+        );
+    }
 
     match conn
         .run({
-            let name = name.to_string();
-            move |c| Room::create_room(c, name, hashed_password)
+            let name = name.clone();
+            move |c| {
+                Room::create_room(
+                    c,
+                    name,
+                    hashed_password,
+                    settings,
+                    accepting_posts_after,
+                    created_by.as_deref(),
+                )
+            }
         })
         .await
     {
-        Ok(_) => format!("Created room {}.", name),
+        Ok(_) => {
+            limiter.record(&creator, &config.room_creation_limit);
+            events.notify(RoomEventKind::Created, &name);
+            format!("Created room {}.", name)
+        }
         _ => "Could not create the room.".into(),
     }
 }
 
 #[delete("/delete_room", data = "<name>")]
-async fn delete_room(_admin: Admin, name: RoomName, conn: DbConn) -> String {
+async fn delete_room(
+    _admin: Admin,
+    name: RoomName,
+    events: &State<RoomEvents>,
+    conn: DbConn,
+) -> String {
     let name = name.0;
 
     match conn
@@ -199,178 +726,2146 @@ async fn delete_room(_admin: Admin, name: RoomName, conn: DbConn) -> String {
         })
         .await
     {
-        Ok(_) => format!("Room {} deleted successfully.", &name),
-        Err(reason) => reason,
+        Ok(true) => {
+            events.notify(RoomEventKind::Deleted, &name);
+            format!("Room {} deleted successfully.", &name)
+        }
+        Ok(false) => format!("Room {} does not exist.", &name),
+        Err(_) => "Could not delete the room.".into(),
     }
 }
 
-#[post("/change_room_password", format = "form", data = "<form>")]
-async fn change_room_password(_admin: Admin, form: Form<RoomLogin>, conn: DbConn) -> String {
-    // Validate the input.
-    if form.password.is_empty() {
-        return "The password cannot be empty.".into();
-    }
-
-    let name = form.name.clone();
-    let hashed_password = rooms::hash_password(&form.password);
+#[post("/archive_room", data = "<name>")]
+async fn archive_room(
+    _admin: Admin,
+    name: RoomName,
+    events: &State<RoomEvents>,
+    conn: DbConn,
+) -> String {
+    let name = name.0;
 
     match conn
-        .run(move |c| Room::change_password(c, &name, &hashed_password))
-        .await
-    {
-        Ok(_) => "The password has been changed.".into(),
-        _ => "There was an error.".into(),
-    }
-}
-
-#[post("/enter_room", format = "form", data = "<login>")]
-async fn enter_room(
-    login: Form<RoomLogin>,
-    session: Session,
-    conn: DbConn,
-) -> Result<Redirect, Flash<Redirect>> {
-    if !conn
         .run({
-            let login = login.clone();
-            move |c| login.can_log_in(c)
+            let name = name.clone();
+            move |c| Room::archive_room(c, &name)
         })
         .await
-        .unwrap_or(false)
     {
-        return Err(Flash::error(
-            Redirect::to("/"),
-            "Your credentials are invalid.",
-        ));
-    }
-
-    conn.run({
-        let login = login.clone();
-        move |c| session.save_room_attempt(c, &login.name, &rooms::hash_password(&login.password))
-    })
-    .await
-    .map(|_| Redirect::to(format!("/room/{}", login.name)))
-    .map_err(|_| Flash::error(Redirect::to("/"), "Could not save your login attempt."))
-}
-
-#[get("/room/<name>")]
-fn room(name: RoomName, room: Option<Room>) -> Result<Template, Flash<Redirect>> {
-    if room.is_none() {
-        return Err(Flash::error(
-            Redirect::to("/"),
-            "Your credentials are invalid.",
-        ));
+        Ok(archive) => {
+            events.notify(RoomEventKind::Archived, &name);
+            format!(
+                "Room {} archived successfully, as archive {}.",
+                &name, archive
+            )
+        }
+        _ => "Could not archive the room.".into(),
     }
-
-    // Populate the room template.
-    let mut context = HashMap::new();
-    context.insert("name", name);
-    Ok(Template::render("room", &context))
 }
 
-#[get("/room/<name>/updates")]
-async fn get_message_updates(
+#[post("/room/<name>/compact")]
+async fn compact_room(
+    _admin: Admin,
     name: RoomName,
-    room: Option<Room>,
-    session: Session,
+    archiver: &State<Archiver>,
     conn: DbConn,
-) -> Result<Json<Updates>, Status> {
-    let room = room.ok_or(Status::Unauthorized)?;
+) -> String {
+    // Archived records are keyed by the ids of messages, so reusing ids
+    // would overwrite them.
+    if archiver.is_enabled() {
+        return "Rooms cannot be compacted while messages are archived.".into();
+    }
     let name = name.0;
 
-    let last_update = conn
+    match conn
         .run({
             let name = name.clone();
-            let session = session.clone();
-            move |c| session.get_room_update(c, &name)
+            move |c| Room::compact(c, &name)
         })
         .await
-        .unwrap_or(0);
-    let now = Message::current_timestamp();
-
-    let updates = conn
-        .run(move |c| room.get_updates_between(c, last_update, now))
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-    conn.run(move |c| session.save_room_update(c, &name, now))
-        .await
-        .map_err(|_| Status::InternalServerError)?;
-
-    Ok(Json(updates))
+    {
+        Ok(Some(removed)) => format!(
+            "Compacted room {}, removing {} pruned messages.",
+            &name, removed
+        ),
+        Ok(None) => format!("Room {} does not exist.", &name),
+        Err(_) => "Could not compact the room.".into(),
+    }
 }
 
-#[post("/room/<_name>/post", format = "json", data = "<message>")]
-async fn post(
-    _name: RoomName,
-    room: Option<Room>,
-    message: Json<MessageJson>,
-    session: Session,
+#[get("/webhooks/<name>")]
+async fn room_webhooks(
+    _admin: Admin,
+    name: RoomName,
     conn: DbConn,
-) -> Result<String, Status> {
-    let room = room.ok_or(Status::Unauthorized)?;
-    let message = message.into_inner();
+) -> Result<Json<Vec<Webhook>>, Status> {
+    conn.run(move |c| Webhook::list(c, &name.0))
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-    if message.content.is_empty() {
-        return Ok("Your message cannot be empty.".into());
-    }
-    if message.content.len() > constraints::MAX_MESSAGE_LEN {
-        return Ok("Your message is too long.".into());
+#[post("/add_webhook", format = "form", data = "<form>")]
+async fn add_webhook(_admin: Admin, form: Form<WebhookForm>, conn: DbConn) -> String {
+    let form = form.into_inner();
+    if !(form.url.starts_with("http://") || form.url.starts_with("https://")) {
+        return "The URL must start with http:// or https://.".into();
     }
+    let secret = form.secret.filter(|secret| !secret.is_empty());
 
-    conn.run(move |c| room.add_message(c, message.content, session.id(), message.reply_to))
+    match conn
+        .run(move |c| Webhook::add(c, &form.room, &form.url, secret.as_deref()))
         .await
-        .map(|_| "Your message has been saved.".into())
-        .map_err(|_| Status::InternalServerError)
+    {
+        Ok(Some(id)) => format!("Added webhook {}.", id),
+        Ok(None) => "The room does not exist.".into(),
+        Err(_) => "Could not add the webhook.".into(),
+    }
 }
 
-#[get("/colors")]
-async fn colors() -> Result<StaticFile, NotFound<String>> {
-    static_file(PathBuf::from("colors.html")).await
+#[delete("/webhook/<id>")]
+async fn delete_webhook(_admin: Admin, id: i32, conn: DbConn) -> String {
+    match conn.run(move |c| Webhook::delete(c, id)).await {
+        Ok(true) => format!("Removed webhook {}.", id),
+        Ok(false) => "There is no webhook with this id.".into(),
+        Err(_) => "Could not remove the webhook.".into(),
+    }
 }
 
-#[get("/static/<file..>")]
-async fn static_file(file: PathBuf) -> Result<StaticFile, NotFound<String>> {
-    let path = Path::new("static/").join(file);
-    Ok(StaticFile(
-        NamedFile::open(&path)
-            .await
-            .map_err(|err| NotFound(err.to_string()))?,
-    ))
+#[get("/archived_rooms")]
+async fn archived_rooms(_admin: Admin, conn: DbConn) -> Result<Json<Vec<ArchiveInfo>>, Status> {
+    conn.run(Room::archived_rooms)
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
 }
 
-#[catch(404)]
-async fn not_found() -> Result<StaticFile, NotFound<String>> {
-    static_file(PathBuf::from("404.html")).await
-}
+#[post("/restore_room", format = "form", data = "<form>")]
+async fn restore_room(
+    _admin: Admin,
+    form: Form<RestoreForm>,
+    events: &State<RoomEvents>,
+    conn: DbConn,
+) -> String {
+    let name = form.name.clone();
+    let archive = form.archive;
+
+    match conn
+        .run(move |c| Room::restore_archive(c, &name, archive))
+        .await
+    {
+        Ok(true) => {
+            events.notify(RoomEventKind::Created, &form.name);
+            format!("Restored archive {} into room {}.", form.archive, form.name)
+        }
+        Ok(false) => "The room already has messages.".into(),
+        _ => "Could not restore the archive.".into(),
+    }
+}
+
+#[post("/change_room_password", format = "form", data = "<form>")]
+async fn change_room_password(
+    _admin: Admin,
+    form: Form<RoomLogin>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> String {
+    // Validate the input.
+    if let Err(reason) = config.constraints.check_password(&form.password) {
+        return reason;
+    }
+
+    let name = form.name.clone();
+    let hashed_password = rooms::hash_password(&form.password);
+
+    match conn
+        .run(move |c| Room::change_password(c, &name, &hashed_password))
+        .await
+    {
+        Ok(_) => "The password has been changed.".into(),
+        _ => "There was an error.".into(),
+    }
+}
+
+#[post("/change_entry_message", format = "form", data = "<form>")]
+async fn change_entry_message(
+    _admin: Admin,
+    form: Form<EntryMessageForm>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> String {
+    let form = form.into_inner();
+    if form.message.len() > config.constraints.max_welcome_message_len {
+        return "The message is too long.".into();
+    }
+
+    // Like the welcome message, the entry message must be cleaned,
+    // since it's shown to all the users of the room.
+    let message = match config.sanitizer.clean(&form.message) {
+        Ok(message) => message,
+        Err(_) => return "The message is too complex.".into(),
+    };
+    let message = Some(message).filter(|message| !message.trim().is_empty());
+
+    match conn
+        .run(move |c| Room::set_entry_message(c, &form.name, message.as_deref()))
+        .await
+    {
+        Ok(true) => "The entry message has been changed.".into(),
+        Ok(false) => "The room does not exist.".into(),
+        _ => "There was an error.".into(),
+    }
+}
+
+#[post("/room/<name>/change_password", format = "form", data = "<form>")]
+async fn change_password(
+    name: RoomName,
+    room: Option<Room>,
+    form: Form<PasswordChange>,
+    session: Session,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<String, Status> {
+    room.ok_or(Status::Unauthorized)?;
+    let name = name.0;
+    let form = form.into_inner();
+
+    // Validate the input.
+    if let Err(reason) = config.constraints.check_password(&form.new_password) {
+        return Ok(reason);
+    }
+
+    let login = RoomLogin {
+        name: name.clone(),
+        password: form.current_password,
+    };
+    if !conn
+        .run(move |c| login.can_log_in(c))
+        .await
+        .unwrap_or(false)
+    {
+        return Ok("The current password is wrong.".into());
+    }
+
+    // The user who changes the password keeps access to the room.
+    let hashed_password = rooms::hash_password(&form.new_password);
+    conn.run(move |c| {
+        Room::change_password(c, &name, &hashed_password)?;
+        session.save_room_attempt(c, &name, &hashed_password)
+    })
+    .await
+    .map(|_| "The password has been changed.".into())
+    .map_err(|_| Status::InternalServerError)
+}
+
+#[post("/create_invite", format = "form", data = "<form>")]
+async fn create_invite(_admin: Admin, form: Form<InviteForm>, conn: DbConn) -> String {
+    // Validate the input.
+    if form.uses.is_some_and(|uses| uses <= 0) {
+        return "The number of uses must be positive.".into();
+    }
+    if form.valid_for.is_some_and(|secs| secs <= 0) {
+        return "The validity period must be positive.".into();
+    }
+
+    let expires = match form.valid_for {
+        Some(secs) => match secs
+            .checked_mul(1000)
+            .and_then(|ms| clock::now_millis().checked_add(ms))
+        {
+            Some(expires) => Some(expires),
+            None => return "The validity period is too long.".into(),
+        },
+        None => None,
+    };
+
+    let name = constraints::normalize_room_name(&form.name);
+    let uses = form.uses;
+
+    match conn
+        .run({
+            let name = name.clone();
+            move |c| Room::create_invite(c, &name, uses, expires)
+        })
+        .await
+    {
+        Ok(code) => format!("Created invite code {} for room {}.", code, name),
+        _ => "Could not create the invite.".into(),
+    }
+}
+
+/// The reason given to users who try to log into more rooms than allowed.
+const TOO_MANY_ROOMS: &str = "You are logged into too many rooms. Log out of one of them first.";
+
+#[post("/join/<code>")]
+async fn join(
+    code: String,
+    session: Session,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let max_rooms = config.max_rooms_per_session;
+    let joined = conn
+        .run(move |c| {
+            // Sessions which cannot log into more rooms do not use up the invite.
+            let mut transaction = c.transaction()?;
+            let name = Room::invited_room(&mut transaction, &code)?;
+            if !session.can_attempt_room(&mut transaction, &name, max_rooms)? {
+                return Ok(None);
+            }
+            let (name, hashed_password) = Room::redeem_invite(&mut transaction, &code)?;
+            session.save_room_attempt(&mut transaction, &name, &hashed_password)?;
+            transaction.commit()?;
+            Ok::<_, db::Error>(Some(name))
+        })
+        .await;
+
+    match joined {
+        Ok(Some(name)) => Ok(Redirect::to(constraints::room_url(&name))),
+        Ok(None) => Err(Flash::error(Redirect::to("/"), TOO_MANY_ROOMS)),
+        Err(_) => Err(Flash::error(
+            Redirect::to("/"),
+            "Your invite is invalid or has expired.",
+        )),
+    }
+}
+
+#[post("/enter_room", format = "form", data = "<login>")]
+async fn enter_room(
+    login: Form<RoomLogin>,
+    session: Session,
+    ip: ClientIp,
+    limiter: &State<LoginLimiter>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<Redirect, Flash<Redirect>> {
+    let mut login = login.into_inner();
+    login.name = constraints::normalize_room_name(&login.name);
+
+    let can_attempt = conn
+        .run({
+            let name = login.name.clone();
+            let session = session.clone();
+            let max_rooms = config.max_rooms_per_session;
+            move |c| session.can_attempt_room(c, &name, max_rooms)
+        })
+        .await
+        .unwrap_or(false);
+    if !can_attempt {
+        return Err(Flash::error(Redirect::to("/"), TOO_MANY_ROOMS));
+    }
+
+    // New sessions are easy to get, so clients are identified by their
+    // addresses, if they are known.
+    let client = ip.0.map_or_else(|| session.id(), |ip| ip.to_string());
+    if let Err(remaining) = limiter.check(&client, &login.name) {
+        return Err(Flash::error(
+            Redirect::to("/"),
+            format!(
+                "Too many failed attempts. Please try again in {} seconds.",
+                remaining.as_secs() + 1
+            ),
+        ));
+    }
+
+    if !conn
+        .run({
+            let login = login.clone();
+            move |c| login.can_log_in(c)
+        })
+        .await
+        .unwrap_or(false)
+    {
+        limiter.record_failure(&client, &login.name, &config.login_limit);
+        return Err(Flash::error(
+            Redirect::to("/"),
+            "Your credentials are invalid.",
+        ));
+    }
+    limiter.record_success(&client, &login.name);
+
+    conn.run({
+        let login = login.clone();
+        move |c| session.save_room_attempt(c, &login.name, &rooms::hash_password(&login.password))
+    })
+    .await
+    .map(|_| Redirect::to(constraints::room_url(&login.name)))
+    .map_err(|_| Flash::error(Redirect::to("/"), "Could not save your login attempt."))
+}
+
+#[post("/check_room_password", format = "form", data = "<login>")]
+async fn check_room_password(
+    login: Form<RoomLogin>,
+    session: Session,
+    ip: ClientIp,
+    limiter: &State<LoginLimiter>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<Json<bool>, Status> {
+    let mut login = login.into_inner();
+    login.name = constraints::normalize_room_name(&login.name);
+
+    // Checking passwords could be used to guess them, so the checks are
+    // limited like logins.
+    let client = ip.0.map_or_else(|| session.id(), |ip| ip.to_string());
+    if limiter.check(&client, &login.name).is_err() {
+        return Err(Status::TooManyRequests);
+    }
+
+    let valid = conn
+        .run({
+            let login = login.clone();
+            move |c| login.can_log_in(c)
+        })
+        .await
+        .unwrap_or(false);
+    if valid {
+        limiter.record_success(&client, &login.name);
+    } else {
+        limiter.record_failure(&client, &login.name, &config.login_limit);
+    }
+    Ok(Json(valid))
+}
+
+#[get("/room/<name>")]
+async fn room(
+    name: RoomName,
+    access: RoomAccess,
+    default_theme: DefaultTheme,
+    session: Option<Session>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<Template, Flash<Redirect>> {
+    // Unless missing rooms are revealed, all users are told the same thing,
+    // so they cannot find out which rooms exist.
+    let room = match access {
+        RoomAccess::Granted(room) => Ok(*room),
+        _ if !config.reveal_missing_rooms => Err("Your credentials are invalid."),
+        RoomAccess::NoSuchRoom => Err("This room does not exist."),
+        RoomAccess::NotAttempted => Err("Please log into the room first."),
+        RoomAccess::WrongPassword => Err("Your password is wrong."),
+    };
+    let room = room.map_err(|error| Flash::error(Redirect::to("/"), error))?;
+
+    let mut entry_message = room.entry_message().map(str::to_owned);
+    // Guests cannot be remembered, so they always see the entry message.
+    let once = entry_message.is_some() && config.entry_message_once;
+    if let Some(session) = session.filter(|_| once) {
+        let name = name.0.clone();
+        let first_time = conn
+            .run(move |c| session.see_entry_message(c, &name))
+            .await
+            .unwrap_or(true);
+        if !first_time {
+            entry_message = None;
+        }
+    }
+
+    // The room's metadata comes from the row which the guard already loaded.
+    let info = config.show_room_info;
+
+    // Populate the room template.
+    let context = json!({
+        "name": name.0,
+        "default_theme": default_theme.0,
+        "entry_message": entry_message,
+        "creation": info.then(|| room.creation()),
+        "accepting_posts_after": room.accepting_posts_after().filter(|_| info),
+    });
+    Ok(Template::render("room", &context))
+}
+
+#[get("/room/<name>/updates?<query..>")]
+async fn get_message_updates(
+    name: RoomName,
+    query: UpdateQuery,
+    room: Option<Room>,
+    session: Option<Session>,
+    permit: PollPermit<'_>,
+    metrics: &State<Metrics>,
+    conn: DbConn,
+) -> Result<NegotiatedUpdates, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let name = name.0;
+    let limit = permit.limit;
+
+    // Clients which missed some messages can ask for them by their ids.
+    match (query.from_id, query.to_id) {
+        (Some(from), Some(to)) => {
+            let from = room.message_id(&from).ok_or(Status::BadRequest)?;
+            let to = room.message_id(&to).ok_or(Status::BadRequest)?;
+            return conn
+                .run(move |c| room.get_updates_in_range(c, from, to, limit))
+                .await
+                .map(NegotiatedUpdates)
+                .map_err(|_| {
+                    metrics.db_error();
+                    Status::InternalServerError
+                });
+        }
+        (None, None) => {}
+        _ => return Err(Status::BadRequest),
+    }
+
+    // Guests keep their own progress, through the cursor.
+    let session = match session {
+        Some(session) => session,
+        None => {
+            let (mut updates, end) = conn
+                .run(move |c| room.get_updates_after(c, query.after, limit))
+                .await
+                .map_err(|_| {
+                    metrics.db_error();
+                    Status::InternalServerError
+                })?;
+            updates.cursor = Some(end);
+            if updates.has_more {
+                permit.allow_continuation();
+            }
+            return Ok(NegotiatedUpdates(updates));
+        }
+    };
+
+    let last_change = conn
+        .run({
+            let name = name.clone();
+            let session = session.clone();
+            move |c| session.get_room_update(c, &name)
+        })
+        .await
+        .unwrap_or(None);
+
+    let (updates, end) = conn
+        .run(move |c| room.get_updates_after(c, last_change, limit))
+        .await
+        .map_err(|_| {
+            metrics.db_error();
+            Status::InternalServerError
+        })?;
+    conn.run(move |c| session.save_room_update(c, &name, end))
+        .await
+        .map_err(|_| {
+            metrics.db_error();
+            Status::InternalServerError
+        })?;
+
+    if updates.has_more {
+        permit.allow_continuation();
+    }
+    Ok(NegotiatedUpdates(updates))
+}
+
+#[get("/room/<name>/snapshot?<order>")]
+async fn get_snapshot(
+    name: RoomName,
+    order: Option<ThreadOrder>,
+    room: Option<Room>,
+    session: Session,
+    config: &State<ForumConfig>,
+    metrics: &State<Metrics>,
+    conn: DbConn,
+) -> Result<CompressedJson<Snapshot>, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let order = order.unwrap_or(config.default_thread_order);
+    let limit = config.max_snapshot_threads;
+
+    let (mut snapshot, last_change) = conn
+        .run(move |c| room.snapshot(c, order, limit))
+        .await
+        .map_err(|_| {
+            metrics.db_error();
+            Status::InternalServerError
+        })?;
+    // Entry messages shown only once are shown by the room's page instead.
+    if config.entry_message_once {
+        snapshot.entry_message = None;
+    }
+
+    // Later updates continue from the snapshot.
+    conn.run(move |c| session.save_room_update(c, &name.0, last_change))
+        .await
+        .map_err(|_| {
+            metrics.db_error();
+            Status::InternalServerError
+        })?;
+
+    Ok(CompressedJson(snapshot))
+}
+
+#[get("/room/<_name>/thread/<id>?<after>&<limit>")]
+async fn get_thread(
+    _name: RoomName,
+    id: MessageRef,
+    after: Option<MessageRef>,
+    limit: Option<i64>,
+    room: Option<Room>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<CompressedJson<Vec<Message>>, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let id = room.message_id(&id).ok_or(Status::NotFound)?;
+    let after = match after {
+        Some(after) => Some(room.message_id(&after).ok_or(Status::BadRequest)?),
+        None => None,
+    };
+    let max = config.max_thread_page_len;
+    let limit = limit.unwrap_or(max).clamp(1, max);
+
+    conn.run(move |c| room.get_thread(c, id, after, limit))
+        .await
+        .map(CompressedJson)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[post("/room/<_name>/messages", format = "json", data = "<ids>")]
+async fn get_messages(
+    _name: RoomName,
+    ids: Json<Vec<MessageRef>>,
+    room: Option<Room>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<CompressedJson<Vec<Message>>, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    if ids.len() > config.max_messages_per_batch {
+        return Err(Status::BadRequest);
+    }
+    // Public ids which cannot be decoded belong to no message, so they are skipped too.
+    let ids: Vec<i32> = ids.iter().filter_map(|id| room.message_id(id)).collect();
+
+    conn.run(move |c| room.get_many(c, &ids))
+        .await
+        .map(CompressedJson)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/room/<_name>/export")]
+async fn export_room(
+    _name: RoomName,
+    room: Option<Room>,
+    format: AcceptsNdjson,
+    conn: DbConn,
+) -> Result<(ContentType, TextStream![String]), Status> {
+    /// The number of messages retrieved from the database at once.
+    const BATCH_LEN: i64 = 500;
+
+    let room = Arc::new(room.ok_or(Status::Unauthorized)?);
+    let ndjson = format.0;
+
+    // The messages are sent as a JSON array, written one message at a time,
+    // so that exporting large rooms does not need much memory. NDJSON clients
+    // receive the messages alone, one per line.
+    let stream = TextStream! {
+        if !ndjson {
+            yield "[".to_string();
+        }
+
+        let mut after = 0;
+        let mut separator = "";
+        loop {
+            let room = room.clone();
+            let batch = match conn.run(move |c| room.get_messages_after(c, after, BATCH_LEN)).await {
+                Ok(batch) => batch,
+                Err(_) => {
+                    // The response has already started, so it's left incomplete,
+                    // which makes the JSON invalid.
+                    eprintln!("Error while exporting messages.");
+                    return;
+                }
+            };
+
+            for message in &batch {
+                let json = if ndjson {
+                    ndjson::line(message)
+                } else {
+                    serde_json::to_string(message).map(|json| format!("{}{}", separator, json))
+                };
+                match json {
+                    Ok(json) => yield json,
+                    Err(_) => return,
+                }
+                separator = ",";
+            }
+
+            match batch.last() {
+                Some(last) if batch.len() as i64 == BATCH_LEN => after = last.id(),
+                _ => break,
+            }
+        }
+
+        if !ndjson {
+            yield "]".to_string();
+        }
+    };
+    let content_type = if ndjson {
+        ndjson::content_type()
+    } else {
+        ContentType::JSON
+    };
+    Ok((content_type, stream))
+}
+
+#[get("/room/<_name>/checksum?<since>")]
+async fn get_checksum(
+    _name: RoomName,
+    since: Option<i64>,
+    room: Option<Room>,
+    conn: DbConn,
+) -> Result<Json<IntervalChecksum>, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let now = clock::now_millis();
+
+    conn.run(move |c| room.interval_checksum(c, since.unwrap_or(0), now))
+        .await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[get("/room/<_name>/threads?<order>")]
+async fn get_threads(
+    _name: RoomName,
+    order: Option<ThreadOrder>,
+    room: Option<Room>,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<CompressedJson<Vec<Message>>, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let order = order.unwrap_or(config.default_thread_order);
+
+    conn.run(move |c| room.get_threads(c, order))
+        .await
+        .map(CompressedJson)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[post("/room/<_name>/post", format = "json", data = "<message>")]
+async fn post(
+    _name: RoomName,
+    room: Option<Room>,
+    message: LimitedJson<MessageJson>,
+    session: Session,
+    options: StorageOptions,
+    metrics: &State<Metrics>,
+    conn: DbConn,
+) -> Result<String, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let message = message.0;
+
+    if !room.can_post(&session) {
+        return Ok("Only admins can post in this room.".into());
+    }
+    if message.content.is_empty() {
+        return Ok("Your message cannot be empty.".into());
+    }
+    match conn
+        .run(move |c| room.add_message(c, message, Some(session.id()), &options))
+        .await
+    {
+        Ok(Ok(stripped)) => {
+            metrics.message_posted();
+            if stripped {
+                return Ok(
+                    "Your message has been saved, but some of its formatting was removed.".into(),
+                );
+            }
+            Ok("Your message has been saved.".into())
+        }
+        Ok(Err(rejection)) => Ok(rejection_reason(rejection).into()),
+        Err(_) => {
+            metrics.db_error();
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Returns the reason given to users whose message was rejected.
+fn rejection_reason(rejection: Rejection) -> &'static str {
+    match rejection {
+        Rejection::TooLong => "Your message is too long.",
+        Rejection::Empty => "Your message cannot be empty.",
+        Rejection::TooComplex => "Your message is too complex.",
+        Rejection::ForbiddenWords => "Your message contains forbidden words.",
+        Rejection::OnlyLinks => "Your message cannot consist only of links.",
+        Rejection::InvalidReply => "The message you replied to cannot be found.",
+        Rejection::OverBudget => "This room has no space left for your message.",
+        Rejection::NotOpen => "This room does not accept posts yet.",
+        Rejection::InvalidTimestamp => {
+            "The time when your message was written is too far from the server's."
+        }
+    }
+}
+
+#[post("/room/<_name>/preview", format = "json", data = "<message>")]
+fn preview_message(
+    _name: RoomName,
+    room: Option<Room>,
+    message: LimitedJson<MessageJson>,
+    options: StorageOptions,
+) -> Result<Json<MessagePreview>, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+
+    // Nothing is stored, so the message is not checked against the room's
+    // budget, its replies, or the time when the room accepts posts.
+    let preview = match room.preview_message(message.0, &options) {
+        Ok((html, stripped)) => MessagePreview {
+            html: Some(html),
+            warning: stripped.then(|| {
+                "The content was modified: some of its formatting will be removed.".into()
+            }),
+            reason: None,
+        },
+        Err(rejection) => MessagePreview {
+            html: None,
+            warning: None,
+            reason: Some(rejection_reason(rejection).into()),
+        },
+    };
+    Ok(Json(preview))
+}
+
+#[get("/room/<_name>/my_count")]
+async fn my_message_count(
+    _name: RoomName,
+    room: Option<Room>,
+    session: Session,
+    conn: DbConn,
+) -> Result<String, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+
+    // Users can only count their own messages, so authors stay anonymous.
+    conn.run(move |c| room.count_messages_by(c, &session.id()))
+        .await
+        .map(|count| count.to_string())
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[delete("/room/<_name>/message/<id>")]
+async fn delete_message(
+    _name: RoomName,
+    id: MessageRef,
+    room: Option<Room>,
+    session: Session,
+    conn: DbConn,
+) -> Result<String, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let id = room.message_id(&id).ok_or(Status::NotFound)?;
+
+    match conn
+        .run(move |c| room.delete_message(c, id, &session.id()))
+        .await
+    {
+        Ok(true) => Ok("Your message has been deleted.".into()),
+        Ok(false) => Ok("You cannot delete this message.".into()),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[post("/room/<_name>/message/<id>/edit", format = "json", data = "<edit>")]
+async fn edit_message(
+    _name: RoomName,
+    id: MessageRef,
+    edit: Json<EditJson>,
+    room: Option<Room>,
+    session: Session,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<String, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let id = room.message_id(&id).ok_or(Status::NotFound)?;
+    let edit = edit.into_inner();
+    let options = config.storage_options();
+    let window = config.edit_window_secs;
+    // Admins can edit any message, at any time.
+    let author = (!session.is_admin()).then(|| session.id());
+
+    match conn
+        .run(move |c| room.edit_message(c, id, edit, author, &options, window))
+        .await
+    {
+        Ok(Ok(EditOutcome::Edited)) => Ok("The message has been edited.".into()),
+        Ok(Ok(EditOutcome::NotFound)) => Ok("You cannot edit this message.".into()),
+        Ok(Ok(EditOutcome::WindowClosed)) => {
+            Ok("This message was posted too long ago to be edited.".into())
+        }
+        Ok(Ok(EditOutcome::OverBudget)) => {
+            Ok("This room has no space left for your message.".into())
+        }
+        Ok(Err(rejection)) => Ok(rejection_reason(rejection).into()),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/room/<_name>/message/<id>/source")]
+async fn message_source(
+    _name: RoomName,
+    id: MessageRef,
+    room: Option<Room>,
+    session: Session,
+    conn: DbConn,
+) -> Result<String, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let id = room.message_id(&id).ok_or(Status::NotFound)?;
+    // Admins can read the source of any message.
+    let author = (!session.is_admin()).then(|| session.id());
+
+    match conn
+        .run(move |c| room.message_source(c, id, author.as_deref()))
+        .await
+    {
+        Ok(Some(source)) => Ok(source),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[post("/room/<_name>/message/<id>/undelete")]
+async fn undelete_message(
+    _name: RoomName,
+    id: MessageRef,
+    room: Option<Room>,
+    session: Session,
+    config: &State<ForumConfig>,
+    conn: DbConn,
+) -> Result<String, Status> {
+    let room = room.ok_or(Status::Unauthorized)?;
+    let id = room.message_id(&id).ok_or(Status::NotFound)?;
+    let window = config.undelete_window_secs;
+
+    match conn
+        .run(move |c| room.undelete_message(c, id, &session.id(), window))
+        .await
+    {
+        Ok(true) => Ok("Your message has been restored.".into()),
+        Ok(false) => Ok("This message cannot be restored anymore.".into()),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/constraints")]
+fn active_constraints(config: &State<ForumConfig>) -> Json<&Constraints> {
+    Json(&config.constraints)
+}
+
+#[get("/colors")]
+async fn colors() -> Result<StaticFile, NotFound<String>> {
+    static_file(PathBuf::from("colors.html")).await
+}
+
+#[get("/metrics")]
+async fn export_metrics(
+    ip: ClientIp,
+    config: &State<ForumConfig>,
+    metrics: &State<Metrics>,
+    conn: DbConn,
+) -> Result<(ContentType, String), Status> {
+    if let Some(allowed) = &config.metrics_allowed_ips {
+        if !ip.0.is_some_and(|ip| allowed.contains(&ip)) {
+            return Err(Status::Forbidden);
+        }
+    }
+
+    let active_rooms = conn.run(Room::active_rooms).await.map_err(|_| {
+        metrics.db_error();
+        Status::InternalServerError
+    })?;
+    Ok((ContentType::Plain, metrics.export(active_rooms.len())))
+}
+
+#[get("/version")]
+fn version_info(config: &Config) -> Json<VersionInfo> {
+    Json(VersionInfo::new(config))
+}
+
+#[get("/robots.txt")]
+fn robots_txt(config: &State<ForumConfig>) -> String {
+    config.robots.content().to_owned()
+}
+
+#[get("/static/<file..>")]
+async fn static_file(file: PathBuf) -> Result<StaticFile, NotFound<String>> {
+    // The reason is not sent, since it might reveal the server's files.
+    StaticFile::open(&file)
+        .await
+        .ok_or_else(|| NotFound("The file could not be found.".into()))
+}
+
+/// Returns the route for the static files, unless they are served by something
+/// else. The handler is still used for the pages which the server shows directly.
+fn static_routes(config: &ForumConfig) -> Vec<Route> {
+    if config.serve_static_files {
+        routes![static_file]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Checks if a request prefers to receive JSON, like API clients do.
+///
+/// Browsers, which navigate with a preference for HTML, and requests
+/// which accept anything, do not prefer JSON.
+fn prefers_json(req: &Request<'_>) -> bool {
+    req.accept()
+        .is_some_and(|accept| accept.preferred().is_json())
+}
+
+#[catch(404)]
+async fn not_found(
+    req: &Request<'_>,
+) -> Either<Json<serde_json::Value>, Result<StaticFile, NotFound<String>>> {
+    if prefers_json(req) {
+        return Either::Left(Json(json!({ "error": "not_found" })));
+    }
+    Either::Right(static_file(PathBuf::from("404.html")).await)
+}
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
+    let figment = config::with_limits(Config::figment());
+    let static_routes = static_routes(&config::read_early(&figment));
+
+    rocket::custom(figment)
         .mount(
             "/",
             routes![
+                active_constraints,
                 active_rooms,
+                add_webhook,
                 admin_login,
                 admin_login_page,
                 admin_pane_for_admin,
                 admin_pane_for_non_admin,
+                archive_room,
+                archived_rooms,
+                broadcast,
+                change_default_theme,
+                change_entry_message,
+                change_password,
                 change_room_password,
                 change_welcome_message,
+                change_welcome_message_json,
+                check_room_password,
+                clean_sessions,
                 colors,
+                compact_room,
+                create_admin,
+                create_invite,
                 create_room,
+                delete_message,
                 delete_room,
+                delete_webhook,
+                edit_message,
+                effective_config,
                 enter_room,
+                export_metrics,
+                export_room,
+                forget_my_room,
+                forget_my_rooms,
+                get_checksum,
                 get_message_updates,
+                get_messages,
+                get_snapshot,
+                get_thread,
+                get_threads,
                 index,
+                join,
+                message_source,
+                my_message_count,
+                my_rooms,
+                notifications,
                 post,
+                preview_message,
+                public_rooms,
+                remove_admin,
+                restore_room,
+                restore_template_version,
+                robots_txt,
                 room,
+                room_event_stream,
+                room_name_available,
+                room_webhooks,
+                rooms_info,
                 session_count,
-                static_file,
+                template_history,
+                undelete_message,
+                unread_counts,
+                version_info,
                 welcome_message,
             ],
         )
-        .register("/", catchers![not_found, sessions::session_expired])
+        .register(
+            "/",
+            catchers![
+                not_found,
+                poll_limiter::too_many_requests,
+                db::unavailable,
+                sessions::session_expired
+            ],
+        )
+        .manage(LoginLimiter::default())
+        .manage(PollLimiter::default())
+        .manage(RateLimiter::default())
+        .manage(RoomCreationLimiter::default())
+        .manage(RoomEvents::default())
+        .manage(WelcomeMessageCache::default())
+        .attach(ConfigFairing)
+        .attach(HttpsFairing)
+        .attach(RateLimitFairing)
+        .attach(MetricsFairing)
+        .attach(ArchiveFairing)
+        .attach(WebhookFairing)
+        .attach(SecurityHeadersFairing)
         .attach(Template::fairing())
         .attach(DbConn::fairing())
         .attach(DbInitFairing)
         .attach(SessionFairing)
+        .mount("/", static_routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::figment::Figment;
+    use rocket::http::{Accept, Cookie, Header};
+    use rocket::local::blocking::{Client, LocalRequest};
+    use rocket::tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::db::testing;
+    use crate::rooms::{PostPermission, RoomSettings};
+    use crate::sessions::SESSION_ID_COOKIE;
+
+    /// Starts a server with only the given routes, backed by the test database.
+    fn test_server(routes: Vec<rocket::Route>) -> Client {
+        configured_server(routes, Figment::new())
+    }
+
+    /// Like `test_server`, but with the given settings merged into the
+    /// configuration, such as `Figment::from(("forum.constraints.min_password_len", 8))`.
+    fn configured_server(routes: Vec<rocket::Route>, settings: Figment) -> Client {
+        let figment = Config::figment()
+            .merge(("databases.db.url", testing::url()))
+            .merge(settings)
+            .select(Config::DEBUG_PROFILE);
+        let rocket = rocket::custom(figment)
+            .mount("/", routes)
+            .manage(RoomEvents::default())
+            .manage(LoginLimiter::default())
+            .manage(RoomCreationLimiter::default())
+            .attach(ConfigFairing)
+            .attach(MetricsFairing)
+            .attach(ArchiveFairing)
+            .attach(WebhookFairing)
+            .attach(Template::fairing())
+            .attach(DbConn::fairing());
+        Client::tracked(rocket).expect("The test server should start")
+    }
+
+    /// Creates an empty room with the default settings, returning its name.
+    fn create_room(client: &mut db::Client) -> String {
+        create_room_with(client, RoomSettings::default())
+    }
+
+    /// Creates an empty room with the given settings, returning its name.
+    fn create_room_with(client: &mut db::Client, settings: RoomSettings) -> String {
+        let name = testing::unique_name("room");
+        Room::create_room(
+            client,
+            name.clone(),
+            rooms::hash_password(""),
+            settings,
+            None,
+            None,
+        )
+        .unwrap();
+        name
+    }
+
+    /// Adds `count` messages to a room, numbered from 1, returning the room's table.
+    fn fill_room(client: &mut db::Client, room: &str, count: i32) -> String {
+        let table_id: i32 = client
+            .query_one("SELECT table_id FROM rooms WHERE name = $1;", &[&room])
+            .unwrap()
+            .get(0);
+        let table = format!("messages{}", table_id);
+        client
+            .execute(
+                &format!(
+                    "INSERT INTO {} (content, timestamp, changed, change_seq, seq)
+                    SELECT '<p>' || i || '</p>', i, i, nextval('message_changes'), i
+                    FROM generate_series(1, $1) AS i;",
+                    table
+                ),
+                &[&count],
+            )
+            .unwrap();
+        table
+    }
+
+    /// Returns the cookie of a session.
+    fn session_cookie(session: &Session) -> Cookie<'static> {
+        Cookie::new(SESSION_ID_COOKIE, session.id())
+    }
+
+    /// Returns the cookie of a new session which entered a room.
+    fn member_cookie(client: &mut db::Client, room: &str) -> Cookie<'static> {
+        let session = Session::start_for_tests(client);
+        session
+            .save_room_attempt(client, room, &rooms::hash_password(""))
+            .unwrap();
+        session_cookie(&session)
+    }
+
+    /// Returns the cookie of a new admin session.
+    fn admin_cookie(client: &mut db::Client) -> Cookie<'static> {
+        let mut session = Session::start_for_tests(client);
+        assert!(session.make_admin(client, "admin"));
+        session_cookie(&session)
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn invites_are_created_for_normalized_names_and_bounded_periods() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        // The room's name is stored composed, but the form spells it decomposed.
+        let room = format!("{}_caf\u{e9}", testing::unique_name("room"));
+        let password = rooms::hash_password("");
+        Room::create_room(
+            &mut db,
+            room.clone(),
+            password,
+            RoomSettings::default(),
+            None,
+            None,
+        )
+        .unwrap();
+        let spelled = room.replace('\u{e9}', "e%CC%81");
+        let cookie = admin_cookie(&mut db);
+
+        let server = test_server(routes![create_invite]);
+        let create = |valid_for: i64| {
+            server
+                .post("/create_invite")
+                .header(ContentType::Form)
+                .private_cookie(cookie.clone())
+                .body(format!("name={}&valid_for={}", spelled, valid_for))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert!(create(3600).starts_with("Created invite code"));
+        assert_eq!(create(i64::MAX), "The validity period is too long.");
+        assert_eq!(create(i64::MAX / 1000), "The validity period is too long.");
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn created_rooms_are_announced() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let cookie = admin_cookie(&mut db);
+        let room = testing::unique_name("room");
+
+        let server = test_server(routes![create_room]);
+        let mut events = server.rocket().state::<RoomEvents>().unwrap().subscribe();
+        let response = server
+            .post("/create_room")
+            .header(ContentType::Form)
+            .private_cookie(cookie)
+            .body(format!("name={}&password=secret", room))
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            format!("Created room {}.", room)
+        );
+
+        let event = events.try_recv().unwrap();
+        assert!(matches!(event.kind, RoomEventKind::Created));
+        assert_eq!(event.room, room);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn restored_rooms_are_announced() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let archived = create_room(&mut db);
+        let archive = Room::archive_room(&mut db, &archived).unwrap();
+        let room = create_room(&mut db);
+        let cookie = admin_cookie(&mut db);
+
+        let server = test_server(routes![restore_room]);
+        let mut events = server.rocket().state::<RoomEvents>().unwrap().subscribe();
+        let response = server
+            .post("/restore_room")
+            .header(ContentType::Form)
+            .private_cookie(cookie)
+            .body(format!("name={}&archive={}", room, archive))
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            format!("Restored archive {} into room {}.", archive, room)
+        );
+
+        let event = events.try_recv().unwrap();
+        assert!(matches!(event.kind, RoomEventKind::Created));
+        assert_eq!(event.room, room);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn the_configured_constraints_are_returned() {
+        let server = configured_server(
+            routes![active_constraints],
+            Figment::new()
+                .merge(("forum.constraints.max_message_len", 1000))
+                .merge(("forum.constraints.min_password_len", 8)),
+        );
+        let constraints: serde_json::Value =
+            server.get("/constraints").dispatch().into_json().unwrap();
+        assert_eq!(constraints["max_message_len"], 1000);
+        assert_eq!(constraints["min_password_len"], 8);
+        assert_eq!(
+            constraints["max_room_name_len"],
+            constraints::MAX_ROOM_NAME_LEN
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn room_names_are_checked_for_availability() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let taken = create_room(&mut db);
+        let cookie = admin_cookie(&mut db);
+
+        let server = configured_server(routes![room_name_available], Figment::new());
+        let check = |name: &str| -> serde_json::Value {
+            server
+                .get(format!("/room_name_available?name={}", name))
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .into_json()
+                .unwrap()
+        };
+
+        let free = check(&testing::unique_name("room"));
+        assert_eq!(free, json!({ "valid": true, "available": true }));
+        let used = check(&taken);
+        assert_eq!(
+            (&used["valid"], &used["available"]),
+            (&json!(true), &json!(false))
+        );
+        assert!(used["reason"].is_string());
+        let invalid = check("no%20spaces");
+        assert_eq!(
+            (&invalid["valid"], &invalid["available"]),
+            (&json!(false), &json!(false))
+        );
+        assert!(invalid["reason"].is_string());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn sessions_with_one_room_get_a_shortcut() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let session = Session::start_for_tests(&mut db);
+        session
+            .save_room_attempt(&mut db, &room, &rooms::hash_password(""))
+            .unwrap();
+
+        let server = configured_server(
+            routes![index],
+            Figment::from(("forum.room_shortcut", "link")),
+        );
+        let page = server
+            .get("/")
+            .private_cookie(session_cookie(&session))
+            .dispatch()
+            .into_string()
+            .unwrap();
+        assert!(page.contains(&format!("href=\"/room/{}\"", room)));
+
+        let server = configured_server(
+            routes![index],
+            Figment::from(("forum.room_shortcut", "redirect")),
+        );
+        let response = server
+            .get("/")
+            .private_cookie(session_cookie(&session))
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some(constraints::room_url(&room).as_str())
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn posted_messages_are_counted() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let cookie = member_cookie(&mut db, &room);
+
+        let server = test_server(routes![export_metrics, post]);
+        let response = server
+            .post(format!("/room/{}/post", room))
+            .header(ContentType::JSON)
+            .private_cookie(cookie)
+            .body(r#"{ "content": "Hello!" }"#)
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "Your message has been saved."
+        );
+
+        let metrics = server.get("/metrics").dispatch().into_string().unwrap();
+        assert!(metrics
+            .lines()
+            .any(|line| line == "messages_posted_total 1"));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn the_default_theme_reaches_the_pages() {
+        // The theme is shown to everyone, so it's changed in a schema of its own.
+        let mut connection = testing::isolated();
+        let cookie = admin_cookie(&mut connection.client());
+
+        let server = configured_server(
+            routes![change_default_theme, index],
+            Figment::from(("databases.db.url", connection.url())),
+        );
+        let response = server
+            .post("/change_default_theme")
+            .header(ContentType::Plain)
+            .private_cookie(cookie)
+            .body("dark")
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "Saved the default theme succesfully."
+        );
+
+        let page = server.get("/").dispatch().into_string().unwrap();
+        assert!(page.contains(r#"data-default-theme="dark""#));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn changed_passwords_lock_other_members_out() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let changer = member_cookie(&mut db, &room);
+        let other = member_cookie(&mut db, &room);
+
+        let server = test_server(routes![change_password, my_message_count]);
+        let change = |current: &str| {
+            server
+                .post(format!("/room/{}/change_password", room))
+                .header(ContentType::Form)
+                .private_cookie(changer.clone())
+                .body(format!("current_password={}&new_password=secret", current))
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(change("wrong"), "The current password is wrong.");
+        assert_eq!(change(""), "The password has been changed.");
+
+        let count = |cookie: &Cookie<'static>| {
+            server
+                .get(format!("/room/{}/my_count", room))
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .status()
+        };
+        assert_eq!(count(&changer), Status::Ok);
+        assert_eq!(count(&other), Status::Unauthorized);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn room_names_are_listed_only_if_public() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let cookie = session_cookie(&Session::start_for_tests(&mut db));
+
+        let server = configured_server(
+            routes![public_rooms],
+            Figment::from(("forum.public_room_list", true)),
+        );
+        let names: Vec<String> = server
+            .get("/rooms")
+            .private_cookie(cookie.clone())
+            .dispatch()
+            .into_json()
+            .unwrap();
+        assert!(names.contains(&room));
+
+        let server = test_server(routes![public_rooms]);
+        let response = server.get("/rooms").private_cookie(cookie).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn large_rooms_are_exported_as_json() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        // More messages than are retrieved at once.
+        fill_room(&mut db, &room, 1234);
+        let cookie = member_cookie(&mut db, &room);
+
+        let server = test_server(routes![export_room]);
+        let response = server
+            .get(format!("/room/{}/export", room))
+            .private_cookie(cookie)
+            .dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let messages: Vec<serde_json::Value> = response.into_json().unwrap();
+        let ids: Vec<_> = messages
+            .iter()
+            .map(|message| message["id"].clone())
+            .collect();
+        assert_eq!(ids, (1..=1234).map(|id| json!(id)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn room_access_tells_why_it_was_denied() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let member = member_cookie(&mut db, &room);
+        let wrong = Session::start_for_tests(&mut db);
+        wrong
+            .save_room_attempt(&mut db, &room, &rooms::hash_password("wrong"))
+            .unwrap();
+        let wrong = session_cookie(&wrong);
+        let guest = session_cookie(&Session::start_for_tests(&mut db));
+
+        let server = configured_server(
+            routes![self::room],
+            Figment::from(("forum.reveal_missing_rooms", true)),
+        );
+        let error = |room: &str, cookie: &Cookie<'static>| {
+            let response = server
+                .get(format!("/room/{}", room))
+                .private_cookie(cookie.clone())
+                .dispatch();
+            response
+                .cookies()
+                .get("_flash")
+                .map(|flash| flash.value().to_owned())
+        };
+        assert_eq!(error(&room, &member), None);
+        assert_eq!(
+            error(&testing::unique_name("room"), &member).as_deref(),
+            Some("5:errorThis room does not exist.")
+        );
+        assert_eq!(
+            error(&room, &wrong).as_deref(),
+            Some("5:errorYour password is wrong.")
+        );
+        assert_eq!(
+            error(&room, &guest).as_deref(),
+            Some("5:errorPlease log into the room first.")
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn entering_a_room_shows_its_entry_message() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        assert!(Room::set_entry_message(&mut db, &room, Some("<p>Welcome!</p>")).unwrap());
+        let cookie = member_cookie(&mut db, &room);
+
+        let server = configured_server(
+            routes![self::room],
+            Figment::from(("forum.entry_message_once", true)),
+        );
+        let enter = || {
+            server
+                .get(format!("/room/{}", room))
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert!(enter().contains("<p>Welcome!</p>"));
+        assert!(!enter().contains("Welcome!"));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn room_pages_can_show_when_rooms_were_created() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let creation: i64 = db
+            .query_one("SELECT creation FROM rooms WHERE name = $1;", &[&room])
+            .unwrap()
+            .get(0);
+        let cookie = member_cookie(&mut db, &room);
+
+        for show_room_info in [true, false] {
+            let server = configured_server(
+                routes![self::room],
+                Figment::from(("forum.show_room_info", show_room_info)),
+            );
+            let page = server
+                .get(format!("/room/{}", room))
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .into_string()
+                .unwrap();
+            let attribute = format!("data-room-creation=\"{}\"", creation);
+            assert_eq!(page.contains(&attribute), show_room_info);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn static_files_can_be_left_to_others() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let cookie = admin_cookie(&mut db);
+
+        for serve_static_files in [true, false] {
+            let config = ForumConfig {
+                serve_static_files,
+                ..ForumConfig::default()
+            };
+            let mut routes = routes![admin_pane_for_admin, admin_pane_for_non_admin];
+            routes.extend(static_routes(&config));
+            let server = test_server(routes);
+
+            let file = server.get("/static/admin_pane.html").dispatch();
+            let expected = if serve_static_files {
+                Status::Ok
+            } else {
+                Status::NotFound
+            };
+            assert_eq!(file.status(), expected);
+            let pane = server
+                .get("/admin_pane")
+                .private_cookie(cookie.clone())
+                .dispatch();
+            assert_eq!(pane.status(), Status::Ok);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn admins_can_clean_stale_sessions() {
+        let mut connection = testing::isolated();
+        let mut db = connection.client();
+        let cookie = admin_cookie(&mut db);
+        for _ in 0..3 {
+            Session::start_for_tests(&mut db);
+        }
+        db.execute(
+            "UPDATE sessions SET last_update = 0 WHERE NOT is_admin;",
+            &[],
+        )
+        .unwrap();
+        Session::start_for_tests(&mut db);
+
+        let server = configured_server(
+            routes![clean_sessions],
+            Figment::from(("databases.db.url", connection.url())),
+        );
+        let clean = || {
+            server
+                .post("/clean_sessions")
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(clean(), "3");
+        assert_eq!(clean(), "0");
+        let mut db = connection.client();
+        let left: i64 = db
+            .query_one("SELECT COUNT(*) FROM sessions;", &[])
+            .unwrap()
+            .get(0);
+        assert_eq!(left, 2);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn guests_can_read_public_rooms_but_not_post() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room_with(
+            &mut db,
+            RoomSettings {
+                public: true,
+                ..RoomSettings::default()
+            },
+        );
+        fill_room(&mut db, &room, 3);
+        let visitor = session_cookie(&Session::start_for_tests(&mut db));
+
+        let server = test_server(routes![get_message_updates, post]);
+        let response = server.get(format!("/room/{}/updates", room)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let updates: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(updates["messages"].as_array().unwrap().len(), 3);
+
+        let post = |request: LocalRequest<'_>| {
+            request
+                .header(ContentType::JSON)
+                .body(r#"{ "content": "Hello!" }"#)
+                .dispatch()
+                .status()
+        };
+        let path = format!("/room/{}/post", room);
+        assert_eq!(post(server.post(&path)), Status::Unauthorized);
+        assert_eq!(
+            post(server.post(&path).private_cookie(visitor)),
+            Status::Unauthorized
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn forgetting_rooms_denies_access_to_all_of_them() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let rooms = [create_room(&mut db), create_room(&mut db)];
+        let session = Session::start_for_tests(&mut db);
+        for room in &rooms {
+            session
+                .save_room_attempt(&mut db, room, &rooms::hash_password(""))
+                .unwrap();
+        }
+        let cookie = session_cookie(&session);
+
+        let server = test_server(routes![my_rooms, forget_my_rooms, my_message_count]);
+        let my_rooms = || -> Vec<String> {
+            let response = server.get("/my_rooms").private_cookie(cookie.clone());
+            let mut names: Vec<String> = response.dispatch().into_json().unwrap();
+            names.sort();
+            names
+        };
+        let count = |room: &str| {
+            server
+                .get(format!("/room/{}/my_count", room))
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .status()
+        };
+        let mut entered = rooms.to_vec();
+        entered.sort();
+        assert_eq!(my_rooms(), entered);
+        assert!(rooms.iter().all(|room| count(room) == Status::Ok));
+
+        let response = server
+            .delete("/my_rooms")
+            .private_cookie(cookie.clone())
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "You have been logged out of all rooms."
+        );
+        assert!(my_rooms().is_empty());
+        assert!(rooms.iter().all(|room| count(room) == Status::Unauthorized));
+    }
+
+    #[test]
+    fn missing_pages_are_described_as_the_client_prefers() {
+        let rocket = rocket::build().register("/", catchers![not_found]);
+        let server = Client::untracked(rocket).unwrap();
+        let missing = |accept: Accept| {
+            let response = server.get("/missing").header(accept).dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+            (response.content_type(), response.into_string().unwrap())
+        };
+
+        let (content_type, body) = missing(Accept::JSON);
+        assert_eq!(content_type, Some(ContentType::JSON));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            json!({ "error": "not_found" })
+        );
+        let (content_type, body) = missing(Accept::HTML);
+        assert_eq!(content_type, Some(ContentType::HTML));
+        assert!(body.contains("<title>404 - Not found</title>"));
+    }
+
+    #[test]
+    fn robots_txt_has_the_configured_directives() {
+        let custom = "User-agent: *\nDisallow: /room/\n";
+        let cases = [
+            (Figment::new(), "User-agent: *\nDisallow:\n"),
+            (
+                Figment::from(("forum.robots", "disallow")),
+                "User-agent: *\nDisallow: /\n",
+            ),
+            (Figment::from(("forum.robots.custom", custom)), custom),
+        ];
+        for (figment, expected) in cases {
+            let rocket = rocket::build()
+                .manage(config::read_early(&figment))
+                .mount("/", routes![robots_txt]);
+            let server = Client::untracked(rocket).unwrap();
+            let response = server.get("/robots.txt").dispatch();
+            assert_eq!(response.into_string().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn room_passwords_are_checked_without_logging_in() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = testing::unique_name("room");
+        let password = rooms::hash_password("secret");
+        Room::create_room(
+            &mut db,
+            room.clone(),
+            password,
+            RoomSettings::default(),
+            None,
+            None,
+        )
+        .unwrap();
+        let cookie = session_cookie(&Session::start_for_tests(&mut db));
+
+        let server = test_server(routes![check_room_password]);
+        let check = |password: &str| {
+            server
+                .post("/check_room_password")
+                .header(ContentType::Form)
+                .private_cookie(cookie.clone())
+                .body(format!("name={}&password={}", room, password))
+                .dispatch()
+                .into_json::<bool>()
+                .unwrap()
+        };
+        assert!(check("secret"));
+        assert!(!check("wrong"));
+
+        let attempts: i64 = db
+            .query_one(
+                "SELECT COUNT(*) FROM room_attempts WHERE name = $1;",
+                &[&room],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(attempts, 0);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn ndjson_clients_receive_a_message_per_line() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        fill_room(&mut db, &room, 3);
+        let cookie = member_cookie(&mut db, &room);
+
+        let server = test_server(routes![export_room, get_message_updates]);
+        let ndjson = Header::new("Accept", "application/x-ndjson");
+        for path in ["updates", "export"] {
+            let response = server
+                .get(format!("/room/{}/{}", room, path))
+                .header(ndjson.clone())
+                .private_cookie(cookie.clone())
+                .dispatch();
+            assert_eq!(response.content_type(), Some(ndjson::content_type()));
+
+            let body = response.into_string().unwrap();
+            let lines: Vec<_> = body.lines().collect();
+            assert_eq!(lines.len(), 3);
+            for (line, content) in lines.iter().zip(["<p>1</p>", "<p>2</p>", "<p>3</p>"]) {
+                let message: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(message["content"], content);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn rooms_list_the_admins_who_created_them() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let cookie = admin_cookie(&mut db);
+        let room = testing::unique_name("room");
+
+        let server = test_server(routes![create_room, rooms_info]);
+        let response = server
+            .post("/create_room")
+            .header(ContentType::Form)
+            .private_cookie(cookie.clone())
+            .body(format!("name={}&password=secret", room))
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            format!("Created room {}.", room)
+        );
+
+        let rooms: serde_json::Value = server
+            .get("/rooms_info")
+            .private_cookie(cookie)
+            .dispatch()
+            .into_json()
+            .unwrap();
+        let info = rooms
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|info| info["name"] == room.as_str())
+            .unwrap();
+        assert_eq!(info["created_by"], "admin");
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn sessions_cannot_enter_rooms_past_the_cap() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let first = create_room(&mut db);
+        let second = create_room(&mut db);
+        let cookie = session_cookie(&Session::start_for_tests(&mut db));
+
+        let server = configured_server(
+            routes![enter_room, forget_my_rooms],
+            Figment::from(("forum.max_rooms_per_session", 1)),
+        );
+        let enter = |room: &str| {
+            let response = server
+                .post("/enter_room")
+                .header(ContentType::Form)
+                .private_cookie(cookie.clone())
+                .body(format!("name={}&password=", room))
+                .dispatch();
+            response
+                .headers()
+                .get_one("Location")
+                .map(str::to_owned)
+                .unwrap()
+        };
+        assert_eq!(enter(&first), constraints::room_url(&first));
+        // Rooms which were already entered do not count twice.
+        assert_eq!(enter(&first), constraints::room_url(&first));
+        assert_eq!(enter(&second), "/");
+
+        server
+            .delete("/my_rooms")
+            .private_cookie(cookie.clone())
+            .dispatch();
+        assert_eq!(enter(&second), constraints::room_url(&second));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn only_admins_post_in_announcement_rooms() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room_with(
+            &mut db,
+            RoomSettings {
+                post_permission: PostPermission::Admins,
+                ..RoomSettings::default()
+            },
+        );
+        let member = member_cookie(&mut db, &room);
+        let mut admin = Session::start_for_tests(&mut db);
+        assert!(admin.make_admin(&mut db, "admin"));
+        admin
+            .save_room_attempt(&mut db, &room, &rooms::hash_password(""))
+            .unwrap();
+        let admin = session_cookie(&admin);
+
+        let server = test_server(routes![get_message_updates, post]);
+        let post = |cookie: &Cookie<'static>| {
+            server
+                .post(format!("/room/{}/post", room))
+                .header(ContentType::JSON)
+                .private_cookie(cookie.clone())
+                .body(r#"{ "content": "Hello!" }"#)
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(post(&member), "Only admins can post in this room.");
+        assert_eq!(post(&admin), "Your message has been saved.");
+
+        for cookie in [&member, &admin] {
+            let updates: serde_json::Value = server
+                .get(format!("/room/{}/updates", room))
+                .private_cookie(cookie.clone())
+                .dispatch()
+                .into_json()
+                .unwrap();
+            assert_eq!(updates["messages"].as_array().unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    #[cfg(feature = "webhooks")]
+    fn posted_messages_are_sent_to_webhooks() {
+        use hmac::{Hmac, Mac, NewMac};
+        use sha2::Sha256;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        // A webhook which accepts a single request, and reports its signature and body.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let (mut signature, mut length) = (None, 0);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    match name.to_ascii_lowercase().as_str() {
+                        "x-forum-signature" => signature = Some(value.trim().to_owned()),
+                        "content-length" => length = value.trim().parse().unwrap(),
+                        _ => {}
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            sender.send((signature, body)).unwrap();
+        });
+
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let url = format!("http://{}/hook", address);
+        Webhook::add(&mut db, &room, &url, Some("hook secret")).unwrap();
+        let member = member_cookie(&mut db, &room);
+
+        let server = configured_server(
+            routes![post],
+            Figment::from(("forum.webhooks.enabled", true)),
+        );
+        let response = server
+            .post(format!("/room/{}/post", room))
+            .header(ContentType::JSON)
+            .private_cookie(member)
+            .body(r#"{ "content": "Hello!" }"#)
+            .dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "Your message has been saved."
+        );
+
+        let (signature, body) = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"hook secret").unwrap();
+        mac.update(&body);
+        let expected = format!("sha256={:x}", mac.finalize().into_bytes());
+        assert_eq!(signature, Some(expected));
+
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["room"], room.as_str());
+        assert!(payload["content"].as_str().unwrap().contains("Hello!"));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn removed_formatting_is_reported() {
+        let mut connection = testing::connect();
+        let mut db = connection.client();
+        let room = create_room(&mut db);
+        let cookie = member_cookie(&mut db, &room);
+
+        let server = configured_server(
+            routes![preview_message, post],
+            Figment::from(("forum.sanitizer.report_removed_elements", true)),
+        );
+        let send = |path: &str, content: &str| {
+            server
+                .post(format!("/room/{}/{}", room, path))
+                .header(ContentType::JSON)
+                .private_cookie(cookie.clone())
+                .body(json!({ "content": content }).to_string())
+                .dispatch()
+        };
+
+        let preview: serde_json::Value = send("preview", "Hello!").into_json().unwrap();
+        assert_eq!(preview, json!({ "html": "<p>Hello!</p>\n" }));
+        let preview: serde_json::Value = send("preview", "<script>alert(1)</script>Hello!")
+            .into_json()
+            .unwrap();
+        assert!(preview["html"].is_string());
+        assert!(preview["warning"]
+            .as_str()
+            .unwrap()
+            .starts_with("The content was modified"));
+        let preview: serde_json::Value = send("preview", " ").into_json().unwrap();
+        assert_eq!(
+            preview,
+            json!({ "reason": "Your message cannot be empty." })
+        );
+
+        let posted = send("post", "<script>alert(1)</script>Hello!");
+        assert_eq!(
+            posted.into_string().unwrap(),
+            "Your message has been saved, but some of its formatting was removed."
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn the_last_admin_cannot_be_removed() {
+        let mut connection = testing::isolated();
+        let mut db = connection.client();
+        for username in ["alice", "bob"] {
+            let created = Admin::create(&mut db, username, "hunter2", None).unwrap();
+            assert!(matches!(created, AdminCreation::Created));
+        }
+        let mut alice = Session::start_for_tests(&mut db);
+        assert!(alice.make_admin(&mut db, "alice"));
+        let admin = session_cookie(&alice);
+        let user = session_cookie(&Session::start_for_tests(&mut db));
+
+        let server = configured_server(
+            routes![remove_admin],
+            Figment::from(("databases.db.url", connection.url())),
+        );
+        let remove = |username: &str, cookie: &Cookie<'static>| {
+            server
+                .delete(format!("/admin/{}?confirm=true", username))
+                .private_cookie(cookie.clone())
+                .dispatch()
+        };
+        assert_ne!(remove("bob", &user).status(), Status::Ok);
+        assert_eq!(
+            remove("bob", &admin).into_string().unwrap(),
+            "The admin account was removed."
+        );
+        assert_eq!(
+            remove("alice", &admin).into_string().unwrap(),
+            "The last admin account cannot be removed."
+        );
+    }
 }
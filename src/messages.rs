@@ -5,17 +5,107 @@
 //!
 //! There are two "types" of messages conceptually: those which start a new
 //! thread, and replies to the main thread message.
+//!
+//! Messages can be deleted by their authors. Deleted messages are kept in the
+//! table, so their deletion can be undone for a short while, but their content
+//! is not sent to clients anymore. Authors can also edit their messages for a
+//! while after posting them. Each message also records when it last changed,
+//! so that changes like deletions and edits reach clients through updates.
+//!
+//! Changes are ordered by numbers taken from a database sequence, not by
+//! their timestamps, which can be equal for changes made in the same
+//! millisecond, or go backwards if the server's clock is adjusted. The changes
+//! of a room are made one at a time, so they are committed in the order of
+//! their numbers, and clients which have seen a change have seen all the
+//! earlier ones too.
+//!
+//! Messages are also numbered consecutively in each room, in the order in
+//! which they were posted, so clients can tell if they missed some of them.
+//!
+//! Threads can be listed in two orders: by the creation time of their first
+//! message, or by their latest activity, meaning the time of their newest
+//! message. The latter "bumps" threads which receive replies.
+//!
+//! Optionally, the hash of each message's content can be stored too, so that
+//! identical messages can be found without comparing their whole contents.
+//!
+//! Also optionally, the content of messages can be stored compressed with
+//! DEFLATE, in a separate binary column. Such messages have an empty `content`
+//! column, and are decompressed when they are retrieved. Short messages, and
+//! messages which do not get smaller, are stored as they are. Encrypted
+//! messages are never compressed, since ciphertext does not compress, and
+//! compressing before encrypting would reveal information through the sizes.
+//!
+//! The original source of messages, as written by their authors, can be stored
+//! too, so that it can be edited instead of the rendered HTML. It's only sent
+//! to the authors of the messages and to admins, and it's encrypted like the
+//! content in encrypted rooms.
+//!
+//! The total size of the messages of a room can be capped by a budget. Once
+//! it's reached, new messages are either refused, or the oldest messages are
+//! pruned to make room for them. Pruned messages are deleted and their
+//! content is erased, so they free their space and cannot be restored.
+//!
+//! Message ids are consecutive numbers, which tell how many messages a room
+//! has. If a secret is configured for them, messages are sent to clients with
+//! opaque public ids instead, which clients can use wherever ids are accepted.
+//!
+//! Optionally, the authors of messages are notified of the replies to them,
+//! as long as their sessions are live. Each message gets a limited number of
+//! such notifications over its lifetime, so popular messages do not flood
+//! their authors.
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{Read, Write};
 
 use ::serde::{Deserialize, Serialize};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use pulldown_cmark::escape::escape_html;
 use pulldown_cmark::html;
 use pulldown_cmark::{Options, Parser};
+use rocket::form::{self, FromForm, FromFormField, ValueField};
+use rocket::outcome::try_outcome;
+use rocket::request::{self, FromParam, FromRequest, Request};
+use rocket::State;
 use rocket_sync_db_pools::postgres::row::Row;
-use rocket_sync_db_pools::postgres::Client;
+use rocket_sync_db_pools::postgres::IsolationLevel;
+use sha2::{Digest, Sha256};
+
+use crate::archive::Archiver;
+use crate::clock;
+use crate::config::ForumConfig;
+use crate::constraints::MAX_MESSAGE_LEN;
+use crate::db::{self, Client, GenericClient, Transaction};
+use crate::encryption::RoomCipher;
+use crate::limited_json::JsonLimit;
+use crate::sanitizer::{self, Sanitizer};
+use crate::webhooks::WebhookDispatcher;
+use crate::word_filter::WordFilter;
+use crate::{query_and_map, query_one_row};
+
+/// The content sent to clients instead of a message which cannot be decrypted.
+const UNREADABLE_CONTENT: &str = "<p><em>This message could not be decrypted.</em></p>";
+
+/// The columns selected when retrieving messages, in the order expected by `Message::from_row`.
+const COLUMNS: &str = "id, content, timestamp, author, reply_to, deleted_at, system, change_seq, \
+    edited_at, compressed_content, seq, client_timestamp";
+
+/// Returns the number of `COLUMNS`, which is the index of the first column
+/// selected after them.
+fn column_count() -> usize {
+    COLUMNS.split(',').count()
+}
+
+/// The SQL expression which gives the stored size (in bytes) of a message's content.
+const STORED_SIZE: &str = "(octet_length(content) + COALESCE(octet_length(compressed_content), 0) \
+    + COALESCE(octet_length(source), 0))";
 
-use crate::db;
-use crate::query_and_map;
+/// Messages shorter than this (in bytes) are not worth compressing.
+const MIN_COMPRESSED_LEN: usize = 128;
 
 /// Sanitizes a user's message and prepares it for being stored.
 ///
@@ -25,23 +115,591 @@ use crate::query_and_map;
 ///
 /// To avoid doing this operation each time we need to send updates to a user,
 /// we first convert the message to the correct form, then store it like that.
-pub fn prepare_for_storage(message: &mut String) {
-    let mut cmark_options = Options::empty();
-    cmark_options.insert(Options::ENABLE_TABLES);
+///
+/// Plain-text messages are not converted from `CommonMark`. Their content is
+/// escaped instead, so it's displayed literally, and blank lines separate
+/// its paragraphs.
+///
+/// If the options limit the number of consecutive blank lines, longer runs
+/// of blank lines are collapsed first.
+///
+/// If the options require it, messages which consist only of links are refused.
+///
+/// The HTML is cleaned by the sanitizer of the given profile, or by the
+/// default sanitizer if there is no such profile.
+///
+/// Finally, the forbidden-words filter is applied. If the message should not
+/// be stored, the reason is returned. Otherwise, it returns `true` if the
+/// sanitizer removed some elements of the message, if the sanitizer is
+/// configured to report it.
+pub fn prepare_for_storage(
+    message: &mut String,
+    format: MessageFormat,
+    profile: Option<&str>,
+    options: &StorageOptions,
+) -> Result<bool, Rejection> {
+    if let Some(max) = options.max_blank_lines {
+        *message = collapse_blank_lines(message, max);
+    }
 
     let mut unsafe_html = String::new();
-    html::push_html(&mut unsafe_html, Parser::new_ext(message, cmark_options));
+    match format {
+        MessageFormat::Markdown => {
+            let mut cmark_options = Options::empty();
+            cmark_options.insert(Options::ENABLE_TABLES);
+            html::push_html(&mut unsafe_html, Parser::new_ext(message, cmark_options));
+        }
+        MessageFormat::Plain => {
+            // Paragraphs are separated by lines which are blank, even if they
+            // hold some whitespace, whatever the line endings of the client.
+            let text = message.replace("\r\n", "\n");
+            let lines: Vec<&str> = text.lines().collect();
+            let paragraphs = lines.split(|line| line.trim().is_empty());
+            for paragraph in paragraphs.filter(|lines| !lines.is_empty()) {
+                let mut escaped = String::new();
+                escape_html(&mut escaped, paragraph.join("\n").trim())
+                    .expect("Writing to a string should not fail");
+                unsafe_html.push_str(&format!("<p>{}</p>\n", escaped.replace('\n', "<br>")));
+            }
+        }
+    }
+
+    let sanitizer = options.sanitizer(profile);
+    let safe_html = sanitizer
+        .clean(&unsafe_html)
+        .map_err(|_| Rejection::TooComplex)?;
+    if is_blank(&safe_html) {
+        return Err(Rejection::Empty);
+    }
+    if options.reject_only_links && sanitizer::is_only_links(&safe_html) {
+        return Err(Rejection::OnlyLinks);
+    }
+    let stripped = sanitizer.report_removed_elements
+        && sanitizer::count_elements(&safe_html) < sanitizer::count_elements(&unsafe_html);
+
+    *message = options
+        .filter
+        .apply(&safe_html)
+        .ok_or(Rejection::ForbiddenWords)?;
+    Ok(stripped)
+}
+
+/// Collapses the runs of more than `max` blank lines of a message, and trims
+/// the whitespace at the end of its lines.
+///
+/// Whitespace is significant in code blocks, so the lines of fenced code
+/// blocks are kept as they are, including fences indented inside list items.
+/// So are the blank lines between indented lines, which might belong to
+/// indented code blocks.
+fn collapse_blank_lines(message: &str, max: usize) -> String {
+    let mut collapsed = String::with_capacity(message.len());
+    // The character and length of the fence which opened the current code block.
+    let mut fence: Option<(char, usize)> = None;
+    let mut blank = 0;
+    let mut indented = false;
+
+    for line in message.lines() {
+        if fence.is_none() && line.trim().is_empty() {
+            blank += 1;
+            continue;
+        }
+        let was_indented = indented;
+        indented = line.starts_with("    ") || line.starts_with('\t');
+        let kept = if fence.is_some() || (was_indented && indented) {
+            blank
+        } else {
+            blank.min(max)
+        };
+        collapsed.push_str(&"\n".repeat(kept));
+        blank = 0;
+        collapsed.push_str(if fence.is_some() {
+            line
+        } else {
+            line.trim_end()
+        });
+        collapsed.push('\n');
+
+        let marker = code_fence(line.trim_start());
+        fence = match (fence, marker) {
+            (None, marker) => marker,
+            (Some((open, open_len)), Some((close, close_len)))
+                if close == open
+                    && close_len >= open_len
+                    && line.trim().chars().all(|c| c == close) =>
+            {
+                None
+            }
+            (open, _) => open,
+        };
+    }
+
+    collapsed.truncate(collapsed.trim_end().len());
+    collapsed
+}
+
+/// Returns the character and length of the fence which starts a line,
+/// if the line starts or ends a fenced code block.
+fn code_fence(line: &str) -> Option<(char, usize)> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.chars().take_while(|other| *other == c).count();
+    (len >= 3).then_some((c, len))
+}
+
+/// Checks if an HTML message would be displayed as empty, meaning it has
+/// no images, and its text consists only of whitespace.
+fn is_blank(html: &str) -> bool {
+    if html.contains("<img") {
+        return false;
+    }
+
+    let mut in_tag = false;
+    html.chars().all(|c| {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag && !c.is_whitespace() => return false,
+            _ => {}
+        }
+        true
+    })
+}
+
+/// Compresses the content of a message, if it's worth it.
+///
+/// It returns `None` if the message is too short, or if it does not get smaller.
+fn compress(content: &str) -> Option<Vec<u8>> {
+    if content.len() < MIN_COMPRESSED_LEN {
+        return None;
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content.as_bytes()).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < content.len()).then_some(compressed)
+}
+
+/// Decompresses the content of a message compressed by `compress`.
+///
+/// It returns `None` if the data is corrupted.
+fn decompress(compressed: &[u8]) -> Option<String> {
+    let mut content = String::new();
+    DeflateDecoder::new(compressed)
+        .read_to_string(&mut content)
+        .ok()?;
+    Some(content)
+}
+
+/// The length (in bytes) of author tokens, before being hex-encoded.
+const AUTHOR_TOKEN_LEN: usize = 6;
+
+/// Derives the anonymous tokens which tell apart the authors of a room's threads.
+///
+/// Each token is derived from a server secret, the room, the thread, and the
+/// author's session id. Tokens cannot be linked across threads or rooms, and
+/// cannot be computed by users, since they do not know the secret, nor the
+/// session ids of others. So users cannot pose as other authors, like admins.
+#[derive(Clone)]
+pub struct AuthorTokens([u8; 32]);
+
+impl AuthorTokens {
+    /// Builds the tokens of the room with the given `table_id`.
+    pub fn new(secret: &str, table_id: i32) -> Self {
+        let key = Sha256::new()
+            .chain(b"author-tokens")
+            .chain(secret.as_bytes())
+            .chain(table_id.to_be_bytes())
+            .finalize();
+        Self(key.into())
+    }
+
+    /// Returns the token of an author in the thread started by the message `root`.
+    fn token(&self, author: &str, root: i32) -> String {
+        let hash = Sha256::new()
+            .chain(self.0)
+            .chain(root.to_be_bytes())
+            .chain(author.as_bytes())
+            .finalize();
+        hash[..AUTHOR_TOKEN_LEN]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// The letters in which public ids are written. Public ids have no digits,
+/// so they cannot be mistaken for ids.
+const PUBLIC_ID_ALPHABET: &[u8; 52] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// The length of public ids, which is enough to write any 32-bit number.
+const PUBLIC_ID_LEN: usize = 6;
+
+/// The number of rounds of the permutation which scrambles ids.
+const PUBLIC_ID_ROUNDS: u8 = 4;
+
+/// Converts the ids of a room's messages to opaque public ids, and back.
+///
+/// Ids are scrambled by a permutation of 32-bit numbers (a Feistel network),
+/// keyed by a server secret and the room. The public id of a message never
+/// changes, but public ids do not tell how many messages a room has, nor in
+/// which order they were posted.
+#[derive(Clone)]
+pub struct PublicIds([u8; 32]);
+
+impl PublicIds {
+    /// Builds the public ids of the room with the given `table_id`.
+    pub fn new(secret: &str, table_id: i32) -> Self {
+        let key = Sha256::new()
+            .chain(b"public-ids")
+            .chain(secret.as_bytes())
+            .chain(table_id.to_be_bytes())
+            .finalize();
+        Self(key.into())
+    }
+
+    /// Returns the public id of the message with the given id.
+    pub fn encode(&self, id: i32) -> String {
+        let (mut left, mut right) = ((id as u32 >> 16) as u16, id as u16);
+        for round in 0..PUBLIC_ID_ROUNDS {
+            (left, right) = (right, left ^ self.round(round, right));
+        }
+
+        let mut scrambled = u64::from(left) << 16 | u64::from(right);
+        let mut public = [0; PUBLIC_ID_LEN];
+        for letter in public.iter_mut().rev() {
+            *letter = PUBLIC_ID_ALPHABET[(scrambled % 52) as usize];
+            scrambled /= 52;
+        }
+        public.iter().map(|&letter| char::from(letter)).collect()
+    }
+
+    /// Returns the id of the message with the given public id.
+    ///
+    /// It returns `None` if the public id is malformed.
+    pub fn decode(&self, public: &str) -> Option<i32> {
+        if public.len() != PUBLIC_ID_LEN {
+            return None;
+        }
+        let mut scrambled: u64 = 0;
+        for letter in public.bytes() {
+            let digit = PUBLIC_ID_ALPHABET.iter().position(|&c| c == letter)?;
+            scrambled = scrambled * 52 + digit as u64;
+        }
+        let scrambled = u32::try_from(scrambled).ok()?;
+
+        let (mut left, mut right) = ((scrambled >> 16) as u16, scrambled as u16);
+        for round in (0..PUBLIC_ID_ROUNDS).rev() {
+            (left, right) = (right ^ self.round(round, left), left);
+        }
+        Some((u32::from(left) << 16 | u32::from(right)) as i32)
+    }
+
+    /// The function applied in each round of the permutation.
+    fn round(&self, round: u8, half: u16) -> u16 {
+        let hash = Sha256::new()
+            .chain(self.0)
+            .chain([round])
+            .chain(half.to_be_bytes())
+            .finalize();
+        u16::from_be_bytes([hash[0], hash[1]])
+    }
+}
+
+/// The id of a message, as it's received from clients.
+///
+/// Clients can send either the id itself, or its public id.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MessageRef {
+    Id(i32),
+    Public(String),
+}
+
+impl MessageRef {
+    /// Parses a message id sent by a client, in either form.
+    fn parse(value: &str) -> Self {
+        match value.parse() {
+            Ok(id) => MessageRef::Id(id),
+            Err(_) => MessageRef::Public(value.to_owned()),
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for MessageRef {
+    type Error = Infallible;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        Ok(MessageRef::parse(param))
+    }
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for MessageRef {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Ok(MessageRef::parse(field.value))
+    }
+}
+
+/// The id of a message, as it's sent to clients.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum ShownId {
+    Id(i32),
+    Public(String),
+}
+
+impl ShownId {
+    /// Returns how the id of a message is shown, given the public ids of
+    /// its room, if they are enabled.
+    pub fn new(id: i32, public_ids: Option<&PublicIds>) -> Self {
+        match public_ids {
+            Some(public_ids) => ShownId::Public(public_ids.encode(id)),
+            None => ShownId::Id(id),
+        }
+    }
+}
+
+impl fmt::Display for ShownId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShownId::Id(id) => write!(f, "{}", id),
+            ShownId::Public(public) => f.write_str(public),
+        }
+    }
+}
+
+/// What is needed to prepare the messages of a room to be sent to clients.
+#[derive(Clone, Copy, Default)]
+pub struct ClientView<'a> {
+    /// The cipher of the room, if its messages are encrypted.
+    pub cipher: Option<&'a RoomCipher>,
+    /// The author tokens of the room, if they are enabled.
+    pub author_tokens: Option<&'a AuthorTokens>,
+    /// The public ids of the room, if they are enabled.
+    pub public_ids: Option<&'a PublicIds>,
+}
+
+/// The default maximum number of replies followed when looking for the
+/// message which started a thread.
+pub const DEFAULT_MAX_REPLY_DEPTH: usize = 16;
+
+/// The settings which affect how messages are prepared and stored.
+#[derive(Clone)]
+pub struct StorageOptions {
+    /// Whether the hash of each message's content is stored.
+    pub hash_content: bool,
+    /// Whether the content of messages is stored compressed.
+    pub compress_content: bool,
+    /// Whether the original source of messages is stored.
+    pub store_source: bool,
+    /// Whether messages which consist only of links are refused.
+    pub reject_only_links: bool,
+    /// The maximum number of consecutive blank lines kept in messages, if any.
+    pub max_blank_lines: Option<usize>,
+    /// How far (in seconds) the times sent by clients, for when they wrote
+    /// their messages, can be from the server's time. If it's missing, these
+    /// times are not stored.
+    pub client_timestamp_window_secs: Option<i64>,
+    /// The filter applied to the words of messages.
+    pub filter: WordFilter,
+    /// The sanitizer which cleans the HTML of messages.
+    pub sanitizer: Sanitizer,
+    /// The sanitizers which rooms can choose instead, by their names.
+    pub sanitizer_profiles: HashMap<String, Sanitizer>,
+    /// The maximum number of replies followed when looking for the
+    /// message which started the thread of a reply.
+    pub max_reply_depth: usize,
+    /// The maximum length (in bytes) of a message, before being prepared.
+    pub max_len: usize,
+    /// The maximum number of reply notifications recorded for each message.
+    /// Replies are not notified if it's 0.
+    pub max_reply_notifications: usize,
+    /// The cap on the total size of the messages of a room, if any.
+    pub room_budget: Option<RoomBudget>,
+    /// The archiver which mirrors new messages to object storage.
+    pub archiver: Archiver,
+    /// The dispatcher which sends new messages to the webhooks of their rooms.
+    pub dispatcher: WebhookDispatcher,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            hash_content: false,
+            compress_content: false,
+            store_source: false,
+            reject_only_links: false,
+            max_blank_lines: None,
+            client_timestamp_window_secs: None,
+            filter: WordFilter::default(),
+            sanitizer: Sanitizer::default(),
+            sanitizer_profiles: HashMap::new(),
+            max_reply_depth: DEFAULT_MAX_REPLY_DEPTH,
+            max_len: MAX_MESSAGE_LEN,
+            max_reply_notifications: 0,
+            room_budget: None,
+            archiver: Archiver::default(),
+            dispatcher: WebhookDispatcher::default(),
+        }
+    }
+}
+
+impl StorageOptions {
+    /// Checks the time when a message was written, as sent by its author,
+    /// returning the time which should be stored.
+    ///
+    /// Nothing is stored if clients cannot send these times. Otherwise,
+    /// times too far from the server's time are refused.
+    pub fn client_timestamp(&self, sent: Option<i64>) -> Result<Option<i64>, Rejection> {
+        let (sent, window) = match (sent, self.client_timestamp_window_secs) {
+            (Some(sent), Some(window)) => (sent, window.saturating_mul(1000)),
+            _ => return Ok(None),
+        };
+        let now = clock::now_millis();
+        if sent < now.saturating_sub(window) || sent > now.saturating_add(window) {
+            return Err(Rejection::InvalidTimestamp);
+        }
+        Ok(Some(sent))
+    }
+
+    /// Returns the sanitizer of a profile, falling back to the default
+    /// sanitizer if the profile is missing or unknown.
+    pub fn sanitizer(&self, profile: Option<&str>) -> &Sanitizer {
+        profile
+            .and_then(|profile| self.sanitizer_profiles.get(profile))
+            .unwrap_or(&self.sanitizer)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for StorageOptions {
+    type Error = ();
+
+    /// Gathers the options from the server's configuration and managed state.
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = try_outcome!(req.guard::<&State<ForumConfig>>().await);
+        let archiver = try_outcome!(req.guard::<&State<Archiver>>().await);
+        let dispatcher = try_outcome!(req.guard::<&State<WebhookDispatcher>>().await);
+        let mut options = config.storage_options();
+        options.archiver = archiver.inner().clone();
+        options.dispatcher = dispatcher.inner().clone();
+        request::Outcome::Success(options)
+    }
+}
+
+/// The reasons for which a message can be refused instead of being stored.
+#[derive(Debug)]
+pub enum Rejection {
+    /// The message is too long.
+    TooLong,
+    /// The message has no content left after being sanitized.
+    Empty,
+    /// The message's HTML is nested too deeply, or has too many elements.
+    TooComplex,
+    /// The message contains forbidden words.
+    ForbiddenWords,
+    /// The message consists only of links.
+    OnlyLinks,
+    /// The message replies to a message which does not exist,
+    /// or whose thread cannot be found.
+    InvalidReply,
+    /// The message does not fit in the size budget of the room.
+    OverBudget,
+    /// The room does not accept posts yet.
+    NotOpen,
+    /// The time when the message was written is too far from the server's time.
+    InvalidTimestamp,
+}
+
+/// What happens to a message which would make its room exceed its size budget.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetPolicy {
+    /// The message is refused.
+    Reject,
+    /// The oldest messages of the room are pruned until the message fits.
+    Prune,
+}
+
+/// A cap on the total size of the messages stored in a room.
+#[derive(Clone, Copy, Debug)]
+pub struct RoomBudget {
+    /// The maximum total size (in bytes) of the stored contents of the messages.
+    pub max_bytes: i64,
+    /// What happens to messages which do not fit in the budget.
+    pub policy: BudgetPolicy,
+}
+
+/// The ways in which looking for the start of a thread can fail.
+#[derive(Debug)]
+pub enum TraversalError {
+    /// The database could not be queried.
+    Db(db::Error),
+    /// The start was not found after following the maximum number of replies.
+    /// This should only happen if the replies form a cycle.
+    TooDeep,
+}
+
+impl From<db::Error> for TraversalError {
+    fn from(err: db::Error) -> Self {
+        TraversalError::Db(err)
+    }
+}
 
-    let safe_html = ammonia::clean(&unsafe_html);
-    *message = safe_html;
+/// The formats in which users can write their messages.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    /// The message is written in `CommonMark`, and may contain HTML.
+    #[default]
+    Markdown,
+    /// The message is displayed literally, as plain text.
+    Plain,
+}
+
+/// The order in which threads can be listed. Newer threads come first.
+#[derive(Clone, Copy, Debug, Deserialize, FromFormField, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadOrder {
+    /// Order threads by the timestamp of their newest message.
+    Activity,
+    /// Order threads by the timestamp of their first message.
+    Created,
+}
+
+impl ThreadOrder {
+    /// Returns the SQL expression by which the threads of a table are sorted,
+    /// where `root` is the message which started each thread.
+    fn sort_key(self, table: &str) -> String {
+        match self {
+            ThreadOrder::Activity => format!(
+                "(SELECT MAX(reply.timestamp) FROM {} AS reply
+                WHERE reply.id = root.id OR reply.reply_to = root.id)",
+                table
+            ),
+            ThreadOrder::Created => "root.timestamp".into(),
+        }
+    }
 }
 
 /// Holds the relevant information of a message.
 #[derive(Debug, Serialize)]
 pub struct Message {
+    #[serde(skip_serializing)]
     id: i32,
+    /// The id sent to clients, which is the public id if they are enabled.
+    #[serde(rename = "id")]
+    shown_id: ShownId,
+    /// The number of the message in its room. Messages are numbered from 1,
+    /// without gaps, in the order in which they were posted. It's not sent
+    /// if public ids are enabled, since it tells how many messages there are.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
     content: String,
     timestamp: i64,
+    /// The time when the author wrote the message, according to the author's
+    /// device. It's only a hint for displaying messages, since clients can
+    /// send any time. Updates are always ordered by the server's timestamps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_timestamp: Option<i64>,
     /// The id of the user author.
     /// It's optional because, as sessions time out, messages can "forget" their author.
     /// It should be skipped when sending messages to clients, because it might
@@ -51,11 +709,37 @@ pub struct Message {
     author: Option<String>,
     /// Messages which start new threads have this field set to `None`.
     /// Replies hold the id of the message which started their thread.
+    #[serde(skip_serializing)]
     reply_to: Option<i32>,
+    /// The form of `reply_to` sent to clients, like `shown_id`.
+    #[serde(rename = "reply_to")]
+    shown_reply_to: Option<ShownId>,
+    /// Deleted messages are sent to clients without their content.
+    deleted: bool,
+    /// Whether the content was changed after the message was posted.
+    edited: bool,
+    /// When the content was last changed, if it was changed after the
+    /// message was posted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edited_at: Option<i64>,
+    /// A token which is the same for all the messages of an author in a thread,
+    /// but differs between threads. It's only set if author tokens are enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author_token: Option<String>,
+    /// System messages are posted by the server itself, for example
+    /// to announce something to all rooms.
+    system: bool,
+    /// The number of the message's last change. It's only used by the server
+    /// to keep track of the updates sent to clients.
+    #[serde(skip_serializing)]
+    change_seq: i64,
 }
 
 impl Message {
     /// Initializes the table which holds messages.
+    ///
+    /// It's safe to call this on an existing table, in which case
+    /// the columns added since the table was created are set up.
     pub fn setup_table(client: &mut Client, table: &str) -> Result<(), db::Error> {
         let sql = format!(
             "CREATE TABLE IF NOT EXISTS {table} (
@@ -66,81 +750,1028 @@ impl Message {
                 reply_to  INT,
                 FOREIGN KEY (author) REFERENCES sessions(id) ON DELETE SET NULL,
                 FOREIGN KEY (reply_to) REFERENCES {table}(id)
-            );",
+            );
+
+            -- Columns added after the table was first created.
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS deleted_at BIGINT;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS changed BIGINT;
+            UPDATE {table} SET changed = timestamp WHERE changed IS NULL;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS content_hash TEXT;
+            CREATE INDEX IF NOT EXISTS {table}_content_hash ON {table} (content_hash);
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS system BOOLEAN NOT NULL DEFAULT FALSE;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS change_seq BIGINT;
+            CREATE INDEX IF NOT EXISTS {table}_change_seq ON {table} (change_seq);
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS edited_at BIGINT;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS compressed_content BYTEA;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS seq BIGINT;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS source TEXT;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS client_timestamp BIGINT;
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS reply_notifications INT NOT NULL DEFAULT 0;
+            -- The change number given to a message when it was posted. It stays
+            -- NULL for older messages, which are never counted as unread.
+            ALTER TABLE {table} ADD COLUMN IF NOT EXISTS posted_change BIGINT;
+            CREATE INDEX IF NOT EXISTS {table}_posted_change ON {table} (posted_change);
+            CREATE UNIQUE INDEX IF NOT EXISTS {table}_seq ON {table} (seq);
+
+            -- Messages from before the numbers were added are numbered in the
+            -- order of their ids, which is the order in which they were posted.
+            UPDATE {table} SET seq = numbered.seq
+            FROM (
+                SELECT id, (SELECT COALESCE(MAX(seq), 0) FROM {table})
+                    + ROW_NUMBER() OVER (ORDER BY id) AS seq
+                FROM {table} WHERE seq IS NULL
+            ) AS numbered
+            WHERE {table}.id = numbered.id;",
             table = table
         );
-        client.execute(&sql, &[]).and(Ok(()))
+        client.batch_execute(&sql)?;
+
+        // Messages from before the change numbers were added are numbered
+        // in the order in which they changed.
+        Message::renumber_changes(client, table, "change_seq IS NULL", "changed, id")
+    }
+
+    /// Gives new change numbers to the messages which match a condition,
+    /// in the given order, so they reach clients as updates.
+    pub fn renumber_changes(
+        client: &mut impl GenericClient,
+        table: &str,
+        condition: &str,
+        order: &str,
+    ) -> Result<(), db::Error> {
+        client.batch_execute(&format!(
+            "UPDATE {table} SET change_seq = numbered.seq
+            FROM (
+                SELECT id, nextval('message_changes') AS seq
+                FROM (SELECT id FROM {table} WHERE {condition} ORDER BY {order}) AS ordered
+            ) AS numbered
+            WHERE {table}.id = numbered.id;",
+            table = table,
+            condition = condition,
+            order = order
+        ))
     }
 
-    /// Returns all messages inserted into the table in the given interval.
+    /// Removes the pruned messages of a table which have no replies, and
+    /// renumbers the rest, so their ids and numbers are consecutive again.
     ///
-    /// The left endpoint is exclusive, and the right one is inclusive -
-    /// i.e., (old, new].
+    /// Replies keep pointing to the same messages, and the next message gets
+    /// the id following the last one. All the messages are given new change
+    /// numbers, greater than the returned one, so clients can tell that their
+    /// copies are outdated. The number of removed messages is also returned.
+    ///
+    /// It should run in a change transaction.
+    pub fn compact(transaction: &mut Transaction, table: &str) -> Result<(u64, i64), db::Error> {
+        let pruned = format!("deleted_at IS NOT NULL AND {} = 0", STORED_SIZE);
+        // Replies are removed first, so the messages they replied to can be removed too.
+        let mut removed = 0;
+        for condition in ["reply_to IS NOT NULL", "TRUE"] {
+            removed += transaction.execute(
+                &format!(
+                    "DELETE FROM {table} AS message WHERE {pruned} AND {condition}
+                    AND NOT EXISTS (SELECT 1 FROM {table} AS reply WHERE reply.reply_to = message.id);",
+                    table = table,
+                    pruned = pruned,
+                    condition = condition
+                ),
+                &[],
+            )?;
+        }
+
+        // The new ids and numbers are negative at first, so they never collide
+        // with those of the messages which were not moved yet.
+        transaction.batch_execute(&format!(
+            "CREATE TEMPORARY TABLE compacted_ids ON COMMIT DROP AS
+                SELECT id AS old_id, (ROW_NUMBER() OVER (ORDER BY id))::INTEGER AS new_id
+                FROM {table};
+
+            UPDATE {table} SET id = -moved.new_id, seq = -moved.new_id,
+                reply_to = -(SELECT parent.new_id FROM compacted_ids AS parent
+                    WHERE parent.old_id = {table}.reply_to)
+            FROM compacted_ids AS moved
+            WHERE {table}.id = moved.old_id;
+
+            UPDATE {table} SET id = -id, seq = -seq, reply_to = -reply_to;
+
+            SELECT setval(pg_get_serial_sequence('{table}', 'id'),
+                (SELECT COALESCE(MAX(id), 0) + 1 FROM {table}), false);",
+            table = table
+        ))?;
+
+        let compacted: i64 = query_one_row!(
+            transaction,
+            "SELECT nextval('message_changes');",
+            &[],
+            |row: Row| row.get(0)
+        )?;
+        Message::renumber_changes(transaction, table, "TRUE", "id")?;
+
+        Ok((removed, compacted))
+    }
+
+    /// Starts a transaction in which a table of messages can be changed.
+    ///
+    /// Changes to the same table wait for each other, so they are committed
+    /// in the order of their change numbers. Reading the table is not blocked.
+    pub fn change_transaction<'a>(
+        client: &'a mut Client,
+        table: &str,
+    ) -> Result<Transaction<'a>, db::Error> {
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute(&format!(
+            "LOCK TABLE {} IN SHARE ROW EXCLUSIVE MODE;",
+            table
+        ))?;
+        Ok(transaction)
+    }
+
+    /// Returns the id of the message.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Builds a message from a row containing the `COLUMNS`.
+    ///
+    /// Compressed content is decompressed.
+    fn from_row(row: Row) -> Self {
+        let deleted_at: Option<i64> = row.get(5);
+        let edited_at: Option<i64> = row.get(8);
+        let compressed: Option<Vec<u8>> = row.get(9);
+        let content = match compressed {
+            Some(compressed) => {
+                decompress(&compressed).unwrap_or_else(|| UNREADABLE_CONTENT.into())
+            }
+            None => row.get(1),
+        };
+        let id = row.get(0);
+        let reply_to: Option<i32> = row.get(4);
+        Message {
+            id,
+            shown_id: ShownId::Id(id),
+            seq: row.get(10),
+            content,
+            timestamp: row.get(2),
+            client_timestamp: row.get(11),
+            author: row.get(3),
+            reply_to,
+            shown_reply_to: reply_to.map(ShownId::Id),
+            deleted: deleted_at.is_some(),
+            edited: edited_at.is_some(),
+            edited_at,
+            author_token: None,
+            system: row.get(6),
+            change_seq: row.get(7),
+        }
+    }
+
+    /// Prepares a message retrieved from the database to be sent to clients.
+    ///
+    /// The content of deleted messages is removed, and the content
+    /// of encrypted messages is decrypted. If the view has author tokens,
+    /// messages which are not deleted get the token of their author. If the
+    /// view has public ids, they replace the ids.
+    fn prepare_for_client(mut self, view: ClientView) -> Self {
+        if let Some(public_ids) = view.public_ids {
+            self.shown_id = ShownId::new(self.id, Some(public_ids));
+            self.shown_reply_to = self
+                .reply_to
+                .map(|reply_to| ShownId::new(reply_to, Some(public_ids)));
+            self.seq = None;
+        }
+        if self.deleted {
+            self.content.clear();
+            return self;
+        }
+        if let Some(cipher) = view.cipher {
+            self.content = cipher
+                .decrypt(&self.content)
+                .unwrap_or_else(|| UNREADABLE_CONTENT.into());
+        }
+        if let (Some(tokens), Some(author)) = (view.author_tokens, &self.author) {
+            let root = self.reply_to.unwrap_or(self.id);
+            self.author_token = Some(tokens.token(author, root));
+        }
+        self
+    }
+
+    /// Returns the messages which changed after the change with the given
+    /// number, in the order in which they changed, together with the number
+    /// of the last change they cover.
+    ///
+    /// Messages change when they are inserted into the table,
+    /// or when they are deleted, or their deletion is undone.
+    ///
+    /// At most `limit` messages are returned. The returned flag tells if
+    /// more messages changed, in which case they can be retrieved by calling
+    /// this function again, starting from the returned change number.
+    ///
+    /// If the messages are encrypted, the view should hold the cipher
+    /// used to encrypt them, so they can be decrypted.
+    pub fn get_changed_after(
+        client: &mut Client,
+        table: &str,
+        after: i64,
+        limit: usize,
+        view: ClientView,
+    ) -> Result<(Vec<Self>, i64, bool), db::Error> {
+        let mut messages: Vec<Self> = query_and_map!(
+            client,
+            &format!(
+                "SELECT {} FROM {} WHERE change_seq > $1 ORDER BY change_seq LIMIT $2;",
+                COLUMNS, table
+            ),
+            &[&after, &(limit as i64 + 1)],
+            Message::from_row
+        )
+        .collect();
+
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        let end = messages.last().map_or(after, |message| message.change_seq);
+
+        let messages = messages
+            .into_iter()
+            .map(|message| message.prepare_for_client(view))
+            .collect();
+        Ok((messages, end, has_more))
+    }
+
+    /// Returns at most `limit` messages with ids greater than `after`, in the
+    /// order of their ids.
+    ///
+    /// This allows going through all the messages of a table in batches,
+    /// by passing the id of the last message of a batch to the next call.
+    pub fn get_after(
+        client: &mut Client,
+        table: &str,
+        after: i32,
+        limit: i64,
+        view: ClientView,
+    ) -> Result<Vec<Self>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            &format!(
+                "SELECT {} FROM {} WHERE id > $1 ORDER BY id LIMIT $2;",
+                COLUMNS, table
+            ),
+            &[&after, &limit],
+            Message::from_row
+        )
+        .map(|message| message.prepare_for_client(view))
+        .collect())
+    }
+
+    /// Returns at most `limit` messages with ids between `from` and `to`
+    /// (both inclusive), in the order of their ids.
+    pub fn get_id_range(
+        client: &mut Client,
+        table: &str,
+        from: i32,
+        to: i32,
+        limit: i64,
+        view: ClientView,
+    ) -> Result<Vec<Self>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            &format!(
+                "SELECT {} FROM {} WHERE $1 <= id AND id <= $2 ORDER BY id LIMIT $3;",
+                COLUMNS, table
+            ),
+            &[&from, &to, &limit],
+            Message::from_row
+        )
+        .map(|message| message.prepare_for_client(view))
+        .collect())
+    }
+
+    /// Returns the messages with the given ids, in the order of their ids.
     ///
-    /// The timestamps should have the format used by the table.
-    pub fn get_between(
+    /// Ids which do not belong to any message are skipped.
+    pub fn get_many(
         client: &mut Client,
         table: &str,
-        old: i64,
-        new: i64,
+        ids: &[i32],
+        view: ClientView,
     ) -> Result<Vec<Self>, db::Error> {
         Ok(query_and_map!(
             client,
             &format!(
-                "SELECT * FROM {} WHERE $1 < timestamp AND timestamp <= $2;",
+                "SELECT {} FROM {} WHERE id = ANY($1) ORDER BY id;",
+                COLUMNS, table
+            ),
+            &[&ids],
+            Message::from_row
+        )
+        .map(|message| message.prepare_for_client(view))
+        .collect())
+    }
+
+    /// Returns a page of the thread started by the message with the given id,
+    /// in the order in which the messages were posted.
+    ///
+    /// The page contains at most `limit` replies posted after the reply with
+    /// the id `after`. The first page, for which `after` is `None`, also
+    /// contains the message which started the thread.
+    pub fn get_thread(
+        client: &mut Client,
+        table: &str,
+        root: i32,
+        after: Option<i32>,
+        limit: i64,
+        view: ClientView,
+    ) -> Result<Vec<Self>, db::Error> {
+        let (after, limit) = match after {
+            Some(after) => (after, limit),
+            // The root is not counted as a reply.
+            None => (root, limit + 1),
+        };
+
+        Ok(query_and_map!(
+            client,
+            &format!(
+                "SELECT {} FROM {}
+                WHERE (id = $1 AND reply_to IS NULL AND $2 = $1) OR (reply_to = $1 AND id > $2)
+                ORDER BY id LIMIT $3;",
+                COLUMNS, table
+            ),
+            &[&root, &after, &limit],
+            Message::from_row
+        )
+        .map(|message| message.prepare_for_client(view))
+        .collect())
+    }
+
+    /// Summarizes the messages posted in the given interval, so clients can
+    /// check if the messages they stored match the server's.
+    ///
+    /// The left endpoint is exclusive, and the right one is inclusive -
+    /// i.e., (since, now]. The checksum is the SHA-256 hash of a line for each
+    /// message, in the order of their ids. Each line has the format
+    /// `<id>:<timestamp>:<deleted>:<edited_at>\n`, where `deleted` is `true`
+    /// or `false`, and `edited_at` is the time of the last edit, or 0 if the
+    /// message was not edited, so edits change the checksum too.
+    pub fn interval_checksum(
+        client: &mut Client,
+        table: &str,
+        since: i64,
+        now: i64,
+    ) -> Result<IntervalChecksum, db::Error> {
+        let mut hasher = Sha256::new();
+        let mut count = 0;
+        for row in client.query(
+            &format!(
+                "SELECT id, timestamp, deleted_at IS NOT NULL, COALESCE(edited_at, 0) FROM {}
+                WHERE $1 < timestamp AND timestamp <= $2 ORDER BY id;",
                 table
             ),
-            &[&old, &new],
-            |row: Row| Message {
-                id: row.get(0),
-                content: row.get(1),
-                timestamp: row.get(2),
-                author: row.get(3),
-                reply_to: row.get(4),
-            }
+            &[&since, &now],
+        )? {
+            let (id, timestamp, deleted, edited_at): (i32, i64, bool, i64) =
+                (row.get(0), row.get(1), row.get(2), row.get(3));
+            hasher.update(format!("{}:{}:{}:{}\n", id, timestamp, deleted, edited_at));
+            count += 1;
+        }
+
+        Ok(IntervalChecksum {
+            count,
+            checksum: format!("{:x}", hasher.finalize()),
+        })
+    }
+
+    /// Returns the messages which start threads, in the given order.
+    ///
+    /// Threads with equal sort keys are ordered by id, to keep the order stable.
+    pub fn get_threads(
+        client: &mut Client,
+        table: &str,
+        order: ThreadOrder,
+        view: ClientView,
+    ) -> Result<Vec<Self>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            &format!(
+                "SELECT {} FROM {} AS root WHERE root.reply_to IS NULL
+                ORDER BY {} DESC, root.id DESC;",
+                COLUMNS,
+                table,
+                order.sort_key(table)
+            ),
+            &[],
+            Message::from_row
         )
+        .map(|message| message.prepare_for_client(view))
         .collect())
     }
 
+    /// Returns the newest `limit` threads of a table in the given order, with
+    /// their reply counts, together with the number of the table's last change.
+    ///
+    /// Everything is read from the same snapshot of the table, so the changes
+    /// made after the returned number are exactly the ones not covered yet.
+    /// The returned flag tells if there are more threads.
+    pub fn get_snapshot(
+        client: &mut Client,
+        table: &str,
+        order: ThreadOrder,
+        limit: i64,
+        view: ClientView,
+    ) -> Result<(Vec<ThreadSummary>, bool, i64), db::Error> {
+        let mut transaction = client
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()?;
+
+        let mut threads: Vec<ThreadSummary> = query_and_map!(
+            transaction,
+            &format!(
+                "SELECT {columns},
+                    (SELECT COUNT(*) FROM {table} AS reply
+                    WHERE reply.reply_to = root.id AND reply.deleted_at IS NULL)
+                FROM {table} AS root WHERE root.reply_to IS NULL
+                ORDER BY {sort_key} DESC, root.id DESC LIMIT $1;",
+                columns = COLUMNS,
+                table = table,
+                sort_key = order.sort_key(table)
+            ),
+            &[&(limit + 1)],
+            |row: Row| {
+                let replies = row.get(column_count());
+                ThreadSummary {
+                    root: Message::from_row(row).prepare_for_client(view),
+                    replies,
+                }
+            }
+        )
+        .collect();
+        let last_change: i64 = query_one_row!(
+            transaction,
+            &format!("SELECT COALESCE(MAX(change_seq), 0) FROM {};", table),
+            &[],
+            |row: Row| row.get(0)
+        )?;
+        transaction.commit()?;
+
+        let has_more = threads.len() as i64 > limit;
+        threads.truncate(limit as usize);
+        Ok((threads, has_more, last_change))
+    }
+
     /// Adds a new message to a given table.
+    ///
+    /// If a cipher is given, the content is encrypted before being stored.
+    /// Otherwise, if `hash_content` is set, the hash of the content is stored
+    /// too, and if `compress` is set, the content is stored compressed.
+    /// Encrypted messages are never hashed, since equal hashes would reveal
+    /// which encrypted messages are identical.
+    ///
+    /// If a budget is given, the total size of the stored contents is kept
+    /// within it, as its policy says. The size of a message is the size of its
+    /// stored content, so encrypted messages take up more of the budget, and
+    /// compressed messages take up less.
+    ///
+    /// The message is numbered after the last message of the table. Since
+    /// the table is changed by one transaction at a time, the numbers have
+    /// no gaps.
+    ///
+    /// It returns the id and the timestamp of the new message, or `None`
+    /// if the message does not fit in the budget.
     pub fn add(
         client: &mut Client,
         table: &str,
+        message: NewMessage,
+        cipher: Option<&RoomCipher>,
+        hash_content: bool,
+        compress: bool,
+        budget: Option<RoomBudget>,
+    ) -> Result<Option<(i32, i64)>, db::Error> {
+        let timestamp = clock::now_millis();
+        let NewMessage {
+            content,
+            source,
+            author,
+            reply_to,
+            system,
+            client_timestamp,
+        } = message;
+        let stored = Message::encode(content, source, cipher, hash_content, compress);
+
+        let mut transaction = Message::change_transaction(client, table)?;
+        if let Some(budget) = budget {
+            let size = stored.size() as i64;
+            if !Message::make_room(&mut transaction, table, size, budget, timestamp, None)? {
+                return Ok(None);
+            }
+        }
+        let id: i32 = query_one_row!(
+            transaction,
+            &format!(
+                "INSERT INTO {table} (content, timestamp, author, reply_to, changed, change_seq,
+                    content_hash, system, compressed_content, seq, source, client_timestamp,
+                    posted_change)
+                SELECT $1, $2, $3, $4, $2, next.change, $5, $6, $7,
+                    (SELECT COALESCE(MAX(seq), 0) + 1 FROM {table}), $8, $9, next.change
+                FROM (SELECT nextval('message_changes') AS change) AS next
+                RETURNING id;",
+                table = table
+            ),
+            &[
+                &stored.content,
+                &timestamp,
+                &author,
+                &reply_to,
+                &stored.hash,
+                &system,
+                &stored.compressed,
+                &stored.source,
+                &client_timestamp,
+            ],
+            |row: Row| row.get(0)
+        )?;
+        transaction.commit().and(Ok(Some((id, timestamp))))
+    }
+
+    /// Returns the content of a message as it should be stored.
+    ///
+    /// The hash is computed from the uncompressed content, so identical
+    /// messages have the same hash, whether they were compressed or not.
+    /// The source, if it's given, is only encrypted.
+    fn encode(
         content: String,
-        author: String,
-        reply_to: Option<i32>,
-    ) -> Result<(), db::Error> {
-        let timestamp = Message::current_timestamp();
+        source: Option<String>,
+        cipher: Option<&RoomCipher>,
+        hash_content: bool,
+        compress: bool,
+    ) -> StoredContent {
+        if let Some(cipher) = cipher {
+            return StoredContent {
+                content: cipher.encrypt(&content),
+                compressed: None,
+                hash: None,
+                source: source.map(|source| cipher.encrypt(&source)),
+            };
+        }
+        let hash = hash_content.then(|| format!("{:x}", Sha256::digest(content.as_bytes())));
+        match compress.then(|| self::compress(&content)).flatten() {
+            Some(compressed) => StoredContent {
+                content: String::new(),
+                compressed: Some(compressed),
+                hash,
+                source,
+            },
+            None => StoredContent {
+                content,
+                compressed: None,
+                hash,
+                source,
+            },
+        }
+    }
+
+    /// Makes sure that a message of the given size fits in the budget of
+    /// a table, pruning the oldest messages if the budget allows it.
+    ///
+    /// It should be called in a change transaction, so the size of the table
+    /// does not change before the message is inserted. The message with the
+    /// id `keep`, if it's given, is never pruned.
+    /// It returns `false` if the message does not fit.
+    fn make_room(
+        transaction: &mut Transaction,
+        table: &str,
+        size: i64,
+        budget: RoomBudget,
+        now: i64,
+        keep: Option<i32>,
+    ) -> Result<bool, db::Error> {
+        if size > budget.max_bytes {
+            return Ok(false);
+        }
+        let used: i64 = query_one_row!(
+            transaction,
+            &format!(
+                "SELECT COALESCE(SUM({}), 0)::BIGINT FROM {};",
+                STORED_SIZE, table
+            ),
+            &[],
+            |row: Row| row.get(0)
+        )?;
+        let excess = used + size - budget.max_bytes;
+        if excess <= 0 {
+            return Ok(true);
+        }
+        if budget.policy == BudgetPolicy::Reject {
+            return Ok(false);
+        }
+
+        // Prune the oldest messages, until the pruned ones held enough bytes.
+        let pruned = transaction.execute(
+            &format!(
+                "WITH sized AS (
+                    SELECT id, {size} AS size, SUM({size}) OVER (ORDER BY id) AS total
+                    FROM {table} WHERE {size} > 0 AND id IS DISTINCT FROM $3
+                )
+                UPDATE {table} SET content = '', compressed_content = NULL, content_hash = NULL,
+                    source = NULL,
+                    deleted_at = COALESCE(deleted_at, $1), changed = $1,
+                    change_seq = nextval('message_changes')
+                FROM sized
+                WHERE {table}.id = sized.id AND sized.total - sized.size < $2;",
+                table = table,
+                size = STORED_SIZE
+            ),
+            &[&now, &excess, &keep],
+        )?;
+        eprintln!(
+            "Pruned {} messages of {} to stay within its budget.",
+            pruned, table
+        );
+        Ok(true)
+    }
+
+    /// Finds the message which started the thread of the given message,
+    /// by following the messages to which they reply.
+    ///
+    /// Threads should only have two levels, but the table does not prevent
+    /// replies from forming chains, or even cycles. At most `max_depth` replies
+    /// are followed, so cycles are detected instead of being followed forever.
+    ///
+    /// It returns `None` if there is no message with the given id.
+    pub fn thread_root(
+        client: &mut Client,
+        table: &str,
+        id: i32,
+        max_depth: usize,
+    ) -> Result<Option<i32>, TraversalError> {
+        let sql = format!("SELECT reply_to FROM {} WHERE id = $1;", table);
+        let mut current = id;
+        for _ in 0..=max_depth {
+            let reply_to: Option<i32> = match client.query_opt(&sql, &[&current])? {
+                Some(row) => row.get(0),
+                None => return Ok(None),
+            };
+            match reply_to {
+                Some(parent) => current = parent,
+                None => return Ok(Some(current)),
+            }
+        }
+        Err(TraversalError::TooDeep)
+    }
 
+    /// Counts the messages posted by the given author which are not deleted.
+    pub fn count_by_author(
+        client: &mut Client,
+        table: &str,
+        author: &str,
+    ) -> Result<i64, db::Error> {
+        query_one_row!(
+            client,
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE author = $1 AND deleted_at IS NULL;",
+                table
+            ),
+            &[&author],
+            |row: Row| row.get(0)
+        )
+    }
+
+    /// Records a notification for the author of a message, about a reply to it.
+    ///
+    /// Only authors whose sessions are still live are notified. Authors are
+    /// not notified of their own replies, nor of replies to deleted messages.
+    /// Each message counts the notifications sent about it, even after they
+    /// are retrieved, and once it reaches `max_notifications`, further
+    /// replies to it are not notified.
+    ///
+    /// It returns `true` if a notification was recorded.
+    pub fn notify_reply(
+        client: &mut Client,
+        table: &str,
+        room: &str,
+        message: i32,
+        reply: i32,
+        replier: Option<&str>,
+        max_notifications: usize,
+    ) -> Result<bool, db::Error> {
+        let max_notifications = i32::try_from(max_notifications).unwrap_or(i32::MAX);
         client
             .execute(
                 &format!(
-                "INSERT INTO {} (content, timestamp, author, reply_to) VALUES ($1, $2, $3, $4);",
+                    "WITH notified AS (
+                        UPDATE {} SET reply_notifications = reply_notifications + 1
+                        WHERE id = $4 AND author IN (SELECT id FROM sessions)
+                        AND author IS DISTINCT FROM $5 AND deleted_at IS NULL
+                        AND reply_notifications < $6
+                        RETURNING id, author
+                    )
+                    INSERT INTO notifications (session, room, message, reply, created)
+                    SELECT author, $1, id, $2, $3 FROM notified;",
+                    table
+                ),
+                &[
+                    &room,
+                    &reply,
+                    &clock::now_millis(),
+                    &message,
+                    &replier,
+                    &max_notifications,
+                ],
+            )
+            .map(|inserted| inserted == 1)
+    }
+
+    /// Marks a message as deleted, if it was posted by the given author.
+    ///
+    /// It returns `false` if there is no such message which is not already deleted.
+    pub fn delete(
+        client: &mut Client,
+        table: &str,
+        id: i32,
+        author: &str,
+    ) -> Result<bool, db::Error> {
+        let now = clock::now_millis();
+        let mut transaction = Message::change_transaction(client, table)?;
+        let updated = transaction.execute(
+            &format!(
+                "UPDATE {} SET deleted_at = $1, changed = $1, change_seq = nextval('message_changes')
+                WHERE id = $2 AND author = $3 AND deleted_at IS NULL;",
                 table
             ),
-                &[&content, &timestamp, &author, &reply_to],
-            )
-            .and(Ok(()))
-            .map_err(Into::into)
+            &[&now, &id, &author],
+        )?;
+        transaction.commit().and(Ok(updated == 1))
+    }
+
+    /// Replaces the content of a message which is not deleted.
+    ///
+    /// If the edit has an author, only their own messages can be edited, and
+    /// only within the edit's window. Otherwise, any message can be edited.
+    /// The content is encrypted, hashed or compressed like when the message was added.
+    ///
+    /// If the new content is larger than the old one, the difference has to
+    /// fit in the budget of the room, like a new message, but the edited
+    /// message itself is never pruned.
+    pub fn edit(
+        client: &mut Client,
+        table: &str,
+        id: i32,
+        edit: MessageEdit,
+        cipher: Option<&RoomCipher>,
+        hash_content: bool,
+        compress: bool,
+    ) -> Result<EditOutcome, db::Error> {
+        let now = clock::now_millis();
+        let stored = Message::encode(edit.content, edit.source, cipher, hash_content, compress);
+
+        let mut transaction = Message::change_transaction(client, table)?;
+        let found: Option<(i64, Option<String>, i64)> = query_and_map!(
+            transaction,
+            &format!(
+                "SELECT timestamp, author, {}::BIGINT FROM {} WHERE id = $1 AND deleted_at IS NULL;",
+                STORED_SIZE, table
+            ),
+            &[&id],
+            |row: Row| (row.get(0), row.get(1), row.get(2))
+        )
+        .next();
+        let (timestamp, author, old_size) = match found {
+            Some(found) => found,
+            None => return Ok(EditOutcome::NotFound),
+        };
+        if let Some(editor) = &edit.author {
+            if author.as_ref() != Some(editor) {
+                return Ok(EditOutcome::NotFound);
+            }
+            if edit.window > 0 && now - timestamp > edit.window {
+                return Ok(EditOutcome::WindowClosed);
+            }
+        }
+        if let Some(budget) = edit.budget {
+            let size = stored.size() as i64;
+            let growth = size - old_size;
+            if size > budget.max_bytes
+                || (growth > 0
+                    && !Message::make_room(&mut transaction, table, growth, budget, now, Some(id))?)
+            {
+                return Ok(EditOutcome::OverBudget);
+            }
+        }
+
+        transaction.execute(
+            &format!(
+                "UPDATE {} SET content = $1, content_hash = $2, edited_at = $3, changed = $3,
+                    change_seq = nextval('message_changes'), compressed_content = $5, source = $6
+                WHERE id = $4;",
+                table
+            ),
+            &[
+                &stored.content,
+                &stored.hash,
+                &now,
+                &id,
+                &stored.compressed,
+                &stored.source,
+            ],
+        )?;
+        transaction.commit().and(Ok(EditOutcome::Edited))
+    }
+
+    /// Undoes the deletion of a message, if it was posted by the given author.
+    ///
+    /// Deletions can only be undone at most `window` milliseconds after they
+    /// happen. It returns `false` if there is no such message which was
+    /// deleted recently enough, or if the message was pruned.
+    pub fn undelete(
+        client: &mut Client,
+        table: &str,
+        id: i32,
+        author: &str,
+        window: i64,
+    ) -> Result<bool, db::Error> {
+        let now = clock::now_millis();
+        let mut transaction = Message::change_transaction(client, table)?;
+        let updated = transaction.execute(
+            &format!(
+                "UPDATE {} SET deleted_at = NULL, changed = $1, change_seq = nextval('message_changes')
+                WHERE id = $2 AND author = $3 AND deleted_at >= $4 AND {} > 0;",
+                table, STORED_SIZE
+            ),
+            &[&now, &id, &author, &(now - window)],
+        )?;
+        transaction.commit().and(Ok(updated == 1))
     }
 
-    /// Returns the current timestamp, as it should be saved in the table.
+    /// Returns the stored source of a message which is not deleted.
     ///
-    /// Since the server might receive multiple messages quickly, timestamps
-    /// should have high enough precision.
-    pub fn current_timestamp() -> i64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Error while calculating the message timestamp")
-            .as_millis() as i64
+    /// If an author is given, only the sources of their own messages are
+    /// returned. It returns `None` if there is no such message, or if its
+    /// source was not stored. Encrypted sources are decrypted with the cipher.
+    pub fn get_source(
+        client: &mut Client,
+        table: &str,
+        id: i32,
+        author: Option<&str>,
+        cipher: Option<&RoomCipher>,
+    ) -> Result<Option<String>, db::Error> {
+        let found: Option<(Option<String>, Option<String>)> = query_and_map!(
+            client,
+            &format!(
+                "SELECT source, author FROM {} WHERE id = $1 AND deleted_at IS NULL;",
+                table
+            ),
+            &[&id],
+            |row: Row| (row.get(0), row.get(1))
+        )
+        .next();
+
+        let (source, owner) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        if author.is_some() && owner.as_deref() != author {
+            return Ok(None);
+        }
+        Ok(match cipher {
+            Some(cipher) => source.and_then(|source| cipher.decrypt(&source)),
+            None => source,
+        })
+    }
+}
+
+/// The content of a message, as it's stored in the table.
+struct StoredContent {
+    /// The content, unless it's compressed, in which case it's empty.
+    content: String,
+    /// The compressed content, if the content was compressed.
+    compressed: Option<Vec<u8>>,
+    /// The hash of the uncompressed content, if it's stored.
+    hash: Option<String>,
+    /// The source of the content, if it's stored.
+    source: Option<String>,
+}
+
+impl StoredContent {
+    /// Returns the number of bytes taken up by the stored content.
+    fn size(&self) -> usize {
+        self.content.len()
+            + self.compressed.as_ref().map_or(0, Vec::len)
+            + self.source.as_ref().map_or(0, String::len)
     }
 }
 
+/// A message which is about to be stored, after being prepared for storage.
+pub struct NewMessage {
+    pub content: String,
+    /// The content as it was written, before being prepared, if it should be stored.
+    pub source: Option<String>,
+    /// The id of the session which posted the message, if it should be stored.
+    pub author: Option<String>,
+    pub reply_to: Option<i32>,
+    /// Whether the message is posted by the server itself.
+    pub system: bool,
+    /// The time when the author wrote the message, as sent by the author.
+    pub client_timestamp: Option<i64>,
+}
+
+/// A new content for an existing message, after being prepared for storage.
+pub struct MessageEdit {
+    pub content: String,
+    /// The content as it was written, before being prepared, if it should be stored.
+    pub source: Option<String>,
+    /// The id of the session which edits the message,
+    /// or `None` if the message is edited by an admin.
+    pub author: Option<String>,
+    /// For how many milliseconds after posting a message its author can edit it.
+    /// Messages can always be edited if it's 0.
+    pub window: i64,
+    /// The cap on the total size of the messages of the room, if any.
+    pub budget: Option<RoomBudget>,
+}
+
+/// The ways in which an attempt to edit a message can end.
+#[derive(Debug)]
+pub enum EditOutcome {
+    /// The message was edited.
+    Edited,
+    /// There is no such message which is not deleted,
+    /// or it was not posted by the editor.
+    NotFound,
+    /// The message was posted too long ago to be edited by its author.
+    WindowClosed,
+    /// The new content does not fit in the budget of the room.
+    OverBudget,
+}
+
+/// The content of the JSON form through which users edit their messages.
+#[derive(Deserialize)]
+pub struct EditJson {
+    pub content: String,
+    #[serde(default)]
+    pub format: MessageFormat,
+}
+
 /// The content of the JSON form through which users send messages.
 #[derive(Deserialize)]
 pub struct MessageJson {
     pub content: String,
-    pub reply_to: Option<i32>,
+    pub reply_to: Option<MessageRef>,
+    #[serde(default)]
+    pub format: MessageFormat,
+    /// The time when the message was written, as a Unix timestamp in
+    /// milliseconds, for clients which post messages written offline.
+    #[serde(default)]
+    pub client_timestamp: Option<i64>,
+}
+
+impl JsonLimit for MessageJson {
+    const NAME: &'static str = "post";
+}
+
+/// A summary of the messages posted in an interval.
+#[derive(Serialize)]
+pub struct IntervalChecksum {
+    /// The number of messages.
+    pub count: usize,
+    /// The hash of the messages' ids, timestamps, and deletion states.
+    pub checksum: String,
+}
+
+/// The query of an update request.
+///
+/// Requests which ask for messages by their ids have both ids. Guests, whose
+/// progress is not kept by the server, send the cursor of their last updates.
+/// Normal update requests have none of these.
+#[derive(FromForm)]
+pub struct UpdateQuery {
+    /// The id of the first message requested.
+    pub from_id: Option<MessageRef>,
+    /// The id of the last message requested.
+    pub to_id: Option<MessageRef>,
+    /// The cursor returned by a guest's previous updates.
+    pub after: Option<i64>,
+}
+
+/// A thread, as it's summarized when a room is first loaded.
+#[derive(Serialize)]
+pub struct ThreadSummary {
+    /// The message which started the thread.
+    #[serde(flatten)]
+    pub root: Message,
+    /// The number of replies which are not deleted.
+    pub replies: i64,
+}
+
+/// A message rendered as it would be stored, sent to users who preview it.
+#[derive(Serialize)]
+pub struct MessagePreview {
+    /// The HTML of the message, if it would be accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    /// Tells users that some of their formatting would be removed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    /// A human-readable reason for why the message would be rejected, if it would be.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
 /// The content of the response sent to users upon an update request.
@@ -148,4 +1779,832 @@ pub struct MessageJson {
 pub struct Updates {
     pub clean_stored: bool,
     pub messages: Vec<Message>,
+    /// Tells the client that more messages are available,
+    /// and that it should request updates again.
+    pub has_more: bool,
+    /// The cursor which guests should send with their next request.
+    /// It's only sent to guests, since the server keeps the others' progress.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::testing::{set_clock, FakeClock};
+    use crate::sessions::Session;
+
+    /// Creates an empty table of messages.
+    fn test_table(client: &mut Client) -> String {
+        let table = db::testing::unique_name("messages_test");
+        Message::setup_table(client, &table).unwrap();
+        table
+    }
+
+    /// Adds a message to a table, returning its id.
+    fn add(client: &mut Client, table: &str, content: &str, budget: Option<RoomBudget>) -> i32 {
+        add_reply(client, table, content, None, budget)
+    }
+
+    /// Adds a message to a table, possibly as a reply, returning its id.
+    fn add_reply(
+        client: &mut Client,
+        table: &str,
+        content: &str,
+        reply_to: Option<i32>,
+        budget: Option<RoomBudget>,
+    ) -> i32 {
+        let message = NewMessage {
+            content: content.into(),
+            source: None,
+            system: true,
+            author: None,
+            reply_to,
+            client_timestamp: None,
+        };
+        Message::add(client, table, message, None, false, false, budget)
+            .unwrap()
+            .unwrap()
+            .0
+    }
+
+    /// Prunes a message, like the room budget does.
+    fn prune(client: &mut Client, table: &str, id: i32) {
+        client
+            .execute(
+                &format!(
+                    "UPDATE {} SET content = '', deleted_at = 1 WHERE id = $1;",
+                    table
+                ),
+                &[&id],
+            )
+            .unwrap();
+    }
+
+    /// Compacts a table, returning the number of removed messages.
+    fn compact(client: &mut Client, table: &str) -> u64 {
+        let mut transaction = Message::change_transaction(client, table).unwrap();
+        let (removed, _) = Message::compact(&mut transaction, table).unwrap();
+        transaction.commit().unwrap();
+        removed
+    }
+
+    /// Returns the id, the number, the replied message and the content of
+    /// each message of a table, in the order of their ids.
+    fn rows(client: &mut Client, table: &str) -> Vec<(i32, i64, Option<i32>, String)> {
+        let sql = format!(
+            "SELECT id, seq, reply_to, content FROM {} ORDER BY id;",
+            table
+        );
+        client
+            .query(&sql, &[])
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+            .collect()
+    }
+
+    /// Edits a message of a table, as an admin.
+    fn edit(
+        client: &mut Client,
+        table: &str,
+        id: i32,
+        content: &str,
+        budget: Option<RoomBudget>,
+    ) -> EditOutcome {
+        let edit = MessageEdit {
+            content: content.into(),
+            source: None,
+            author: None,
+            window: 0,
+            budget,
+        };
+        Message::edit(client, table, id, edit, None, false, false).unwrap()
+    }
+
+    /// Tries to add a message to a table, returning `None` if it does not fit the budget.
+    fn try_add(client: &mut Client, table: &str, content: &str, budget: RoomBudget) -> Option<i32> {
+        let message = NewMessage {
+            content: content.into(),
+            source: None,
+            system: true,
+            author: None,
+            reply_to: None,
+            client_timestamp: None,
+        };
+        Message::add(client, table, message, None, false, false, Some(budget))
+            .unwrap()
+            .map(|(id, _)| id)
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn messages_over_the_budget_are_rejected() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let budget = RoomBudget {
+            max_bytes: 10,
+            policy: BudgetPolicy::Reject,
+        };
+        assert!(try_add(&mut client, &table, "12345", budget).is_some());
+        assert!(try_add(&mut client, &table, "12345", budget).is_some());
+        assert_eq!(try_add(&mut client, &table, "1", budget), None);
+        assert_eq!(rows(&mut client, &table).len(), 2);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn messages_over_the_budget_prune_the_oldest() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let budget = RoomBudget {
+            max_bytes: 10,
+            policy: BudgetPolicy::Prune,
+        };
+        let first = add(&mut client, &table, "12345", Some(budget));
+        let second = add(&mut client, &table, "12345", Some(budget));
+        let third = try_add(&mut client, &table, "1", budget).unwrap();
+
+        let contents: Vec<_> = rows(&mut client, &table)
+            .into_iter()
+            .map(|(id, _, _, content)| (id, content))
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                (first, String::new()),
+                (second, "12345".into()),
+                (third, "1".into())
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn edits_which_do_not_fit_the_budget_are_refused() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let budget = RoomBudget {
+            max_bytes: 10,
+            policy: BudgetPolicy::Reject,
+        };
+        let id = add(&mut client, &table, "12345", Some(budget));
+
+        assert!(matches!(
+            edit(&mut client, &table, id, "1234567890", Some(budget)),
+            EditOutcome::Edited
+        ));
+        assert!(matches!(
+            edit(&mut client, &table, id, "12345678901", Some(budget)),
+            EditOutcome::OverBudget
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn edits_prune_other_messages_to_fit_the_budget() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let budget = RoomBudget {
+            max_bytes: 10,
+            policy: BudgetPolicy::Prune,
+        };
+        let first = add(&mut client, &table, "12345", Some(budget));
+        let second = add(&mut client, &table, "12345", Some(budget));
+
+        assert!(matches!(
+            edit(&mut client, &table, second, "1234567890", Some(budget)),
+            EditOutcome::Edited
+        ));
+        let messages =
+            Message::get_many(&mut client, &table, &[first, second], ClientView::default())
+                .unwrap();
+        let content = |id| {
+            messages
+                .iter()
+                .find(|m| m.id == id)
+                .unwrap()
+                .content
+                .clone()
+        };
+        assert!(content(first).is_empty());
+        assert_eq!(content(second), "1234567890");
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn new_and_edited_messages_change_the_interval_checksum() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let id = add(&mut client, &table, "Hello!", None);
+        let checksum = |client: &mut Client| {
+            Message::interval_checksum(client, &table, 0, i64::MAX)
+                .unwrap()
+                .checksum
+        };
+
+        let first = checksum(&mut client);
+        assert_eq!(checksum(&mut client), first);
+        add(&mut client, &table, "Hello there!", None);
+        let added = checksum(&mut client);
+        assert_ne!(added, first);
+        edit(&mut client, &table, id, "Hello again!", None);
+        assert_ne!(checksum(&mut client), added);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn compaction_keeps_the_reply_links_of_threads() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let first = add(&mut client, &table, "first", None);
+        let pruned = add(&mut client, &table, "", None);
+        let reply = add_reply(&mut client, &table, "reply", Some(first), None);
+        let pruned_reply = add_reply(&mut client, &table, "", Some(first), None);
+        add_reply(&mut client, &table, "nested", Some(reply), None);
+        add(&mut client, &table, "last", None);
+        prune(&mut client, &table, pruned);
+        prune(&mut client, &table, pruned_reply);
+
+        assert_eq!(compact(&mut client, &table), 2);
+        assert_eq!(
+            rows(&mut client, &table),
+            [
+                (1, 1, None, "first".into()),
+                (2, 2, Some(1), "reply".into()),
+                (3, 3, Some(2), "nested".into()),
+                (4, 4, None, "last".into()),
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn compaction_keeps_pruned_messages_with_replies() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        add(&mut client, &table, "", None);
+        let root = add(&mut client, &table, "", None);
+        add_reply(&mut client, &table, "reply", Some(root), None);
+        prune(&mut client, &table, 1);
+        prune(&mut client, &table, root);
+
+        assert_eq!(compact(&mut client, &table), 1);
+        assert_eq!(
+            rows(&mut client, &table),
+            [(1, 1, None, "".into()), (2, 2, Some(1), "reply".into())]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn compaction_removes_pruned_replies_with_their_pruned_roots() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let root = add(&mut client, &table, "", None);
+        let reply = add_reply(&mut client, &table, "", Some(root), None);
+        add(&mut client, &table, "kept", None);
+        prune(&mut client, &table, root);
+        prune(&mut client, &table, reply);
+
+        assert_eq!(compact(&mut client, &table), 2);
+        assert_eq!(rows(&mut client, &table), [(1, 1, None, "kept".into())]);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn messages_after_compaction_continue_the_ids() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let pruned = add(&mut client, &table, "", None);
+        let root = add(&mut client, &table, "root", None);
+        prune(&mut client, &table, pruned);
+        compact(&mut client, &table);
+
+        let reply = add_reply(&mut client, &table, "reply", Some(1), None);
+        assert!(root > 1);
+        assert_eq!(reply, 2);
+        assert_eq!(
+            rows(&mut client, &table),
+            [(1, 1, None, "root".into()), (2, 2, Some(1), "reply".into())]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn compacted_messages_reach_clients_as_updates() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        add(&mut client, &table, "first", None);
+        add(&mut client, &table, "second", None);
+
+        let mut transaction = Message::change_transaction(&mut client, &table).unwrap();
+        let (_, compacted) = Message::compact(&mut transaction, &table).unwrap();
+        transaction.commit().unwrap();
+
+        let sql = format!("SELECT MIN(change_seq) FROM {};", table);
+        let oldest: i64 = client.query_one(&sql, &[]).unwrap().get(0);
+        assert!(oldest > compacted);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn encrypted_messages_are_decrypted_for_clients() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let cipher = RoomCipher::new("secret", 1);
+        let message = NewMessage {
+            content: "<p>Hello</p>".into(),
+            source: None,
+            system: false,
+            author: None,
+            reply_to: None,
+            client_timestamp: None,
+        };
+        Message::add(
+            &mut client,
+            &table,
+            message,
+            Some(&cipher),
+            true,
+            true,
+            None,
+        )
+        .unwrap();
+
+        let sql = format!("SELECT content, content_hash FROM {};", table);
+        let row = client.query_one(&sql, &[]).unwrap();
+        let (stored, hash): (String, Option<String>) = (row.get(0), row.get(1));
+        assert_ne!(stored, "<p>Hello</p>");
+        assert_eq!(hash, None);
+
+        let view = ClientView {
+            cipher: Some(&cipher),
+            ..ClientView::default()
+        };
+        let (messages, _, _) =
+            Message::get_changed_after(&mut client, &table, 0, 10, view).unwrap();
+        assert_eq!(messages[0].content, "<p>Hello</p>");
+
+        let (messages, _, _) =
+            Message::get_changed_after(&mut client, &table, 0, 10, ClientView::default()).unwrap();
+        assert_eq!(messages[0].content, stored);
+    }
+
+    #[test]
+    fn compressed_contents_decompress_to_the_same_html() {
+        let html = "<p>Hello, world!</p>".repeat(MIN_COMPRESSED_LEN);
+        let compressed = compress(&html).unwrap();
+        assert!(compressed.len() < html.len());
+        assert_eq!(decompress(&compressed).as_deref(), Some(html.as_str()));
+        assert_eq!(decompress(b"not deflate"), None);
+    }
+
+    #[test]
+    fn short_contents_are_not_compressed() {
+        assert_eq!(compress("<p>Hello</p>"), None);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn compressed_messages_round_trip_through_storage() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let html = "<p>Hello, world!</p>".repeat(MIN_COMPRESSED_LEN);
+        for compress in [true, false] {
+            let message = NewMessage {
+                content: html.clone(),
+                source: None,
+                system: false,
+                author: None,
+                reply_to: None,
+                client_timestamp: None,
+            };
+            Message::add(&mut client, &table, message, None, true, compress, None).unwrap();
+        }
+
+        let sql = format!(
+            "SELECT content, compressed_content IS NOT NULL, content_hash FROM {} ORDER BY id;",
+            table
+        );
+        let rows = client.query(&sql, &[]).unwrap();
+        let (content, compressed): (String, bool) = (rows[0].get(0), rows[0].get(1));
+        assert!(content.is_empty() && compressed);
+        assert_eq!(rows[0].get::<_, String>(2), rows[1].get::<_, String>(2));
+
+        let (messages, _, _) =
+            Message::get_changed_after(&mut client, &table, 0, 10, ClientView::default()).unwrap();
+        assert_eq!(messages[0].content, html);
+        assert_eq!(messages[1].content, html);
+    }
+
+    #[test]
+    fn author_tokens_depend_on_the_thread_and_the_room() {
+        let tokens = AuthorTokens::new("secret", 1);
+        let token = tokens.token("author", 1);
+        assert_eq!(token.len(), 2 * AUTHOR_TOKEN_LEN);
+        assert_eq!(tokens.token("author", 1), token);
+        assert_ne!(tokens.token("author", 2), token);
+        assert_ne!(tokens.token("other", 1), token);
+        assert_ne!(AuthorTokens::new("secret", 2).token("author", 1), token);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn authors_get_one_token_per_thread() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let author = Session::start_for_tests(&mut client).id();
+        let mut post = |reply_to: Option<i32>| {
+            let message = NewMessage {
+                content: "<p>Hello</p>".into(),
+                source: None,
+                system: false,
+                author: Some(author.clone()),
+                reply_to,
+                client_timestamp: None,
+            };
+            Message::add(&mut client, &table, message, None, false, false, None)
+                .unwrap()
+                .unwrap()
+                .0
+        };
+        let root = post(None);
+        post(Some(root));
+        post(None);
+
+        let tokens = AuthorTokens::new("secret", 1);
+        let view = ClientView {
+            author_tokens: Some(&tokens),
+            ..ClientView::default()
+        };
+        let (messages, _, _) =
+            Message::get_changed_after(&mut client, &table, 0, 10, view).unwrap();
+        let tokens: Vec<_> = messages
+            .iter()
+            .map(|message| message.author_token.clone())
+            .collect();
+        assert!(tokens[0].is_some());
+        assert_eq!(tokens[0], tokens[1]);
+        assert_ne!(tokens[0], tokens[2]);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn deletions_can_be_undone_within_the_window() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let author = Session::start_for_tests(&mut client).id();
+        let clock = FakeClock::new(clock::now_millis());
+        set_clock(Box::new(clock.clone()));
+        let message = NewMessage {
+            content: "<p>Hello</p>".into(),
+            source: None,
+            system: false,
+            author: Some(author.clone()),
+            reply_to: None,
+            client_timestamp: None,
+        };
+        let (id, _) = Message::add(&mut client, &table, message, None, false, false, None)
+            .unwrap()
+            .unwrap();
+        let window = 10_000;
+
+        assert!(Message::delete(&mut client, &table, id, &author).unwrap());
+        clock.advance(window);
+        assert!(Message::undelete(&mut client, &table, id, &author, window).unwrap());
+        assert!(!Message::undelete(&mut client, &table, id, &author, window).unwrap());
+
+        assert!(Message::delete(&mut client, &table, id, &author).unwrap());
+        clock.advance(window + 1);
+        assert!(!Message::undelete(&mut client, &table, id, &author, window).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn authors_can_edit_within_the_window() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let author = Session::start_for_tests(&mut client).id();
+        let clock = FakeClock::new(clock::now_millis());
+        set_clock(Box::new(clock.clone()));
+        let message = NewMessage {
+            content: "<p>Hello</p>".into(),
+            source: None,
+            system: false,
+            author: Some(author.clone()),
+            reply_to: None,
+            client_timestamp: None,
+        };
+        let (id, _) = Message::add(&mut client, &table, message, None, false, false, None)
+            .unwrap()
+            .unwrap();
+        let window = 10_000;
+        let mut edit = |content: &str| {
+            let edit = MessageEdit {
+                content: content.into(),
+                source: None,
+                author: Some(author.clone()),
+                window,
+                budget: None,
+            };
+            Message::edit(&mut client, &table, id, edit, None, false, false).unwrap()
+        };
+
+        clock.advance(window);
+        assert!(matches!(edit("<p>Hello again</p>"), EditOutcome::Edited));
+        clock.advance(1);
+        assert!(matches!(edit("<p>Too late</p>"), EditOutcome::WindowClosed));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn bumped_threads_come_first_by_activity() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let clock = FakeClock::new(clock::now_millis());
+        set_clock(Box::new(clock.clone()));
+        let old = add(&mut client, &table, "old", None);
+        clock.advance(1000);
+        add(&mut client, &table, "new", None);
+        clock.advance(1000);
+        add_reply(&mut client, &table, "bump", Some(old), None);
+
+        let mut threads = |order| {
+            Message::get_threads(&mut client, &table, order, ClientView::default())
+                .unwrap()
+                .into_iter()
+                .map(|thread| thread.content)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(threads(ThreadOrder::Activity), ["old", "new"]);
+        assert_eq!(threads(ThreadOrder::Created), ["new", "old"]);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn identical_contents_have_the_same_hash() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        for (content, hash_content) in [("a", true), ("a", true), ("b", true), ("a", false)] {
+            let message = NewMessage {
+                content: content.into(),
+                source: None,
+                system: false,
+                author: None,
+                reply_to: None,
+                client_timestamp: None,
+            };
+            Message::add(
+                &mut client,
+                &table,
+                message,
+                None,
+                hash_content,
+                false,
+                None,
+            )
+            .unwrap();
+        }
+
+        let sql = format!("SELECT content_hash FROM {} ORDER BY id;", table);
+        let hashes: Vec<Option<String>> = client
+            .query(&sql, &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        let expected = format!("{:x}", Sha256::digest(b"a"));
+        assert_eq!(hashes[0].as_ref(), Some(&expected));
+        assert_eq!(hashes[1], hashes[0]);
+        assert!(hashes[2].is_some() && hashes[2] != hashes[0]);
+        assert_eq!(hashes[3], None);
+    }
+
+    /// Prepares a message for storage with the default options.
+    fn prepare(content: &str, format: MessageFormat) -> Result<String, Rejection> {
+        let mut message = content.to_owned();
+        prepare_for_storage(&mut message, format, None, &StorageOptions::default()).and(Ok(message))
+    }
+
+    #[test]
+    fn plain_messages_are_shown_literally() {
+        let content = "# Not a heading\n*not emphasis* <b>not bold</b>\n\nA paragraph";
+        assert_eq!(
+            prepare(content, MessageFormat::Plain).unwrap(),
+            "<p># Not a heading<br>*not emphasis* &lt;b&gt;not bold&lt;/b&gt;</p>\n<p>A paragraph</p>\n"
+        );
+        assert_eq!(
+            prepare(content, MessageFormat::Markdown).unwrap(),
+            "<h1>Not a heading</h1>\n<p><em>not emphasis</em> <b>not bold</b></p>\n<p>A paragraph</p>\n"
+        );
+        for content in ["a\r\n\r\nb", "a\n \nb"] {
+            assert_eq!(
+                prepare(content, MessageFormat::Plain).unwrap(),
+                "<p>a</p>\n<p>b</p>\n"
+            );
+        }
+    }
+
+    #[test]
+    fn public_ids_decode_to_their_ids_without_showing_the_order() {
+        let ids = PublicIds::new("secret", 1);
+        let public: Vec<_> = (1..=1000).map(|id| ids.encode(id)).collect();
+        for (id, public) in (1..=1000).zip(&public) {
+            assert_eq!(ids.decode(public), Some(id));
+        }
+
+        let mut sorted = public.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), public.len());
+        assert_ne!(sorted, public);
+
+        // Other rooms give the same messages other public ids.
+        assert_ne!(PublicIds::new("secret", 2).encode(1), public[0]);
+        assert_eq!(ids.decode("not an id"), None);
+    }
+
+    #[test]
+    fn blank_lines_are_collapsed_outside_code_blocks() {
+        let message = "Hello   \n\n\n\n\nthere\t\n\n```\na  \n\n\n\nb\n```\n\n\n\nend  \n\n";
+        assert_eq!(
+            collapse_blank_lines(message, 1),
+            "Hello\n\nthere\n\n```\na  \n\n\n\nb\n```\n\nend"
+        );
+
+        // Fences can be indented, like inside list items.
+        let message = "- item\n\n    ```\n    a  \n\n\n\n  b\n    ```\n\n\n\n- next";
+        assert_eq!(
+            collapse_blank_lines(message, 1),
+            "- item\n\n    ```\n    a  \n\n\n\n  b\n    ```\n\n- next"
+        );
+    }
+
+    #[test]
+    fn messages_of_only_links_are_refused_if_enabled() {
+        let options = StorageOptions {
+            reject_only_links: true,
+            ..StorageOptions::default()
+        };
+        let refusing = |content: &str| {
+            let mut message = content.to_owned();
+            prepare_for_storage(&mut message, MessageFormat::Markdown, None, &options)
+        };
+        for content in ["<https://example.com>", "[a site](https://example.com)"] {
+            assert!(matches!(refusing(content), Err(Rejection::OnlyLinks)));
+            assert!(prepare(content, MessageFormat::Markdown).is_ok());
+        }
+        assert!(refusing("Have a look at [this site](https://example.com).").is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn missing_ids_are_skipped_when_getting_many_messages() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let ids: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|content| add(&mut client, &table, content, None))
+            .collect();
+
+        let wanted = [ids[2], 1000, ids[0], -1, ids[2]];
+        let messages =
+            Message::get_many(&mut client, &table, &wanted, ClientView::default()).unwrap();
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["a", "c"]);
+        assert!(
+            Message::get_many(&mut client, &table, &[], ClientView::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn backlogs_are_delivered_in_chunks() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        for i in 0..5 {
+            add(&mut client, &table, &i.to_string(), None);
+        }
+
+        let mut chunks = Vec::new();
+        let mut after = 0;
+        loop {
+            let (messages, end, has_more) =
+                Message::get_changed_after(&mut client, &table, after, 2, ClientView::default())
+                    .unwrap();
+            chunks.push(messages.into_iter().map(|m| m.content).collect::<Vec<_>>());
+            after = end;
+            if !has_more {
+                break;
+            }
+        }
+        assert_eq!(chunks, [vec!["0", "1"], vec!["2", "3"], vec!["4"]]);
+
+        let (messages, end, has_more) =
+            Message::get_changed_after(&mut client, &table, after, 2, ClientView::default())
+                .unwrap();
+        assert!(messages.is_empty() && !has_more);
+        assert_eq!(end, after);
+    }
+
+    #[test]
+    fn messages_left_empty_by_the_sanitizer_are_refused() {
+        for content in ["<script>alert(1)</script>", "   \n\n  ", "<div>  </div>"] {
+            assert!(matches!(
+                prepare(content, MessageFormat::Markdown),
+                Err(Rejection::Empty)
+            ));
+        }
+        assert!(matches!(
+            prepare(" \n\n\t", MessageFormat::Plain),
+            Err(Rejection::Empty)
+        ));
+        assert!(prepare("<img src=\"/cat.png\">", MessageFormat::Markdown).is_ok());
+        assert!(prepare("<b>hi</b>", MessageFormat::Markdown).is_ok());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn threads_are_paged_with_the_root_first() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let root = add(&mut client, &table, "root", None);
+        add(&mut client, &table, "other", None);
+        for i in 1..=5 {
+            add_reply(
+                &mut client,
+                &table,
+                &format!("reply {}", i),
+                Some(root),
+                None,
+            );
+        }
+
+        let mut page = |after| {
+            Message::get_thread(&mut client, &table, root, after, 2, ClientView::default())
+                .unwrap()
+                .into_iter()
+                .map(|message| (message.id, message.content))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            page(None),
+            [
+                (1, "root".into()),
+                (3, "reply 1".into()),
+                (4, "reply 2".into())
+            ]
+        );
+        assert_eq!(
+            page(Some(4)),
+            [(5, "reply 3".into()), (6, "reply 4".into())]
+        );
+        assert_eq!(page(Some(6)), [(7, "reply 5".into())]);
+        assert!(page(Some(7)).is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn cyclic_replies_are_not_followed_forever() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let table = test_table(&mut client);
+        let first = add(&mut client, &table, "first", None);
+        let second = add_reply(&mut client, &table, "second", Some(first), None);
+        let third = add_reply(&mut client, &table, "third", Some(second), None);
+        assert!(matches!(
+            Message::thread_root(&mut client, &table, third, 10),
+            Ok(Some(root)) if root == first
+        ));
+
+        let sql = format!("UPDATE {} SET reply_to = $1 WHERE id = $2;", table);
+        client.execute(&sql, &[&third, &first]).unwrap();
+        assert!(matches!(
+            Message::thread_root(&mut client, &table, third, 10),
+            Err(TraversalError::TooDeep)
+        ));
+    }
 }
@@ -0,0 +1,132 @@
+//! Module for collecting metrics about the server.
+//!
+//! Operators can monitor the server by scraping the metrics, which are
+//! exported in the text format used by [Prometheus](https://prometheus.io).
+//!
+//! Counters are kept in memory, so they start from zero whenever the server
+//! starts. They are updated at the relevant places in the code, through the
+//! `Metrics` managed state. Gauges, such as the number of active rooms,
+//! are computed when the metrics are exported.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::{Build, Data, Request, Rocket};
+
+/// The counters maintained by the server.
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    messages_posted: AtomicU64,
+    sessions_created: AtomicU64,
+    sessions_expired: AtomicU64,
+    db_errors: AtomicU64,
+}
+
+/// A handle to the server's metrics, which can be shared between threads.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+impl Metrics {
+    /// Records that a request was received.
+    pub fn request_received(&self) {
+        self.0.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a message was posted.
+    pub fn message_posted(&self) {
+        self.0.messages_posted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a new session was started.
+    pub fn session_created(&self) {
+        self.0.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that some sessions expired.
+    pub fn sessions_expired(&self, count: u64) {
+        self.0.sessions_expired.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records that an operation on the database failed.
+    pub fn db_error(&self) {
+        self.0.db_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Exports the metrics in the Prometheus text format.
+    ///
+    /// The number of active rooms is not tracked by the counters,
+    /// so it should be given by the caller.
+    pub fn export(&self, active_rooms: usize) -> String {
+        let counters = [
+            (
+                "requests_total",
+                "The number of requests received.",
+                &self.0.requests,
+            ),
+            (
+                "messages_posted_total",
+                "The number of messages posted.",
+                &self.0.messages_posted,
+            ),
+            (
+                "sessions_created_total",
+                "The number of sessions started.",
+                &self.0.sessions_created,
+            ),
+            (
+                "sessions_expired_total",
+                "The number of sessions removed after being inactive.",
+                &self.0.sessions_expired,
+            ),
+            (
+                "db_errors_total",
+                "The number of failed database operations.",
+                &self.0.db_errors,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed);
+            // Writing to a string cannot fail.
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} counter", name);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+
+        let _ = writeln!(text, "# HELP active_rooms The number of existing rooms.");
+        let _ = writeln!(text, "# TYPE active_rooms gauge");
+        let _ = writeln!(text, "active_rooms {}", active_rooms);
+        text
+    }
+}
+
+/// A fairing which makes the metrics available as managed state,
+/// and counts the requests received by the server.
+///
+/// It should be attached before other fairings which update the metrics.
+#[derive(Default)]
+pub struct MetricsFairing;
+
+#[rocket::async_trait]
+impl Fairing for MetricsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Metrics Fairing",
+            kind: Kind::Ignite | Kind::Request,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        Ok(rocket.manage(Metrics::default()))
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if let Some(metrics) = req.rocket().state::<Metrics>() {
+            metrics.request_received();
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Module for sending messages as newline-delimited JSON.
+//!
+//! Programs which process messages one at a time, like shell tools or
+//! streaming parsers, can ask for NDJSON through the `Accept` header, by
+//! preferring `application/x-ndjson`. Such responses contain one message per
+//! line, as a separate JSON object, instead of a single JSON value. Other
+//! clients keep receiving the normal JSON responses.
+//!
+//! The fields of updates which are not messages are sent as headers instead.
+
+use std::io::Cursor;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::serde_json;
+use serde::Serialize;
+
+use crate::compression::CompressedJson;
+use crate::messages::Updates;
+
+/// Returns the content type of NDJSON responses.
+pub fn content_type() -> ContentType {
+    ContentType::new("application", "x-ndjson")
+}
+
+/// Checks if the client which sent a request prefers NDJSON responses.
+fn accepts_ndjson(req: &Request<'_>) -> bool {
+    req.accept().is_some_and(|accept| {
+        let preferred = accept.preferred();
+        preferred.top() == "application" && preferred.sub() == "x-ndjson"
+    })
+}
+
+/// A request guard which tells if the client prefers NDJSON responses.
+pub struct AcceptsNdjson(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptsNdjson {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(AcceptsNdjson(accepts_ndjson(req)))
+    }
+}
+
+/// Writes a value as a line of NDJSON.
+pub fn line<T: Serialize>(value: &T) -> serde_json::Result<String> {
+    serde_json::to_string(value).map(|mut json| {
+        json.push('\n');
+        json
+    })
+}
+
+/// Updates sent in the format which the client prefers.
+///
+/// As NDJSON, each message is sent on its own line, and the other fields are
+/// sent in the `X-Clean-Stored`, `X-Has-More` and `X-Cursor` headers.
+/// Otherwise, they are sent as a (possibly compressed) JSON object.
+pub struct NegotiatedUpdates(pub Updates);
+
+impl<'r> Responder<'r, 'static> for NegotiatedUpdates {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let updates = self.0;
+        if !accepts_ndjson(req) {
+            return Response::build_from(CompressedJson(updates).respond_to(req)?)
+                .raw_header_adjoin("Vary", "Accept")
+                .ok();
+        }
+
+        let mut body = String::new();
+        for message in &updates.messages {
+            body.push_str(&line(message).map_err(|_| Status::InternalServerError)?);
+        }
+
+        let mut response = Response::build();
+        response
+            .header(content_type())
+            .raw_header("Vary", "Accept")
+            .header(Header::new(
+                "X-Clean-Stored",
+                updates.clean_stored.to_string(),
+            ))
+            .header(Header::new("X-Has-More", updates.has_more.to_string()));
+        if let Some(cursor) = updates.cursor {
+            response.header(Header::new("X-Cursor", cursor.to_string()));
+        }
+        response.sized_body(body.len(), Cursor::new(body)).ok()
+    }
+}
@@ -0,0 +1,209 @@
+//! Module for limiting how often clients poll for the updates of rooms.
+//!
+//! Clients which poll for updates in a tight loop, because they misbehave or
+//! are buggy, query the database needlessly. If the `min_update_interval_ms`
+//! setting is configured, clients which poll a room again too soon receive a
+//! `429 Too Many Requests` response instead, with a `Retry-After` header.
+//!
+//! Clients are identified by their session cookies, or by their IP addresses
+//! if they have no sessions. The times of the polls are only kept in memory.
+//!
+//! Clients which missed many messages receive them in chunks, asking for the
+//! next chunk as soon as they receive one which says it has more. Such polls
+//! continue the previous one, so they are not limited.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::{Header, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::{catch, State};
+
+use crate::client_ip::client_ip;
+use crate::config::ForumConfig;
+use crate::sessions;
+
+/// How often (in seconds) the polls which are too old to matter are forgotten.
+const PRUNE_PERIOD_SECS: u64 = 60;
+
+/// The times of the last polls of clients.
+struct Polls {
+    /// The time of the last poll of each client to each room.
+    last: HashMap<(String, String), Instant>,
+    /// The time when old polls were last forgotten.
+    pruned: Instant,
+}
+
+/// Keeps track of the polls made by clients, as managed state.
+pub struct PollLimiter(Mutex<Polls>);
+
+impl Default for PollLimiter {
+    fn default() -> Self {
+        Self(Mutex::new(Polls {
+            last: HashMap::new(),
+            pruned: Instant::now(),
+        }))
+    }
+}
+
+impl PollLimiter {
+    /// Records a poll of a client to a room, unless it comes less than
+    /// `interval` after the previous one.
+    ///
+    /// If the poll comes too soon, it's not recorded, and the duration
+    /// which the client should wait is returned.
+    pub fn check(&self, client: &str, room: &str, interval: Duration) -> Result<(), Duration> {
+        let mut polls = match self.0.lock() {
+            Ok(polls) => polls,
+            Err(_) => return Ok(()),
+        };
+        let now = Instant::now();
+
+        // Forget old polls every once in a while, so the map does not keep growing.
+        if now.duration_since(polls.pruned) >= Duration::from_secs(PRUNE_PERIOD_SECS) {
+            polls
+                .last
+                .retain(|_, last| now.duration_since(*last) < interval);
+            polls.pruned = now;
+        }
+
+        let key = (client.to_owned(), room.to_owned());
+        if let Some(last) = polls.last.get(&key) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < interval {
+                return Err(interval - elapsed);
+            }
+        }
+        polls.last.insert(key, now);
+        Ok(())
+    }
+
+    /// Forgets the last poll of a client to a room, so its next poll is
+    /// allowed right away.
+    pub fn forget(&self, client: &str, room: &str) {
+        if let Ok(mut polls) = self.0.lock() {
+            polls.last.remove(&(client.to_owned(), room.to_owned()));
+        }
+    }
+}
+
+/// The duration which a throttled client should wait, kept in the request's
+/// local cache for the catcher.
+struct RetryAfter(Option<Duration>);
+
+/// A request guard which allows a client to poll for the updates of a room.
+///
+/// It fails with `429 Too Many Requests` if the client polled the room too
+/// recently. Requests for ranges of messages, which clients send to fill gaps,
+/// are not polls, so they are not limited. The guard also holds the maximum
+/// number of messages sent per update.
+pub struct PollPermit<'r> {
+    pub limit: usize,
+    /// The limiter which recorded the poll, with the client and the room.
+    poll: Option<(&'r PollLimiter, String, &'r str)>,
+}
+
+impl PollPermit<'_> {
+    /// Allows the client to ask for the next chunk of updates right away,
+    /// since the updates it received say that there are more.
+    pub fn allow_continuation(&self) {
+        if let Some((limiter, client, room)) = &self.poll {
+            limiter.forget(client, room);
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PollPermit<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = try_outcome!(req.guard::<&State<ForumConfig>>().await);
+        let mut permit = PollPermit {
+            limit: config.max_messages_per_update,
+            poll: None,
+        };
+        if config.min_update_interval_ms == 0 || req.query_value::<&str>("from_id").is_some() {
+            return request::Outcome::Success(permit);
+        }
+
+        let limiter = try_outcome!(req.guard::<&State<PollLimiter>>().await);
+        let client =
+            sessions::session_cookie(req).or_else(|| client_ip(req).map(|ip| ip.to_string()));
+        let room = req.uri().path().segments().get(1).unwrap_or_default();
+        let (client, room) = match client {
+            Some(client) => (client, room),
+            None => return request::Outcome::Success(permit),
+        };
+
+        let interval = Duration::from_millis(config.min_update_interval_ms);
+        match limiter.check(&client, room, interval) {
+            Ok(()) => {
+                permit.poll = Some((limiter.inner(), client, room));
+                request::Outcome::Success(permit)
+            }
+            Err(wait) => {
+                req.local_cache(|| RetryAfter(Some(wait)));
+                request::Outcome::Error((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+/// A response which tells a client to wait before sending another request.
+pub struct TooManyRequests(Option<Duration>);
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response =
+            Response::build_from("Please wait before polling again.".respond_to(req)?);
+        response.status(Status::TooManyRequests);
+        if let Some(wait) = self.0 {
+            // Clients should not retry before the interval ends, so round up.
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response.header(Header::new("Retry-After", secs.to_string()));
+        }
+        response.ok()
+    }
+}
+
+/// A catcher for clients which poll too often, which tells them how long to wait.
+#[catch(429)]
+pub fn too_many_requests(req: &Request<'_>) -> TooManyRequests {
+    TooManyRequests(req.local_cache(|| RetryAfter(None)).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn polls_which_come_too_soon_are_refused() {
+        let limiter = PollLimiter::default();
+        assert!(limiter.check("client", "room", INTERVAL).is_ok());
+        let wait = limiter.check("client", "room", INTERVAL).unwrap_err();
+        assert!(wait <= INTERVAL);
+    }
+
+    #[test]
+    fn polls_are_limited_per_client_and_room() {
+        let limiter = PollLimiter::default();
+        assert!(limiter.check("client", "room", INTERVAL).is_ok());
+        assert!(limiter.check("client", "other", INTERVAL).is_ok());
+        assert!(limiter.check("other", "room", INTERVAL).is_ok());
+    }
+
+    #[test]
+    fn forgotten_polls_allow_the_next_one() {
+        let limiter = PollLimiter::default();
+        assert!(limiter.check("client", "room", INTERVAL).is_ok());
+        limiter.forget("client", "room");
+        assert!(limiter.check("client", "room", INTERVAL).is_ok());
+        assert!(limiter.check("client", "room", INTERVAL).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Module for limiting the number of requests made by each client.
+//!
+//! Apart from the limits of specific endpoints, the server can cap the rate of
+//! all the requests coming from the same IP address, to mitigate crude floods.
+//! Each address has a token bucket, which holds at most `max_requests` tokens
+//! and is refilled at a rate of `max_requests` per `window_secs` seconds. Each
+//! request takes a token, and requests which find the bucket empty receive a
+//! `429 Too Many Requests` response, with a `Retry-After` header.
+//!
+//! Fairings cannot stop requests from reaching their handlers, so throttled
+//! requests are sent to a path which matches no route instead, and their
+//! responses are replaced. Handlers never run for them.
+//!
+//! The limit is disabled unless `max_requests` is set in the `rate_limit`
+//! section of the configuration. Requests whose paths start with one of the
+//! `exempt` prefixes, like those of static files, are never limited. The
+//! buckets are only kept in memory.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Status};
+use rocket::{Data, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::client_ip::client_ip;
+use crate::config::ForumConfig;
+
+/// The number of independently locked parts of the limiter, so requests from
+/// different clients rarely wait for each other.
+const SHARDS: usize = 16;
+
+/// How often (in seconds) the buckets which are full again are forgotten.
+const PRUNE_PERIOD_SECS: u64 = 60;
+
+/// The path to which throttled requests are sent. No route should match it.
+const THROTTLED_PATH: &str = "/rate_limited";
+
+/// The settings of the rate limiter.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimit {
+    /// The number of requests which a client can make in each window.
+    /// Requests are not limited if it's 0.
+    pub max_requests: u32,
+    /// The duration (in seconds) of the window.
+    pub window_secs: u64,
+    /// The prefixes of the paths whose requests are not limited.
+    pub exempt: Vec<String>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            max_requests: 0,
+            window_secs: 60,
+            exempt: vec!["/static/".into()],
+        }
+    }
+}
+
+impl RateLimit {
+    /// Checks if requests to the given path are limited.
+    fn applies_to(&self, path: &str) -> bool {
+        self.max_requests > 0 && !self.exempt.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// The tokens left to a client, as of the last time they were counted.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A part of the limiter, which holds the buckets of some of the clients.
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// The time when full buckets were last forgotten.
+    pruned: Instant,
+}
+
+/// Keeps track of the requests made by clients, as managed state.
+pub struct RateLimiter(Vec<Mutex<Shard>>);
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self(
+            (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        buckets: HashMap::new(),
+                        pruned: Instant::now(),
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+impl RateLimiter {
+    /// Takes a token from the bucket of a client.
+    ///
+    /// If the bucket is empty, the duration after which the client
+    /// gets a new token is returned.
+    pub fn check(&self, client: IpAddr, limit: &RateLimit) -> Result<(), Duration> {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        let mut shard = match self.0[hasher.finish() as usize % SHARDS].lock() {
+            Ok(shard) => shard,
+            Err(_) => return Ok(()),
+        };
+        let now = Instant::now();
+        let capacity = f64::from(limit.max_requests);
+        let per_sec = capacity / limit.window_secs as f64;
+
+        // Buckets which were refilled completely are the same as new buckets,
+        // so they are forgotten every once in a while.
+        if now.duration_since(shard.pruned) >= Duration::from_secs(PRUNE_PERIOD_SECS) {
+            let window = Duration::from_secs(limit.window_secs);
+            shard
+                .buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated) < window);
+            shard.pruned = now;
+        }
+
+        let bucket = shard.buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// The duration which a throttled client should wait, kept in the request's local cache.
+struct Throttled(Option<Duration>);
+
+/// A fairing which throttles the clients which make too many requests.
+///
+/// It should be attached after the `ConfigFairing`, and the `RateLimiter`
+/// should be managed.
+#[derive(Default)]
+pub struct RateLimitFairing;
+
+#[rocket::async_trait]
+impl Fairing for RateLimitFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limit Fairing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let limit = match req.rocket().state::<ForumConfig>() {
+            Some(config) if config.rate_limit.applies_to(req.uri().path().as_str()) => {
+                &config.rate_limit
+            }
+            _ => return,
+        };
+        let (limiter, client) = match (req.rocket().state::<RateLimiter>(), client_ip(req)) {
+            (Some(limiter), Some(client)) => (limiter, client),
+            _ => return,
+        };
+
+        if let Err(wait) = limiter.check(client, limit) {
+            req.local_cache(|| Throttled(Some(wait)));
+            req.set_uri(Origin::parse(THROTTLED_PATH).expect("The path should be valid."));
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let wait = match req.local_cache(|| Throttled(None)).0 {
+            Some(wait) => wait,
+            None => return,
+        };
+
+        let body = "Too many requests. Please try again later.";
+        res.set_status(Status::TooManyRequests);
+        res.set_header(ContentType::Plain);
+        res.set_sized_body(body.len(), Cursor::new(body));
+        // Clients should not retry before they get a new token, so round up.
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        res.set_header(Header::new("Retry-After", secs.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    fn limit() -> RateLimit {
+        RateLimit {
+            max_requests: 3,
+            window_secs: 60,
+            ..RateLimit::default()
+        }
+    }
+
+    /// Makes the bucket of a client look as if it was last counted earlier.
+    fn rewind(limiter: &RateLimiter, client: IpAddr, by: Duration) {
+        for shard in &limiter.0 {
+            if let Some(bucket) = shard.lock().unwrap().buckets.get_mut(&client) {
+                bucket.updated -= by;
+            }
+        }
+    }
+
+    #[test]
+    fn clients_over_the_rate_are_throttled_until_the_window_passes() {
+        let limiter = RateLimiter::default();
+        for _ in 0..3 {
+            assert!(limiter.check(CLIENT, &limit()).is_ok());
+        }
+        let wait = limiter.check(CLIENT, &limit()).unwrap_err();
+        assert!(wait <= Duration::from_secs(20));
+        assert!(limiter.check(OTHER, &limit()).is_ok());
+
+        rewind(&limiter, CLIENT, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(limiter.check(CLIENT, &limit()).is_ok());
+        }
+        assert!(limiter.check(CLIENT, &limit()).is_err());
+    }
+
+    #[test]
+    fn exempt_paths_are_not_limited() {
+        assert!(limit().applies_to("/room/lobby"));
+        assert!(!limit().applies_to("/static/style.css"));
+        assert!(!RateLimit::default().applies_to("/room/lobby"));
+    }
+}
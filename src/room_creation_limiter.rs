@@ -0,0 +1,134 @@
+//! Module for limiting how quickly admins create rooms.
+//!
+//! Each room gets its own table, so an admin account which creates rooms in a
+//! quick burst, whether compromised or careless, can bloat the database. If
+//! `max_rooms` is set in the `room_creation_limit` section of the
+//! configuration, each admin can create at most that many rooms in each
+//! window of `window_secs` seconds.
+//!
+//! Admins are identified by their usernames, or by their sessions if their
+//! usernames are not known. The creation times are only kept in memory.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// The settings of the room creation limiter.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RoomCreationLimit {
+    /// The number of rooms which an admin can create in each window.
+    /// Room creation is not limited if it's 0.
+    pub max_rooms: usize,
+    /// The duration (in seconds) of the window.
+    pub window_secs: u64,
+}
+
+impl Default for RoomCreationLimit {
+    fn default() -> Self {
+        Self {
+            max_rooms: 0,
+            window_secs: 3600,
+        }
+    }
+}
+
+/// Keeps track of the rooms created recently by each admin, as managed state.
+#[derive(Default)]
+pub struct RoomCreationLimiter(Mutex<HashMap<String, VecDeque<Instant>>>);
+
+impl RoomCreationLimiter {
+    /// Checks if an admin can create another room in the current window.
+    ///
+    /// If the room cannot be created, the duration after which the admin
+    /// can create another room is returned. Rooms are only counted once
+    /// they are recorded, with `record`.
+    pub fn check(&self, admin: &str, limit: &RoomCreationLimit) -> Result<(), Duration> {
+        if limit.max_rooms == 0 {
+            return Ok(());
+        }
+        let mut all = match self.0.lock() {
+            Ok(all) => all,
+            Err(_) => return Ok(()),
+        };
+        let now = Instant::now();
+        let window = Duration::from_secs(limit.window_secs);
+        Self::forget_old(&mut all, now, window);
+
+        match all.get(admin) {
+            Some(created) if created.len() >= limit.max_rooms => {
+                // Another room can be created once the oldest creation leaves the window.
+                let oldest = created.front().copied().unwrap_or(now);
+                Err(window.saturating_sub(now.duration_since(oldest)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that an admin created a room.
+    pub fn record(&self, admin: &str, limit: &RoomCreationLimit) {
+        if limit.max_rooms == 0 {
+            return;
+        }
+        if let Ok(mut all) = self.0.lock() {
+            all.entry(admin.to_owned())
+                .or_default()
+                .push_back(Instant::now());
+        }
+    }
+
+    /// Forgets the creations which left the window, and the admins left
+    /// without any, so the map does not keep growing.
+    fn forget_old(all: &mut HashMap<String, VecDeque<Instant>>, now: Instant, window: Duration) {
+        all.retain(|_, created| {
+            while created
+                .front()
+                .is_some_and(|first| now.duration_since(*first) >= window)
+            {
+                created.pop_front();
+            }
+            !created.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(max_rooms: usize) -> RoomCreationLimit {
+        RoomCreationLimit {
+            max_rooms,
+            window_secs: 60,
+        }
+    }
+
+    #[test]
+    fn checks_do_not_use_up_the_limit() {
+        let limiter = RoomCreationLimiter::default();
+        for _ in 0..3 {
+            assert!(limiter.check("alice", &limit(1)).is_ok());
+        }
+    }
+
+    #[test]
+    fn recorded_rooms_use_up_the_limit() {
+        let limiter = RoomCreationLimiter::default();
+        limiter.record("alice", &limit(2));
+        assert!(limiter.check("alice", &limit(2)).is_ok());
+        limiter.record("alice", &limit(2));
+
+        let wait = limiter.check("alice", &limit(2)).unwrap_err();
+        assert!(wait <= Duration::from_secs(60));
+        assert!(limiter.check("bob", &limit(2)).is_ok());
+    }
+
+    #[test]
+    fn unlimited_creation_is_not_recorded() {
+        let limiter = RoomCreationLimiter::default();
+        limiter.record("alice", &limit(0));
+        assert!(limiter.0.lock().unwrap().is_empty());
+    }
+}
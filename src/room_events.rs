@@ -0,0 +1,69 @@
+//! Module for telling admins when rooms are created or removed.
+//!
+//! The admin pane lists the active rooms. Instead of asking for the list again
+//! and again, it can subscribe to a stream of server-sent events, which says
+//! when a room is created, deleted, or archived. The stream is disabled unless
+//! the `room_event_stream` setting is enabled.
+//!
+//! Events are only kept in memory, for as long as it takes to send them to the
+//! subscribers. Subscribers which fall too far behind miss some events, so
+//! they receive a `resync` event instead, after which they should ask for the
+//! list of rooms again.
+
+use rocket::tokio::sync::broadcast::{self, Receiver, Sender};
+use serde::Serialize;
+
+/// The number of events kept for subscribers which have not received them yet.
+const CAPACITY: usize = 64;
+
+/// The ways in which the set of rooms changes.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomEventKind {
+    Created,
+    Deleted,
+    Archived,
+}
+
+impl RoomEventKind {
+    /// Returns the name of the server-sent events of this kind.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoomEventKind::Created => "created",
+            RoomEventKind::Deleted => "deleted",
+            RoomEventKind::Archived => "archived",
+        }
+    }
+}
+
+/// A change to the set of rooms.
+#[derive(Clone, Debug, Serialize)]
+pub struct RoomEvent {
+    pub kind: RoomEventKind,
+    pub room: String,
+}
+
+/// A handle used to send room events to their subscribers, as managed state.
+pub struct RoomEvents(Sender<RoomEvent>);
+
+impl Default for RoomEvents {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl RoomEvents {
+    /// Sends an event to all the current subscribers.
+    pub fn notify(&self, kind: RoomEventKind, room: &str) {
+        // Sending only fails if there are no subscribers, who would not care.
+        let _ = self.0.send(RoomEvent {
+            kind,
+            room: room.to_owned(),
+        });
+    }
+
+    /// Returns a receiver of the events sent from now on.
+    pub fn subscribe(&self) -> Receiver<RoomEvent> {
+        self.0.subscribe()
+    }
+}
@@ -12,18 +12,52 @@
 //! should help decrease network traffic by avoiding the resending of the
 //! entire message-table content repeatedly. To achieve this, the `Room`
 //! struct allows retrieving updates only for given time intervals.
+//!
+//! Instead of sharing a room's password, admins can also hand out invite
+//! codes, which grant access to a room a limited number of times, or until
+//! they expire. Invites are held in the `invites` table.
+//!
+//! Instead of being deleted, rooms can be archived. Their message tables are
+//! kept, renamed to `archived_messages{table_id}`, and listed in the
+//! `archived_rooms` table, so they can later be restored into a new room.
+//! Archives are identified by the ids of their tables, since several rooms
+//! with the same name might be archived.
+//!
+//! Rooms can also have their messages encrypted at rest. This is chosen when
+//! a room is created, and requires an encryption secret to be configured.
+//!
+//! Public rooms can be read by anyone, without a password, and even without a
+//! session if the server runs in guest mode. Posting in them still requires
+//! logging in, like in any other room.
+//!
+//! Deployments with a fixed set of rooms can list them in the configuration.
+//! The listed rooms are created when the server starts, if they are missing.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
 
-use ::serde::Deserialize;
+use ::serde::{Deserialize, Serialize};
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+use rocket::http::Method;
 use rocket::outcome::try_outcome;
 use rocket::request::{self, FromRequest, Request};
-use rocket_sync_db_pools::postgres::error::SqlState;
 use rocket_sync_db_pools::postgres::row::Row;
-use rocket_sync_db_pools::postgres::Client;
+use rocket_sync_db_pools::postgres::types::ToSql;
 use sha2::{Digest, Sha256};
 
-use crate::db::{self, DbConn};
-use crate::messages::{self, Message, Updates};
+use crate::archive::ArchiveRecord;
+use crate::config::ForumConfig;
+use crate::db::{self, Client, DbConn, GenericClient};
+use crate::encryption::RoomCipher;
+use crate::messages::{
+    self, AuthorTokens, ClientView, EditJson, EditOutcome, IntervalChecksum, Message, MessageEdit,
+    MessageFormat, MessageJson, MessageRef, NewMessage, PublicIds, Rejection, ShownId,
+    StorageOptions, ThreadOrder, ThreadSummary, TraversalError, Updates,
+};
 use crate::sessions::Session;
+use crate::webhooks::WebhookPayload;
 use crate::*;
 
 /// Returns the hash of a password, as it should be stored in the database.
@@ -33,15 +67,104 @@ pub fn hash_password(password: &str) -> String {
     format!("{:x}", Sha256::digest(password.as_bytes()))
 }
 
+/// The settings of a room, chosen when the room is created.
+///
+/// The settings which are not chosen explicitly are taken from the
+/// `room_defaults` section of the configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// Whether the room's messages are encrypted at rest.
+    pub encrypted: bool,
+    /// Whether messages can reply to threads. If not, all messages are
+    /// posted as new threads, like in a chat room.
+    pub threading_enabled: bool,
+    /// Whether the sessions which post messages are stored as their authors.
+    /// If not, messages cannot be linked to their authors, even by someone who
+    /// has access to the database, but authors cannot delete their messages.
+    pub store_author: bool,
+    /// Whether anyone can read the room's messages, without logging in.
+    pub public: bool,
+    /// Whether messages can be written in `CommonMark`. If not, all messages
+    /// are displayed literally, as plain text.
+    pub markdown_enabled: bool,
+    /// Who can post messages in the room.
+    pub post_permission: PostPermission,
+    /// The name of the sanitizer profile which cleans the room's messages.
+    /// If it's missing, or not configured, the default sanitizer is used.
+    pub sanitizer_profile: Option<String>,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            encrypted: false,
+            threading_enabled: true,
+            store_author: true,
+            public: false,
+            markdown_enabled: true,
+            post_permission: PostPermission::Everyone,
+            sanitizer_profile: None,
+        }
+    }
+}
+
+/// Who can post messages in a room. Everyone who can enter the room can read it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, FromFormField, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PostPermission {
+    /// Everyone who can enter the room.
+    #[default]
+    Everyone,
+    /// Only admins, so the room is used for announcements.
+    Admins,
+}
+
+impl PostPermission {
+    /// Returns the name under which the permission is stored.
+    fn as_str(self) -> &'static str {
+        match self {
+            PostPermission::Everyone => "everyone",
+            PostPermission::Admins => "admins",
+        }
+    }
+
+    /// Parses a stored permission. Unknown permissions only allow admins to post.
+    fn from_stored(name: &str) -> Self {
+        match name {
+            "everyone" => PostPermission::Everyone,
+            _ => PostPermission::Admins,
+        }
+    }
+}
+
 /// Holds relevant information about a room.
 ///
 /// It's tied to a row in the rooms table.
 pub struct Room {
+    name: String,
     /// The hashed password used to log into the room.
     password: String,
     /// A number used to identify the table which holds the room's messages.
     table_id: i32,
+    /// The time when the room was created, as a Unix timestamp in milliseconds.
     creation: i64,
+    settings: RoomSettings,
+    /// The message shown to users when they enter the room, if there is one.
+    /// It's sanitized HTML.
+    entry_message: Option<String>,
+    /// The time (as a Unix timestamp in milliseconds) before which users
+    /// cannot post in the room. Rooms without it accept posts right away.
+    accepting_posts_after: Option<i64>,
+    /// The cipher used for the room's messages, if they are encrypted.
+    /// It's set by the request guard, since it requires the server's configuration.
+    cipher: Option<RoomCipher>,
+    /// The tokens which tell apart the authors of each thread, if they are enabled.
+    /// Like the cipher, it's set by the request guard.
+    author_tokens: Option<AuthorTokens>,
+    /// The public ids of the room's messages, if they are enabled.
+    /// Like the cipher, it's set by the request guard.
+    public_ids: Option<PublicIds>,
 }
 
 impl Room {
@@ -50,15 +173,38 @@ impl Room {
     /// Each room has a table for its messages. To ensure that these tables
     /// receive unique names, each room has an associated `table_id`, which
     /// becomes part of the name. The naming scheme is: `messages{table_id}`.
+    ///
+    /// If `accepting_posts_after` is set, users cannot post in the room before
+    /// that time, given as a Unix timestamp in milliseconds. The username of
+    /// the admin who created the room is kept in `created_by`, if known.
     pub fn create_room(
         client: &mut Client,
         name: String,
         hashed_password: String,
+        settings: RoomSettings,
+        accepting_posts_after: Option<i64>,
+        created_by: Option<&str>,
     ) -> Result<(), db::Error> {
-        let creation = Message::current_timestamp();
+        let creation = clock::now_millis();
         client.execute(
-            "INSERT INTO rooms (name, password, creation) VALUES ($1, $2, $3);",
-            &[&name, &hashed_password, &creation],
+            "INSERT INTO rooms
+                (name, password, creation, encrypted, threading_enabled, store_author, public, markdown_enabled,
+                post_permission, accepting_posts_after, created_by, sanitizer_profile)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12);",
+            &[
+                &name,
+                &hashed_password,
+                &creation,
+                &settings.encrypted,
+                &settings.threading_enabled,
+                &settings.store_author,
+                &settings.public,
+                &settings.markdown_enabled,
+                &settings.post_permission.as_str(),
+                &accepting_posts_after,
+                &created_by,
+                &settings.sanitizer_profile,
+            ],
         )?;
 
         let table_id: i32 = query_one_row!(
@@ -72,26 +218,231 @@ impl Room {
         Message::setup_table(client, &table).and(Ok(()))
     }
 
+    /// Makes sure that a room listed in the configuration exists,
+    /// creating it if it's missing.
+    ///
+    /// The password of an existing room is only changed if the room should
+    /// have its password reset. Its other settings are never changed.
+    /// The settings which the room does not list are taken from the defaults.
+    /// It returns `true` if the room was created.
+    pub fn provision(
+        client: &mut Client,
+        room: &ProvisionedRoom,
+        defaults: &RoomSettings,
+    ) -> Result<bool, db::Error> {
+        let name = constraints::normalize_room_name(&room.name);
+        let hashed_password = hash_password(room.password.as_deref().unwrap_or_default());
+
+        if !Room::exists(client, &name)? {
+            Room::create_room(
+                client,
+                name,
+                hashed_password,
+                room.settings(defaults),
+                None,
+                None,
+            )?;
+            return Ok(true);
+        }
+        if room.reset_password {
+            Room::change_password(client, &name, &hashed_password)?;
+        }
+        Ok(false)
+    }
+
     /// Deletes a room from the database, also removing its message table.
     ///
-    /// If the operation fails, the reason is returned as a readable string.
-    pub fn delete_room(client: &mut Client, name: &str) -> Result<(), String> {
-        let table_id: i32 = query_one_row!(
+    /// Both happen in one transaction, so a room is never left without its
+    /// messages, or the other way around. It returns `false` if there is no
+    /// room with the given name.
+    pub fn delete_room(client: &mut Client, name: &str) -> Result<bool, db::Error> {
+        let mut transaction = client.transaction()?;
+
+        let table_id: i32 = match transaction.query_opt(
+            "DELETE FROM rooms WHERE name = $1 RETURNING table_id;",
+            &[&name],
+        )? {
+            Some(row) => row.get(0),
+            None => return Ok(false),
+        };
+
+        transaction.execute(&format!("DROP TABLE IF EXISTS messages{};", table_id), &[])?;
+        transaction.commit().and(Ok(true))
+    }
+
+    /// Drops the message tables which do not belong to any room.
+    ///
+    /// Such tables should not exist, but they could have been left behind
+    /// by older versions of the server, which did not delete rooms atomically.
+    /// The tables of archived rooms are kept.
+    pub fn drop_orphan_tables(client: &mut Client) -> Result<(), db::Error> {
+        let tables: Vec<String> = query_and_map!(
             client,
-            "SELECT table_id FROM rooms WHERE name = $1;",
+            "SELECT tablename FROM pg_tables
+            WHERE schemaname = current_schema() AND tablename ~ '^messages[0-9]+$'
+            AND NOT EXISTS (SELECT 1 FROM rooms WHERE 'messages' || rooms.table_id = tablename);",
+            &[],
+            |row: Row| row.get(0)
+        )
+        .collect();
+
+        for table in tables {
+            client.batch_execute(&format!("DROP TABLE IF EXISTS {};", table))?;
+        }
+        Ok(())
+    }
+
+    /// Checks if a room with the given name exists.
+    pub fn exists(client: &mut Client, name: &str) -> Result<bool, db::Error> {
+        query_one_row!(
+            client,
+            "SELECT EXISTS (SELECT 1 FROM rooms WHERE name = $1);",
             &[&name],
             |row: Row| row.get(0)
         )
-        .map_err(|_| "Error while retrieving table_id.")?;
+    }
+
+    /// Archives a room, removing it while keeping its messages.
+    ///
+    /// The archive gets the name of the room, and can be restored into
+    /// another room using `restore_archive`. It returns the id of the archive.
+    pub fn archive_room(client: &mut Client, name: &str) -> Result<i32, db::Error> {
+        let now = clock::now_millis();
+        let mut transaction = client.transaction()?;
+
+        let table_id: i32 = query_one_row!(
+            transaction,
+            "SELECT table_id FROM rooms WHERE name = $1;",
+            &[&name],
+            |row: Row| row.get(0)
+        )?;
+        transaction.execute(
+            "INSERT INTO archived_rooms (name, table_id, encrypted, archived)
+            SELECT name, table_id, encrypted, $2 FROM rooms WHERE name = $1;",
+            &[&name, &now],
+        )?;
+        transaction.execute("DELETE FROM rooms WHERE name = $1;", &[&name])?;
+        transaction.batch_execute(&format!(
+            "ALTER TABLE messages{id} RENAME TO archived_messages{id};",
+            id = table_id
+        ))?;
+
+        transaction.commit()?;
+        Ok(table_id)
+    }
+
+    /// Moves the messages of an archive into an existing room, removing the archive.
+    ///
+    /// The room takes over the archive's `table_id`, and its own (empty)
+    /// message table is dropped. It returns `false` without changing anything
+    /// if the room already has messages.
+    pub fn restore_archive(
+        client: &mut Client,
+        name: &str,
+        archive: i32,
+    ) -> Result<bool, db::Error> {
+        let mut transaction = client.transaction()?;
+
+        let (table_id, encrypted): (i32, bool) = query_one_row!(
+            transaction,
+            "SELECT table_id, encrypted FROM archived_rooms WHERE table_id = $1 FOR UPDATE;",
+            &[&archive],
+            |row: Row| (row.get(0), row.get(1))
+        )?;
+        let old_table_id: i32 = query_one_row!(
+            transaction,
+            "SELECT table_id FROM rooms WHERE name = $1;",
+            &[&name],
+            |row: Row| row.get(0)
+        )?;
+
+        let has_messages: bool = query_one_row!(
+            transaction,
+            &format!("SELECT EXISTS (SELECT 1 FROM messages{});", old_table_id),
+            &[],
+            |row: Row| row.get(0)
+        )?;
+        if has_messages {
+            return Ok(false);
+        }
+
+        transaction.batch_execute(&format!(
+            "DROP TABLE messages{old_id};
+            ALTER TABLE archived_messages{id} RENAME TO messages{id};",
+            old_id = old_table_id,
+            id = table_id
+        ))?;
+        transaction.execute(
+            "UPDATE rooms SET table_id = $1, encrypted = $2 WHERE name = $3;",
+            &[&table_id, &encrypted, &name],
+        )?;
+        transaction.execute(
+            "DELETE FROM archived_rooms WHERE table_id = $1;",
+            &[&archive],
+        )?;
+        transaction.commit()?;
 
+        // The archive might predate some columns. Also, its messages should
+        // reach clients as updates, so they must appear as changed.
         let table = format!("messages{}", table_id);
-        match client.execute("DELETE FROM rooms WHERE name = $1;", &[&name]) {
-            Ok(1) => client
-                .execute(&format!("DROP TABLE IF EXISTS {};", table), &[])
-                .map(|_| ())
-                .map_err(|_| "Error while deleting the messages table.".into()),
-            _ => Err("Error while deleting room metadata.".into()),
+        Message::setup_table(client, &table)?;
+        client.execute(
+            &format!("UPDATE {} SET changed = $1;", table),
+            &[&clock::now_millis()],
+        )?;
+        Message::renumber_changes(client, &table, "TRUE", "id")?;
+
+        Ok(true)
+    }
+
+    /// Returns all the archives, oldest first.
+    pub fn archived_rooms(client: &mut Client) -> Result<Vec<ArchiveInfo>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            "SELECT table_id, name, archived FROM archived_rooms ORDER BY archived, table_id;",
+            &[],
+            |row: Row| ArchiveInfo {
+                id: row.get(0),
+                name: row.get(1),
+                archived: row.get(2),
+            }
+        )
+        .collect())
+    }
+
+    /// Posts a system message in every room.
+    ///
+    /// The message is posted in each room separately, so failing to post it
+    /// in some rooms does not affect the others. The names of those rooms
+    /// are returned.
+    pub fn broadcast(
+        client: &mut Client,
+        content: &str,
+        encryption_secret: Option<&str>,
+        options: &StorageOptions,
+    ) -> Result<Vec<String>, db::Error> {
+        let mut failed = Vec::new();
+        for name in Room::active_rooms(client)? {
+            let message = MessageJson {
+                content: content.to_owned(),
+                reply_to: None,
+                format: MessageFormat::Markdown,
+                client_timestamp: None,
+            };
+            let posted = match Room::from_db(client, &name) {
+                Ok(mut room) => {
+                    room.init_cipher(encryption_secret)
+                        && room
+                            .add_message(client, message, None, options)
+                            .is_ok_and(|added| added.is_ok())
+                }
+                Err(_) => false,
+            };
+            if !posted {
+                failed.push(name);
+            }
         }
+        Ok(failed)
     }
 
     /// Returns a list with the names of all the rooms stored in the database.
@@ -103,152 +454,910 @@ impl Room {
         )
     }
 
+    /// Returns information about all the rooms stored in the database,
+    /// in the order in which they were created.
+    pub fn rooms_info(client: &mut Client) -> Result<Vec<RoomInfo>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            "SELECT name, creation, created_by FROM rooms ORDER BY creation, name;",
+            &[],
+            |row: Row| RoomInfo {
+                name: row.get(0),
+                creation: row.get(1),
+                created_by: row.get(2),
+            }
+        )
+        .collect())
+    }
+
+    /// Counts the messages which a session has not received yet, in each
+    /// room it can access.
+    ///
+    /// Messages count if they were posted after the last updates the
+    /// session received from their room, unless they are deleted. Edits and
+    /// other changes of older messages don't count. The counts are capped at
+    /// `cap`, so large backlogs are not counted fully.
+    pub fn unread_counts(
+        client: &mut Client,
+        session: &Session,
+        cap: i64,
+    ) -> Result<BTreeMap<String, i64>, db::Error> {
+        let rooms: Vec<(String, i32, i64)> = query_and_map!(
+            client,
+            "SELECT rooms.name, rooms.table_id, room_updates.last_change FROM room_attempts
+            JOIN rooms ON rooms.name = room_attempts.name AND rooms.password = room_attempts.password
+            LEFT JOIN room_updates
+                ON room_updates.id = room_attempts.id AND room_updates.name = room_attempts.name
+            WHERE room_attempts.id = $1;",
+            &[&session.id()],
+            |row: Row| (row.get(0), row.get(1), row.get::<_, Option<i64>>(2).unwrap_or(0))
+        )
+        .collect();
+
+        let mut counts: BTreeMap<String, i64> =
+            rooms.iter().map(|(name, _, _)| (name.clone(), 0)).collect();
+        if rooms.is_empty() {
+            return Ok(counts);
+        }
+
+        // All the rooms are counted in one query. Each room is identified by
+        // its position `i` in the list, and its last change is parameter `$(i + 2)`.
+        let unread = rooms
+            .iter()
+            .enumerate()
+            .map(|(i, (_, table_id, _))| {
+                format!(
+                    "(SELECT {i} AS room FROM messages{table_id}
+                    WHERE posted_change > ${param} AND deleted_at IS NULL LIMIT $1)",
+                    i = i,
+                    table_id = table_id,
+                    param = i + 2,
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" UNION ALL ");
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&cap];
+        params.extend(
+            rooms
+                .iter()
+                .map(|(_, _, last_change)| last_change as &(dyn ToSql + Sync)),
+        );
+
+        let rows = query_and_map!(
+            client,
+            &format!(
+                "SELECT room, COUNT(*) FROM ({}) AS unread GROUP BY room;",
+                unread
+            ),
+            &params,
+            |row: Row| (row.get::<_, i32>(0), row.get::<_, i64>(1))
+        );
+        for (room, count) in rows {
+            counts.insert(rooms[room as usize].0.clone(), count);
+        }
+        Ok(counts)
+    }
+
     /// Changes the password of the given room.
+    ///
+    /// The login attempts made with other passwords are removed, so users
+    /// who logged in with the old password lose access to the room, and
+    /// have to log in again.
     pub fn change_password(
         client: &mut Client,
         name: &str,
         hashed_password: &str,
     ) -> Result<(), db::Error> {
+        let mut transaction = client.transaction()?;
+        transaction.execute(
+            "UPDATE rooms SET password = $1 WHERE name = $2;",
+            &[&hashed_password, &name],
+        )?;
+        transaction.execute(
+            "DELETE FROM room_attempts WHERE name = $1 AND password <> $2;",
+            &[&name, &hashed_password],
+        )?;
+        transaction.commit()
+    }
+
+    /// Sets the message shown to users when they enter a room,
+    /// or removes it if `message` is `None`.
+    ///
+    /// The message is shown again to users who have already seen the old one.
+    /// It returns `false` if there is no room with the given name.
+    pub fn set_entry_message(
+        client: &mut Client,
+        name: &str,
+        message: Option<&str>,
+    ) -> Result<bool, db::Error> {
+        let mut transaction = client.transaction()?;
+        let updated = transaction.execute(
+            "UPDATE rooms SET entry_message = $1 WHERE name = $2;",
+            &[&message, &name],
+        )?;
+        transaction.execute(
+            "UPDATE room_attempts SET entry_message_seen = FALSE WHERE name = $1;",
+            &[&name],
+        )?;
+        transaction.commit().and(Ok(updated == 1))
+    }
+
+    /// Returns the message shown to users when they enter the room, if there is one.
+    pub fn entry_message(&self) -> Option<&str> {
+        self.entry_message.as_deref()
+    }
+
+    /// Returns the time when the room was created, as a Unix timestamp in milliseconds.
+    pub fn creation(&self) -> i64 {
+        self.creation
+    }
+
+    /// Returns the time before which users cannot post in the room, as a Unix
+    /// timestamp in milliseconds, if there is one.
+    pub fn accepting_posts_after(&self) -> Option<i64> {
+        self.accepting_posts_after
+    }
+
+    /// Creates an invite code which grants access to the given room.
+    ///
+    /// The code can be used `uses` times, or indefinitely if `uses` is `None`.
+    /// Similarly, it expires at the `expires` timestamp, if one is given.
+    /// The timestamp should have the format used by the messages database.
+    pub fn create_invite(
+        client: &mut Client,
+        name: &str,
+        uses: Option<i32>,
+        expires: Option<i64>,
+    ) -> Result<String, db::Error> {
+        const CODE_LEN: usize = 32;
+        let code: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(CODE_LEN)
+            .collect();
+
         client
             .execute(
-                "UPDATE rooms SET password = $1 WHERE name = $2;",
-                &[&hashed_password, &name],
+                "INSERT INTO invites (code, name, uses_left, expires) VALUES ($1, $2, $3, $4);",
+                &[&code, &name, &uses, &expires],
             )
-            .and(Ok(()))
-            .map_err(Into::into)
+            .and(Ok(code))
+    }
+
+    /// Returns the name of the room to which an invite code grants access,
+    /// without using up the code.
+    ///
+    /// The invite stays locked until the end of the transaction, so it cannot
+    /// be used up in the meantime. The operation fails if the code does not
+    /// exist.
+    pub fn invited_room(client: &mut impl GenericClient, code: &str) -> Result<String, db::Error> {
+        query_one_row!(
+            client,
+            "SELECT name FROM invites WHERE code = $1 FOR UPDATE;",
+            &[&code],
+            |row: Row| row.get(0)
+        )
+    }
+
+    /// Uses up an invite code, returning the name and the hashed password
+    /// of the room it grants access to.
+    ///
+    /// The operation fails if the code does not exist, has expired,
+    /// or has already been used the maximum number of times. The code is
+    /// checked and used up by a single statement, so concurrent redemptions
+    /// cannot use it more times than allowed.
+    pub fn redeem_invite(
+        client: &mut impl GenericClient,
+        code: &str,
+    ) -> Result<(String, String), db::Error> {
+        let now = clock::now_millis();
+
+        // Exhausted and expired invites are useless, so we can remove them.
+        client.execute(
+            "DELETE FROM invites WHERE uses_left <= 0 OR expires <= $1;",
+            &[&now],
+        )?;
+
+        query_one_row!(
+            client,
+            "UPDATE invites SET uses_left = uses_left - 1
+            WHERE code = $1 AND (uses_left IS NULL OR uses_left > 0)
+            AND (expires IS NULL OR expires > $2)
+            RETURNING name, (SELECT password FROM rooms WHERE rooms.name = invites.name);",
+            &[&code, &now],
+            |row: Row| (row.get(0), row.get(1))
+        )
     }
 
-    /// Returns the next incremental updates a user should receive when requested.
+    /// Returns the next incremental updates a user should receive when requested,
+    /// together with the number of the last change they cover.
     ///
-    /// The timestamps should be given in the format used by the messages database.
-    pub fn get_updates_between(
+    /// `last_change` is the number returned for the user's previous updates,
+    /// if they received any. At most `limit` messages are sent at once, so users
+    /// who missed many messages receive them in chunks. The returned number
+    /// should be saved for the user, so the next chunk starts where this one ended.
+    pub fn get_updates_after(
         &self,
         client: &mut Client,
-        last_update: i64,
-        now: i64,
-    ) -> Result<Updates, db::Error> {
-        // If this room is a recreation, the client might have messages from
-        // the old room in their caches, so they should remove those first.
-        let clean_stored = last_update <= self.creation;
+        last_change: Option<i64>,
+        limit: usize,
+    ) -> Result<(Updates, i64), db::Error> {
+        // Users who never received updates might have messages from an older
+        // room with the same name in their caches, so they should remove those
+        // first. The users' progress is removed together with the old room.
+        // Compacting the room changes the ids of its messages, so users whose
+        // progress is older than the compaction have to start over too.
+        let table = format!("messages{}", self.table_id);
+        let mut after = last_change;
+        let (clean_stored, messages, end, has_more) = loop {
+            let (messages, end, has_more) =
+                Message::get_changed_after(client, &table, after.unwrap_or(0), limit, self.view())?;
+            // It's checked after the messages are read, so a compaction which
+            // renumbered them in the meantime is not missed.
+            let compacted: i64 = query_one_row!(
+                client,
+                "SELECT compacted_change FROM rooms WHERE table_id = $1;",
+                &[&self.table_id],
+                |row: Row| row.get(0)
+            )?;
+            match after {
+                Some(last) if last < compacted => after = None,
+                _ => break (after.is_none(), messages, end, has_more),
+            }
+        };
 
+        let updates = Updates {
+            clean_stored,
+            messages,
+            has_more,
+            cursor: None,
+        };
+        Ok((updates, end))
+    }
+
+    /// Returns the messages of the room with ids between `from` and `to`
+    /// (both inclusive), to fill a gap in the messages a user has.
+    ///
+    /// At most `limit` messages are sent at once. If there are more, `has_more`
+    /// is set, and the rest can be requested starting after the last message.
+    /// The user's progress through the room's updates is not affected.
+    pub fn get_updates_in_range(
+        &self,
+        client: &mut Client,
+        from: i32,
+        to: i32,
+        limit: usize,
+    ) -> Result<Updates, db::Error> {
         let table = format!("messages{}", self.table_id);
-        let messages = Message::get_between(client, &table, last_update, now)?;
+        let mut messages =
+            Message::get_id_range(client, &table, from, to, limit as i64 + 1, self.view())?;
 
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
         Ok(Updates {
-            clean_stored,
+            clean_stored: false,
             messages,
+            has_more,
+            cursor: None,
         })
     }
 
-    /// Adds a new message to the room.
-    pub fn add_message(
+    /// Returns a batch of at most `limit` messages of the room, which were
+    /// posted after the message with the given id.
+    pub fn get_messages_after(
         &self,
         client: &mut Client,
-        mut content: String,
-        author: String,
-        reply_to: Option<i32>,
-    ) -> Result<(), db::Error> {
-        messages::prepare_for_storage(&mut content);
-
+        after: i32,
+        limit: i64,
+    ) -> Result<Vec<Message>, db::Error> {
         let table = format!("messages{}", self.table_id);
-        Message::add(client, &table, content, author, reply_to)
+        Message::get_after(client, &table, after, limit, self.view())
     }
 
-    /// Tries to retrieve the database entry associated with a room, given its name.
-    fn from_db(client: &mut Client, name: &str) -> Result<Room, db::Error> {
-        query_one_row!(
-            client,
-            "SELECT password, table_id, creation FROM rooms WHERE name = $1;",
-            &[&name],
-            |row: Row| Room {
-                password: row.get(0),
-                table_id: row.get(1),
-                creation: row.get(2),
-            }
-        )
+    /// Summarizes the messages posted in the room in the given interval.
+    pub fn interval_checksum(
+        &self,
+        client: &mut Client,
+        since: i64,
+        now: i64,
+    ) -> Result<IntervalChecksum, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::interval_checksum(client, &table, since, now)
     }
 
-    /// Checks if the given password allows access to the room.
-    fn valid_password(&self, hashed_password: &str) -> bool {
-        self.password == hashed_password
+    /// Returns the room's messages with the given ids, skipping the missing ones.
+    pub fn get_many(&self, client: &mut Client, ids: &[i32]) -> Result<Vec<Message>, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::get_many(client, &table, ids, self.view())
     }
-}
-
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for Room {
-    type Error = ();
 
-    /// Since rooms are password-protected, we must make sure a user is
-    /// allowed access to a room before it can interact with it. This request
-    /// guard should be the main way of checking users' access to a room.
+    /// Returns a page of replies from one of the room's threads.
     ///
-    /// To test if the permission check failed because the user did not provide
-    /// a valid password, you can use an `Option<Room>` field in the function
-    /// header, like this:
-    ///
-    /// ```rust ignore
-    /// #[get("/room/<name>")]
-    /// fn test_room_access(name: RoomName, room: Option<Room>) -> &'static str {
-    ///     if room.is_some() {
-    ///         "You have access to this room."
-    ///     } else {
-    ///         "You do not have acces to this room."
-    ///     }
-    /// }
-    /// ```
-    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        // Try to extract the name of the room.
-        let name = {
-            // Room requests should have URLs that start with `/room/<name>`.
-            let mut segs = req.uri().path().segments();
-            if segs.next() != Some("room") {
-                return request::Outcome::Forward(Status::BadRequest);
-            }
-            match segs.next() {
-                Some(name) => name.to_owned(),
-                _ => return request::Outcome::Forward(Status::BadRequest),
-            }
-        };
+    /// The first page also contains the message which started the thread.
+    pub fn get_thread(
+        &self,
+        client: &mut Client,
+        root: i32,
+        after: Option<i32>,
+        limit: i64,
+    ) -> Result<Vec<Message>, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::get_thread(client, &table, root, after, limit, self.view())
+    }
 
-        let conn = try_outcome!(req.guard::<DbConn>().await);
+    /// Returns the messages which start the room's threads, in the given order.
+    pub fn get_threads(
+        &self,
+        client: &mut Client,
+        order: ThreadOrder,
+    ) -> Result<Vec<Message>, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::get_threads(client, &table, order, self.view())
+    }
 
-        // Retrieve the room entry.
-        let room = {
-            let name = name.clone();
-            match conn.run(move |c| Room::from_db(c, &name)).await {
-                Ok(room) => room,
-                _ => return request::Outcome::Forward(Status::NotFound),
-            }
-        };
+    /// Returns the initial view of the room, with its newest `limit` threads
+    /// in the given order, and the number of the last change it covers.
+    pub fn snapshot(
+        &self,
+        client: &mut Client,
+        order: ThreadOrder,
+        limit: i64,
+    ) -> Result<(Snapshot, i64), db::Error> {
+        let table = format!("messages{}", self.table_id);
+        let (threads, has_more_threads, last_change) =
+            Message::get_snapshot(client, &table, order, limit, self.view())?;
 
-        // Find the user's password attempt.
-        let hashed_password = {
-            let name = name.clone();
-            let session = try_outcome!(req.guard::<Session>().await);
-            match conn.run(move |c| session.get_room_attempt(c, &name)).await {
-                Ok(password) => password,
-                Err(e) if e.code() == Some(&SqlState::NO_DATA) => {
-                    return request::Outcome::Forward(Status::Unauthorized)
-                }
-                _ => return request::Outcome::Forward(Status::InternalServerError),
-            }
+        let snapshot = Snapshot {
+            entry_message: self.entry_message.clone(),
+            threads,
+            has_more_threads,
         };
+        Ok((snapshot, last_change))
+    }
 
-        if hashed_password == room.password {
-            request::Outcome::Success(room)
-        } else {
-            request::Outcome::Forward(Status::Unauthorized)
+    /// Renders a message as it would be stored in the room, without storing it.
+    ///
+    /// It returns the message's HTML, and whether the sanitizer removed some
+    /// elements of the message, if the sanitizer is configured to report it.
+    pub fn preview_message(
+        &self,
+        message: MessageJson,
+        options: &StorageOptions,
+    ) -> Result<(String, bool), Rejection> {
+        if message.content.len() > options.max_len {
+            return Err(Rejection::TooLong);
         }
+        let mut content = message.content;
+        let stripped = messages::prepare_for_storage(
+            &mut content,
+            self.format(message.format),
+            self.settings.sanitizer_profile.as_deref(),
+            options,
+        )?;
+        Ok((content, stripped))
     }
-}
 
-/// The content of a form used to hold login credentials for a room.
-#[derive(Clone, Deserialize, FromForm)]
-pub struct RoomLogin {
-    pub name: String,
-    /// The plaintext password of the room.
-    pub password: String,
-}
+    /// Adds a new message to the room.
+    ///
+    /// If threading is disabled for the room, replies are posted as new threads.
+    /// If `CommonMark` is disabled for the room, messages are posted as plain text.
+    /// Replies to replies are posted in the thread of the message they reply to.
+    /// If the room does not store authors, the author is discarded.
+    /// Messages without an author are posted as system messages.
+    /// If the message is refused before being stored, the reason is returned.
+    /// Otherwise, it returns whether the sanitizer reported that it removed
+    /// some elements of the message.
+    ///
+    /// Messages with authors are refused until the room accepts posts.
+    ///
+    /// If reply notifications are enabled, the author of the message which a
+    /// reply answers is notified of it, even if the reply is posted in the
+    /// thread of another message.
+    ///
+    /// Unless the room is encrypted, the message is also archived, and sent to
+    /// the webhooks of the room, if archival and webhooks are enabled.
+    pub fn add_message(
+        &self,
+        client: &mut Client,
+        message: MessageJson,
+        author: Option<String>,
+        options: &StorageOptions,
+    ) -> Result<Result<bool, Rejection>, db::Error> {
+        if author.is_some() && !self.accepts_posts() {
+            return Ok(Err(Rejection::NotOpen));
+        }
+        if message.content.len() > options.max_len {
+            return Ok(Err(Rejection::TooLong));
+        }
+        let client_timestamp = match options.client_timestamp(message.client_timestamp) {
+            Ok(client_timestamp) => client_timestamp,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        let mut content = message.content;
+        let source = options.store_source.then(|| content.clone());
+        let format = self.format(message.format);
+        let stripped = match messages::prepare_for_storage(
+            &mut content,
+            format,
+            self.settings.sanitizer_profile.as_deref(),
+            options,
+        ) {
+            Ok(stripped) => stripped,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        let table = format!("messages{}", self.table_id);
+        let replied = message
+            .reply_to
+            .filter(|_| self.settings.threading_enabled)
+            .map(|reply_to| self.message_id(&reply_to));
+        let reply_to = match replied {
+            Some(None) => return Ok(Err(Rejection::InvalidReply)),
+            Some(Some(id)) => {
+                match Message::thread_root(client, &table, id, options.max_reply_depth) {
+                    Ok(Some(root)) => Some(root),
+                    Ok(None) => return Ok(Err(Rejection::InvalidReply)),
+                    Err(TraversalError::TooDeep) => {
+                        eprintln!("The replies of {} form a cycle at message {}.", table, id);
+                        return Ok(Err(Rejection::InvalidReply));
+                    }
+                    Err(TraversalError::Db(err)) => return Err(err),
+                }
+            }
+            None => None,
+        };
+
+        let shared = (options.archiver.is_enabled() || options.dispatcher.is_enabled())
+            && !self.settings.encrypted;
+        let plaintext = shared.then(|| content.clone());
+        let system = author.is_none();
+        let replier = author.clone();
+        let message = NewMessage {
+            content,
+            source,
+            system,
+            author: author.filter(|_| self.settings.store_author),
+            reply_to,
+            client_timestamp,
+        };
+        let (id, timestamp) = match Message::add(
+            client,
+            &table,
+            message,
+            self.cipher.as_ref(),
+            options.hash_content,
+            options.compress_content,
+            options.room_budget,
+        )? {
+            Some(added) => added,
+            None => return Ok(Err(Rejection::OverBudget)),
+        };
+
+        if let Some(Some(replied)) = replied.filter(|_| options.max_reply_notifications > 0) {
+            // The message is already posted, so failing to notify its author is only logged.
+            if let Err(err) = Message::notify_reply(
+                client,
+                &table,
+                &self.name,
+                replied,
+                id,
+                replier.as_deref(),
+                options.max_reply_notifications,
+            ) {
+                eprintln!("Could not notify a reply in room {}: {}", self.name, err);
+            }
+        }
+
+        if let Some(content) = plaintext {
+            // Other services get the same ids as clients.
+            let shown = |id| ShownId::new(id, self.public_ids.as_ref());
+            if options.dispatcher.is_enabled() {
+                let payload = WebhookPayload {
+                    room: self.name.clone(),
+                    id: shown(id),
+                    timestamp,
+                    reply_to: reply_to.map(shown),
+                    system,
+                    content: content.clone(),
+                };
+                // The message is already posted, so failing to find the webhooks is only logged.
+                if let Err(err) = options.dispatcher.dispatch(client, payload) {
+                    eprintln!("Could not find the webhooks of room {}: {}", self.name, err);
+                }
+            }
+            options.archiver.archive(ArchiveRecord {
+                room: self.name.clone(),
+                table_id: self.table_id,
+                id: shown(id),
+                timestamp,
+                reply_to: reply_to.map(shown),
+                system,
+                content,
+            });
+        }
+        Ok(Ok(stripped))
+    }
+
+    /// Edits a message of the room.
+    ///
+    /// Authors can edit their own messages, at most `window_secs` seconds after
+    /// posting them, unless it's 0. If there is no author, the edit is made by
+    /// an admin, who can edit any message. The new content is prepared for
+    /// storage like new messages are, and the reason is returned if it's refused.
+    pub fn edit_message(
+        &self,
+        client: &mut Client,
+        id: i32,
+        edit: EditJson,
+        author: Option<String>,
+        options: &StorageOptions,
+        window_secs: i64,
+    ) -> Result<Result<EditOutcome, Rejection>, db::Error> {
+        if edit.content.len() > options.max_len {
+            return Ok(Err(Rejection::TooLong));
+        }
+        let mut content = edit.content;
+        let source = options.store_source.then(|| content.clone());
+        let format = self.format(edit.format);
+        if let Err(rejection) = messages::prepare_for_storage(
+            &mut content,
+            format,
+            self.settings.sanitizer_profile.as_deref(),
+            options,
+        ) {
+            return Ok(Err(rejection));
+        }
+
+        let table = format!("messages{}", self.table_id);
+        let edit = MessageEdit {
+            content,
+            source,
+            author,
+            window: window_secs.saturating_mul(1000),
+            budget: options.room_budget,
+        };
+        Message::edit(
+            client,
+            &table,
+            id,
+            edit,
+            self.cipher.as_ref(),
+            options.hash_content,
+            options.compress_content,
+        )
+        .map(Ok)
+    }
+
+    /// Returns the source of a message of the room, as it was written.
+    ///
+    /// If an author is given, only the sources of their own messages are returned.
+    pub fn message_source(
+        &self,
+        client: &mut Client,
+        id: i32,
+        author: Option<&str>,
+    ) -> Result<Option<String>, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::get_source(client, &table, id, author, self.cipher.as_ref())
+    }
+
+    /// Counts the messages posted in the room by the given author.
+    ///
+    /// Messages whose authors were not stored, or whose authors' sessions
+    /// expired, are not counted.
+    pub fn count_messages_by(&self, client: &mut Client, author: &str) -> Result<i64, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::count_by_author(client, &table, author)
+    }
+
+    /// Deletes a message from the room, if it was posted by the given author.
+    ///
+    /// It returns `false` if the author has no such message.
+    pub fn delete_message(
+        &self,
+        client: &mut Client,
+        id: i32,
+        author: &str,
+    ) -> Result<bool, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::delete(client, &table, id, author)
+    }
+
+    /// Undoes the deletion of a message, if it was posted by the given author
+    /// and deleted at most `window_secs` seconds ago.
+    pub fn undelete_message(
+        &self,
+        client: &mut Client,
+        id: i32,
+        author: &str,
+        window_secs: i64,
+    ) -> Result<bool, db::Error> {
+        let table = format!("messages{}", self.table_id);
+        Message::undelete(client, &table, id, author, window_secs.saturating_mul(1000))
+    }
+
+    /// Compacts the messages of a room, removing the pruned messages which have
+    /// no replies, and renumbering the rest, so their ids are consecutive again.
+    ///
+    /// Users receive all the messages of the room again, with their new ids.
+    /// The notifications about replies in the room which were not retrieved
+    /// yet are removed, since they refer to the old ids.
+    /// It returns the number of removed messages, or `None` if the room does
+    /// not exist.
+    pub fn compact(client: &mut Client, name: &str) -> Result<Option<u64>, db::Error> {
+        let table_id: i32 =
+            match client.query_opt("SELECT table_id FROM rooms WHERE name = $1;", &[&name])? {
+                Some(row) => row.get(0),
+                None => return Ok(None),
+            };
+        let table = format!("messages{}", table_id);
+
+        let mut transaction = Message::change_transaction(client, &table)?;
+        let (removed, compacted) = Message::compact(&mut transaction, &table)?;
+        transaction.execute(
+            "UPDATE rooms SET compacted_change = $1 WHERE name = $2;",
+            &[&compacted, &name],
+        )?;
+        transaction.execute("DELETE FROM notifications WHERE room = $1;", &[&name])?;
+        transaction.commit()?;
+
+        Ok(Some(removed))
+    }
+
+    /// Makes sure the message tables of all rooms are set up correctly.
+    pub fn setup_all_tables(client: &mut Client) -> Result<(), db::Error> {
+        let table_ids: Vec<i32> =
+            query_and_map!(client, "SELECT table_id FROM rooms;", &[], |row: Row| row
+                .get(0))
+            .collect();
+
+        for table_id in table_ids {
+            Message::setup_table(client, &format!("messages{}", table_id))?;
+        }
+        Ok(())
+    }
+
+    /// Tries to retrieve the database entry associated with a room, given its name.
+    fn from_db(client: &mut Client, name: &str) -> Result<Room, db::Error> {
+        query_one_row!(
+            client,
+            "SELECT password, table_id, encrypted, threading_enabled, store_author, entry_message, public,
+                markdown_enabled, post_permission, accepting_posts_after, sanitizer_profile, creation
+            FROM rooms WHERE name = $1;",
+            &[&name],
+            |row: Row| Room {
+                name: name.to_owned(),
+                password: row.get(0),
+                table_id: row.get(1),
+                creation: row.get(11),
+                settings: RoomSettings {
+                    encrypted: row.get(2),
+                    threading_enabled: row.get(3),
+                    store_author: row.get(4),
+                    public: row.get(6),
+                    markdown_enabled: row.get(7),
+                    post_permission: PostPermission::from_stored(row.get(8)),
+                    sanitizer_profile: row.get(10),
+                },
+                entry_message: row.get(5),
+                accepting_posts_after: row.get(9),
+                cipher: None,
+                author_tokens: None,
+                public_ids: None,
+            }
+        )
+    }
+
+    /// Sets up the author tokens of the room, if the server has a secret for them.
+    ///
+    /// Rooms which do not store authors have no tokens.
+    fn init_author_tokens(&mut self, secret: Option<&str>) {
+        if self.settings.store_author {
+            self.author_tokens = secret.map(|secret| AuthorTokens::new(secret, self.table_id));
+        }
+    }
+
+    /// Sets up the public ids of the room, if the server has a secret for them.
+    fn init_public_ids(&mut self, secret: Option<&str>) {
+        self.public_ids = secret.map(|secret| PublicIds::new(secret, self.table_id));
+    }
+
+    /// Returns the id of a message referenced by a client.
+    ///
+    /// If public ids are enabled, only public ids are understood, so clients
+    /// cannot reach messages by guessing their ids. Otherwise, only the ids
+    /// themselves are understood. It returns `None` if the reference cannot
+    /// be understood.
+    pub fn message_id(&self, message: &MessageRef) -> Option<i32> {
+        match (message, self.public_ids.as_ref()) {
+            (MessageRef::Id(id), None) => Some(*id),
+            (MessageRef::Public(public), Some(public_ids)) => public_ids.decode(public),
+            _ => None,
+        }
+    }
+
+    /// Returns what is needed to prepare the room's messages for clients.
+    fn view(&self) -> ClientView<'_> {
+        ClientView {
+            cipher: self.cipher.as_ref(),
+            author_tokens: self.author_tokens.as_ref(),
+            public_ids: self.public_ids.as_ref(),
+        }
+    }
+
+    /// Sets up the cipher of an encrypted room, using the server's encryption secret.
+    ///
+    /// It returns `false` if the room is encrypted, but the server does not
+    /// have an encryption secret configured.
+    fn init_cipher(&mut self, secret: Option<&str>) -> bool {
+        if !self.settings.encrypted {
+            return true;
+        }
+        match secret {
+            Some(secret) => {
+                self.cipher = Some(RoomCipher::new(secret, self.table_id));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks if a session can post messages in the room.
+    ///
+    /// Messages posted by the server itself are not affected.
+    pub fn can_post(&self, session: &Session) -> bool {
+        match self.settings.post_permission {
+            PostPermission::Everyone => true,
+            PostPermission::Admins => session.is_admin(),
+        }
+    }
+
+    /// Checks if users can post in the room yet.
+    fn accepts_posts(&self) -> bool {
+        self.accepting_posts_after
+            .is_none_or(|after| clock::now_millis() >= after)
+    }
+
+    /// Returns the format in which a message written in the given format is
+    /// stored, since rooms can disable `CommonMark`.
+    fn format(&self, format: MessageFormat) -> MessageFormat {
+        if self.settings.markdown_enabled {
+            format
+        } else {
+            MessageFormat::Plain
+        }
+    }
+
+    /// Checks if the given password allows access to the room.
+    fn valid_password(&self, hashed_password: &str) -> bool {
+        self.password == hashed_password
+    }
+}
+
+/// The result of checking a user's access to a room.
+///
+/// Unlike an `Option<Room>`, it tells apart the reasons for which access was
+/// not granted, so handlers can respond to each of them. Since revealing
+/// which rooms exist is not always desired, handlers should check the
+/// `reveal_missing_rooms` option before telling users a room does not exist.
+///
+/// The guard forwards if the URL is not a room URL, or if the check fails
+/// because of an internal error.
+pub enum RoomAccess {
+    /// The user can access the room.
+    Granted(Box<Room>),
+    /// The user tried to log into the room, but the password was wrong.
+    WrongPassword,
+    /// There is no room with the name from the URL.
+    NoSuchRoom,
+    /// The user has not tried to log into the room.
+    NotAttempted,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RoomAccess {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // Try to extract the name of the room.
+        let name = {
+            // Room requests should have URLs that start with `/room/<name>`.
+            let mut segs = req.uri().path().segments();
+            if segs.next() != Some("room") {
+                return request::Outcome::Forward(Status::BadRequest);
+            }
+            match segs.next() {
+                Some(name) => constraints::normalize_room_name(name),
+                _ => return request::Outcome::Forward(Status::BadRequest),
+            }
+        };
+
+        let conn = try_outcome!(req.guard::<DbConn>().await);
+
+        // Retrieve the room entry.
+        let mut room = {
+            let name = name.clone();
+            match conn.run(move |c| Room::from_db(c, &name)).await {
+                Ok(room) => room,
+                _ => return request::Outcome::Success(RoomAccess::NoSuchRoom),
+            }
+        };
+
+        // Encrypted rooms cannot be used without their cipher.
+        let config = try_outcome!(req.guard::<&State<ForumConfig>>().await);
+        if !room.init_cipher(config.encryption_secret.as_deref()) {
+            return request::Outcome::Forward(Status::InternalServerError);
+        }
+        room.init_author_tokens(config.author_token_secret.as_deref());
+        room.init_public_ids(config.public_id_secret.as_deref());
+
+        // Anyone can read public rooms, but only users who logged in can post.
+        if room.settings.public && req.method() == Method::Get {
+            return request::Outcome::Success(RoomAccess::Granted(Box::new(room)));
+        }
+
+        // Find the user's password attempt.
+        let hashed_password = {
+            let name = name.clone();
+            let session = match req.guard::<Session>().await {
+                request::Outcome::Success(session) => session,
+                _ => return request::Outcome::Success(RoomAccess::NotAttempted),
+            };
+            match conn.run(move |c| session.get_room_attempt(c, &name)).await {
+                Ok(Some(password)) => password,
+                Ok(None) => return request::Outcome::Success(RoomAccess::NotAttempted),
+                _ => return request::Outcome::Forward(Status::InternalServerError),
+            }
+        };
+
+        if room.valid_password(&hashed_password) {
+            request::Outcome::Success(RoomAccess::Granted(Box::new(room)))
+        } else {
+            request::Outcome::Success(RoomAccess::WrongPassword)
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Room {
+    type Error = ();
+
+    /// Since rooms are password-protected, we must make sure a user is
+    /// allowed access to a room before it can interact with it. This request
+    /// guard should be the main way of checking users' access to a room.
+    ///
+    /// To test if the permission check failed because the user did not provide
+    /// a valid password, you can use an `Option<Room>` field in the function
+    /// header, like this:
+    ///
+    /// ```rust ignore
+    /// #[get("/room/<name>")]
+    /// fn test_room_access(name: RoomName, room: Option<Room>) -> &'static str {
+    ///     if room.is_some() {
+    ///         "You have access to this room."
+    ///     } else {
+    ///         "You do not have acces to this room."
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// If the reason for which access was denied matters, use `RoomAccess` instead.
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match try_outcome!(req.guard::<RoomAccess>().await) {
+            RoomAccess::Granted(room) => request::Outcome::Success(*room),
+            RoomAccess::NoSuchRoom => request::Outcome::Forward(Status::NotFound),
+            RoomAccess::WrongPassword | RoomAccess::NotAttempted => {
+                request::Outcome::Forward(Status::Unauthorized)
+            }
+        }
+    }
+}
+
+/// The content of a form used to hold login credentials for a room.
+#[derive(Clone, Deserialize, FromForm)]
+pub struct RoomLogin {
+    pub name: String,
+    /// The plaintext password of the room.
+    pub password: String,
+}
 
 impl RoomLogin {
     /// Checks if the form contains the correct credentials to log into a room.
@@ -258,3 +1367,891 @@ impl RoomLogin {
         Ok(room.valid_password(&hashed_password))
     }
 }
+
+/// Describes whether a name can be used for a new room.
+#[derive(Serialize)]
+pub struct NameAvailability {
+    /// Whether the name respects the room-name constraints.
+    pub valid: bool,
+    /// Whether the name is valid and not used by an existing room.
+    pub available: bool,
+    /// A human-readable reason for why the name cannot be used, if it can't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Information about an archived room, as it's shown to admins.
+#[derive(Serialize)]
+pub struct ArchiveInfo {
+    /// The id of the archive, which is used to restore it.
+    pub id: i32,
+    /// The name of the archived room.
+    pub name: String,
+    /// The time when the room was archived, as a Unix timestamp in milliseconds.
+    pub archived: i64,
+}
+
+/// Information about a room, as it's shown to admins.
+#[derive(Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// The time when the room was created, as a Unix timestamp in milliseconds.
+    pub creation: i64,
+    /// The username of the admin who created the room. It's missing for rooms
+    /// created from the configuration, or before creators were recorded.
+    pub created_by: Option<String>,
+}
+
+/// The initial view of a room, sent to users when they first load it.
+#[derive(Serialize)]
+pub struct Snapshot {
+    /// The room's entry message, if it has one which should be shown.
+    pub entry_message: Option<String>,
+    /// The newest threads of the room.
+    pub threads: Vec<ThreadSummary>,
+    /// Whether the room has older threads, which were left out.
+    pub has_more_threads: bool,
+}
+
+/// The content of a form used by admins to create new rooms.
+#[derive(FromForm)]
+pub struct NewRoom {
+    pub name: String,
+    /// The plaintext password of the room.
+    pub password: String,
+    /// Whether the room's messages should be encrypted at rest.
+    pub encrypted: Option<bool>,
+    /// Whether messages can reply to threads.
+    pub threading_enabled: Option<bool>,
+    /// Whether the authors of messages are stored.
+    pub store_author: Option<bool>,
+    /// Whether anyone can read the room.
+    pub public: Option<bool>,
+    /// Whether messages can be written in `CommonMark`.
+    pub markdown_enabled: Option<bool>,
+    /// Who can post messages in the room.
+    pub post_permission: Option<PostPermission>,
+    /// The name of the sanitizer profile which cleans the room's messages.
+    pub sanitizer_profile: Option<String>,
+    /// The time (as a Unix timestamp in milliseconds) when the room starts
+    /// accepting posts. If it's missing, the grace period of new rooms is used.
+    pub accepting_posts_after: Option<i64>,
+}
+
+impl NewRoom {
+    /// Returns the settings chosen for the new room.
+    ///
+    /// The settings which were not chosen are taken from the defaults.
+    pub fn settings(&self, defaults: &RoomSettings) -> RoomSettings {
+        RoomSettings {
+            encrypted: self.encrypted.unwrap_or(defaults.encrypted),
+            threading_enabled: self.threading_enabled.unwrap_or(defaults.threading_enabled),
+            store_author: self.store_author.unwrap_or(defaults.store_author),
+            public: self.public.unwrap_or(defaults.public),
+            markdown_enabled: self.markdown_enabled.unwrap_or(defaults.markdown_enabled),
+            post_permission: self.post_permission.unwrap_or(defaults.post_permission),
+            sanitizer_profile: self
+                .sanitizer_profile
+                .clone()
+                .or_else(|| defaults.sanitizer_profile.clone()),
+        }
+    }
+}
+
+/// A room which should exist when the server starts, as listed in the configuration.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProvisionedRoom {
+    pub name: String,
+    /// The plaintext password of the room.
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// A file which holds the plaintext password of the room, used if
+    /// `password` is missing. Trailing whitespace is ignored.
+    pub password_file: Option<PathBuf>,
+    /// Whether the password of the room is reset to the configured one
+    /// when the server starts, if the room already exists.
+    pub reset_password: bool,
+    /// Whether the room's messages should be encrypted at rest.
+    pub encrypted: Option<bool>,
+    /// Whether messages can reply to threads.
+    pub threading_enabled: Option<bool>,
+    /// Whether the authors of messages are stored.
+    pub store_author: Option<bool>,
+    /// Whether anyone can read the room.
+    pub public: Option<bool>,
+    /// Whether messages can be written in `CommonMark`.
+    pub markdown_enabled: Option<bool>,
+    /// Who can post messages in the room.
+    pub post_permission: Option<PostPermission>,
+    /// The name of the sanitizer profile which cleans the room's messages.
+    pub sanitizer_profile: Option<String>,
+}
+
+impl ProvisionedRoom {
+    /// Reads the password of the room from its file, if it's not given directly.
+    ///
+    /// If the file cannot be read, the reason is returned.
+    pub fn load(&mut self) -> Result<(), String> {
+        if let (None, Some(path)) = (&self.password, &self.password_file) {
+            let password = fs::read_to_string(path).map_err(|err| {
+                format!(
+                    "Could not read the password file {}: {}",
+                    path.display(),
+                    err
+                )
+            })?;
+            self.password = Some(password.trim_end().to_owned());
+        }
+        Ok(())
+    }
+
+    /// Returns the settings chosen for the room.
+    ///
+    /// The settings which were not chosen are taken from the defaults.
+    pub fn settings(&self, defaults: &RoomSettings) -> RoomSettings {
+        RoomSettings {
+            encrypted: self.encrypted.unwrap_or(defaults.encrypted),
+            threading_enabled: self.threading_enabled.unwrap_or(defaults.threading_enabled),
+            store_author: self.store_author.unwrap_or(defaults.store_author),
+            public: self.public.unwrap_or(defaults.public),
+            markdown_enabled: self.markdown_enabled.unwrap_or(defaults.markdown_enabled),
+            post_permission: self.post_permission.unwrap_or(defaults.post_permission),
+            sanitizer_profile: self
+                .sanitizer_profile
+                .clone()
+                .or_else(|| defaults.sanitizer_profile.clone()),
+        }
+    }
+}
+
+/// The content of a form used by admins to set the entry message of a room.
+#[derive(FromForm)]
+pub struct EntryMessageForm {
+    pub name: String,
+    /// The message, as HTML. An empty message removes the current one.
+    pub message: String,
+}
+
+/// The content of a form used by users to change the password of a room.
+#[derive(FromForm)]
+pub struct PasswordChange {
+    /// The current plaintext password of the room.
+    pub current_password: String,
+    /// The new plaintext password of the room.
+    pub new_password: String,
+}
+
+/// The content of a form used by admins to restore archived rooms.
+#[derive(FromForm)]
+pub struct RestoreForm {
+    /// The name of the room which receives the archived messages.
+    pub name: String,
+    /// The id of the archive.
+    pub archive: i32,
+}
+
+/// The content of a form used by admins to create invite codes.
+#[derive(FromForm)]
+pub struct InviteForm {
+    pub name: String,
+    /// How many times the invite can be used. It's unlimited if missing.
+    pub uses: Option<i32>,
+    /// For how many seconds the invite stays valid. It never expires if missing.
+    pub valid_for: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::testing::{set_clock, FakeClock};
+    use crate::sanitizer::Sanitizer;
+    use crate::sessions::Notification;
+
+    /// Creates a room with the default settings.
+    fn test_room(client: &mut Client) -> Room {
+        room_with(client, RoomSettings::default())
+    }
+
+    /// Creates a room with the given settings.
+    fn room_with(client: &mut Client, settings: RoomSettings) -> Room {
+        let name = db::testing::unique_name("room");
+        Room::create_room(
+            client,
+            name.clone(),
+            hash_password(""),
+            settings,
+            None,
+            None,
+        )
+        .unwrap();
+        Room::from_db(client, &name).unwrap()
+    }
+
+    /// Posts a message to a room, possibly as a reply.
+    fn post(client: &mut Client, room: &Room, author: &Session, reply_to: Option<i32>) {
+        let message = MessageJson {
+            content: "Hello!".into(),
+            reply_to: reply_to.map(MessageRef::Id),
+            format: MessageFormat::Markdown,
+            client_timestamp: None,
+        };
+        let options = StorageOptions {
+            max_reply_notifications: 2,
+            ..StorageOptions::default()
+        };
+        room.add_message(client, message, Some(author.id()), &options)
+            .unwrap()
+            .unwrap();
+    }
+
+    /// Checks that a notification is about a reply to the given message.
+    fn is_about(notification: &Notification, room: &Room, message: i32, reply: i32) -> bool {
+        notification.room == room.name
+            && matches!(notification.message, ShownId::Id(id) if id == message)
+            && matches!(notification.reply, ShownId::Id(id) if id == reply)
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn replies_notify_live_authors() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        let replier = Session::start_for_tests(&mut client);
+
+        post(&mut client, &room, &author, None);
+        post(&mut client, &room, &replier, Some(1));
+        post(&mut client, &room, &author, Some(1));
+
+        let notifications = author.take_notifications(&mut client, None).unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert!(is_about(&notifications[0], &room, 1, 2));
+        assert!(replier
+            .take_notifications(&mut client, None)
+            .unwrap()
+            .is_empty());
+        // Notifications are only sent once.
+        assert!(author
+            .take_notifications(&mut client, None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn reply_notifications_are_capped_per_message() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        let replier = Session::start_for_tests(&mut client);
+
+        post(&mut client, &room, &author, None);
+        post(&mut client, &room, &replier, Some(1));
+        assert_eq!(
+            author.take_notifications(&mut client, None).unwrap().len(),
+            1
+        );
+        post(&mut client, &room, &replier, Some(1));
+        post(&mut client, &room, &replier, Some(1));
+
+        // The cap counts the notifications which were already retrieved.
+        let notifications = author.take_notifications(&mut client, None).unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert!(is_about(&notifications[0], &room, 1, 3));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn replies_to_ended_sessions_are_not_notified() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        let replier = Session::start_for_tests(&mut client);
+
+        post(&mut client, &room, &author, None);
+        client
+            .execute("DELETE FROM sessions WHERE id = $1;", &[&author.id()])
+            .unwrap();
+        post(&mut client, &room, &replier, Some(1));
+
+        let notified: i64 = client
+            .query_one(
+                "SELECT COUNT(*) FROM notifications WHERE room = $1;",
+                &[&room.name],
+            )
+            .unwrap()
+            .get(0);
+        assert_eq!(notified, 0);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn invites_are_not_redeemed_past_their_uses() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let code = Room::create_invite(&mut client, &room.name, Some(2), None).unwrap();
+
+        for _ in 0..2 {
+            let (name, password) = Room::redeem_invite(&mut client, &code).unwrap();
+            assert_eq!(name, room.name);
+            assert_eq!(password, room.password);
+        }
+        assert!(Room::redeem_invite(&mut client, &code).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn expired_invites_are_not_redeemed() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let expired = clock::now_millis() - 1;
+        let code = Room::create_invite(&mut client, &room.name, None, Some(expired)).unwrap();
+
+        assert!(Room::redeem_invite(&mut client, &code).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn raw_ids_are_refused_when_public_ids_are_enabled() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let mut room = test_room(&mut client);
+        assert_eq!(room.message_id(&MessageRef::Id(7)), Some(7));
+
+        room.init_public_ids(Some("secret"));
+        let public = PublicIds::new("secret", room.table_id).encode(7);
+        assert_eq!(room.message_id(&MessageRef::Id(7)), None);
+        assert_eq!(room.message_id(&MessageRef::Public(public)), Some(7));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn rooms_with_the_same_name_can_be_archived() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        post(&mut client, &room, &author, None);
+        let first = Room::archive_room(&mut client, &room.name).unwrap();
+        let recreated = Room::create_room(
+            &mut client,
+            room.name.clone(),
+            hash_password(""),
+            RoomSettings::default(),
+            None,
+            None,
+        );
+        assert!(recreated.is_ok());
+        let second = Room::archive_room(&mut client, &room.name).unwrap();
+        assert_ne!(first, second);
+
+        let archives = Room::archived_rooms(&mut client).unwrap();
+        let named: Vec<i32> = archives
+            .iter()
+            .filter(|archive| archive.name == room.name)
+            .map(|archive| archive.id)
+            .collect();
+        assert_eq!(named, [first, second]);
+
+        let target = test_room(&mut client);
+        assert!(Room::restore_archive(&mut client, &target.name, first).unwrap());
+        let restored = Room::from_db(&mut client, &target.name).unwrap();
+        assert_eq!(restored.table_id, first);
+        let archives = Room::archived_rooms(&mut client).unwrap();
+        assert!(archives.iter().all(|archive| archive.id != first));
+        assert!(archives.iter().any(|archive| archive.id == second));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn replies_start_threads_when_threading_is_disabled() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = room_with(
+            &mut client,
+            RoomSettings {
+                threading_enabled: false,
+                ..RoomSettings::default()
+            },
+        );
+        let author = Session::start_for_tests(&mut client);
+
+        post(&mut client, &room, &author, None);
+        post(&mut client, &room, &author, Some(1));
+
+        let sql = format!(
+            "SELECT reply_to FROM messages{} ORDER BY id;",
+            room.table_id
+        );
+        let replies: Vec<Option<i32>> = client
+            .query(&sql, &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        assert_eq!(replies, [None, None]);
+    }
+
+    /// Checks if a table exists.
+    fn table_exists(client: &mut Client, table: &str) -> bool {
+        client
+            .query_one("SELECT to_regclass($1) IS NOT NULL;", &[&table])
+            .unwrap()
+            .get(0)
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn deleted_rooms_lose_their_messages() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let table = format!("messages{}", room.table_id);
+
+        assert!(Room::delete_room(&mut client, &room.name).unwrap());
+        assert!(!Room::exists(&mut client, &room.name).unwrap());
+        assert!(!table_exists(&mut client, &table));
+        assert!(!Room::delete_room(&mut client, &room.name).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn orphan_message_tables_are_dropped() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let kept = test_room(&mut client);
+        let archived = test_room(&mut client);
+        let archive = Room::archive_room(&mut client, &archived.name).unwrap();
+        // An older server could fail between deleting a room and its messages.
+        let orphan = test_room(&mut client);
+        client
+            .execute("DELETE FROM rooms WHERE name = $1;", &[&orphan.name])
+            .unwrap();
+
+        Room::drop_orphan_tables(&mut client).unwrap();
+        assert!(!table_exists(
+            &mut client,
+            &format!("messages{}", orphan.table_id)
+        ));
+        assert!(table_exists(
+            &mut client,
+            &format!("messages{}", kept.table_id)
+        ));
+        assert!(table_exists(
+            &mut client,
+            &format!("archived_messages{}", archive)
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn broadcasts_reach_every_room() {
+        let mut connection = db::testing::isolated();
+        let mut client = connection.client();
+        let rooms = [test_room(&mut client), test_room(&mut client)];
+        let author = Session::start_for_tests(&mut client);
+        post(&mut client, &rooms[0], &author, None);
+
+        let failed = Room::broadcast(
+            &mut client,
+            "Maintenance tonight",
+            None,
+            &StorageOptions::default(),
+        )
+        .unwrap();
+        assert!(failed.is_empty());
+        for room in &rooms {
+            let sql = format!(
+                "SELECT content, system FROM messages{} ORDER BY id DESC LIMIT 1;",
+                room.table_id
+            );
+            let latest = client.query_one(&sql, &[]).unwrap();
+            let (content, system): (String, bool) = (latest.get(0), latest.get(1));
+            assert_eq!(content, "<p>Maintenance tonight</p>\n");
+            assert!(system);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn authors_are_only_stored_if_the_room_wants_them() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let author = Session::start_for_tests(&mut client);
+        for store_author in [true, false] {
+            let room = room_with(
+                &mut client,
+                RoomSettings {
+                    store_author,
+                    ..RoomSettings::default()
+                },
+            );
+            post(&mut client, &room, &author, None);
+
+            let sql = format!("SELECT author FROM messages{};", room.table_id);
+            let stored: Option<String> = client.query_one(&sql, &[]).unwrap().get(0);
+            assert_eq!(stored, store_author.then(|| author.id()));
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn messages_posted_at_once_are_each_sent_once() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+
+        // Both messages get the same timestamp.
+        set_clock(Box::new(FakeClock::new(clock::now_millis())));
+        post(&mut client, &room, &author, None);
+        post(&mut client, &room, &author, None);
+
+        let ids = |updates: &Updates| updates.messages.iter().map(Message::id).collect::<Vec<_>>();
+        let (updates, end) = room.get_updates_after(&mut client, None, 1).unwrap();
+        assert_eq!(ids(&updates), [1]);
+        assert!(updates.has_more);
+        let (updates, end) = room.get_updates_after(&mut client, Some(end), 1).unwrap();
+        assert_eq!(ids(&updates), [2]);
+        let (updates, _) = room.get_updates_after(&mut client, Some(end), 1).unwrap();
+        assert!(updates.messages.is_empty());
+        assert!(!updates.has_more);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn sessions_count_their_own_messages() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        let other = Session::start_for_tests(&mut client);
+        let silent = Session::start_for_tests(&mut client);
+
+        post(&mut client, &room, &author, None);
+        post(&mut client, &room, &other, Some(1));
+        post(&mut client, &room, &author, Some(1));
+
+        let mut count =
+            |session: &Session| room.count_messages_by(&mut client, &session.id()).unwrap();
+        assert_eq!(count(&author), 2);
+        assert_eq!(count(&other), 1);
+        assert_eq!(count(&silent), 0);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn gaps_are_filled_exactly() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        for _ in 0..6 {
+            post(&mut client, &room, &author, None);
+        }
+
+        let ids = |updates: &Updates| updates.messages.iter().map(Message::id).collect::<Vec<_>>();
+        let updates = room.get_updates_in_range(&mut client, 2, 4, 10).unwrap();
+        assert_eq!(ids(&updates), [2, 3, 4]);
+        assert!(!updates.has_more);
+        let updates = room.get_updates_in_range(&mut client, 2, 4, 2).unwrap();
+        assert_eq!(ids(&updates), [2, 3]);
+        assert!(updates.has_more);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn snapshots_show_the_newest_threads_and_continue_with_updates() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        let clock = FakeClock::new(clock::now_millis());
+        set_clock(Box::new(clock.clone()));
+        for reply_to in [None, None, None, Some(1)] {
+            post(&mut client, &room, &author, reply_to);
+            clock.advance(1);
+        }
+
+        let (snapshot, last_change) = room.snapshot(&mut client, ThreadOrder::Created, 2).unwrap();
+        let threads: Vec<_> = snapshot
+            .threads
+            .iter()
+            .map(|thread| (thread.root.id(), thread.replies))
+            .collect();
+        assert_eq!(threads, [(3, 0), (2, 0)]);
+        assert!(snapshot.has_more_threads);
+
+        let ids = |updates: &Updates| updates.messages.iter().map(Message::id).collect::<Vec<_>>();
+        let (updates, _) = room
+            .get_updates_after(&mut client, Some(last_change), 10)
+            .unwrap();
+        assert!(updates.messages.is_empty());
+        post(&mut client, &room, &author, Some(2));
+        let (updates, _) = room
+            .get_updates_after(&mut client, Some(last_change), 10)
+            .unwrap();
+        assert_eq!(ids(&updates), [5]);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn new_posts_count_as_unread_until_polled() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        let reader = Session::start_for_tests(&mut client);
+        reader
+            .save_room_attempt(&mut client, &room.name, &hash_password(""))
+            .unwrap();
+        let unread =
+            |client: &mut Client| Room::unread_counts(client, &reader, 100).unwrap()[&room.name];
+
+        post(&mut client, &room, &author, None);
+        post(&mut client, &room, &author, None);
+        assert_eq!(unread(&mut client), 2);
+
+        let (_, last_change) = room.get_updates_after(&mut client, None, 10).unwrap();
+        reader
+            .save_room_update(&mut client, &room.name, last_change)
+            .unwrap();
+        assert_eq!(unread(&mut client), 0);
+
+        let edit = EditJson {
+            content: "Edited".into(),
+            format: MessageFormat::Markdown,
+        };
+        let outcome = room
+            .edit_message(&mut client, 1, edit, None, &StorageOptions::default(), 0)
+            .unwrap();
+        assert!(matches!(outcome, Ok(EditOutcome::Edited)));
+        assert_eq!(unread(&mut client), 0);
+
+        post(&mut client, &room, &author, Some(1));
+        assert_eq!(unread(&mut client), 1);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn markdown_is_shown_literally_if_disabled() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        for markdown_enabled in [true, false] {
+            let room = room_with(
+                &mut client,
+                RoomSettings {
+                    markdown_enabled,
+                    ..RoomSettings::default()
+                },
+            );
+            let message = MessageJson {
+                content: "# heading".into(),
+                reply_to: None,
+                format: MessageFormat::Markdown,
+                client_timestamp: None,
+            };
+            room.add_message(&mut client, message, None, &StorageOptions::default())
+                .unwrap()
+                .unwrap();
+
+            let sql = format!("SELECT content FROM messages{};", room.table_id);
+            let stored: String = client.query_one(&sql, &[]).unwrap().get(0);
+            assert_eq!(stored.contains("<h1>heading</h1>"), markdown_enabled);
+            assert_eq!(stored.contains("# heading"), !markdown_enabled);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn messages_are_numbered_per_room() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let rooms = [test_room(&mut client), test_room(&mut client)];
+        let author = Session::start_for_tests(&mut client);
+        for room in [&rooms[0], &rooms[1], &rooms[0], &rooms[0], &rooms[1]] {
+            post(&mut client, room, &author, None);
+        }
+
+        let numbers = |client: &mut Client, room: &Room| -> Vec<i64> {
+            let sql = format!("SELECT seq FROM messages{} ORDER BY id;", room.table_id);
+            let rows = client.query(&sql, &[]).unwrap();
+            rows.into_iter().map(|row| row.get(0)).collect()
+        };
+        assert_eq!(numbers(&mut client, &rooms[0]), [1, 2, 3]);
+        assert_eq!(numbers(&mut client, &rooms[1]), [1, 2]);
+
+        // Each update continues from the previous one.
+        let room = &rooms[0];
+        let mut received = Vec::new();
+        let mut last_change = None;
+        loop {
+            let (updates, end) = room.get_updates_after(&mut client, last_change, 1).unwrap();
+            received.extend(updates.messages.iter().map(Message::id));
+            last_change = Some(end);
+            if !updates.has_more {
+                break;
+            }
+        }
+        assert_eq!(received, [1, 2, 3]);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn rooms_refuse_posts_until_they_open() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let clock = FakeClock::new(1_000_000);
+        set_clock(Box::new(clock.clone()));
+
+        let name = db::testing::unique_name("room");
+        let settings = RoomSettings::default();
+        Room::create_room(
+            &mut client,
+            name.clone(),
+            hash_password(""),
+            settings,
+            Some(1_060_000),
+            None,
+        )
+        .unwrap();
+        let room = Room::from_db(&mut client, &name).unwrap();
+        let author = Session::start_for_tests(&mut client);
+
+        let message = || MessageJson {
+            content: "Hello!".into(),
+            reply_to: None,
+            format: MessageFormat::Markdown,
+            client_timestamp: None,
+        };
+        let options = StorageOptions::default();
+        let result = room.add_message(&mut client, message(), Some(author.id()), &options);
+        assert!(matches!(result, Ok(Err(Rejection::NotOpen))));
+
+        clock.advance(60_000);
+        let result = room.add_message(&mut client, message(), Some(author.id()), &options);
+        assert!(matches!(result, Ok(Ok(_))));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn sources_are_stored_as_written() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+        let other = Session::start_for_tests(&mut client);
+
+        let source = "**Hello**, <i>there</i>!\n\n- one\n- two  ";
+        let message = MessageJson {
+            content: source.into(),
+            reply_to: None,
+            format: MessageFormat::Markdown,
+            client_timestamp: None,
+        };
+        let options = StorageOptions {
+            store_source: true,
+            ..StorageOptions::default()
+        };
+        room.add_message(&mut client, message, Some(author.id()), &options)
+            .unwrap()
+            .unwrap();
+
+        let stored = room.message_source(&mut client, 1, Some(&author.id()));
+        assert_eq!(stored.unwrap().as_deref(), Some(source));
+        // Only the author, or an admin, can read the source.
+        let stored = room.message_source(&mut client, 1, Some(&other.id()));
+        assert_eq!(stored.unwrap(), None);
+        let stored = room.message_source(&mut client, 1, None);
+        assert_eq!(stored.unwrap().as_deref(), Some(source));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn rooms_clean_messages_with_their_sanitizer_profiles() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let strict = room_with(
+            &mut client,
+            RoomSettings {
+                sanitizer_profile: Some("strict".into()),
+                ..RoomSettings::default()
+            },
+        );
+        let lenient = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+
+        let strict_sanitizer = Sanitizer {
+            url_schemes: Some(["https".to_owned()].into()),
+            ..Sanitizer::default()
+        };
+        let options = StorageOptions {
+            sanitizer_profiles: [("strict".to_owned(), strict_sanitizer)].into(),
+            ..StorageOptions::default()
+        };
+        let stored = |client: &mut Client, room: &Room| -> String {
+            let message = MessageJson {
+                content: "Write [to me](mailto:me@example.com).".into(),
+                reply_to: None,
+                format: MessageFormat::Markdown,
+                client_timestamp: None,
+            };
+            room.add_message(client, message, Some(author.id()), &options)
+                .unwrap()
+                .unwrap();
+            let sql = format!("SELECT content FROM messages{};", room.table_id);
+            client.query_one(&sql, &[]).unwrap().get(0)
+        };
+
+        let strict = stored(&mut client, &strict);
+        let lenient = stored(&mut client, &lenient);
+        assert!(!strict.contains("mailto:"));
+        assert!(lenient.contains("mailto:me@example.com"));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn client_timestamps_are_kept_if_close_to_the_server_time() {
+        let mut connection = db::testing::connect();
+        let mut client = connection.client();
+        let now = 1_600_000_000_000;
+        set_clock(Box::new(FakeClock::new(now)));
+        let room = test_room(&mut client);
+        let author = Session::start_for_tests(&mut client);
+
+        let options = StorageOptions {
+            client_timestamp_window_secs: Some(60),
+            ..StorageOptions::default()
+        };
+        let mut post = |client_timestamp| {
+            let message = MessageJson {
+                content: "Hello!".into(),
+                reply_to: None,
+                format: MessageFormat::Markdown,
+                client_timestamp: Some(client_timestamp),
+            };
+            room.add_message(&mut client, message, Some(author.id()), &options)
+                .unwrap()
+        };
+        assert!(post(now - 30_000).is_ok());
+        assert!(matches!(
+            post(now - 120_000),
+            Err(Rejection::InvalidTimestamp)
+        ));
+        assert!(matches!(
+            post(now + 120_000),
+            Err(Rejection::InvalidTimestamp)
+        ));
+
+        let (updates, _) = room.get_updates_after(&mut client, None, 10).unwrap();
+        assert_eq!(updates.messages.len(), 1);
+        let message = serde_json::to_value(&updates.messages[0]).unwrap();
+        assert_eq!(message["client_timestamp"], now - 30_000);
+    }
+}
@@ -0,0 +1,251 @@
+//! Module for sanitizing the HTML received from users.
+//!
+//! Messages and the welcome message are cleaned with [ammonia](https://docs.rs/ammonia)
+//! before being stored, to prevent attacks like HTML-injection. Some of the
+//! sanitizer's rules can be changed through the `sanitizer` section of the
+//! configuration, in which case they apply to all sanitized HTML. Rooms can
+//! choose other rules instead, from the named profiles of the
+//! `sanitizer_profiles` section.
+//!
+//! Even clean HTML can be slow to render if it's deeply nested, or if it has
+//! very many elements. Cleaned HTML which exceeds the configured limits is
+//! refused, instead of being stored.
+//!
+//! Optionally, users can be told when the sanitizer removed some elements of
+//! their messages, so they notice that some of their formatting was dropped.
+
+use std::collections::HashSet;
+
+use ammonia::Builder;
+use serde::{Deserialize, Serialize};
+
+/// The elements which cannot have content, so they are never closed.
+const VOID_ELEMENTS: &[&str] = &["area", "br", "col", "hr", "img", "wbr"];
+
+/// Holds the configurable rules of the sanitizer.
+///
+/// Rules which are not configured keep ammonia's defaults.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Sanitizer {
+    /// The URL schemes allowed in links and image sources, such as `https`
+    /// or `mailto`. URLs with other schemes are removed.
+    pub url_schemes: Option<HashSet<String>>,
+    /// The maximum depth to which elements can be nested.
+    pub max_depth: usize,
+    /// The maximum number of elements.
+    pub max_elements: usize,
+    /// Whether users are warned when elements of their messages are removed.
+    pub report_removed_elements: bool,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Self {
+            url_schemes: None,
+            max_depth: 32,
+            max_elements: 5000,
+            report_removed_elements: false,
+        }
+    }
+}
+
+/// The error returned for HTML which is too complex to be stored.
+#[derive(Debug)]
+pub struct TooComplex;
+
+impl Sanitizer {
+    /// Cleans an HTML string, removing everything which is not allowed.
+    ///
+    /// It fails if the cleaned HTML is nested too deeply, or has too many elements.
+    pub fn clean(&self, html: &str) -> Result<String, TooComplex> {
+        let mut builder = Builder::default();
+        if let Some(schemes) = &self.url_schemes {
+            builder.url_schemes(schemes.iter().map(String::as_str).collect());
+        }
+        let html = builder.clean(html).to_string();
+        self.check_complexity(&html).and(Ok(html))
+    }
+
+    /// Checks that cleaned HTML does not exceed the limits on its complexity.
+    ///
+    /// The cleaner escapes the `<` characters of text, so each one starts a tag.
+    fn check_complexity(&self, html: &str) -> Result<(), TooComplex> {
+        let (mut depth, mut elements) = (0usize, 0usize);
+        let mut rest = html;
+        while let Some(start) = rest.find('<') {
+            let tag = &rest[start + 1..];
+            let end = tag_end(tag);
+            rest = tag.get(end + 1..).unwrap_or_default();
+
+            let tag = &tag[..end];
+            if tag.starts_with('/') {
+                depth = depth.saturating_sub(1);
+                continue;
+            }
+            elements += 1;
+            let name = tag
+                .split(|c: char| c.is_ascii_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if !VOID_ELEMENTS.contains(&name.as_str()) {
+                depth += 1;
+            }
+
+            if depth > self.max_depth || elements > self.max_elements {
+                return Err(TooComplex);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts the elements of an HTML string, by counting their opening tags.
+///
+/// Closing tags, comments and doctypes are not counted.
+pub fn count_elements(html: &str) -> usize {
+    let mut count = 0;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let tag = &rest[start + 1..];
+        let end = tag_end(tag);
+        if !tag.starts_with(['/', '!']) {
+            count += 1;
+        }
+        rest = tag.get(end + 1..).unwrap_or_default();
+    }
+    count
+}
+
+/// Checks if an HTML string consists only of links, meaning it has at least
+/// one `a` element, and all its text and images are inside `a` elements.
+pub fn is_only_links(html: &str) -> bool {
+    let mut links = 0;
+    let mut depth = 0usize;
+    let mut rest = html;
+    loop {
+        let start = rest.find('<').unwrap_or(rest.len());
+        if depth == 0 && !rest[..start].trim().is_empty() {
+            return false;
+        }
+        let tag = match rest.get(start + 1..) {
+            Some(tag) => tag,
+            None => return links > 0,
+        };
+        let end = tag_end(tag);
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect();
+        match (tag.starts_with('/'), name.to_ascii_lowercase().as_str()) {
+            (false, "a") => {
+                links += 1;
+                depth += 1;
+            }
+            (true, "a") => depth = depth.saturating_sub(1),
+            (false, "img") if depth == 0 => return false,
+            _ => {}
+        }
+        rest = tag.get(end + 1..).unwrap_or_default();
+    }
+}
+
+/// Returns the position of the `>` which ends a tag, skipping quoted attribute values.
+fn tag_end(tag: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '>') => return i,
+            _ => {}
+        }
+    }
+    tag.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(depth: usize) -> String {
+        format!("{}text{}", "<b>".repeat(depth), "</b>".repeat(depth))
+    }
+
+    #[test]
+    fn deeply_nested_html_is_refused() {
+        let sanitizer = Sanitizer {
+            max_depth: 4,
+            ..Sanitizer::default()
+        };
+        assert_eq!(sanitizer.clean(&nested(4)).unwrap(), nested(4));
+        assert!(sanitizer.clean(&nested(5)).is_err());
+    }
+
+    #[test]
+    fn void_and_sibling_elements_do_not_nest() {
+        let sanitizer = Sanitizer {
+            max_depth: 2,
+            ..Sanitizer::default()
+        };
+        let html = "<p><b>a</b><br><i>b</i><br></p><p>c</p>";
+        assert_eq!(sanitizer.clean(html).unwrap(), html);
+    }
+
+    #[test]
+    fn html_with_too_many_elements_is_refused() {
+        let sanitizer = Sanitizer {
+            max_elements: 3,
+            ..Sanitizer::default()
+        };
+        assert!(sanitizer.clean("<p>a</p><p>b</p><p>c</p>").is_ok());
+        assert!(sanitizer.clean("<p>a</p><p>b</p><p>c</p><p>d</p>").is_err());
+    }
+
+    #[test]
+    fn escaped_text_and_attributes_are_not_tags() {
+        let sanitizer = Sanitizer {
+            max_depth: 1,
+            max_elements: 1,
+            ..Sanitizer::default()
+        };
+        assert!(sanitizer.clean("<p>1 &lt; 2</p>").is_ok());
+        assert!(sanitizer.clean(r#"<a title="<b><b>">x</a>"#).is_ok());
+    }
+
+    #[test]
+    fn links_keep_only_allowed_schemes() {
+        let default = Sanitizer::default();
+        assert_eq!(
+            default
+                .clean(r#"<a href="https://example.com">x</a>"#)
+                .unwrap(),
+            r#"<a href="https://example.com" rel="noopener noreferrer">x</a>"#
+        );
+        assert_eq!(
+            default
+                .clean(r#"<a href="javascript:alert(1)">x</a>"#)
+                .unwrap(),
+            r#"<a rel="noopener noreferrer">x</a>"#
+        );
+        assert_eq!(
+            default.clean(r#"<a href="matrix:r/room">x</a>"#).unwrap(),
+            r#"<a rel="noopener noreferrer">x</a>"#
+        );
+
+        let custom = Sanitizer {
+            url_schemes: Some(["https", "matrix"].map(String::from).into()),
+            ..Sanitizer::default()
+        };
+        assert_eq!(
+            custom.clean(r#"<a href="matrix:r/room">x</a>"#).unwrap(),
+            r#"<a href="matrix:r/room" rel="noopener noreferrer">x</a>"#
+        );
+        assert_eq!(
+            custom.clean(r#"<a href="mailto:a@b.c">x</a>"#).unwrap(),
+            r#"<a rel="noopener noreferrer">x</a>"#
+        );
+    }
+}
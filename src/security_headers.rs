@@ -0,0 +1,125 @@
+//! Module for adding security headers to the server's responses.
+//!
+//! Messages are sanitized before being stored, but browsers can still be told
+//! to restrict what pages are allowed to do, as a second line of defense.
+//! For example, the `Content-Security-Policy` header stops injected scripts
+//! from running, even if some of them get past the sanitizer.
+//!
+//! The headers can be changed through the `security_headers` section of the
+//! configuration. The default policy allows the scripts and fonts used by the
+//! front-end, which are served by the server itself or loaded from jsDelivr and
+//! Google Fonts. The pages do not use inline scripts, so none are allowed.
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ForumConfig;
+
+/// Holds the values of the security headers. Empty values disable their headers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    /// The value of the `Content-Security-Policy` header.
+    pub content_security_policy: String,
+    /// The value of the `X-Frame-Options` header.
+    pub frame_options: String,
+    /// The value of the `Referrer-Policy` header.
+    pub referrer_policy: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            content_security_policy: "default-src 'self'; \
+                script-src 'self' https://cdn.jsdelivr.net; \
+                style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; \
+                font-src 'self' https://fonts.gstatic.com; \
+                img-src 'self' https: data:; \
+                object-src 'none'; \
+                base-uri 'self'; \
+                form-action 'self'; \
+                frame-ancestors 'none'"
+                .into(),
+            frame_options: "DENY".into(),
+            referrer_policy: "same-origin".into(),
+        }
+    }
+}
+
+/// A fairing which adds the configured security headers to all responses.
+///
+/// It should be attached after the `ConfigFairing`.
+pub struct SecurityHeadersFairing;
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeadersFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security Headers Fairing",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        // Browsers should not guess the types of responses,
+        // since they might treat messages as scripts.
+        res.set_header(Header::new("X-Content-Type-Options", "nosniff"));
+
+        let headers = match req.rocket().state::<ForumConfig>() {
+            Some(config) => &config.security_headers,
+            None => return,
+        };
+        let configured = [
+            ("Content-Security-Policy", &headers.content_security_policy),
+            ("X-Frame-Options", &headers.frame_options),
+            ("Referrer-Policy", &headers.referrer_policy),
+        ];
+        for (name, value) in configured {
+            if !value.is_empty() {
+                res.set_header(Header::new(name, value.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[rocket::get("/")]
+    fn index() -> &'static str {
+        "Hello!"
+    }
+
+    #[test]
+    fn responses_carry_the_configured_headers() {
+        let config = ForumConfig {
+            security_headers: SecurityHeaders {
+                content_security_policy: "default-src 'none'".into(),
+                frame_options: "SAMEORIGIN".into(),
+                referrer_policy: String::new(),
+            },
+            ..ForumConfig::default()
+        };
+        let rocket = rocket::build()
+            .manage(config)
+            .mount("/", rocket::routes![index])
+            .attach(SecurityHeadersFairing);
+        let client = Client::untracked(rocket).unwrap();
+
+        let response = client.get("/").dispatch();
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_one("Content-Security-Policy"),
+            Some("default-src 'none'")
+        );
+        assert_eq!(headers.get_one("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(headers.get_one("X-Content-Type-Options"), Some("nosniff"));
+        // Empty values disable their headers.
+        assert_eq!(headers.get_one("Referrer-Policy"), None);
+    }
+}
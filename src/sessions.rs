@@ -3,36 +3,152 @@
 //! Sessions are handled mostly server-side. Users receive cookies which
 //! identify their session, but do not contain other information themselves.
 //!
+//! Optionally, the database only holds the hashes of the session ids, while
+//! the cookies hold the ids themselves. This way, someone who reads the
+//! database cannot build valid cookies. The hashes are then used everywhere
+//! the database refers to sessions, so the server works with them instead of
+//! the ids, except when reading and writing cookies.
+//!
+//! By default, the cookies are private, meaning they are encrypted with
+//! Rocket's `secret_key`, so sessions end whenever the key changes. Servers
+//! which rotate the key can sign the cookies with a secret of their own
+//! instead, in which case the cookies hold the ids in plaintext, together
+//! with their HMAC-SHA256 signatures.
+//!
 //! This module also implements the "cleaning" behaviour of old sessions,
 //! which removes stale sessions automatically.
 //!
+//! In guest mode, visitors who only read rooms are not given sessions, so
+//! they can read public rooms without filling the sessions table.
+//!
 //! This module contributes to the incremental-updates mechanism, which allows
 //! us to send only those updates which users do not already have. To achieve
 //! this, we store the last time a user received updates for each room they
 //! visit.
+//!
+//! Sessions also collect notifications about replies to the messages they
+//! posted, until they retrieve them. The notifications end with the session.
 
-use std::time::{SystemTime, UNIX_EPOCH};
-
+use ::serde::Serialize;
+use hmac::{Hmac, Mac, NewMac};
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::Cookie;
+use rocket::figment::Figment;
+use rocket::http::{Cookie, Method};
 use rocket::outcome::try_outcome;
 use rocket::request::{FromRequest, Outcome, Request};
 use rocket::tokio::time::{sleep, Duration};
-use rocket::{Data, Rocket};
+use rocket::{Config, Data, Rocket};
 use rocket_sync_db_pools::postgres::row::Row;
-use rocket_sync_db_pools::postgres::Client;
+use sha2::{Digest, Sha256};
 
-use crate::db::{self, DbConn};
+use crate::config::{ForumConfig, SessionCookieMode};
+use crate::db::{self, Client, DbConn, GenericClient};
+use crate::messages::{PublicIds, ShownId};
+use crate::metrics::Metrics;
+use crate::query_and_map;
 use crate::*;
 
+/// For how many seconds a session is kept after its last update,
+/// unless it's an admin's session.
+pub const SESSION_TIMEOUT_SECS: i64 = 1200;
+
+/// How often (in seconds) the cleaner removes old sessions.
+pub const CLEANER_PERIOD_SECS: u64 = 300;
+
 /// The name of the cookie used to hold a session's id.
-const SESSION_ID_COOKIE: &str = "session_id";
+pub const SESSION_ID_COOKIE: &str = "session_id";
 
 /// The custom HTTP status indicating that a user's session has expired.
 const SESSION_EXPIRED: Status = Status::new(491);
 
+/// Returns the secret used to sign session cookies, if they are signed.
+fn signing_secret<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    req.rocket()
+        .state::<ForumConfig>()
+        .filter(|config| config.session_cookie == SessionCookieMode::Signed)
+        .and_then(|config| config.session_cookie_secret.as_deref())
+}
+
+/// Returns the signature of a session id, in hex.
+fn signature(secret: &str, id: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(id.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Returns the session id held by a signed cookie, if its signature is valid.
+fn verify_signed(secret: &str, value: &str) -> Option<String> {
+    let (id, signature) = value.rsplit_once('.')?;
+    if !signature.is_ascii() || signature.len() % 2 != 0 {
+        return None;
+    }
+    let signature = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(id.as_bytes());
+    mac.verify(&signature).ok()?;
+    Some(id.to_owned())
+}
+
+/// Returns the session id held by a request's session cookie, if it's valid.
+pub fn session_cookie(req: &Request<'_>) -> Option<String> {
+    match signing_secret(req) {
+        Some(secret) => {
+            let cookie = req.cookies().get(SESSION_ID_COOKIE)?;
+            verify_signed(secret, cookie.value())
+        }
+        None => req
+            .cookies()
+            .get_private(SESSION_ID_COOKIE)
+            .map(|cookie| cookie.value().to_owned()),
+    }
+}
+
+/// Gives a session cookie which holds the given session id.
+fn add_session_cookie(req: &Request<'_>, id: String) {
+    match signing_secret(req) {
+        Some(secret) => {
+            let value = format!("{}.{}", id, signature(secret, &id));
+            let cookie = Cookie::build((SESSION_ID_COOKIE, value)).http_only(true);
+            req.cookies().add(cookie);
+        }
+        None => {
+            let cookie = Cookie::build((SESSION_ID_COOKIE, id)).http_only(true);
+            req.cookies().add_private(cookie);
+        }
+    }
+}
+
+/// Removes the session cookie of a request.
+pub fn remove_session_cookie(req: &Request<'_>) {
+    if signing_secret(req).is_some() {
+        req.cookies().remove(Cookie::build(SESSION_ID_COOKIE));
+    } else {
+        req.cookies()
+            .remove_private(Cookie::build(SESSION_ID_COOKIE));
+    }
+}
+
+/// A notification telling the author of a message about a reply to it.
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    /// The room of the messages.
+    pub room: String,
+    /// The message which received the reply.
+    pub message: ShownId,
+    /// The reply.
+    pub reply: ShownId,
+    /// When the reply was posted, as a Unix timestamp in milliseconds.
+    pub created: i64,
+}
+
 /// Holds relevant information about a session.
 ///
 /// It's closely tied to a row in the sessions table.
@@ -41,6 +157,7 @@ pub struct Session {
     id: String,
     last_update: i64,
     is_admin: bool,
+    admin_username: Option<String>,
 }
 
 impl Session {
@@ -56,23 +173,29 @@ impl Session {
         self.is_admin
     }
 
+    /// Returns the username of the admin account the session logged in with.
+    pub fn admin_username(&self) -> Option<&str> {
+        self.admin_username.as_deref()
+    }
+
     /// Returns the session id.
     pub fn id(&self) -> String {
         self.id.clone()
     }
 
-    /// Sets the session to belong to an administrator.
+    /// Sets the session to belong to the administrator with the given username.
     ///
     /// It makes the necessary updates to the database,
     /// and returns true if the operation succeeds.
-    pub fn make_admin(&mut self, client: &mut Client) -> bool {
+    pub fn make_admin(&mut self, client: &mut Client, username: &str) -> bool {
         match client.execute(
-            "UPDATE sessions SET is_admin = TRUE WHERE id = $1;",
-            &[&self.id],
+            "UPDATE sessions SET is_admin = TRUE, admin_username = $2 WHERE id = $1;",
+            &[&self.id, &username],
         ) {
             // The query should update exactly one row.
             Ok(1) => {
                 self.is_admin = true;
+                self.admin_username = Some(username.to_owned());
                 true
             }
             _ => false,
@@ -82,7 +205,7 @@ impl Session {
     /// Saves a room-login attempt for the user with the associated session.
     pub fn save_room_attempt(
         &self,
-        client: &mut Client,
+        client: &mut impl GenericClient,
         name: &str,
         hashed_password: &str,
     ) -> Result<(), db::Error> {
@@ -97,45 +220,176 @@ impl Session {
     }
 
     /// Retrieves the last password associated with a login attempt for a given room, if it exists.
-    pub fn get_room_attempt(&self, client: &mut Client, name: &str) -> Result<String, db::Error> {
-        query_one_row!(
+    pub fn get_room_attempt(
+        &self,
+        client: &mut Client,
+        name: &str,
+    ) -> Result<Option<String>, db::Error> {
+        Ok(client
+            .query_opt(
+                "SELECT password FROM room_attempts WHERE id = $1 AND name = $2;",
+                &[&self.id, &name],
+            )?
+            .map(|row| row.get(0)))
+    }
+
+    /// Records that the user has seen the entry message of a room.
+    ///
+    /// It returns `true` if the user had not seen it before.
+    pub fn see_entry_message(&self, client: &mut Client, name: &str) -> Result<bool, db::Error> {
+        client
+            .execute(
+                "UPDATE room_attempts SET entry_message_seen = TRUE
+                WHERE id = $1 AND name = $2 AND NOT entry_message_seen;",
+                &[&self.id, &name],
+            )
+            .map(|updated| updated == 1)
+    }
+
+    /// Returns the names of the rooms which the session can currently access,
+    /// meaning those for which its last login attempt used the right password.
+    pub fn accessible_rooms(&self, client: &mut Client) -> Result<Vec<String>, db::Error> {
+        Ok(query_and_map!(
             client,
-            "SELECT password FROM room_attempts WHERE id = $1 AND name = $2;",
-            &[&self.id, &name],
+            "SELECT rooms.name FROM room_attempts
+            JOIN rooms ON rooms.name = room_attempts.name AND rooms.password = room_attempts.password
+            WHERE room_attempts.id = $1 ORDER BY rooms.name;",
+            &[&self.id],
             |row: Row| row.get(0)
         )
+        .collect())
     }
 
-    /// Sets the given timestamp as the user's last-update time for the given room.
+    /// Checks if the session can make a login attempt for a room, given the
+    /// maximum number of rooms a session can have attempts for.
+    ///
+    /// Attempts for rooms which the session already tried to log into are
+    /// always allowed, since they replace the older attempts.
+    pub fn can_attempt_room(
+        &self,
+        client: &mut impl GenericClient,
+        name: &str,
+        max_rooms: Option<i64>,
+    ) -> Result<bool, db::Error> {
+        let max_rooms = match max_rooms {
+            Some(max_rooms) => max_rooms,
+            None => return Ok(true),
+        };
+        let others: i64 = query_one_row!(
+            client,
+            "SELECT COUNT(*) FROM room_attempts WHERE id = $1 AND name <> $2;",
+            &[&self.id, &name],
+            |row: Row| row.get(0)
+        )?;
+        Ok(others < max_rooms)
+    }
+
+    /// Removes the session's login attempt for a room and its progress through
+    /// the room's updates, so it cannot access the room until it logs in again.
+    ///
+    /// It returns `false` if the session had not tried to log into the room.
+    pub fn forget_room(&self, client: &mut Client, name: &str) -> Result<bool, db::Error> {
+        let mut transaction = client.transaction()?;
+        let forgotten = transaction.execute(
+            "DELETE FROM room_attempts WHERE id = $1 AND name = $2;",
+            &[&self.id, &name],
+        )?;
+        transaction.execute(
+            "DELETE FROM room_updates WHERE id = $1 AND name = $2;",
+            &[&self.id, &name],
+        )?;
+        transaction.commit().and(Ok(forgotten == 1))
+    }
+
+    /// Removes the session's login attempts and its progress through the
+    /// updates of all rooms, so it cannot access any room until it logs in
+    /// again. The session itself is kept.
+    ///
+    /// It returns the number of rooms for which login attempts were removed.
+    pub fn forget_all_rooms(&self, client: &mut Client) -> Result<u64, db::Error> {
+        let mut transaction = client.transaction()?;
+        let forgotten =
+            transaction.execute("DELETE FROM room_attempts WHERE id = $1;", &[&self.id])?;
+        transaction.execute("DELETE FROM room_updates WHERE id = $1;", &[&self.id])?;
+        transaction.commit().and(Ok(forgotten))
+    }
+
+    /// Saves the number of the last change of a room which was sent to the user.
+    ///
+    /// The time of the update is saved too.
     pub fn save_room_update(
         &self,
         client: &mut Client,
         name: &str,
-        timestamp: i64,
+        last_change: i64,
     ) -> Result<(), db::Error> {
         client
             .execute(
-                "INSERT INTO room_updates (id, name, timestamp) VALUES ($1, $2, $3)
-            ON CONFLICT (id, name) DO UPDATE SET timestamp = excluded.timestamp;",
-                &[&self.id, &name, &timestamp],
+                "INSERT INTO room_updates (id, name, timestamp, last_change) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (id, name) DO UPDATE
+            SET timestamp = excluded.timestamp, last_change = excluded.last_change;",
+                &[&self.id, &name, &clock::now_secs(), &last_change],
             )
             .and(Ok(()))
             .map_err(Into::into)
     }
 
-    /// Retrieves the timestamp of the last time a user got updates for a given room.
-    pub fn get_room_update(&self, client: &mut Client, name: &str) -> Result<i64, db::Error> {
-        query_one_row!(
+    /// Retrieves the number of the last change of a room which was sent to the user.
+    ///
+    /// It returns `None` if the user has not received any updates from the room.
+    pub fn get_room_update(
+        &self,
+        client: &mut Client,
+        name: &str,
+    ) -> Result<Option<i64>, db::Error> {
+        Ok(client
+            .query_opt(
+                "SELECT last_change FROM room_updates WHERE id = $1 AND name = $2;",
+                &[&self.id, &name],
+            )?
+            .and_then(|row| row.get(0)))
+    }
+
+    /// Removes the session's notifications and returns them, oldest first.
+    ///
+    /// If a secret is given for public ids, the messages are referred to by
+    /// their public ids.
+    pub fn take_notifications(
+        &self,
+        client: &mut Client,
+        public_id_secret: Option<&str>,
+    ) -> Result<Vec<Notification>, db::Error> {
+        let mut notifications: Vec<_> = query_and_map!(
             client,
-            "SELECT timestamp FROM room_updates WHERE id = $1 AND name = $2;",
-            &[&self.id, &name],
-            |row: Row| row.get(0)
+            "DELETE FROM notifications USING rooms
+            WHERE notifications.session = $1 AND rooms.name = notifications.room
+            RETURNING notifications.id, notifications.room, rooms.table_id,
+            notifications.message, notifications.reply, notifications.created;",
+            &[&self.id],
+            |row: Row| {
+                let public_ids = public_id_secret.map(|secret| PublicIds::new(secret, row.get(2)));
+                let notification = Notification {
+                    room: row.get(1),
+                    message: ShownId::new(row.get(3), public_ids.as_ref()),
+                    reply: ShownId::new(row.get(4), public_ids.as_ref()),
+                    created: row.get(5),
+                };
+                (row.get::<_, i32>(0), notification)
+            }
         )
+        .collect();
+
+        // Deleted rows are returned in no particular order.
+        notifications.sort_by_key(|(id, _)| *id);
+        Ok(notifications
+            .into_iter()
+            .map(|(_, notification)| notification)
+            .collect())
     }
 
     /// Keeps a session "alive" by updating its timestamp.
     fn keep_alive(&mut self, client: &mut Client) -> Result<(), db::Error> {
-        self.last_update = Session::current_timestamp();
+        self.last_update = clock::now_secs();
 
         client
             .execute(
@@ -150,30 +404,57 @@ impl Session {
     fn from_db(client: &mut Client, id: &str) -> Result<Session, db::Error> {
         query_one_row!(
             client,
-            "SELECT last_update, is_admin FROM sessions WHERE id = $1;",
+            "SELECT last_update, is_admin, admin_username FROM sessions WHERE id = $1;",
             &[&id],
             |row: Row| Session {
                 id: String::from(id),
                 last_update: row.get(0),
                 is_admin: row.get(1),
+                admin_username: row.get(2),
             }
         )
     }
 
     /// Tries to start a new session and inserts it into the database.
-    fn start_new(client: &mut Client) -> Result<String, db::Error> {
+    ///
+    /// It returns the id which should be sent to the user. If `hash_id` is set,
+    /// only the hash of the id is stored.
+    fn start_new(client: &mut Client, hash_id: bool) -> Result<String, db::Error> {
         let id = Session::new_session_id();
-        let last_update = Session::current_timestamp();
+        let now = clock::now_secs();
 
         client
             .execute(
-                "INSERT INTO sessions (id, last_update, is_admin) VALUES ($1, $2, $3);",
-                &[&id, &last_update, &false],
+                "INSERT INTO sessions (id, last_update, is_admin, created_at) VALUES ($1, $2, $3, $2);",
+                &[&Session::stored_id(&id, hash_id), &now, &false],
             )
             .and(Ok(id))
             .map_err(Into::into)
     }
 
+    /// Starts a new session, for tests which need one.
+    #[cfg(test)]
+    pub fn start_for_tests(client: &mut Client) -> Session {
+        let id = Session::start_new(client, false).expect("The session should be started");
+        Session::from_db(client, &id).expect("The session should exist")
+    }
+
+    /// Returns the id under which a session is stored, given the id sent to the user.
+    fn stored_id(id: &str, hash_id: bool) -> String {
+        if hash_id {
+            format!("{:x}", Sha256::digest(id.as_bytes()))
+        } else {
+            id.to_owned()
+        }
+    }
+
+    /// Checks if the server stores the hashes of session ids, instead of the ids.
+    fn hashes_ids(req: &Request<'_>) -> bool {
+        req.rocket()
+            .state::<ForumConfig>()
+            .is_some_and(|config| config.hash_session_ids)
+    }
+
     /// Returns a (probably) new, valid session id.
     fn new_session_id() -> String {
         const ID_LEN: usize = 64;
@@ -182,16 +463,6 @@ impl Session {
             .take(ID_LEN)
             .collect()
     }
-
-    /// Returns the current timestamp, as it should be saved in the database.
-    ///
-    /// Timestamps represent Unix time points.
-    fn current_timestamp() -> i64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Error while calculating the session timestamp.")
-            .as_secs() as i64
-    }
 }
 
 #[rocket::async_trait]
@@ -202,8 +473,8 @@ impl<'r> FromRequest<'r> for Session {
     /// cookie to identify an existing entry in the sessions table.
     async fn from_request(req: &'r Request<'_>) -> Outcome<Session, Self::Error> {
         // Try to retrieve the user's existing session, if it exists.
-        let session_id = match req.cookies().get_private(SESSION_ID_COOKIE) {
-            Some(cookie) => cookie.value().parse::<String>().unwrap(),
+        let session_id = match session_cookie(req) {
+            Some(id) => Session::stored_id(&id, Session::hashes_ids(req)),
             None => return Outcome::Forward(Status::Unauthorized),
         };
 
@@ -224,16 +495,31 @@ impl SessionFairing {
     /// Attempts to start a "cleaner" thread which removes old sessions
     /// from the database.
     ///
-    /// The thread cleans the database every `PERIOD` seconds.
-    fn start_cleaner(conn: DbConn) {
+    /// The thread cleans the database every `CLEANER_PERIOD_SECS` seconds, removing at most
+    /// `batch_size` sessions per statement.
+    fn start_cleaner(
+        conn: DbConn,
+        metrics: Metrics,
+        batch_size: i64,
+        admin_timeout: i64,
+        max_lifetime: Option<i64>,
+    ) {
         rocket::tokio::task::spawn(async move {
             loop {
-                if conn.run(SessionFairing::delete_old).await.is_err() {
-                    eprintln!("Error while cleaning old sessions.");
+                match conn
+                    .run(move |c| {
+                        SessionFairing::delete_old(c, batch_size, admin_timeout, max_lifetime, None)
+                    })
+                    .await
+                {
+                    Ok(deleted) => metrics.sessions_expired(deleted),
+                    Err(_) => {
+                        metrics.db_error();
+                        eprintln!("Error while cleaning old sessions.");
+                    }
                 }
 
-                const PERIOD: Duration = Duration::from_secs(300);
-                sleep(PERIOD).await;
+                sleep(Duration::from_secs(CLEANER_PERIOD_SECS)).await;
             }
         });
     }
@@ -241,20 +527,82 @@ impl SessionFairing {
     /// Deletes "old" sessions from the database.
     ///
     /// A session is considered old if its last update happened more than
-    /// `TIMEOUT_SECS` seconds before the function was called.
-    fn delete_old(client: &mut Client) -> Result<(), db::Error> {
-        const TIMEOUT_SECS: i64 = 1200;
-        let too_old = Session::current_timestamp() - TIMEOUT_SECS;
+    /// `SESSION_TIMEOUT_SECS` seconds before the function was called, or more
+    /// than `admin_timeout` seconds if it's an admin's session. If `max_lifetime`
+    /// is set, sessions created more than `max_lifetime` seconds before are old
+    /// too, however active they are.
+    ///
+    /// Sessions are deleted in batches of at most `batch_size`, so a large
+    /// backlog does not keep the table locked for long. The session with the
+    /// id `keep` is never deleted. It returns the total number of deleted sessions.
+    pub fn delete_old(
+        client: &mut Client,
+        batch_size: i64,
+        admin_timeout: i64,
+        max_lifetime: Option<i64>,
+        keep: Option<&str>,
+    ) -> Result<u64, db::Error> {
+        let now = clock::now_secs();
+        let too_old = now - SESSION_TIMEOUT_SECS;
+        let admin_too_old = now - admin_timeout;
+        let created_too_early = max_lifetime.map(|lifetime| now - lifetime);
+
+        let mut total = 0;
+        loop {
+            let deleted = client.execute(
+                "DELETE FROM sessions WHERE id IN (
+                    SELECT id FROM sessions
+                    WHERE (last_update < CASE WHEN is_admin THEN $4::BIGINT ELSE $1::BIGINT END
+                            OR created_at < $5)
+                        AND id IS DISTINCT FROM $3
+                    LIMIT $2
+                );",
+                &[
+                    &too_old,
+                    &batch_size,
+                    &keep,
+                    &admin_too_old,
+                    &created_too_early,
+                ],
+            )?;
+            total += deleted;
+            if deleted < batch_size as u64 {
+                return Ok(total);
+            }
+        }
+    }
+}
 
-        client
-            .execute("DELETE FROM sessions WHERE last_update < $1;", &[&too_old])
-            .and(Ok(()))
-            .map_err(Into::into)
+/// Checks that Rocket's `secret_key` is configured, if the sessions depend on it.
+///
+/// It returns an error if the server should not start, since Rocket requires
+/// the key outside of the debug profile, or a warning if sessions will not
+/// survive a restart.
+fn check_secret_key(figment: &Figment, signed: bool) -> Result<Option<&'static str>, &'static str> {
+    if figment.find_value("secret_key").is_ok() {
+        return Ok(None);
+    }
+    match (figment.profile() == Config::DEBUG_PROFILE, signed) {
+        (true, true) => Ok(None),
+        (true, false) => Ok(Some(
+            "secret_key is not configured, so sessions will end when the server restarts.",
+        )),
+        (false, true) => Err(
+            "secret_key is not configured. Rocket requires it outside of \
+            the debug profile. Since session cookies are signed, it does not \
+            need to stay the same across restarts.",
+        ),
+        (false, false) => Err(
+            "secret_key is not configured. Session cookies are encrypted \
+            with it, so it should stay the same across restarts.",
+        ),
     }
 }
 
 /// The fairing is reponsible for assigning sessions to new users, and keeping
 /// existing sessions alive. It also removes stale sessions from the database.
+///
+/// It should be attached after the `ConfigFairing` and the `MetricsFairing`.
 #[rocket::async_trait]
 impl Fairing for SessionFairing {
     fn info(&self) -> Info {
@@ -265,9 +613,39 @@ impl Fairing for SessionFairing {
     }
 
     /// Makes sure stale sessions are removed automatically by a cleaner thread.
+    ///
+    /// It also checks that Rocket's `secret_key` is configured, since private
+    /// cookies cannot be read after a restart without it. Rocket refuses to
+    /// start without the key outside of the debug profile, so the reason is
+    /// explained first. In the debug profile, only a warning is shown.
     async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let signed = rocket
+            .state::<ForumConfig>()
+            .is_some_and(|config| config.session_cookie == SessionCookieMode::Signed);
+        match check_secret_key(rocket.figment(), signed) {
+            Ok(Some(warning)) => eprintln!("Warning: {}", warning),
+            Ok(None) => {}
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                return Err(rocket);
+            }
+        }
+
+        let metrics = match rocket.state::<Metrics>() {
+            Some(metrics) => metrics.clone(),
+            None => return Err(rocket),
+        };
+        let (batch_size, admin_timeout, max_lifetime) = match rocket.state::<ForumConfig>() {
+            Some(config) => (
+                config.session_cleaner_batch_size,
+                config.admin_session_timeout_secs,
+                config.max_session_lifetime_secs,
+            ),
+            None => return Err(rocket),
+        };
+
         if let Some(conn) = DbConn::get_one(&rocket).await {
-            SessionFairing::start_cleaner(conn);
+            SessionFairing::start_cleaner(conn, metrics, batch_size, admin_timeout, max_lifetime);
             Ok(rocket)
         } else {
             Err(rocket)
@@ -278,7 +656,12 @@ impl Fairing for SessionFairing {
     ///
     /// If the user is new, the fairing creates a new session and sets the
     /// appropriate cookies. If the user already has a session, we keep it alive.
+    /// In guest mode, new users who only read rooms are not given sessions.
     async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let metrics = match req.rocket().state::<Metrics>() {
+            Some(metrics) => metrics.clone(),
+            None => return,
+        };
         let conn = match req.guard::<DbConn>().await {
             Outcome::Success(conn) => conn,
             _ => {
@@ -292,6 +675,7 @@ impl Fairing for SessionFairing {
         match req.guard::<Session>().await {
             Outcome::Success(mut session) => {
                 if conn.run(move |c| session.keep_alive(c)).await.is_err() {
+                    metrics.db_error();
                     eprintln!("Could not keep the session alive.");
                 }
                 return;
@@ -300,24 +684,183 @@ impl Fairing for SessionFairing {
             _ => {}
         };
 
+        // In guest mode, rooms can be read without a session.
+        let guest_mode = req
+            .rocket()
+            .state::<ForumConfig>()
+            .is_some_and(|config| config.guest_mode);
+        if guest_mode
+            && req.method() == Method::Get
+            && req.uri().path().segments().next() == Some("room")
+        {
+            return;
+        }
+
         // Give the user a new session.
-        if let Ok(id) = conn.run(Session::start_new).await {
-            let cookie = Cookie::build((SESSION_ID_COOKIE, id)).http_only(true);
-            req.cookies().add_private(cookie);
+        let hash_id = Session::hashes_ids(req);
+        if let Ok(id) = conn.run(move |c| Session::start_new(c, hash_id)).await {
+            add_session_cookie(req, id);
+            metrics.session_created();
         } else {
+            metrics.db_error();
             eprintln!("Could not start a new session.");
         }
     }
 }
 
 /// A catcher for SESSION_EXPIRED messages which removes a user's old session id cookie.
+///
+/// Clients which prefer JSON receive an error object instead of a redirect.
 #[catch(491)]
-pub async fn session_expired(req: &Request<'_>) -> Flash<Redirect> {
-    req.cookies()
-        .remove_private(Cookie::build(sessions::SESSION_ID_COOKIE));
+pub async fn session_expired(
+    req: &Request<'_>,
+) -> Either<Json<serde_json::Value>, Flash<Redirect>> {
+    remove_session_cookie(req);
 
-    Flash::error(
+    if prefers_json(req) {
+        return Either::Left(Json(json!({ "error": "session_expired" })));
+    }
+    Either::Right(Flash::error(
         Redirect::to("/"),
         "It is possible that your session expired. Try again.",
-    )
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::testing::{set_clock, FakeClock};
+    use crate::db::testing;
+
+    /// Deletes the old sessions, with admin sessions lasting twice as long.
+    fn delete_old(client: &mut Client) {
+        SessionFairing::delete_old(client, 100, 2 * SESSION_TIMEOUT_SECS, None, None).unwrap();
+    }
+
+    fn exists(client: &mut Client, session: &Session) -> bool {
+        Session::from_db(client, &session.id()).is_ok()
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn sessions_expire_after_their_timeout() {
+        let mut connection = testing::connect();
+        let mut client = connection.client();
+        // The clock is far in the past, so the sessions of other tests are not old.
+        let clock = FakeClock::new(1_600_000_000_000);
+        set_clock(Box::new(clock.clone()));
+        let user = Session::start_for_tests(&mut client);
+        let mut admin = Session::start_for_tests(&mut client);
+        assert!(admin.make_admin(&mut client, "admin"));
+
+        clock.advance(SESSION_TIMEOUT_SECS * 1000);
+        delete_old(&mut client);
+        assert!(exists(&mut client, &user));
+
+        clock.advance(1000);
+        delete_old(&mut client);
+        assert!(!exists(&mut client, &user));
+        assert!(exists(&mut client, &admin));
+
+        clock.advance(SESSION_TIMEOUT_SECS * 1000);
+        delete_old(&mut client);
+        assert!(!exists(&mut client, &admin));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn active_sessions_end_after_their_lifetime() {
+        let mut connection = testing::connect();
+        let mut client = connection.client();
+        let clock = FakeClock::new(1_600_000_000_000);
+        set_clock(Box::new(clock.clone()));
+        let mut user = Session::start_for_tests(&mut client);
+        let lifetime = 3 * SESSION_TIMEOUT_SECS;
+        let delete_old = |client: &mut Client| {
+            SessionFairing::delete_old(client, 100, SESSION_TIMEOUT_SECS, Some(lifetime), None)
+                .unwrap();
+        };
+
+        // The session is kept alive more often than it would time out.
+        for _ in 0..6 {
+            clock.advance(SESSION_TIMEOUT_SECS * 500);
+            user.keep_alive(&mut client).unwrap();
+            delete_old(&mut client);
+            assert!(exists(&mut client, &user));
+        }
+        clock.advance(1000);
+        user.keep_alive(&mut client).unwrap();
+        delete_old(&mut client);
+        assert!(!exists(&mut client, &user));
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn stale_sessions_are_removed_across_batches() {
+        let mut connection = testing::isolated();
+        let mut client = connection.client();
+        let clock = FakeClock::new(clock::now_millis());
+        set_clock(Box::new(clock.clone()));
+        let sessions: Vec<_> = (0..5)
+            .map(|_| Session::start_for_tests(&mut client))
+            .collect();
+
+        clock.advance((SESSION_TIMEOUT_SECS + 1) * 1000);
+        let deleted = SessionFairing::delete_old(&mut client, 2, SESSION_TIMEOUT_SECS, None, None);
+        assert_eq!(deleted.unwrap(), 5);
+        assert!(sessions.iter().all(|session| !exists(&mut client, session)));
+    }
+
+    #[rocket::get("/me")]
+    fn me(session: Session) -> String {
+        session.id()
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn hashed_sessions_are_found_through_their_raw_ids() {
+        let mut connection = testing::connect();
+        let mut client = connection.client();
+        let id = Session::start_new(&mut client, true).unwrap();
+        let stored = Session::stored_id(&id, true);
+        assert_ne!(stored, id);
+        assert!(Session::from_db(&mut client, &id).is_err());
+        assert!(Session::from_db(&mut client, &stored).is_ok());
+
+        let figment = Config::figment()
+            .merge(("databases.db.url", testing::url()))
+            .select(Config::DEBUG_PROFILE);
+        let config = ForumConfig {
+            hash_session_ids: true,
+            ..ForumConfig::default()
+        };
+        let rocket = rocket::custom(figment)
+            .manage(config)
+            .mount("/", rocket::routes![me])
+            .attach(DbConn::fairing());
+        let server = rocket::local::blocking::Client::tracked(rocket).unwrap();
+        let response = server
+            .get("/me")
+            .private_cookie(Cookie::new(SESSION_ID_COOKIE, id))
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), stored);
+    }
+
+    #[test]
+    fn missing_secret_keys_are_reported() {
+        let figment = |profile| Figment::new().select(profile);
+        assert!(check_secret_key(&figment(Config::RELEASE_PROFILE), false).is_err());
+        assert!(check_secret_key(&figment(Config::RELEASE_PROFILE), true).is_err());
+        assert!(check_secret_key(&figment(Config::DEBUG_PROFILE), false)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            check_secret_key(&figment(Config::DEBUG_PROFILE), true),
+            Ok(None)
+        );
+
+        let key = "hPRYyVRiMyxpw5sBB1XeCMN1kFsDCqKvBi2QJxBVHQk=";
+        let figment = figment(Config::RELEASE_PROFILE).merge(("secret_key", key));
+        assert_eq!(check_secret_key(&figment, false), Ok(None));
+    }
 }
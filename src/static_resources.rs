@@ -6,17 +6,42 @@
 //!
 //! Since static resources do not change, clients can cache them.
 //! This behaviour is implemented by the `StaticFile` custom responder.
+//!
+//! Only files inside the `static/` directory can be served. The paths of
+//! requested files are resolved before being opened, so paths which lead
+//! outside the directory, through `..` segments or symlinks, are rejected.
+
+use std::path::Path;
 
 use rocket::config::Config;
 use rocket::fs::NamedFile;
 use rocket::http::hyper::header::CACHE_CONTROL;
 use rocket::http::Header;
 use rocket::response::{self, Responder, Response};
+use rocket::tokio::fs;
 use rocket::Request;
 
+/// The directory which holds the static resources.
+const STATIC_DIR: &str = "static/";
+
 /// A static file which can be served to clients.
 pub struct StaticFile(pub NamedFile);
 
+impl StaticFile {
+    /// Opens a file, given its path relative to the static directory.
+    ///
+    /// It returns `None` if the file does not exist, or if it's
+    /// not inside the static directory.
+    pub async fn open(file: &Path) -> Option<Self> {
+        let root = fs::canonicalize(STATIC_DIR).await.ok()?;
+        let path = fs::canonicalize(root.join(file)).await.ok()?;
+        if !path.starts_with(&root) {
+            return None;
+        }
+        NamedFile::open(path).await.ok().map(StaticFile)
+    }
+}
+
 /// Tells clients that they should cache the file received as a response.
 ///
 /// Caching is not activated while developing, to allow for modifications
@@ -41,3 +66,20 @@ impl<'r> Responder<'r, 'static> for StaticFile {
             .ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn only_files_inside_the_directory_are_opened() {
+        assert!(StaticFile::open(Path::new("css/room.css")).await.is_some());
+        assert!(StaticFile::open(Path::new("css/missing.css"))
+            .await
+            .is_none());
+        assert!(StaticFile::open(Path::new("../Cargo.toml")).await.is_none());
+        assert!(StaticFile::open(Path::new("css/../../Cargo.toml"))
+            .await
+            .is_none());
+    }
+}
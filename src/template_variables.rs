@@ -3,52 +3,236 @@
 //! This module provides code for retrieving and interacting with some data
 //! used as template variables. The data types from this module should probably
 //! implement some useful traits to make interaction with them easy.
+//!
+//! The welcome message is needed on every visit of the main page, but it rarely
+//! changes, so it's kept in memory for a short while, instead of being read
+//! from the database each time.
+//!
+//! Each saved value is also kept in the `template_history` table, so admins
+//! can restore earlier versions. Only the latest versions of each variable are
+//! kept, as many as the `template_history_len` setting allows.
 
-use rocket::data::ToByteUnit;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+use ::serde::{Deserialize, Serialize};
 use rocket::data::{Data, FromData};
-use rocket::http::Status;
 use rocket::outcome::try_outcome;
 use rocket::request::{self, FromRequest, Request};
 use rocket_sync_db_pools::postgres::row::Row;
-use rocket_sync_db_pools::postgres::Client;
 
-use crate::db::{self, DbConn};
+use crate::clock;
+use crate::config::ForumConfig;
+use crate::constraints;
+use crate::db::{self, Client, DbConn, GenericClient};
+use crate::limited_json::JsonLimit;
+use crate::sanitizer::Sanitizer;
 use crate::*;
 
-/// The maximum length (in bytes) allowed for a welcome message.
+/// The default maximum length (in bytes) allowed for a welcome message.
 pub const MAX_WELCOME_MESSAGE_LEN: usize = 2048;
 
+/// The names of the color themes known by the frontend.
+pub const THEMES: [&str; 3] = ["light", "dark", "hack2022"];
+
+/// The default number of versions kept in the history of each template variable.
+pub const DEFAULT_TEMPLATE_HISTORY_LEN: usize = 20;
+
+/// The names of the template variables which admins can change.
+pub const VARIABLES: [&str; 2] = ["welcome_message", "default_theme"];
+
+/// Saves the value of a template variable to the database, adding it to the
+/// variable's history.
+///
+/// If the variable has no history yet, its current value is saved as the first
+/// version, so it can still be restored after the change.
+///
+/// Only the latest `history_len` versions of the variable are kept.
+fn save_variable(
+    client: &mut Client,
+    name: &str,
+    value: &str,
+    admin: Option<&str>,
+    history_len: usize,
+) -> Result<(), db::Error> {
+    let now = clock::now_millis();
+    let mut transaction = client.transaction()?;
+    transaction.execute(
+        "INSERT INTO template_history (name, value, saved)
+        SELECT name, value, $2 FROM template_variables
+        WHERE name = $1 AND value IS NOT NULL
+        AND NOT EXISTS (SELECT 1 FROM template_history WHERE name = $1);",
+        &[&name, &now],
+    )?;
+    transaction.execute(
+        "INSERT INTO template_variables (name, value) VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE SET value = excluded.value;",
+        &[&name, &value],
+    )?;
+    transaction.execute(
+        "INSERT INTO template_history (name, value, saved, admin) VALUES ($1, $2, $3, $4);",
+        &[&name, &value, &now, &admin],
+    )?;
+    transaction.execute(
+        "DELETE FROM template_history WHERE name = $1 AND id NOT IN (
+            SELECT id FROM template_history WHERE name = $1 ORDER BY id DESC LIMIT $2
+        );",
+        &[&name, &(history_len as i64)],
+    )?;
+    transaction.commit()
+}
+
+/// Retrieves the value of a template variable from the database.
+///
+/// If the variable has not been set, an empty string is returned.
+fn load_variable(client: &mut Client, name: &str) -> String {
+    match query_one_row!(
+        client,
+        "SELECT value FROM template_variables WHERE name = $1;",
+        &[&name],
+        |row: Row| row.get::<_, Option<String>>(0)
+    ) {
+        Ok(value) => value.unwrap_or_default(),
+        // The variable might not exist yet in the database.
+        _ => "".into(),
+    }
+}
+
+/// A saved version of a template variable.
+#[derive(Serialize)]
+pub struct VariableVersion {
+    pub id: i32,
+    pub value: String,
+    /// The time (as a Unix timestamp in milliseconds) when the version was saved.
+    pub saved: i64,
+    /// The username of the admin who saved the version, if it's known.
+    pub admin: Option<String>,
+}
+
+impl VariableVersion {
+    /// Returns the saved versions of a template variable, newest first.
+    pub fn list(client: &mut Client, name: &str) -> Result<Vec<VariableVersion>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            "SELECT id, value, saved, admin FROM template_history
+            WHERE name = $1 ORDER BY id DESC;",
+            &[&name],
+            |row: Row| VariableVersion {
+                id: row.get(0),
+                value: row.get(1),
+                saved: row.get(2),
+                admin: row.get(3),
+            }
+        )
+        .collect())
+    }
+
+    /// Makes a saved version the current value of its variable again. The
+    /// restored value is added to the history, like any other saved value.
+    ///
+    /// It returns the name of the variable, or `None` if the version does not exist.
+    pub fn restore(
+        client: &mut Client,
+        id: i32,
+        admin: Option<&str>,
+        history_len: usize,
+    ) -> Result<Option<String>, db::Error> {
+        let (name, value): (String, String) = match client.query_opt(
+            "SELECT name, value FROM template_history WHERE id = $1;",
+            &[&id],
+        )? {
+            Some(row) => (row.get(0), row.get(1)),
+            None => return Ok(None),
+        };
+        save_variable(client, &name, &value, admin, history_len)?;
+        Ok(Some(name))
+    }
+}
+
 /// Represents an HTML string which should be displayed on the main page
 /// to greet users and give them some useful information.
 pub struct WelcomeMessage(pub String);
 
 impl WelcomeMessage {
-    /// Saves the message to the database.
-    pub fn save_to_db(&self, client: &mut Client) -> Result<(), db::Error> {
-        client
-            .execute(
-                "INSERT INTO template_variables (name, value) VALUES ('welcome_message', $1)
-            ON CONFLICT (name) DO UPDATE SET value = excluded.value;",
-                &[&self.0],
-            )
-            .and(Ok(()))
-            .map_err(Into::into)
+    /// Cleans a welcome message written by an admin, using the server's
+    /// sanitizer if there is one.
+    ///
+    /// It's important that this message is cleaned, otherwise an attacker
+    /// who manages to obtain admin rights might insert malicious code which
+    /// all users would receive.
+    pub fn clean(message: &str, sanitizer: Option<&Sanitizer>) -> Result<Self, String> {
+        let message = match sanitizer {
+            Some(sanitizer) => sanitizer.clean(message),
+            None => Ok(ammonia::clean(message)),
+        };
+        message
+            .map(Self)
+            .map_err(|_| "The message is too complex.".into())
+    }
+
+    /// Saves the message to the database, on behalf of an admin.
+    pub fn save_to_db(
+        &self,
+        client: &mut Client,
+        admin: Option<&str>,
+        history_len: usize,
+    ) -> Result<(), db::Error> {
+        save_variable(client, "welcome_message", &self.0, admin, history_len)
     }
 
     /// Retrieves the current welcome message from the database.
     ///
     /// If the welcome message has not been set, an empty message is returned.
     fn from_db(client: &mut Client) -> WelcomeMessage {
-        match query_one_row!(
-            client,
-            "SELECT value FROM template_variables WHERE name = 'welcome_message';",
-            &[],
-            |row: Row| WelcomeMessage(row.get(0))
-        ) {
-            Ok(message) => message,
-            // The message might not exist yet in the database.
-            _ => Self("".into()),
+        Self(load_variable(client, "welcome_message"))
+    }
+}
+
+/// A copy of the welcome message kept in memory, as managed state.
+///
+/// Copies expire after `welcome_message_cache_secs` seconds, and the copy
+/// should be invalidated whenever the message changes.
+#[derive(Default)]
+pub struct WelcomeMessageCache(Mutex<CacheState>);
+
+#[derive(Default)]
+struct CacheState {
+    /// The cached message, and the time when it was read.
+    message: Option<(String, Instant)>,
+    /// Incremented on each invalidation, so that messages read before an
+    /// invalidation are not cached after it.
+    generation: u64,
+}
+
+impl WelcomeMessageCache {
+    /// Returns the cached message, if it's younger than `ttl`.
+    fn get(&self, ttl: Duration) -> Option<String> {
+        let state = self.0.lock().ok()?;
+        match &state.message {
+            Some((message, read)) if read.elapsed() < ttl => Some(message.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the current generation of the cache.
+    fn generation(&self) -> u64 {
+        self.0.lock().map_or(0, |state| state.generation)
+    }
+
+    /// Caches a message read when the cache had the given generation.
+    fn set(&self, message: &str, generation: u64) {
+        if let Ok(mut state) = self.0.lock() {
+            if state.generation == generation {
+                state.message = Some((message.to_owned(), Instant::now()));
+            }
+        }
+    }
+
+    /// Removes the cached message, so the next request reads it from the database.
+    pub fn invalidate(&self) {
+        if let Ok(mut state) = self.0.lock() {
+            state.message = None;
+            state.generation += 1;
         }
     }
 }
@@ -58,8 +242,23 @@ impl<'r> FromRequest<'r> for WelcomeMessage {
     type Error = ();
 
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let cache = req.rocket().state::<WelcomeMessageCache>();
+        let ttl = req
+            .rocket()
+            .state::<ForumConfig>()
+            .map_or(0, |config| config.welcome_message_cache_secs);
+        let ttl = Duration::from_secs(ttl);
+
+        if let Some(message) = cache.and_then(|cache| cache.get(ttl)) {
+            return request::Outcome::Success(Self(message));
+        }
+
+        let generation = cache.map_or(0, WelcomeMessageCache::generation);
         let conn = try_outcome!(req.guard::<DbConn>().await);
         let msg = conn.run(WelcomeMessage::from_db).await;
+        if let Some(cache) = cache {
+            cache.set(&msg.0, generation);
+        }
         request::Outcome::Success(msg)
     }
 }
@@ -71,23 +270,193 @@ impl<'r> FromData<'r> for WelcomeMessage {
     /// Parses and cleans a welcome message from a body of data.
     ///
     /// The message should be sent as a plaintext string.
-    ///
-    /// It's important that this message is cleaned, otherwise an attacker
-    /// who manages to obtain admin rights might insert malicious code which
-    /// all users would receive.
-    async fn from_data(_req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
-        let message = match data
-            .open(MAX_WELCOME_MESSAGE_LEN.bytes())
-            .into_string()
-            .await
-        {
-            Ok(string) => string.into_inner(),
-            Err(err) => {
-                return data::Outcome::Error((Status::InternalServerError, format!("{:?}", err)));
-            }
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = match req.rocket().state::<ForumConfig>() {
+            Some(config) => config.constraints.max_welcome_message_len,
+            None => MAX_WELCOME_MESSAGE_LEN,
+        };
+
+        let message = match constraints::read_body(req, data, limit).await {
+            Ok(string) => string,
+            Err(err) => return data::Outcome::Error(err),
         };
 
-        let message = ammonia::clean(&message);
-        data::Outcome::Success(Self(message))
+        let sanitizer = req
+            .rocket()
+            .state::<ForumConfig>()
+            .map(|config| &config.sanitizer);
+        match WelcomeMessage::clean(&message, sanitizer) {
+            Ok(message) => data::Outcome::Success(message),
+            Err(reason) => data::Outcome::Error((Status::UnprocessableEntity, reason)),
+        }
+    }
+}
+
+/// The content of the JSON form through which admins change the welcome message.
+#[derive(Deserialize)]
+pub struct WelcomeMessageJson {
+    /// The new message, as HTML.
+    pub message: String,
+}
+
+impl JsonLimit for WelcomeMessageJson {
+    const NAME: &'static str = "welcome_message";
+}
+
+/// Describes the result of changing the welcome message through JSON.
+#[derive(Serialize)]
+pub struct WelcomeMessageResult {
+    /// Whether the message was saved.
+    pub ok: bool,
+    /// The message as it was stored, after being cleaned, if it was saved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_html: Option<String>,
+    /// The length (in bytes) of the submitted message.
+    ///
+    /// If the body was too large to be read, it's the number of bytes read.
+    pub length: usize,
+    /// The maximum length (in bytes) allowed for the submitted message.
+    pub max_length: usize,
+    /// A human-readable reason for why the message was not saved, if it wasn't.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Represents the name of the color theme which first-time visitors see.
+///
+/// Users can still choose another theme, which is stored client-side.
+/// An empty name means that the theme follows the preference of the browser.
+pub struct DefaultTheme(pub String);
+
+impl DefaultTheme {
+    /// Builds a default theme from its name, if it's a known theme or empty.
+    pub fn new(name: &str) -> Option<Self> {
+        let name = name.trim();
+        (name.is_empty() || THEMES.contains(&name)).then(|| Self(name.into()))
+    }
+
+    /// Saves the theme to the database, on behalf of an admin.
+    pub fn save_to_db(
+        &self,
+        client: &mut Client,
+        admin: Option<&str>,
+        history_len: usize,
+    ) -> Result<(), db::Error> {
+        save_variable(client, "default_theme", &self.0, admin, history_len)
+    }
+
+    /// Retrieves the current default theme from the database.
+    ///
+    /// If the default theme has not been set, an empty name is returned.
+    fn from_db(client: &mut Client) -> DefaultTheme {
+        Self(load_variable(client, "default_theme"))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DefaultTheme {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let conn = try_outcome!(req.guard::<DbConn>().await);
+        let theme = conn.run(DefaultTheme::from_db).await;
+        request::Outcome::Success(theme)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing;
+
+    fn values(client: &mut Client, name: &str) -> Vec<String> {
+        let versions = VariableVersion::list(client, name).unwrap();
+        versions.into_iter().map(|version| version.value).collect()
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn values_without_history_are_kept_as_first_version() {
+        let mut connection = testing::connect();
+        let mut client = connection.client();
+        let name = testing::unique_name("variable");
+        client
+            .execute(
+                "INSERT INTO template_variables (name, value) VALUES ($1, 'old');",
+                &[&name],
+            )
+            .unwrap();
+
+        save_variable(&mut client, &name, "new", Some("alice"), 20).unwrap();
+        assert_eq!(values(&mut client, &name), ["new", "old"]);
+
+        save_variable(&mut client, &name, "newer", None, 20).unwrap();
+        assert_eq!(values(&mut client, &name), ["newer", "new", "old"]);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn new_variables_are_not_seeded() {
+        let mut connection = testing::connect();
+        let mut client = connection.client();
+        let name = testing::unique_name("variable");
+
+        save_variable(&mut client, &name, "first", None, 20).unwrap();
+        assert_eq!(values(&mut client, &name), ["first"]);
+    }
+
+    #[test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    fn restored_versions_become_the_current_value() {
+        let mut connection = testing::connect();
+        let mut client = connection.client();
+        let name = testing::unique_name("variable");
+        for value in ["<p>first</p>", "<p>second</p>", "<p>third</p>"] {
+            save_variable(&mut client, &name, value, None, 20).unwrap();
+        }
+
+        let first = VariableVersion::list(&mut client, &name)
+            .unwrap()
+            .pop()
+            .unwrap();
+        let restored = VariableVersion::restore(&mut client, first.id, Some("alice"), 20);
+        assert_eq!(restored.unwrap().as_deref(), Some(name.as_str()));
+        assert_eq!(load_variable(&mut client, &name), "<p>first</p>");
+        assert_eq!(
+            values(&mut client, &name),
+            [
+                "<p>first</p>",
+                "<p>third</p>",
+                "<p>second</p>",
+                "<p>first</p>"
+            ]
+        );
+        assert_eq!(
+            VariableVersion::restore(&mut client, -1, None, 20).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn cached_welcome_messages_are_kept_until_invalidated() {
+        let cache = WelcomeMessageCache::default();
+        let ttl = Duration::from_secs(60);
+        assert_eq!(cache.get(ttl), None);
+
+        cache.set("Hello!", cache.generation());
+        assert_eq!(cache.get(ttl).as_deref(), Some("Hello!"));
+        assert_eq!(cache.get(Duration::ZERO), None);
+
+        cache.invalidate();
+        assert_eq!(cache.get(ttl), None);
+    }
+
+    #[test]
+    fn messages_read_before_an_invalidation_are_not_cached() {
+        let cache = WelcomeMessageCache::default();
+        let generation = cache.generation();
+        cache.invalidate();
+        cache.set("Old message", generation);
+        assert_eq!(cache.get(Duration::from_secs(60)), None);
     }
 }
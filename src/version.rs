@@ -0,0 +1,42 @@
+//! Module for describing the build of the server which is running.
+//!
+//! This information can help when debugging deployments, since it makes it
+//! easy to check which version of the code is actually being served.
+
+use rocket::Config;
+use serde::Serialize;
+
+/// Holds information about the running build of the server.
+#[derive(Serialize)]
+pub struct VersionInfo {
+    /// The version of the crate, as found in `Cargo.toml`.
+    version: &'static str,
+    /// The hash of the commit which was built, set by the build script.
+    commit: &'static str,
+    /// The Rocket profile the server is running with (e.g. "debug", "release").
+    profile: String,
+}
+
+impl VersionInfo {
+    /// Returns the version information of the server running with the given config.
+    pub fn new(config: &Config) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: env!("GIT_COMMIT_HASH"),
+            profile: config.profile.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_version_of_the_crate_is_reported() {
+        let info = VersionInfo::new(&Config::debug_default());
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.commit, env!("GIT_COMMIT_HASH"));
+        assert_eq!(info.profile, "debug");
+    }
+}
@@ -0,0 +1,344 @@
+//! Module for notifying other services of posted messages, through webhooks.
+//!
+//! For integrations, such as chat bridges or email notifications, admins can
+//! add webhooks to rooms. When a message is posted in a room, a JSON payload
+//! describing it is sent in a POST request to each webhook of the room.
+//! Requests are sent by a background task, so posting never waits for them.
+//! Failed requests are retried a few times, waiting twice as long before each
+//! retry, and then given up.
+//!
+//! Payloads wait in a queue of limited capacity, and are dropped (and logged)
+//! if it's full, so slow webhooks cannot make the server run out of memory.
+//! At most `max_concurrent_requests` webhooks are notified at once.
+//!
+//! Webhooks which have a secret receive the signature of each payload in the
+//! `X-Forum-Signature` header, as `sha256=<HMAC-SHA256 of the body, in hex>`,
+//! so they can check that the payload was sent by the server.
+//!
+//! Webhooks are disabled unless `enabled` is set in the `webhooks` section of
+//! the configuration. Sending requests also requires the server to be built
+//! with the `webhooks` feature. Like for archival, messages of encrypted rooms
+//! are never sent.
+
+use ::serde::{Deserialize, Serialize};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::tokio::sync::mpsc::error::TrySendError;
+use rocket::tokio::sync::mpsc::Sender;
+use rocket::{Build, Rocket};
+use rocket_sync_db_pools::postgres::row::Row;
+
+use crate::config::ForumConfig;
+use crate::db::{self, Client, GenericClient};
+use crate::messages::ShownId;
+use crate::*;
+
+/// The header which holds the signature of a payload.
+#[cfg(feature = "webhooks")]
+const SIGNATURE_HEADER: &str = "X-Forum-Signature";
+
+/// The settings of webhooks.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// Whether payloads are sent to the webhooks of rooms.
+    pub enabled: bool,
+    /// The number of times a payload is sent to a webhook, before giving up.
+    pub max_attempts: u32,
+    /// The duration (in milliseconds) waited before the first retry.
+    pub retry_delay_ms: u64,
+    /// The duration (in seconds) after which a request is considered failed.
+    pub timeout_secs: u64,
+    /// The number of payloads which can wait to be sent. Further payloads
+    /// are dropped until there is room in the queue again.
+    pub queue_capacity: usize,
+    /// The maximum number of webhooks notified at the same time, including
+    /// those waiting to retry.
+    pub max_concurrent_requests: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_attempts: 4,
+            retry_delay_ms: 1000,
+            timeout_secs: 10,
+            queue_capacity: 1000,
+            max_concurrent_requests: 16,
+        }
+    }
+}
+
+/// A webhook of a room, as it's shown to admins. Secrets are never shown.
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    /// Whether the payloads sent to the webhook are signed.
+    pub signed: bool,
+}
+
+/// The address of a webhook, and the secret used to sign its payloads.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+struct WebhookTarget {
+    url: String,
+    secret: Option<String>,
+}
+
+impl Webhook {
+    /// Returns the webhooks of a room.
+    pub fn list(client: &mut Client, room: &str) -> Result<Vec<Webhook>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            "SELECT id, url, secret IS NOT NULL FROM room_webhooks WHERE room = $1 ORDER BY id;",
+            &[&room],
+            |row: Row| Webhook {
+                id: row.get(0),
+                url: row.get(1),
+                signed: row.get(2),
+            }
+        )
+        .collect())
+    }
+
+    /// Adds a webhook to a room, returning its id.
+    ///
+    /// It returns `None` if the room does not exist.
+    pub fn add(
+        client: &mut Client,
+        room: &str,
+        url: &str,
+        secret: Option<&str>,
+    ) -> Result<Option<i32>, db::Error> {
+        client
+            .query_opt(
+                "INSERT INTO room_webhooks (room, url, secret)
+                SELECT name, $2, $3 FROM rooms WHERE name = $1
+                RETURNING id;",
+                &[&room, &url, &secret],
+            )
+            .map(|row| row.map(|row| row.get(0)))
+    }
+
+    /// Removes a webhook, returning true if it existed.
+    pub fn delete(client: &mut Client, id: i32) -> Result<bool, db::Error> {
+        client
+            .execute("DELETE FROM room_webhooks WHERE id = $1;", &[&id])
+            .map(|deleted| deleted > 0)
+    }
+
+    /// Returns the addresses and secrets of the webhooks of a room.
+    fn targets(client: &mut Client, room: &str) -> Result<Vec<WebhookTarget>, db::Error> {
+        Ok(query_and_map!(
+            client,
+            "SELECT url, secret FROM room_webhooks WHERE room = $1;",
+            &[&room],
+            |row: Row| WebhookTarget {
+                url: row.get(0),
+                secret: row.get(1),
+            }
+        )
+        .collect())
+    }
+}
+
+/// The content of a form used by admins to add webhooks to rooms.
+#[derive(FromForm)]
+pub struct WebhookForm {
+    pub room: String,
+    pub url: String,
+    /// The secret used to sign payloads. Payloads are not signed if it's
+    /// missing or empty.
+    pub secret: Option<String>,
+}
+
+/// The payload sent to webhooks when a message is posted.
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    pub room: String,
+    /// The id of the message, which is its public id if those are enabled.
+    pub id: ShownId,
+    pub timestamp: i64,
+    pub reply_to: Option<ShownId>,
+    pub system: bool,
+    /// The HTML content of the message, as it was stored.
+    pub content: String,
+}
+
+/// A payload, together with the webhooks which should receive it.
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+struct Delivery {
+    targets: Vec<WebhookTarget>,
+    payload: WebhookPayload,
+}
+
+/// A handle used to send payloads to webhooks, as managed state.
+///
+/// If webhooks are disabled, dispatching payloads does nothing.
+#[derive(Clone, Default)]
+pub struct WebhookDispatcher(Option<Sender<Delivery>>);
+
+impl WebhookDispatcher {
+    /// Checks if payloads are sent to webhooks.
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Queues a payload to be sent in the background to the webhooks of its room.
+    /// The payload is dropped if the queue is full.
+    ///
+    /// The database is only used to find the webhooks of the room.
+    pub fn dispatch(&self, client: &mut Client, payload: WebhookPayload) -> Result<(), db::Error> {
+        let sender = match &self.0 {
+            Some(sender) => sender,
+            None => return Ok(()),
+        };
+        let targets = Webhook::targets(client, &payload.room)?;
+        if targets.is_empty() {
+            return Ok(());
+        }
+        match sender.try_send(Delivery { targets, payload }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(delivery)) => eprintln!(
+                "Dropped the webhook payload of message {} of room {}, since the queue is full.",
+                delivery.payload.id, delivery.payload.room
+            ),
+            Err(TrySendError::Closed(_)) => {
+                eprintln!("Could not notify webhooks, since the dispatcher stopped.")
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A fairing which starts the webhook dispatcher and makes it available as
+/// managed state.
+///
+/// It should be attached after the `ConfigFairing`.
+#[derive(Default)]
+pub struct WebhookFairing;
+
+#[rocket::async_trait]
+impl Fairing for WebhookFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Webhook Fairing",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = match rocket.state::<ForumConfig>() {
+            Some(config) => config.webhooks.clone(),
+            None => return Err(rocket),
+        };
+        let dispatcher = if config.enabled {
+            start(config)
+        } else {
+            WebhookDispatcher::default()
+        };
+        Ok(rocket.manage(dispatcher))
+    }
+}
+
+/// Starts the background task which sends payloads to webhooks.
+#[cfg(feature = "webhooks")]
+fn start(config: WebhookConfig) -> WebhookDispatcher {
+    use rocket::serde::json::serde_json;
+    use rocket::tokio::sync::{mpsc, Semaphore};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let (sender, mut deliveries) = mpsc::channel::<Delivery>(config.queue_capacity);
+    let requests = Arc::new(Semaphore::new(config.max_concurrent_requests));
+    rocket::tokio::spawn(async move {
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+        {
+            Ok(client) => client,
+            Err(err) => {
+                eprintln!("Could not start the webhook dispatcher: {}", err);
+                return;
+            }
+        };
+
+        while let Some(delivery) = deliveries.recv().await {
+            let body = match serde_json::to_vec(&delivery.payload) {
+                Ok(body) => body,
+                Err(_) => continue,
+            };
+            // Each webhook is retried on its own, so slow webhooks do not delay the others.
+            // Payloads wait in the queue while too many webhooks are being notified.
+            for target in delivery.targets {
+                let permit = match requests.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+                let (client, body, config) = (client.clone(), body.clone(), config.clone());
+                rocket::tokio::spawn(async move {
+                    deliver(client, target, body, config).await;
+                    drop(permit);
+                });
+            }
+        }
+    });
+    WebhookDispatcher(Some(sender))
+}
+
+/// Sends a payload to a webhook, retrying with increasing delays if it fails.
+#[cfg(feature = "webhooks")]
+async fn deliver(
+    client: reqwest::Client,
+    target: WebhookTarget,
+    body: Vec<u8>,
+    config: WebhookConfig,
+) {
+    use std::time::Duration;
+
+    let mut delay = Duration::from_millis(config.retry_delay_ms);
+    let mut error = String::new();
+    for attempt in 1..=config.max_attempts {
+        let mut request = client
+            .post(&target.url)
+            .header("Content-Type", "application/json")
+            .body(body.clone());
+        if let Some(secret) = &target.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, &body));
+        }
+
+        match request.send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => error = format!("status {}", response.status()),
+            Err(err) => error = err.to_string(),
+        }
+        if attempt < config.max_attempts {
+            rocket::tokio::time::sleep(delay).await;
+            delay = delay.saturating_mul(2);
+        }
+    }
+    eprintln!(
+        "Gave up notifying webhook {} after {} attempts: {}",
+        target.url, config.max_attempts, error
+    );
+}
+
+/// Returns the signature of a payload, as sent in the `SIGNATURE_HEADER`.
+#[cfg(feature = "webhooks")]
+fn signature(secret: &str, body: &[u8]) -> String {
+    use hmac::{Hmac, Mac, NewMac};
+    use sha2::Sha256;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Reports that webhooks cannot be notified without the `webhooks` feature.
+#[cfg(not(feature = "webhooks"))]
+fn start(_config: WebhookConfig) -> WebhookDispatcher {
+    eprintln!("Webhooks are not notified, since the server was built without `webhooks`.");
+    WebhookDispatcher::default()
+}
@@ -0,0 +1,178 @@
+//! Module for filtering forbidden words out of messages.
+//!
+//! Communities can configure a list of words which should not appear in
+//! messages. Messages which contain them are either rejected, or have the
+//! words masked with asterisks, depending on the filter's mode.
+//!
+//! The filter works on the HTML of rendered messages, so only the text is
+//! checked, not the tags. Words are only matched as a whole, and regardless
+//! of case, so words which merely contain a forbidden word are not affected.
+//!
+//! The words can be listed in the `word_filter` section of the configuration,
+//! or in a file with one word per line. The filter is disabled if no words
+//! are configured.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// What happens to messages which contain forbidden words.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    /// The message is not saved.
+    Reject,
+    /// The forbidden words are replaced with asterisks.
+    #[default]
+    Mask,
+}
+
+/// Holds the configuration of the forbidden-words filter.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WordFilter {
+    /// The forbidden words.
+    pub words: Vec<String>,
+    /// A file containing more forbidden words, one per line.
+    pub words_file: Option<PathBuf>,
+    /// What happens to messages which contain forbidden words.
+    pub mode: FilterMode,
+    /// The forbidden words from all sources, in lowercase.
+    /// It's filled in by `load`.
+    #[serde(skip)]
+    forbidden: HashSet<String>,
+}
+
+impl WordFilter {
+    /// Gathers the forbidden words, reading the words file if there is one.
+    ///
+    /// If the file cannot be read, the reason is returned.
+    pub fn load(&mut self) -> Result<(), String> {
+        let from_file = match &self.words_file {
+            Some(path) => fs::read_to_string(path).map_err(|err| {
+                format!("Could not read the words file {}: {}", path.display(), err)
+            })?,
+            None => String::new(),
+        };
+
+        self.forbidden = self
+            .words
+            .iter()
+            .map(String::as_str)
+            .chain(from_file.lines())
+            .map(str::trim)
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        Ok(())
+    }
+
+    /// Checks the text of an HTML message for forbidden words.
+    ///
+    /// If the message contains any, it returns `None` when messages should be
+    /// rejected, or the message with the words masked otherwise.
+    pub fn apply(&self, html: &str) -> Option<String> {
+        if self.forbidden.is_empty() {
+            return Some(html.to_owned());
+        }
+
+        let mut filtered = String::with_capacity(html.len());
+        let mut found = false;
+        let mut in_tag = false;
+        let mut in_entity = false;
+        let mut word = String::new();
+
+        for c in html.chars() {
+            if !in_tag && !in_entity && c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+
+            found |= self.push_word(&mut filtered, &mut word);
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                // Character references, such as `&amp;`, are not words.
+                '&' if !in_tag => in_entity = true,
+                c if in_entity && !(c.is_alphanumeric() || c == '#') => in_entity = false,
+                _ => {}
+            }
+            filtered.push(c);
+        }
+        found |= self.push_word(&mut filtered, &mut word);
+
+        match (found, self.mode) {
+            (true, FilterMode::Reject) => None,
+            _ => Some(filtered),
+        }
+    }
+
+    /// Moves a word to the output, masking it if it's forbidden.
+    ///
+    /// It returns `true` if the word was forbidden.
+    fn push_word(&self, output: &mut String, word: &mut String) -> bool {
+        let forbidden = self.forbidden.contains(&word.to_lowercase());
+        if forbidden {
+            output.extend(word.chars().map(|_| '*'));
+        } else {
+            output.push_str(word);
+        }
+        word.clear();
+        forbidden
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(mode: FilterMode) -> WordFilter {
+        let mut filter = WordFilter {
+            words: vec!["darn".into(), " Heck ".into()],
+            mode,
+            ..WordFilter::default()
+        };
+        filter.load().unwrap();
+        filter
+    }
+
+    #[test]
+    fn forbidden_words_are_masked() {
+        let filter = filter(FilterMode::Mask);
+        assert_eq!(
+            filter.apply("<p>Darn it, heck!</p>").as_deref(),
+            Some("<p>**** it, ****!</p>")
+        );
+    }
+
+    #[test]
+    fn messages_with_forbidden_words_are_rejected() {
+        let filter = filter(FilterMode::Reject);
+        assert_eq!(filter.apply("<p>Oh, DARN.</p>"), None);
+        assert_eq!(
+            filter.apply("<p>Fine.</p>").as_deref(),
+            Some("<p>Fine.</p>")
+        );
+    }
+
+    #[test]
+    fn only_whole_words_of_the_text_match() {
+        let filter = filter(FilterMode::Reject);
+        for html in [
+            "<p>darned heckle</p>",
+            "<darn>fine</darn>",
+            r#"<a href="/darn">link</a>"#,
+            "<p>&heck;</p>",
+        ] {
+            assert_eq!(filter.apply(html).as_deref(), Some(html));
+        }
+    }
+
+    #[test]
+    fn empty_filters_keep_messages() {
+        let filter = WordFilter::default();
+        assert_eq!(filter.apply("<p>darn</p>").as_deref(), Some("<p>darn</p>"));
+    }
+}
@@ -60,6 +60,10 @@ const displayActiveRooms = (rooms) => {
         changeButton.textContent = 'Change';
         changeButton.addEventListener('click', () => changePassword(name, input.value));
 
+        const entryMessageButton = document.createElement('button');
+        entryMessageButton.textContent = 'Entry message';
+        entryMessageButton.addEventListener('click', () => changeEntryMessage(name));
+
         const deleteButton = document.createElement('button');
         deleteButton.classList.add('delete-button');
         deleteButton.textContent = 'Delete room!';
@@ -69,6 +73,7 @@ const displayActiveRooms = (rooms) => {
         controls.classList.add('room-controls');
         controls.appendChild(input);
         controls.appendChild(changeButton);
+        controls.appendChild(entryMessageButton);
         controls.appendChild(deleteButton);
 
         const item = document.createElement('li');
@@ -139,6 +144,34 @@ const changePassword = (name, password) => {
         .then(() => refreshRooms());
 }
 
+/**
+ * Prompts for the entry message of a given room, then sends it to the server.
+ * An empty message removes the room's current entry message.
+ * @param {string} name The name of the room whose entry message we want to change.
+ */
+const changeEntryMessage = (name) => {
+    const message = window.prompt(
+        `Type the message shown to users entering room '${name}'. ` +
+        `Leave it empty to remove the current message.`
+    );
+    if (message === null) {
+        return;
+    }
+
+    fetch('/change_entry_message', {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/x-www-form-urlencoded',
+        },
+        body: urlencodePairs({
+            name: name,
+            message: message.trim(),
+        }),
+    })
+        .then((response) => response.text())
+        .then((status) => alert(status));
+};
+
 /**
  * Converts the given (key, value) pairs into a URL-encoded string.
  * @param {object} pairsObject An object containing (key, value) pairs.
@@ -173,14 +206,22 @@ window.addEventListener('load', () => {
         fetch('/change_welcome_message', {
             method: 'POST',
             headers: {
-                'Content-Type': 'text/plain',
+                'Content-Type': 'application/json',
             },
-            body: content.value,
+            body: JSON.stringify({message: content.value}),
         })
-            .then((response) => response.text())
-            .then((status) => alert(status))
-            // We should reload the message, since the server might modify it.
-            .then(() => populateContent());
+            .then((response) => response.json())
+            .then((result) => {
+                if (!result.ok) {
+                    alert(`${result.reason} (${result.length}/${result.max_length} bytes)`);
+                    return;
+                }
+                // Show the message as it was stored, since the server might modify it.
+                content.value = result.stored_html;
+                const remaining = result.max_length - result.length;
+                alert(`Saved your message succesfully. ${remaining} bytes remain.`);
+            })
+            .catch(() => alert('Could not save your welcome message.'));
     });
 });
 
@@ -216,3 +257,12 @@ window.addEventListener('load', async () => {
 
 // Fetch and display all the active rooms when the page loads.
 window.addEventListener('load', refreshRooms);
+
+// Keep the list of rooms up to date when other admins change it, if the
+// server streams room events. Otherwise, the stream fails and is closed.
+window.addEventListener('load', () => {
+    const events = new EventSource('/rooms/stream');
+    for (const kind of ['created', 'deleted', 'archived', 'resync']) {
+        events.addEventListener(kind, refreshRooms);
+    }
+});
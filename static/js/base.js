@@ -1,16 +1,77 @@
 /** @file Provides code needed by all pages. */
 
+/**
+ * The preset color themes, by name.
+ * @type {object}
+ */
+const THEMES = {
+    light: {
+        '--background1': '#eceff1',
+        '--background2': '#90a4ae',
+        '--primary1': '#cfd8dc',
+        '--primary2': '#b0bec5',
+        '--secondary1': '#960018',
+        '--secondary2': '#7c0a02',
+        '--extra1': '#fafafa',
+        '--extra2': '#c21807',
+        '--text-color1': '#000000',
+        '--text-color2': '#660000',
+        '--text-color3': '#ffffff',
+        '--text-color4': '#fafafa',
+        '--text-faded1': '#424242',
+        '--mark-background': '#fafafa',
+        '--mark-text-color': '#660000',
+    },
+    dark: {
+        '--background1': '#121212',
+        '--background2': '#1f282d',
+        '--primary1': '#1f282d',
+        '--primary2': '#263a38',
+        '--secondary1': '#e64a19',
+        '--secondary2': '#d84315',
+        '--extra1': '#090c0d',
+        '--extra2': '#d84315',
+        '--text-color1': '#eceff1',
+        '--text-color2': '#ff5722',
+        '--text-color3': '#000000',
+        '--text-color4': '#eceff1',
+        '--text-faded1': '#90a4ae',
+        '--mark-background': '#090c0d',
+        '--mark-text-color': '#ff5722',
+    },
+    hack2022: {
+        '--background1': '#f6f6c9',
+        '--background2': '#4fa095',
+        '--primary1': '#bad1c2',
+        '--primary2': '#628e90',
+        '--secondary1': '#150050',
+        '--secondary2': '#0f3464',
+        '--extra1': '#fcfcfc',
+        '--extra2': '#c21807',
+        '--text-color1': '#153462',
+        '--text-color2': '#42032c',
+        '--text-color3': '#ffffff',
+        '--text-color4': '#fafafa',
+        '--text-faded1': '#424242',
+        '--mark-background': '#fafafa',
+        '--mark-text-color': '#660000',
+    },
+};
+
 /**
  * Loads the chosen colors from storage and returns them.
  *
- * If there are no colors stored, the default values are returned.
+ * If there are no colors stored, the colors of the server's default theme
+ * are returned. If the server has no default theme, the colors matching
+ * the browser's preference are returned.
  *
  * @return {Promise<object>} The object containing the color properties.
  */
 const loadStoredColors = async () => {
     const style = getComputedStyle(document.documentElement);
+    const defaultTheme = THEMES[document.documentElement.dataset.defaultTheme];
 
-    const storedColors = JSON.parse(localStorage.getItem('colors')) ?? {
+    const storedColors = JSON.parse(localStorage.getItem('colors')) ?? defaultTheme ?? {
         '--background1': style.getPropertyValue('--background1'),
         '--background2': style.getPropertyValue('--background2'),
         '--primary1': style.getPropertyValue('--primary1'),
@@ -1,5 +1,11 @@
 /** @file Provides code to interact with the room page. */
 
+/**
+ * The name of the room shown on the page.
+ * @type {string}
+ */
+const roomName = document.documentElement.dataset.roomName;
+
 /**
  * A global variable which maps ids to their corresponding threads.
  * @type {Map<number, Thread>}
@@ -23,7 +29,9 @@ class Message {
          * The content of the message, as an HTML string.
          * @type {string}
          */
-        this.content = messageStruct.content;
+        this.content = messageStruct.deleted
+            ? '<p><em>This message was deleted.</em></p>'
+            : messageStruct.content;
         /**
          * The UNIX timestamp of the moment the message was posted, in milliseconds.
          * @type {number}
@@ -35,6 +43,17 @@ class Message {
          * @type {number?}
          */
         this.replyTo = messageStruct.reply_to;
+        /**
+         * Whether the message was posted by the server itself.
+         * @type {boolean}
+         */
+        this.system = messageStruct.system;
+        /**
+         * A token shared by the messages of the same author in this thread.
+         * It's `null` if the server does not send author tokens.
+         * @type {string?}
+         */
+        this.authorToken = messageStruct.author_token ?? null;
     }
 
     /**
@@ -48,9 +67,12 @@ class Message {
 
         const box = document.createElement('div');
         box.classList.add('message');
+        if (this.system) {
+            box.classList.add('system-message');
+        }
         box.innerHTML = `
             <div class="message-info">
-                <p id="message${this.id}" class="message-id">#${this.id}</p>
+                <p id="message${this.id}" class="message-id">#${this.id}${this.authorTokenHtml()}</p>
                 <p class="message-timestamp">${this.timestampHtml()}</p>
             </div>
             <div class="message-content">
@@ -60,6 +82,18 @@ class Message {
         return box;
     }
 
+    /**
+     * Returns an HTML representation of the message's author token,
+     * or an empty string if it has none.
+     * @return {string} The HTML string.
+     */
+    authorTokenHtml() {
+        if (this.authorToken === null) {
+            return '';
+        }
+        return ` <span class="author-token" title="Author in this thread">${this.authorToken}</span>`;
+    }
+
     /**
      * Returns an HTML representation of the message's timestamp.
      * @return {string} The HTML string.
@@ -379,13 +413,76 @@ const sendMessageToServer = async (content, replyTo) => {
     });
 };
 
-/** Gets message updates from the server and redisplays all messages. */
+/**
+ * The cursor of the last updates received, for guests without a session.
+ * The server keeps the progress of other users itself.
+ */
+let guestCursor = null;
+
+/**
+ * Gets message updates from the server and redisplays all messages.
+ *
+ * The server might send the updates in several chunks, in which case
+ * they are all requested before redisplaying the messages.
+ */
 const refreshMessages = async () => {
-    const delta = await getDelta();
-    await applyDelta(delta, threads);
+    let delta;
+    do {
+        delta = await getDelta();
+        if (delta.cursor !== undefined) {
+            guestCursor = delta.cursor;
+        }
+        await applyDelta(delta, threads);
+    } while (delta.has_more);
+    await fillGaps().catch(() => {});
     displayThreads(threads);
 };
 
+/**
+ * Requests again the messages which are missing from the stored ones.
+ *
+ * The server numbers the messages of a room consecutively, through their
+ * `seq` field, so a missing number means a message was lost. Messages are
+ * numbered in the order of their ids, so the lost ones are requested by the
+ * range of ids between their neighbours.
+ */
+const fillGaps = async () => {
+    const stored = JSON.parse(localStorage.getItem(`msg${roomName}`)) ?? [];
+    const numbered = stored
+        .filter((message) => message.seq !== undefined)
+        .sort((a, b) => a.seq - b.seq);
+
+    let previous = {id: 0, seq: 0};
+    for (const message of numbered) {
+        if (message.seq > previous.seq + 1) {
+            await fetchIdRange(previous.id + 1, message.id - 1);
+        }
+        previous = message;
+    }
+};
+
+/**
+ * Fetches the messages with ids in the given range (both inclusive)
+ * and applies them to the threads' data structure.
+ * @param {number} from The id of the first message of the range.
+ * @param {number} to The id of the last message of the range.
+ * @throws Will throw an error if a response was not successful.
+ */
+const fetchIdRange = async (from, to) => {
+    while (from <= to) {
+        const response = await fetch(`/room/${roomName}/updates?from_id=${from}&to_id=${to}`);
+        if (!response.ok) {
+            throw new Error('Message range response was not OK.');
+        }
+        const delta = await response.json();
+        await applyDelta(delta, threads);
+        if (!delta.has_more || delta.messages.length === 0) {
+            return;
+        }
+        from = delta.messages[delta.messages.length - 1].id + 1;
+    }
+};
+
 /**
  * Requests the next "delta" update from the server and returns its response.
  *
@@ -406,7 +503,8 @@ const getDelta = async () => {
     // Block other calls from issuing the request.
     localStorage.setItem(`updating${roomName}`, 'true');
 
-    return fetch(`/room/${roomName}/updates`)
+    const query = guestCursor === null ? '' : `?after=${guestCursor}`;
+    return fetch(`/room/${roomName}/updates${query}`)
         .then((response) => {
             localStorage.setItem(`updating${roomName}`, 'false');
             if (!response.ok) {
@@ -433,12 +531,24 @@ const applyDelta = async (delta, threads) => {
         threads.clear();
     }
 
+    // Messages which changed (e.g. were deleted) replace their older versions.
+    const changedIds = new Set(delta.messages.map((message) => message.id));
+    const storedCount = messages.length;
+    messages = messages.filter((message) => !changedIds.has(message.id));
+    const replacedSome = messages.length !== storedCount;
+
     // Store the new messages.
     messages.push(...delta.messages);
     localStorage.setItem(`msg${roomName}`, JSON.stringify(messages));
 
     // Add the new messages to the threads data structure.
-    addMessagesToThreads(delta.messages, threads);
+    // If some messages were replaced, the threads have to be rebuilt.
+    if (replacedSome) {
+        threads.clear();
+        addMessagesToThreads(messages, threads);
+    } else {
+        addMessagesToThreads(delta.messages, threads);
+    }
 };
 
 /**
@@ -644,57 +754,9 @@ window.addEventListener('load', () => {
     });
 
     // Buttons for preset themes.
-    document.getElementById('light-theme-button').onclick = () => changeColors({
-        '--background1': '#eceff1',
-        '--background2': '#90a4ae',
-        '--primary1': '#cfd8dc',
-        '--primary2': '#b0bec5',
-        '--secondary1': '#960018',
-        '--secondary2': '#7c0a02',
-        '--extra1': '#fafafa',
-        '--extra2': '#c21807',
-        '--text-color1': '#000000',
-        '--text-color2': '#660000',
-        '--text-color3': '#ffffff',
-        '--text-color4': '#fafafa',
-        '--text-faded1': '#424242',
-        '--mark-background': '#fafafa',
-        '--mark-text-color': '#660000',
-    });
-    document.getElementById('dark-theme-button').onclick = () => changeColors({
-        '--background1': '#121212',
-        '--background2': '#1f282d',
-        '--primary1': '#1f282d',
-        '--primary2': '#263a38',
-        '--secondary1': '#e64a19',
-        '--secondary2': '#d84315',
-        '--extra1': '#090c0d',
-        '--extra2': '#d84315',
-        '--text-color1': '#eceff1',
-        '--text-color2': '#ff5722',
-        '--text-color3': '#000000',
-        '--text-color4': '#eceff1',
-        '--text-faded1': '#90a4ae',
-        '--mark-background': '#090c0d',
-        '--mark-text-color': '#ff5722',
-    });
-    document.getElementById('hack2022-theme-button').onclick = () => changeColors({
-        '--background1': '#f6f6c9',
-        '--background2': '#4fa095',
-        '--primary1': '#bad1c2',
-        '--primary2': '#628e90',
-        '--secondary1': '#150050',
-        '--secondary2': '#0f3464',
-        '--extra1': '#fcfcfc',
-        '--extra2': '#c21807',
-        '--text-color1': '#153462',
-        '--text-color2': '#42032c',
-        '--text-color3': '#ffffff',
-        '--text-color4': '#fafafa',
-        '--text-faded1': '#424242',
-        '--mark-background': '#fafafa',
-        '--mark-text-color': '#660000',
-    });
+    document.getElementById('light-theme-button').onclick = () => changeColors(THEMES.light);
+    document.getElementById('dark-theme-button').onclick = () => changeColors(THEMES.dark);
+    document.getElementById('hack2022-theme-button').onclick = () => changeColors(THEMES.hack2022);
 });
 
 // Set up the quick guide overlay.